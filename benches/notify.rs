@@ -0,0 +1,115 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kaspad_stratum::kaspad::{KaspadHandle, RpcBlock, RpcBlockHeader, RpcBlockLevelParents};
+use kaspad_stratum::stratum::jobs::{Expiry, JobParams, Jobs};
+use kaspad_stratum::stratum::NotifyFormat;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, watch};
+
+fn hash(seed: usize) -> String {
+    format!("{seed:064x}")
+}
+
+/// A template shaped like a mainnet one
+fn template() -> RpcBlock {
+    let parents = (0..8)
+        .map(|level| RpcBlockLevelParents {
+            parent_hashes: (0..10).map(|i| hash(level * 10 + i)).collect(),
+        })
+        .collect();
+    RpcBlock {
+        header: Some(RpcBlockHeader {
+            version: 1,
+            parents,
+            hash_merkle_root: hash(100),
+            accepted_id_merkle_root: hash(101),
+            utxo_commitment: hash(102),
+            timestamp: 1_700_000_000_000,
+            bits: 0x1b0404cb,
+            nonce: 0,
+            daa_score: 60_000_000,
+            blue_work: "3bc3ec5e1f2aa8d".into(),
+            pruning_point: hash(103),
+            blue_score: 59_000_000,
+        }),
+        transactions: vec![],
+        verbose_data: None,
+    }
+}
+
+fn job(rt: &Runtime) -> JobParams {
+    let (handle, _recv) = KaspadHandle::new();
+    let jobs = Jobs::new(handle, Expiry::Grace(Duration::ZERO), false);
+    rt.block_on(jobs.insert(template())).unwrap()
+}
+
+#[derive(Serialize)]
+struct Notify<'a> {
+    id: u64,
+    method: &'static str,
+    params: &'a RawValue,
+}
+
+/// What every connection does for a new job, the params are serialized
+/// once per job
+fn notify(job: &JobParams, id: u64) -> Vec<u8> {
+    let req = Notify {
+        id,
+        method: "mining.notify",
+        params: &job.notify(NotifyFormat::Words),
+    };
+    serde_json::to_vec(&req).unwrap()
+}
+
+fn serialization(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let job = job(&rt);
+    c.bench_function("notify_serialize", |b| b.iter(|| notify(&job, 1)));
+}
+
+fn fan_out(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("broadcast_fan_out");
+    group.measurement_time(Duration::from_secs(10));
+
+    for conns in [100u64, 1000, 5000] {
+        let (send, recv) = watch::channel(Some(job(&rt)));
+        let (done_send, mut done) = mpsc::unbounded_channel();
+        for i in 0..conns {
+            let mut recv = recv.clone();
+            let done_send = done_send.clone();
+            rt.spawn(async move {
+                let mut writer = tokio::io::sink();
+                while recv.changed().await.is_ok() {
+                    let data = notify(recv.borrow().as_ref().unwrap(), i);
+                    writer.write_all(&data).await.unwrap();
+                    writer.write_all(b"\n").await.unwrap();
+                    let _ = done_send.send(());
+                }
+            });
+        }
+
+        group.throughput(Throughput::Elements(conns));
+        group.bench_with_input(BenchmarkId::from_parameter(conns), &conns, |b, &conns| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        send.send_modify(|_| {});
+                        for _ in 0..conns {
+                            done.recv().await;
+                        }
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialization, fan_out);
+criterion_main!(benches);
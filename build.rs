@@ -1,15 +1,33 @@
 use std::path::Path;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let build = tonic_build::configure()
-        .type_attribute("protowire.RpcBlockHeader", "#[derive(serde::Serialize)]")
-        .type_attribute(
-            "protowire.RpcBlockLevelParents",
-            "#[derive(serde::Serialize)]",
+    // Blocks in kaspad's JSON shape, for template files
+    let mut build = tonic_build::configure();
+    for block_type in [
+        "RpcBlock",
+        "RpcBlockHeader",
+        "RpcBlockLevelParents",
+        "RpcBlockVerboseData",
+        "RpcTransaction",
+        "RpcTransactionInput",
+        "RpcScriptPublicKey",
+        "RpcTransactionOutput",
+        "RpcOutpoint",
+        "RpcTransactionVerboseData",
+        "RpcTransactionInputVerboseData",
+        "RpcTransactionOutputVerboseData",
+    ] {
+        build = build.type_attribute(
+            format!("protowire.{block_type}"),
+            "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"camelCase\", default)]",
         );
+    }
 
-    let proto_path: &Path = "proto/protowire.proto".as_ref();
-    let proto_dir = proto_path.parent().unwrap();
-    build.compile(&[proto_path], &[proto_dir])?;
+    let protos: [&Path; 2] = [
+        "proto/protowire.proto".as_ref(),
+        "proto/kaspawalletd.proto".as_ref(),
+    ];
+    let proto_dir = protos[0].parent().unwrap();
+    build.compile(&protos, &[proto_dir])?;
     Ok(())
 }
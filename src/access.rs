@@ -0,0 +1,425 @@
+use crate::admin::token_matches;
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{header, HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, warn};
+
+/// Time between checks of the ACL file
+const ACL_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// Listeners rules can be given for
+const LISTENERS: [&str; 4] = ["stratum", "metrics", "api", "admin"];
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`, a single address
+/// without the prefix length
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual stack listeners show up as mapped addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("invalid address {addr:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix length {p:?}"))?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length {prefix} is longer than the address");
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rule {
+    Allow,
+    Deny,
+}
+
+/// Rules of each listener in file order
+type Lists = HashMap<String, Vec<(Rule, Cidr)>>;
+
+/// Allow and deny rules per listener from a file, reloaded when it changes.
+/// Each line is `<listener> <allow|deny> <cidr>`, e.g. `admin allow
+/// 10.0.0.0/8`, with lines starting with `#` skipped. The first rule of the
+/// listener matching a client decides, clients matching none are allowed
+/// unless the listener has allow rules.
+#[derive(Clone)]
+pub struct Acl {
+    lists: Arc<RwLock<Lists>>,
+    path: Arc<PathBuf>,
+    /// Contents of the file the rules were parsed from
+    text: Arc<Mutex<String>>,
+}
+
+impl Acl {
+    /// Load the file and watch it, it has to be valid at start
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let text = read(&path)?;
+        let acl = Acl {
+            lists: Arc::new(RwLock::new(parse_acl(&text)?)),
+            path: Arc::new(path),
+            text: Arc::new(Mutex::new(text)),
+        };
+        tokio::spawn(acl.clone().watch());
+        Ok(acl)
+    }
+
+    pub fn allows(&self, listener: &str, ip: IpAddr) -> bool {
+        let lists = self.lists.read().unwrap();
+        let rules = match lists.get(listener) {
+            Some(rules) => rules,
+            None => return true,
+        };
+        match rules.iter().find(|(_, net)| net.contains(ip)) {
+            Some((rule, _)) => *rule == Rule::Allow,
+            None => !rules.iter().any(|(rule, _)| *rule == Rule::Allow),
+        }
+    }
+
+    /// Switch to the rules in the file if it changed, whether it did. A
+    /// broken edit keeps the previous rules until it's fixed.
+    pub fn reload(&self) -> Result<bool> {
+        let current = read(&self.path)?;
+        let mut text = self.text.lock().unwrap();
+        if current == *text {
+            return Ok(false);
+        }
+        let lists =
+            parse_acl(&current).with_context(|| format!("{} is invalid", self.path.display()))?;
+        *self.lists.write().unwrap() = lists;
+        *text = current;
+        info!("Reloaded the ACL {}", self.path.display());
+        Ok(true)
+    }
+
+    async fn watch(self) {
+        let mut interval = time::interval_at(
+            time::Instant::now() + ACL_RELOAD_INTERVAL,
+            ACL_RELOAD_INTERVAL,
+        );
+        // Warned about once, not on every check
+        let mut failed = None;
+        loop {
+            interval.tick().await;
+            match self.reload() {
+                Ok(_) => failed = None,
+                Err(e) => {
+                    let error = format!("{e:#}");
+                    if failed.as_ref() != Some(&error) {
+                        warn!("Keeping the previous ACL: {error}");
+                        failed = Some(error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read(path: &PathBuf) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))
+}
+
+fn parse_acl(text: &str) -> Result<Lists> {
+    let mut lists = Lists::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (listener, rule, net) = match fields[..] {
+            [listener, rule, net] => (listener, rule, net),
+            _ => bail!("Line {} isn't <listener> <allow|deny> <cidr>", i + 1),
+        };
+        if !LISTENERS.contains(&listener) {
+            bail!("Line {}: unknown listener {listener:?}", i + 1);
+        }
+        let rule = match rule {
+            "allow" => Rule::Allow,
+            "deny" => Rule::Deny,
+            _ => bail!("Line {}: {rule:?} is neither allow nor deny", i + 1),
+        };
+        let net = net.parse().with_context(|| format!("Line {}", i + 1))?;
+        lists.entry(listener.into()).or_default().push((rule, net));
+    }
+    Ok(lists)
+}
+
+/// Read `<user> <token>` lines, skipping those starting with `#`. A file
+/// without any is refused, as no token would leave the admin API open.
+pub fn read_users(path: &PathBuf) -> Result<Vec<(String, String)>> {
+    let mut users = vec![];
+    for (i, line) in read(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((user, token)) => users.push((user.into(), token.trim().into())),
+            None => bail!("Line {} of {} has no token", i + 1, path.display()),
+        }
+    }
+    if users.is_empty() {
+        bail!("{} has no users", path.display());
+    }
+    Ok(users)
+}
+
+/// Who may use an HTTP listener
+#[derive(Clone, Default)]
+pub struct Access {
+    /// Bearer tokens accepted with the user they belong to, empty for
+    /// unnamed tokens. No token is needed if there are none.
+    tokens: Arc<[(String, String)]>,
+    /// Networks clients may connect from, any if empty
+    allow: Arc<[Cidr]>,
+    /// Rules of the ACL file and the listener they're looked up for
+    acl: Option<(Acl, &'static str)>,
+}
+
+impl Access {
+    /// Empty tokens are left out
+    pub fn new(tokens: impl IntoIterator<Item = String>, allow: &[Cidr]) -> Self {
+        Access::default()
+            .with_users(tokens.into_iter().map(|t| (String::new(), t)))
+            .allow(allow)
+    }
+
+    /// Also accept the tokens of these `(user, token)`s
+    pub fn with_users(self, users: impl IntoIterator<Item = (String, String)>) -> Self {
+        let tokens = self.tokens.iter().cloned();
+        let users = users.into_iter().filter(|(_, t)| !t.is_empty());
+        Access {
+            tokens: tokens.chain(users).collect(),
+            ..self
+        }
+    }
+
+    fn allow(self, allow: &[Cidr]) -> Self {
+        Access {
+            allow: allow.into(),
+            ..self
+        }
+    }
+
+    /// Also apply the rules of `listener` in the ACL
+    pub fn with_acl(self, acl: Option<&Acl>, listener: &'static str) -> Self {
+        Access {
+            acl: acl.map(|acl| (acl.clone(), listener)),
+            ..self
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip));
+        allowed
+            && self
+                .acl
+                .as_ref()
+                .is_none_or(|(acl, listener)| acl.allows(listener, ip))
+    }
+
+    /// The status to refuse a request with, `None` if it may pass
+    pub fn check(&self, ip: IpAddr, headers: &HeaderMap) -> Option<StatusCode> {
+        if !self.allows(ip) {
+            return Some(StatusCode::FORBIDDEN);
+        }
+        if self.tokens.is_empty() {
+            return None;
+        }
+        let valid = self.user(headers).is_some();
+        (!valid).then_some(StatusCode::UNAUTHORIZED)
+    }
+
+    /// The user of the request's token, empty for unnamed tokens
+    pub fn user(&self, headers: &HeaderMap) -> Option<&str> {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        self.tokens
+            .iter()
+            .find(|(_, t)| token_matches(given, t))
+            .map(|(user, _)| user.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_acl, read_users, Access, Acl, Cidr};
+    use hyper::{header, HeaderMap, StatusCode};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_networks() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(net.contains(ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::1")));
+        let host: Cidr = "192.168.1.5".parse().unwrap();
+        assert_eq!(host.to_string(), "192.168.1.5/32");
+        assert!(host.contains(ip("192.168.1.5")));
+        assert!(!host.contains(ip("192.168.1.6")));
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn checks_requests() {
+        let headers = |token: &str| {
+            let mut headers = HeaderMap::new();
+            let value = format!("Bearer {token}").parse().unwrap();
+            headers.insert(header::AUTHORIZATION, value);
+            headers
+        };
+        let open = Access::default();
+        assert_eq!(open.check(ip("8.8.8.8"), &HeaderMap::new()), None);
+
+        let lan = ["10.0.0.0/8".parse().unwrap()];
+        let access = Access::new(["read".into(), "admin".into(), "".into()], &lan);
+        assert_eq!(access.check(ip("10.0.0.1"), &headers("read")), None);
+        assert_eq!(access.check(ip("10.0.0.1"), &headers("admin")), None);
+        assert_eq!(
+            access.check(ip("10.0.0.1"), &headers("")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            access.check(ip("10.0.0.1"), &HeaderMap::new()),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            access.check(ip("8.8.8.8"), &headers("read")),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        let users = access.with_users([("alice".into(), "a11ce".into())]);
+        assert_eq!(users.check(ip("10.0.0.1"), &headers("a11ce")), None);
+        assert_eq!(users.user(&headers("a11ce")), Some("alice"));
+        assert_eq!(users.user(&headers("admin")), Some(""));
+        assert_eq!(users.user(&headers("bob")), None);
+    }
+
+    #[tokio::test]
+    async fn applies_acls() {
+        let text = "\
+# admin from the LAN only
+admin deny 10.9.0.0/16
+admin allow 10.0.0.0/8
+stratum deny 203.0.113.0/24
+";
+        let path = std::env::temp_dir().join(format!("kaspad-stratum-acl-{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let acl = Acl::load(path.clone()).unwrap();
+        assert!(acl.allows("admin", ip("10.1.2.3")));
+        assert!(!acl.allows("admin", ip("8.8.8.8")));
+        // The first matching rule decides
+        assert!(!acl.allows("admin", ip("10.9.0.1")));
+        // Only listeners with allow rules refuse unmatched clients
+        assert!(acl.allows("stratum", ip("8.8.8.8")));
+        assert!(!acl.allows("stratum", ip("203.0.113.7")));
+        assert!(acl.allows("metrics", ip("203.0.113.7")));
+
+        let access = Access::default().with_acl(Some(&acl), "admin");
+        assert!(access.allows(ip("10.0.0.1")));
+        assert_eq!(
+            access.check(ip("8.8.8.8"), &HeaderMap::new()),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        for invalid in [
+            "admin allow",
+            "relay allow 10.0.0.0/8",
+            "admin permit 10.0.0.0/8",
+            "admin allow 10.0.0.0/40",
+        ] {
+            assert!(parse_acl(invalid).is_err(), "{invalid}");
+        }
+
+        assert!(!acl.reload().unwrap());
+        std::fs::write(&path, "admin allow 8.8.8.0/24\n").unwrap();
+        assert!(acl.reload().unwrap());
+        assert!(acl.allows("admin", ip("8.8.8.8")));
+        // A broken edit keeps the rules in effect
+        std::fs::write(&path, "admin allow").unwrap();
+        assert!(acl.reload().is_err());
+        assert!(acl.allows("admin", ip("8.8.8.8")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_users() {
+        let path =
+            std::env::temp_dir().join(format!("kaspad-stratum-users-{}", std::process::id()));
+        std::fs::write(&path, "# admins\nalice  secret\n\nbob other\n").unwrap();
+        let users = read_users(&path).unwrap();
+        assert_eq!(
+            users,
+            [
+                ("alice".to_string(), "secret".to_string()),
+                ("bob".into(), "other".into())
+            ]
+        );
+        // Without tokens the admin API would take any request
+        for invalid in ["", "# nobody yet\n", "alice\n"] {
+            std::fs::write(&path, invalid).unwrap();
+            assert!(read_users(&path).is_err(), "{invalid:?}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use crate::access::{Access, Acl};
+use crate::http;
+use crate::stratum::{Auth, Control, Tls};
+use anyhow::{bail, Context, Result};
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Operator API managing a running instance over HTTP with JSON bodies.
+/// Every request needs an admin token as `Authorization: Bearer <token>`.
+#[derive(Clone)]
+pub struct Admin {
+    control: Control,
+    access: Access,
+    /// Woken when the operator asks for a new template
+    refresh: Arc<Notify>,
+    audit: Option<AuditLog>,
+    /// Re-read on `POST /reload`, by name
+    reloadable: Vec<(&'static str, Reloadable)>,
+}
+
+/// Settings read from a file, which can change without a restart
+#[derive(Clone)]
+pub enum Reloadable {
+    Acl(Acl),
+    Tls(Tls),
+    Auth(Auth),
+}
+
+impl Reloadable {
+    /// Whether the file changed since it was last read
+    fn reload(&self) -> Result<bool> {
+        match self {
+            Reloadable::Acl(acl) => acl.reload(),
+            Reloadable::Tls(tls) => tls.reload(),
+            Reloadable::Auth(auth) => auth.reload(),
+        }
+    }
+}
+
+/// Appends every request of the admin API that changes something, refused
+/// ones included, to a file as one JSON object per line
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        Ok(AuditLog {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn record(&self, entry: &Value) {
+        let line = format!("{entry}\n");
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Unable to write the audit log: {e}");
+        }
+    }
+}
+
+/// What the audit log keeps of a request
+struct Action<'a> {
+    ip: IpAddr,
+    /// Empty for unnamed tokens, `None` if refused
+    user: Option<&'a str>,
+    method: &'a Method,
+    path: &'a str,
+    body: &'a [u8],
+    status: StatusCode,
+    response: &'a Value,
+}
+
+impl Action<'_> {
+    fn to_json(&self, time: SystemTime) -> Value {
+        let time = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        // Bodies are kept as sent when they aren't JSON
+        let request = serde_json::from_slice(self.body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(self.body).into()));
+        json!({
+            "time": time,
+            "user": self.user,
+            "ip": self.ip.to_canonical(),
+            "method": self.method.as_str(),
+            "path": self.path,
+            "request": request,
+            "status": self.status.as_u16(),
+            "response": self.response,
+        })
+    }
+}
+
+/// Connections of a worker or from an address, or one by its id
+#[derive(Deserialize)]
+struct Target {
+    worker: Option<String>,
+    ip: Option<IpAddr>,
+    id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Ban {
+    ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct SetDifficulty {
+    worker: String,
+    /// In stratum units
+    difficulty: f64,
+}
+
+#[derive(Deserialize)]
+struct DryRun {
+    enabled: bool,
+}
+
+impl Admin {
+    pub fn new(
+        control: Control,
+        access: Access,
+        refresh: Arc<Notify>,
+        audit: Option<AuditLog>,
+    ) -> Self {
+        Admin {
+            control,
+            access,
+            refresh,
+            audit,
+            reloadable: vec![],
+        }
+    }
+
+    /// Also re-read `reloadable` on `POST /reload`
+    pub fn with_reloadable(mut self, name: &'static str, reloadable: Reloadable) -> Self {
+        self.reloadable.push((name, reloadable));
+        self
+    }
+
+    /// Re-read every reloadable setting, listing which changed, which
+    /// didn't and why the others failed
+    fn reload(&self) -> Value {
+        let (mut changed, mut unchanged, mut failed) = (vec![], vec![], serde_json::Map::new());
+        for (name, reloadable) in &self.reloadable {
+            match reloadable.reload() {
+                Ok(true) => changed.push(*name),
+                Ok(false) => unchanged.push(*name),
+                Err(e) => {
+                    warn!("Unable to reload the {name}: {e:#}");
+                    failed.insert(name.to_string(), format!("{e:#}").into());
+                }
+            }
+        }
+        info!("Admin reloaded the settings, changed: {changed:?}");
+        json!({ "changed": changed, "unchanged": unchanged, "failed": failed })
+    }
+
+    fn audit(&self, action: Action) {
+        if let Some(audit) = &self.audit {
+            // Reads change nothing
+            if action.method != Method::GET {
+                audit.record(&action.to_json(SystemTime::now()));
+            }
+        }
+    }
+
+    pub async fn serve(self, addr: SocketAddr, tls: Option<Tls>) -> Result<()> {
+        info!("Serving the admin API on {addr}");
+        http::serve(addr, tls, move |ip| {
+            let admin = self.clone();
+            service_fn(move |req| handle(admin.clone(), ip, req))
+        })
+        .await
+    }
+
+    /// `None` for unknown routes
+    fn route(&self, method: &Method, path: &str, body: &[u8]) -> Result<Option<Value>> {
+        let res = match (method, path) {
+            (&Method::GET, "/workers") => serde_json::to_value(self.control.connections())?,
+            (&Method::POST, "/kick") => {
+                let target: Target = serde_json::from_slice(body)?;
+                let kicked = match (target.worker, target.ip, target.id) {
+                    (Some(worker), None, None) => {
+                        let kicked = self.control.kick_worker(&worker);
+                        info!("Admin kicked {kicked} connections of {worker}");
+                        kicked
+                    }
+                    (None, Some(ip), None) => {
+                        let kicked = self.control.kick_ip(ip);
+                        info!("Admin kicked {kicked} connections from {ip}");
+                        kicked
+                    }
+                    (None, None, Some(id)) => {
+                        let kicked = self.control.kick_connection(id);
+                        info!("Admin kicked {kicked} connections with id {id}");
+                        kicked
+                    }
+                    _ => bail!("Expected one of worker, ip or id"),
+                };
+                json!({ "kicked": kicked })
+            }
+            (&Method::POST, "/ban") => {
+                let ban: Ban = serde_json::from_slice(body)?;
+                let kicked = self.control.ban(ban.ip);
+                info!("Admin banned {}, closing {kicked} connections", ban.ip);
+                json!({ "kicked": kicked })
+            }
+            (&Method::POST, "/difficulty") => {
+                let req: SetDifficulty = serde_json::from_slice(body)?;
+                if !(req.difficulty > 0.0 && req.difficulty.is_finite()) {
+                    bail!("Difficulty must be positive");
+                }
+                let updated = self.control.set_difficulty(&req.worker, req.difficulty);
+                info!(
+                    "Admin set the difficulty of {} to {} on {updated} connections",
+                    req.worker, req.difficulty
+                );
+                json!({ "updated": updated })
+            }
+            (&Method::POST, "/refresh") => {
+                info!("Admin requested a new template");
+                self.refresh.notify_one();
+                json!({})
+            }
+            (&Method::POST, "/reload") => self.reload(),
+            (&Method::GET, "/dry-run") => json!({ "enabled": self.control.dry_run() }),
+            (&Method::PUT, "/dry-run") => {
+                let req: DryRun = serde_json::from_slice(body)?;
+                info!("Admin set dry run to {}", req.enabled);
+                self.control.set_dry_run(req.enabled);
+                json!({ "enabled": req.enabled })
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(res))
+    }
+}
+
+/// Compared in constant time, so the token can't be guessed byte by byte
+pub(crate) fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn handle(
+    admin: Admin,
+    ip: IpAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    if let Some(status) = admin.access.check(ip, req.headers()) {
+        let error = json!({ "error": status.canonical_reason().unwrap_or_default() });
+        admin.audit(Action {
+            ip,
+            user: None,
+            method: &method,
+            path: &path,
+            body: &[],
+            status,
+            response: &error,
+        });
+        return Ok(reply(status, error));
+    }
+    let user = admin.access.user(req.headers()).map(String::from);
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(e) => {
+            let error = json!({ "error": e.to_string() });
+            return Ok(reply(StatusCode::BAD_REQUEST, error));
+        }
+    };
+    let (status, res) = match admin.route(&method, &path, &body) {
+        Ok(Some(res)) => (StatusCode::OK, res),
+        Ok(None) => (StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+        Err(e) => (StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+    };
+    admin.audit(Action {
+        ip,
+        user: user.as_deref(),
+        method: &method,
+        path: &path,
+        body: &body,
+        status,
+        response: &res,
+    });
+    Ok(reply(status, res))
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Body> {
+    let mut res = Response::new(Body::from(body.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::{token_matches, Action};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn matches_tokens() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+
+    #[test]
+    fn audits_actions() {
+        let action = Action {
+            ip: "::ffff:10.0.0.5".parse().unwrap(),
+            user: Some("alice"),
+            method: &Method::POST,
+            path: "/ban",
+            body: br#"{"ip": "1.2.3.4"}"#,
+            status: StatusCode::OK,
+            response: &json!({ "kicked": 2 }),
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            action.to_json(time),
+            json!({
+                "time": 1_700_000_000,
+                "user": "alice",
+                "ip": "10.0.0.5",
+                "method": "POST",
+                "path": "/ban",
+                "request": { "ip": "1.2.3.4" },
+                "status": 200,
+                "response": { "kicked": 2 },
+            })
+        );
+        let refused = Action {
+            user: None,
+            body: b"not json",
+            status: StatusCode::UNAUTHORIZED,
+            ..action
+        };
+        let entry = refused.to_json(time);
+        assert_eq!(entry["user"], json!(null));
+        assert_eq!(entry["request"], "not json");
+    }
+}
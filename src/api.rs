@@ -0,0 +1,326 @@
+use crate::access::Access;
+use crate::http;
+use crate::stratum::{format_hashrate, Accounting, Stats, Tls, Window};
+use anyhow::Result;
+use hyper::service::service_fn;
+use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Read-only pool statistics over HTTP for dashboards and miners
+#[derive(Clone)]
+pub struct Api {
+    stats: Stats,
+    accounting: Option<Accounting>,
+    access: Access,
+}
+
+impl Api {
+    pub fn new(stats: Stats, accounting: Option<Accounting>, access: Access) -> Self {
+        Api {
+            stats,
+            accounting,
+            access,
+        }
+    }
+
+    pub async fn serve(self, addr: SocketAddr, tls: Option<Tls>) -> Result<()> {
+        info!("Serving the stats API on {addr}");
+        http::serve(addr, tls, move |ip| {
+            let api = self.clone();
+            service_fn(move |req| handle(api.clone(), ip, req))
+        })
+        .await
+    }
+
+    /// When the list behind a route last changed, for conditional requests
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        match (path, &self.accounting) {
+            ("/rounds", Some(accounting)) => accounting.rounds_modified(),
+            ("/shares", _) => self.stats.shares_modified(),
+            _ => None,
+        }
+    }
+
+    /// `None` for unknown routes
+    fn route(&self, path: &str, query: Option<&str>) -> Result<Option<Value>> {
+        let res = match (path, &self.accounting) {
+            ("/rounds", Some(accounting)) => {
+                Page::parse(query)?.apply(accounting.rounds(), |r| r.time)?
+            }
+            ("/shares", _) => Page::parse(query)?.apply(self.stats.latest_shares(), |s| s.time)?,
+            ("/balances", Some(accounting)) => serde_json::to_value(accounting.balances())?,
+            (path, Some(accounting)) if path.starts_with("/balances/") => {
+                let address = &path["/balances/".len()..];
+                // Addresses without credits have nothing to show
+                let balance = accounting.balance(address).unwrap_or_default();
+                serde_json::to_value(balance)?
+            }
+            ("/reward", _) => match self.stats.reward() {
+                Some((daa_score, reward)) => json!({
+                    "daa_score": daa_score,
+                    "subsidy": reward.subsidy,
+                    "fees": reward.fees,
+                    "total": reward.total(),
+                }),
+                None => Value::Null,
+            },
+            ("/workers", _) => serde_json::to_value(self.stats.worker_hashrates())?,
+            (path, _) if path.starts_with("/workers/") => {
+                let worker = &path["/workers/".len()..];
+                let hashrate = match self.stats.worker_hashrates().remove(worker) {
+                    Some(h) => h,
+                    None => return Ok(None),
+                };
+                let mut charts = serde_json::Map::new();
+                for window in Window::ALL {
+                    let chart = self.stats.worker_chart(worker, window);
+                    charts.insert(window.name().into(), serde_json::to_value(chart)?);
+                }
+                let formatted = json!({
+                    "5m": format_hashrate(hashrate.five_minutes),
+                    "1h": format_hashrate(hashrate.hour),
+                    "24h": format_hashrate(hashrate.day),
+                });
+                json!({ "hashrate": hashrate, "formatted": formatted, "charts": charts })
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(res))
+    }
+}
+
+/// A query the client has to fix
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+/// Slice of a list, newest first, from `?limit=&offset=&from=&to=` with
+/// the times in seconds since the epoch
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Page {
+    limit: Option<usize>,
+    offset: usize,
+    /// Inclusive
+    from: Option<u64>,
+    /// Exclusive
+    to: Option<u64>,
+}
+
+impl Page {
+    fn parse(query: Option<&str>) -> Result<Self> {
+        let mut page = Page::default();
+        for pair in query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|p| !p.is_empty())
+        {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| BadRequest(format!("Invalid {key}: {value:?}")))
+            };
+            match key {
+                "limit" => page.limit = Some(number()? as usize),
+                "offset" => page.offset = number()? as usize,
+                "from" => page.from = Some(number()?),
+                "to" => page.to = Some(number()?),
+                _ => return Err(BadRequest(format!("Unknown parameter {key}")).into()),
+            }
+        }
+        Ok(page)
+    }
+
+    /// Apply to a list ordered oldest first
+    fn apply<T: Serialize>(&self, items: Vec<T>, time: impl Fn(&T) -> u64) -> Result<Value> {
+        let items: Vec<_> = items
+            .into_iter()
+            .rev()
+            .filter(|item| {
+                let time = time(item);
+                self.from.is_none_or(|from| time >= from) && self.to.is_none_or(|to| time < to)
+            })
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        // Through text, as `Value` can't hold u128 such as the work of a round
+        Ok(serde_json::from_str(&serde_json::to_string(&items)?)?)
+    }
+}
+
+/// Validator of a list, changing with every modification
+fn etag(modified: SystemTime) -> String {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{nanos:x}\"")
+}
+
+/// Whether the client's copy, validated by `If-None-Match` or else
+/// `If-Modified-Since`, is still current
+fn not_modified(headers: &HeaderMap, modified: SystemTime) -> bool {
+    if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+        let etag = etag(modified);
+        return tags.to_str().is_ok_and(|tags| {
+            tags.split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == etag)
+        });
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    // HTTP dates have a resolution of seconds
+    let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    since.is_some_and(|since| secs(modified) <= secs(since))
+}
+
+async fn handle(api: Api, ip: IpAddr, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if let Some(status) = api.access.check(ip, req.headers()) {
+        let error = status.canonical_reason().unwrap_or_default();
+        return Ok(reply(status, json!({ "error": error })));
+    }
+    if req.method() != Method::GET {
+        return Ok(reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "Method not allowed" }),
+        ));
+    }
+    let path = req.uri().path();
+    let modified = api.modified(path);
+    // Lists the client already has aren't built again
+    let mut res = match modified {
+        Some(modified) if not_modified(req.headers(), modified) => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::NOT_MODIFIED;
+            res
+        }
+        _ => match api.route(path, req.uri().query()) {
+            Ok(Some(res)) => reply(StatusCode::OK, res),
+            Ok(None) => reply(StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+            Err(e) if e.is::<BadRequest>() => {
+                reply(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() }))
+            }
+            Err(e) => reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": e.to_string() }),
+            ),
+        },
+    };
+    let cacheable = matches!(res.status(), StatusCode::OK | StatusCode::NOT_MODIFIED);
+    if let (Some(modified), true) = (modified, cacheable) {
+        let headers = res.headers_mut();
+        if let Ok(etag) = etag(modified).parse() {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Ok(date) = httpdate::fmt_http_date(modified).parse() {
+            headers.insert(header::LAST_MODIFIED, date);
+        }
+    }
+    Ok(res)
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Body> {
+    let mut res = Response::new(Body::from(body.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::{etag, not_modified, Page};
+    use hyper::{header, HeaderMap};
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn pages_lists() {
+        assert_eq!(Page::parse(None).unwrap(), Page::default());
+        let page = Page::parse(Some("limit=2&offset=1&from=20")).unwrap();
+        assert_eq!(
+            page,
+            Page {
+                limit: Some(2),
+                offset: 1,
+                from: Some(20),
+                to: None
+            }
+        );
+        assert!(Page::parse(Some("limit=-1")).is_err());
+        assert!(Page::parse(Some("sort=time")).is_err());
+
+        let times: Vec<u64> = (10..=60).step_by(10).collect();
+        let apply = |query| {
+            Page::parse(Some(query))
+                .unwrap()
+                .apply(times.clone(), |&t| t)
+                .unwrap()
+        };
+        // Newest first
+        assert_eq!(apply(""), json!([60, 50, 40, 30, 20, 10]));
+        assert_eq!(apply("limit=2&offset=1&from=20"), json!([50, 40]));
+        assert_eq!(apply("from=20&to=40"), json!([30, 20]));
+        assert_eq!(apply("offset=9"), json!([]));
+
+        let work = [(10, 1u128 << 40)];
+        let page = Page::default().apply(work.to_vec(), |&(t, _)| t).unwrap();
+        assert_eq!(page, json!([[10, 1u64 << 40]]));
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let headers = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        assert!(!not_modified(&HeaderMap::new(), modified));
+
+        let tag = etag(modified);
+        assert!(not_modified(
+            &headers(header::IF_NONE_MATCH, &tag),
+            modified
+        ));
+        let list = format!("\"old\", W/{tag}");
+        assert!(not_modified(
+            &headers(header::IF_NONE_MATCH, &list),
+            modified
+        ));
+        let later = modified + Duration::from_millis(1);
+        assert!(!not_modified(&headers(header::IF_NONE_MATCH, &tag), later));
+
+        let date = httpdate::fmt_http_date(modified);
+        assert!(not_modified(
+            &headers(header::IF_MODIFIED_SINCE, &date),
+            modified
+        ));
+        let later = modified + Duration::from_secs(1);
+        assert!(!not_modified(
+            &headers(header::IF_MODIFIED_SINCE, &date),
+            later
+        ));
+        assert!(!not_modified(
+            &headers(header::IF_MODIFIED_SINCE, "yesterday"),
+            modified
+        ));
+    }
+}
@@ -0,0 +1,66 @@
+//! Failure injection hooks for soak testing. Without the `chaos` feature
+//! every hook is a no-op.
+
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+pub struct Chaos {
+    /// Probability of dropping a message received from kaspad
+    pub drop_response: f64,
+    /// Upper bound of a random delay before each block submission
+    pub submit_delay: Duration,
+    /// Probability of closing a client connection on each received message
+    pub close_connection: f64,
+}
+
+#[cfg(feature = "chaos")]
+mod imp {
+    use super::Chaos;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    static CONFIG: OnceLock<Chaos> = OnceLock::new();
+
+    pub fn init(chaos: Chaos) {
+        let _ = CONFIG.set(chaos);
+    }
+
+    fn chance(p: impl Fn(&Chaos) -> f64) -> bool {
+        CONFIG.get().is_some_and(|c| rand::random::<f64>() < p(c))
+    }
+
+    pub fn drop_response() -> bool {
+        chance(|c| c.drop_response)
+    }
+
+    pub fn submit_delay() -> Option<Duration> {
+        let max = CONFIG.get()?.submit_delay;
+        (!max.is_zero()).then(|| max.mul_f64(rand::random()))
+    }
+
+    pub fn close_connection() -> bool {
+        chance(|c| c.close_connection)
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+mod imp {
+    use super::Chaos;
+    use std::time::Duration;
+
+    pub fn init(_: Chaos) {}
+
+    pub fn drop_response() -> bool {
+        false
+    }
+
+    pub fn submit_delay() -> Option<Duration> {
+        None
+    }
+
+    pub fn close_connection() -> bool {
+        false
+    }
+}
+
+pub use imp::*;
@@ -0,0 +1,162 @@
+use crate::stratum::SOMPI_PER_KAS;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// What became of a share
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome<'a> {
+    Accepted,
+    /// With the reason
+    Rejected(&'a str),
+    /// A block kaspad accepted
+    Block,
+    /// A block kaspad refused, with its error
+    BlockRejected(&'a str),
+}
+
+/// A line per share and a banner per found block on stdout, like miners
+/// print them, for watching the pool in a terminal
+#[derive(Clone, Copy, Debug)]
+pub struct Console {
+    color: bool,
+}
+
+impl Console {
+    /// Colored unless stdout isn't a terminal, `NO_COLOR` is set, or it
+    /// goes to journald
+    pub fn new() -> Self {
+        let journald = std::env::var_os("JOURNAL_STREAM").is_some();
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        Console {
+            color: std::io::stdout().is_terminal() && !journald && !no_color,
+        }
+    }
+
+    pub fn share(&self, worker: &str, difficulty: f64, elapsed: Duration, outcome: Outcome) {
+        self.print(&self.share_line(SystemTime::now(), worker, difficulty, elapsed, outcome));
+    }
+
+    pub fn block_found(&self, hash: &str, worker: &str, effort: f64, reward: u64) {
+        self.print(&self.banner(hash, worker, effort, reward));
+    }
+
+    fn print(&self, text: &str) {
+        // Written at once, so lines of several connections don't interleave
+        let _ = std::io::stdout().lock().write_all(text.as_bytes());
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{color}{text}{RESET}"),
+            false => text.to_string(),
+        }
+    }
+
+    fn share_line(
+        &self,
+        time: SystemTime,
+        worker: &str,
+        difficulty: f64,
+        elapsed: Duration,
+        outcome: Outcome,
+    ) -> String {
+        let (color, result) = match outcome {
+            Outcome::Accepted => (GREEN, "accepted".to_string()),
+            Outcome::Rejected(reason) => (RED, format!("rejected ({reason})")),
+            Outcome::Block => (BOLD_YELLOW, "block accepted".to_string()),
+            Outcome::BlockRejected(error) => (RED, format!("block rejected ({error})")),
+        };
+        let worker = match worker {
+            "" => "-",
+            w => w,
+        };
+        format!(
+            "{} {worker:<24} diff {:>10} {:>6}ms  {}\n",
+            self.paint(DIM, &clock(time)),
+            format_difficulty(difficulty),
+            elapsed.as_millis(),
+            self.paint(color, &result)
+        )
+    }
+
+    fn banner(&self, hash: &str, worker: &str, effort: f64, reward: u64) -> String {
+        let rule = "=".repeat(72);
+        let kas = reward as f64 / SOMPI_PER_KAS as f64;
+        let text = format!(
+            "{rule}\n  BLOCK FOUND by {worker}, {:.1}% effort, {kas:.2} KAS\n  {hash}\n{rule}",
+            effort * 100.0
+        );
+        let mut banner = self.paint(BOLD_YELLOW, &text);
+        banner.push('\n');
+        banner
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Time of day in UTC
+fn clock(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) % 86400;
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Stratum difficulty with as many decimals as small ones need
+fn format_difficulty(difficulty: f64) -> String {
+    match difficulty {
+        d if d >= 100.0 => format!("{d:.0}"),
+        d if d >= 1.0 => format!("{d:.2}"),
+        d => format!("{d:.4}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Console, Outcome};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn formats_shares() {
+        let plain = Console { color: false };
+        let at = UNIX_EPOCH + Duration::from_secs(1_714_564_805);
+        let line = plain.share_line(
+            at,
+            "rig1",
+            16.0,
+            Duration::from_millis(3),
+            Outcome::Accepted,
+        );
+        assert_eq!(
+            line,
+            format!(
+                "12:00:05 {:<24} diff {:>10} {:>6}ms  accepted\n",
+                "rig1", "16.00", 3
+            )
+        );
+        let line = plain.share_line(at, "", 0.001, Duration::ZERO, Outcome::Rejected("stale"));
+        assert!(
+            line.contains(" - ") && line.contains("0.0010") && line.ends_with("rejected (stale)\n")
+        );
+
+        let colored = Console { color: true };
+        let line = colored.share_line(at, "rig1", 4096.0, Duration::ZERO, Outcome::Block);
+        assert!(line.contains("4096") && line.contains("\x1b[1;33mblock accepted\x1b[0m"));
+
+        let banner = plain.banner("ab12", "rig1", 0.852, 12_345_000_000);
+        assert!(banner.contains("BLOCK FOUND by rig1, 85.2% effort, 123.45 KAS\n  ab12\n"));
+    }
+}
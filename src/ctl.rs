@@ -0,0 +1,205 @@
+use anyhow::{bail, Result};
+use clap::{ArgEnum, Args, Subcommand};
+use hyper::{header, Body, Method, Request, StatusCode};
+use kaspad_stratum::http;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Args)]
+pub struct CtlArgs {
+    /// Address of the admin API of the running instance
+    #[clap(short, long, default_value = "127.0.0.1:6970")]
+    admin_addr: String,
+    /// Reach the admin API over HTTPS, for instances running with --http-tls
+    #[clap(long)]
+    https: bool,
+    /// Token of the admin API
+    #[clap(
+        short,
+        long,
+        env = "KASPAD_STRATUM_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    token: String,
+    #[clap(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// Inspect connected workers
+    Workers {
+        #[clap(subcommand)]
+        command: WorkersCommand,
+    },
+    /// Close the connections of a worker or an IP address, or one by its id as #<ID>
+    Kick { target: String },
+    /// Refuse an IP address for 10 minutes and close its connections
+    Ban { ip: IpAddr },
+    /// Set the share difficulty of a worker
+    SetDiff { worker: String, difficulty: f64 },
+    /// Request a new template from kaspad
+    Refresh,
+    /// Re-read the ACL, TLS certificates and credentials files
+    Reload,
+    /// Show or change whether found blocks are kept from kaspad
+    DryRun {
+        #[clap(arg_enum)]
+        state: Option<Toggle>,
+    },
+}
+
+#[derive(Subcommand)]
+enum WorkersCommand {
+    /// List the connections with their worker, agent and difficulty
+    List,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Toggle {
+    On,
+    Off,
+}
+
+pub async fn run(args: CtlArgs) -> Result<()> {
+    let ctl = Ctl {
+        base: match args.https {
+            true => format!("https://{}", args.admin_addr),
+            false => format!("http://{}", args.admin_addr),
+        },
+        token: args.token,
+    };
+    match args.command {
+        CtlCommand::Workers {
+            command: WorkersCommand::List,
+        } => {
+            let workers = ctl.request(Method::GET, "/workers", None).await?;
+            print_workers(workers.as_array().map(Vec::as_slice).unwrap_or_default());
+        }
+        CtlCommand::Kick { target } => {
+            let id = target
+                .strip_prefix('#')
+                .and_then(|id| id.parse::<u64>().ok());
+            let body = match (id, target.parse::<IpAddr>()) {
+                (Some(id), _) => json!({ "id": id }),
+                (None, Ok(ip)) => json!({ "ip": ip }),
+                (None, Err(_)) => json!({ "worker": target }),
+            };
+            let res = ctl.request(Method::POST, "/kick", Some(body)).await?;
+            println!("Kicked {} connections", res["kicked"]);
+        }
+        CtlCommand::Ban { ip } => {
+            let res = ctl
+                .request(Method::POST, "/ban", Some(json!({ "ip": ip })))
+                .await?;
+            println!("Banned {ip}, kicked {} connections", res["kicked"]);
+        }
+        CtlCommand::SetDiff { worker, difficulty } => {
+            let body = json!({ "worker": worker, "difficulty": difficulty });
+            let res = ctl.request(Method::POST, "/difficulty", Some(body)).await?;
+            println!("Updated {} connections", res["updated"]);
+        }
+        CtlCommand::Refresh => {
+            ctl.request(Method::POST, "/refresh", None).await?;
+            println!("Requested a new template");
+        }
+        CtlCommand::Reload => {
+            let res = ctl.request(Method::POST, "/reload", None).await?;
+            let names = |key: &str| {
+                let names: Vec<_> = res[key]
+                    .as_array()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect();
+                if names.is_empty() {
+                    "-".into()
+                } else {
+                    names.join(", ")
+                }
+            };
+            println!("Changed: {}", names("changed"));
+            println!("Unchanged: {}", names("unchanged"));
+            if let Some(failed) = res["failed"].as_object() {
+                for (name, error) in failed {
+                    println!("Failed {name}: {}", error.as_str().unwrap_or_default());
+                }
+                if !failed.is_empty() {
+                    bail!("{} settings kept their previous value", failed.len());
+                }
+            }
+        }
+        CtlCommand::DryRun { state } => {
+            let res = match state {
+                Some(state) => {
+                    let body = json!({ "enabled": matches!(state, Toggle::On) });
+                    ctl.request(Method::PUT, "/dry-run", Some(body)).await?
+                }
+                None => ctl.request(Method::GET, "/dry-run", None).await?,
+            };
+            let state = if res["enabled"] == true { "on" } else { "off" };
+            println!("Dry run is {state}");
+        }
+    }
+    Ok(())
+}
+
+struct Ctl {
+    base: String,
+    token: String,
+}
+
+impl Ctl {
+    async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{}{path}", self.base))
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))?;
+        let res = http::client().request(req).await?;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let value: Value = serde_json::from_slice(&body).unwrap_or_default();
+        if status != StatusCode::OK {
+            match value["error"].as_str() {
+                Some(error) => bail!("{status}: {error}"),
+                None => bail!("{status}"),
+            }
+        }
+        Ok(value)
+    }
+}
+
+fn print_workers(workers: &[Value]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    println!(
+        "{:>6} {:<32} {:<22} {:<20} {:>12} {:>10}",
+        "ID", "WORKER", "ADDRESS", "AGENT", "DIFFICULTY", "CONNECTED"
+    );
+    for w in workers {
+        let connected = now.saturating_sub(w["connected"].as_u64().unwrap_or(now));
+        println!(
+            "{:>6} {:<32} {:<22} {:<20} {:>12} {:>10}",
+            w["id"].as_u64().unwrap_or_default(),
+            w["worker"].as_str().unwrap_or("-"),
+            w["addr"].as_str().unwrap_or("-"),
+            w["agent"].as_str().unwrap_or("-"),
+            w["difficulty"].as_f64().unwrap_or_default(),
+            format_age(connected)
+        );
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{s}s"),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+    }
+}
@@ -0,0 +1,367 @@
+use crate::console::Console;
+use crate::http::{self, HttpClient};
+use crate::SUMMARY;
+use anyhow::{anyhow, Result};
+use hyper::{header, Body, Method, Request, Uri};
+use serde::Serialize;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// Minimum time between repeated [`Event::TemplateErrors`]
+const TEMPLATE_ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Noteworthy changes reported to the operator
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    WorkerOffline {
+        worker: String,
+        silent_secs: u64,
+    },
+    WorkerOnline {
+        worker: String,
+        offline_secs: u64,
+    },
+    /// Kaspad keeps failing template requests, sent for the first error and
+    /// then at most once per [`TEMPLATE_ALERT_INTERVAL`]
+    TemplateErrors {
+        consecutive: u32,
+        error: String,
+    },
+    TemplatesRecovered {
+        errors: u32,
+    },
+    /// The local clock differs from kaspad's template timestamps by more
+    /// than the allowed skew, positive when the local clock is ahead
+    ClockSkew {
+        skew_secs: f64,
+    },
+    ClockSynced {
+        skew_secs: f64,
+    },
+    BlockFound {
+        hash: String,
+        daa_score: u64,
+        /// Network difficulty
+        difficulty: u64,
+        /// Round effort, 1 for an average round
+        effort: f64,
+        worker: String,
+        /// Subsidy in sompi
+        reward: u64,
+        /// Estimated fees in sompi
+        fees: u64,
+    },
+    /// A reorg turned a found block that had been merged as blue red, its
+    /// reward was taken back from the immature balances
+    BlockReverted {
+        hash: String,
+        daa_score: u64,
+        /// Sompi
+        reward: u64,
+        worker: String,
+    },
+}
+
+impl Event {
+    fn log(&self) {
+        match self {
+            Event::WorkerOffline {
+                worker,
+                silent_secs,
+            } => warn!("Worker {worker} offline, no shares for {silent_secs}s"),
+            Event::WorkerOnline {
+                worker,
+                offline_secs,
+            } => info!("Worker {worker} back online after {offline_secs}s"),
+            Event::TemplateErrors { consecutive, error } => {
+                warn!("Template request failed {consecutive} times in a row: {error}")
+            }
+            Event::TemplatesRecovered { errors } => {
+                info!("Receiving templates again after {errors} errors")
+            }
+            Event::ClockSkew { skew_secs } => {
+                warn!("Local clock is {skew_secs:+.1}s off kaspad's template timestamp, check NTP")
+            }
+            Event::ClockSynced { skew_secs } => {
+                info!("Local clock back within {skew_secs:+.1}s of kaspad")
+            }
+            Event::BlockFound {
+                hash,
+                effort,
+                worker,
+                reward,
+                fees,
+                ..
+            } => info!(
+                target: SUMMARY,
+                "Found block {hash} by {worker} with {:.1}% effort, reward {reward} sompi and about {fees} in fees",
+                effort * 100.0
+            ),
+            Event::BlockReverted {
+                hash,
+                reward,
+                worker,
+                ..
+            } => warn!(
+                "Block {hash} by {worker} was merged as red after a reorg, its reward of {reward} sompi is reverted"
+            ),
+        }
+    }
+}
+
+/// Logs events and forwards them to the configured hooks
+#[derive(Clone, Default)]
+pub struct Notifier {
+    pub webhook: Option<Webhook>,
+    /// Shell command run for every found block
+    pub on_block_found: Option<String>,
+    /// Found blocks are announced with a banner
+    pub console: Option<Console>,
+}
+
+impl Notifier {
+    pub fn emit(&self, event: Event) {
+        event.log();
+        if let Some(webhook) = &self.webhook {
+            webhook.send(&event);
+        }
+        if let (
+            Some(console),
+            Event::BlockFound {
+                hash,
+                effort,
+                worker,
+                reward,
+                fees,
+                ..
+            },
+        ) = (&self.console, &event)
+        {
+            console.block_found(hash, worker, *effort, reward + fees);
+        }
+        if let (Some(command), Event::BlockFound { .. }) = (&self.on_block_found, &event) {
+            run_hook(command, &event);
+        }
+    }
+}
+
+/// Counts template requests kaspad answered with an error in a row, so a
+/// bad pay address or an unsynced node is reported without flooding the log
+#[derive(Default)]
+pub struct TemplateErrors {
+    consecutive: u32,
+    last_alert: Option<Instant>,
+}
+
+impl TemplateErrors {
+    pub fn consecutive(&self) -> u32 {
+        self.consecutive
+    }
+
+    /// Record an error, returning the alert if one is due
+    pub fn error(&mut self, error: String, now: Instant) -> Option<Event> {
+        self.consecutive += 1;
+        if let Some(last) = self.last_alert {
+            if now.duration_since(last) < TEMPLATE_ALERT_INTERVAL {
+                return None;
+            }
+        }
+        self.last_alert = Some(now);
+        Some(Event::TemplateErrors {
+            consecutive: self.consecutive,
+            error,
+        })
+    }
+
+    /// Record a template, returning an event if errors preceded it
+    pub fn template(&mut self) -> Option<Event> {
+        let errors = std::mem::take(&mut self.consecutive);
+        self.last_alert = None;
+        (errors > 0).then_some(Event::TemplatesRecovered { errors })
+    }
+}
+
+/// Compares template timestamps against the local clock, reporting only
+/// when the skew crosses the limit in either direction
+pub struct ClockSkew {
+    max: f64,
+    skewed: bool,
+}
+
+impl ClockSkew {
+    pub fn new(max: Duration) -> Self {
+        ClockSkew {
+            max: max.as_secs_f64(),
+            skewed: false,
+        }
+    }
+
+    pub fn skewed(&self) -> bool {
+        self.skewed
+    }
+
+    /// Check a template timestamp against the local time, both in
+    /// milliseconds since the epoch
+    pub fn check(&mut self, timestamp: i64, now: i64) -> (f64, Option<Event>) {
+        let skew_secs = (now - timestamp) as f64 / 1000.0;
+        let skewed = skew_secs.abs() > self.max;
+        let event = match (self.skewed, skewed) {
+            (false, true) => Some(Event::ClockSkew { skew_secs }),
+            (true, false) => Some(Event::ClockSynced { skew_secs }),
+            _ => None,
+        };
+        self.skewed = skewed;
+        (skew_secs, event)
+    }
+}
+
+/// Run `command` in the shell with the event as JSON on stdin and its
+/// fields in `BLOCK_*` environment variables
+fn run_hook(command: &str, event: &Event) {
+    let json = match serde_json::to_value(event) {
+        Ok(v) => v,
+        Err(e) => return warn!("Unable to serialize hook event: {e}"),
+    };
+    let mut cmd = shell(command);
+    if let Some(fields) = json.as_object() {
+        for (key, value) in fields {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            cmd.env(format!("BLOCK_{}", key.to_uppercase()), value);
+        }
+    }
+    cmd.stdin(Stdio::piped());
+    let command = command.to_string();
+    tokio::spawn(async move {
+        let mut child = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => return warn!("Unable to run {command:?}: {e}"),
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            // The hook may ignore its input
+            let _ = stdin.write_all(json.to_string().as_bytes()).await;
+        }
+        match child.wait().await {
+            Ok(status) if !status.success() => warn!("{command:?} exited with {status}"),
+            Ok(_) => {}
+            Err(e) => warn!("Unable to run {command:?}: {e}"),
+        }
+    });
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Posts events as JSON to an HTTP(S) endpoint
+#[derive(Clone)]
+pub struct Webhook {
+    url: Uri,
+    client: HttpClient,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self> {
+        let url: Uri = url.parse()?;
+        if url.host().is_none() {
+            return Err(anyhow!("webhook url {url} has no host"));
+        }
+        Ok(Webhook {
+            url,
+            client: http::client(),
+        })
+    }
+
+    /// Send in the background, failures are only logged
+    pub fn send<T: Serialize>(&self, event: &T) {
+        let body = match serde_json::to_vec(event) {
+            Ok(b) => b,
+            Err(e) => return warn!("Unable to serialize webhook event: {e}"),
+        };
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+        let req = match req {
+            Ok(r) => r,
+            Err(e) => return warn!("Unable to build webhook request: {e}"),
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            match client.request(req).await {
+                Ok(res) if !res.status().is_success() => {
+                    warn!("Webhook responded with {}", res.status())
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Webhook failed: {e}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClockSkew, Event, TemplateErrors, TEMPLATE_ALERT_INTERVAL};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rate_limits_template_errors() {
+        let mut errors = TemplateErrors::default();
+        let start = Instant::now();
+        let alert = |e: Option<Event>| match e {
+            Some(Event::TemplateErrors { consecutive, .. }) => Some(consecutive),
+            _ => None,
+        };
+        assert_eq!(alert(errors.error("not synced".into(), start)), Some(1));
+        assert_eq!(alert(errors.error("not synced".into(), start)), None);
+        let later = start + TEMPLATE_ALERT_INTERVAL;
+        assert_eq!(alert(errors.error("not synced".into(), later)), Some(3));
+        assert_eq!(
+            alert(errors.error("not synced".into(), later + Duration::from_secs(1))),
+            None
+        );
+        assert_eq!(errors.consecutive(), 4);
+
+        assert!(matches!(
+            errors.template(),
+            Some(Event::TemplatesRecovered { errors: 4 })
+        ));
+        assert!(errors.template().is_none());
+        assert_eq!(alert(errors.error("not synced".into(), later)), Some(1));
+    }
+
+    #[test]
+    fn reports_clock_skew_changes() {
+        let mut clock = ClockSkew::new(Duration::from_secs(10));
+        let now = 1_700_000_000_000;
+        assert!(matches!(clock.check(now - 500, now), (s, None) if s == 0.5));
+        assert!(matches!(
+            clock.check(now + 12_000, now),
+            (_, Some(Event::ClockSkew { skew_secs })) if skew_secs == -12.0
+        ));
+        assert!(clock.skewed());
+        assert!(clock.check(now + 15_000, now).1.is_none());
+        assert!(matches!(
+            clock.check(now, now).1,
+            Some(Event::ClockSynced { .. })
+        ));
+        assert!(!clock.skewed());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::stratum::Tls;
+use anyhow::{anyhow, Result};
+use hyper::client::HttpConnector;
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Client, Request, Response};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// Time an HTTPS client has to complete the handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Client for outgoing HTTP and HTTPS requests
+pub fn client() -> HttpClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// Serve HTTP on `addr`, or HTTPS with the certificate `tls` keeps
+/// reloaded. `service` handles the requests of a client address.
+pub async fn serve<F, S>(addr: SocketAddr, tls: Option<Tls>, service: F) -> Result<()>
+where
+    F: Fn(IpAddr) -> S,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                // Out of file descriptors, say
+                warn!("Unable to accept on {addr}: {e}");
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let service = service(peer.ip());
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let res = match tls {
+                None => Http::new()
+                    .serve_connection(stream, service)
+                    .await
+                    .map_err(Into::into),
+                Some(tls) => {
+                    match time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor().accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => Http::new()
+                            .serve_connection(stream, service)
+                            .await
+                            .map_err(Into::into),
+                        Ok(Err(e)) => Err(anyhow!("TLS handshake failed: {e}")),
+                        Err(_) => Err(anyhow!("TLS handshake timed out")),
+                    }
+                }
+            };
+            if let Err(e) = res {
+                debug!("HTTP connection from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve;
+    use crate::stratum::{Tls, TlsConfig};
+    use hyper::service::service_fn;
+    use hyper::{Body, Response};
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::{self, Certificate, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    fn data(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn serves_https() {
+        let tls = Tls::new(TlsConfig {
+            cert: data("cert.pem"),
+            key: data("key.pem"),
+            ports: vec![],
+        })
+        .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap();
+        tokio::spawn(serve(addr, Some(tls), |ip| {
+            service_fn(move |_| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(ip.to_string())))
+            })
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut roots = RootCertStore::empty();
+        let pem = std::fs::read(data("cert.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut &pem[..]).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let name = "localhost".try_into().unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(name, conn)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("127.0.0.1"), "{res}");
+
+        // Plain HTTP finds no server
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut res = vec![];
+        let _ = plain.read_to_end(&mut res).await;
+        assert!(!res.starts_with(b"HTTP/1.1 200"));
+    }
+}
@@ -1,143 +1,461 @@
-use anyhow::Result;
-use log::{debug, info, warn};
+mod backend;
+mod extra_data;
+mod header;
+mod network;
+mod offline;
+mod pay_address;
+
+use crate::chaos;
+use crate::metrics;
+use crate::repeated::Repeated;
+use anyhow::{anyhow, Result};
+pub use backend::{is_backend, Backend};
+pub use extra_data::ExtraData;
+pub use header::Header;
+pub use network::Network;
+use offline::OfflineTask;
+pub use offline::TemplateFile;
+pub use pay_address::PayAddresses;
 use proto::kaspad_message::Payload;
 use proto::submit_block_response_message::RejectReason;
-pub use proto::RpcBlock;
 use proto::*;
+pub use proto::{RpcBlock, RpcBlockHeader, RpcBlockLevelParents};
 use rpc_client::RpcClient;
-use tokio::sync::mpsc;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_stream::StreamExt;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::Endpoint;
+use tracing::{debug, info, warn};
 
 pub type Send<T> = mpsc::UnboundedSender<T>;
 type Recv<T> = mpsc::UnboundedReceiver<T>;
+/// Receives kaspad's verdict on a submitted block, `None` if accepted
+pub type SubmitResult = oneshot::Receiver<Option<Box<str>>>;
+type SubmitReply = oneshot::Sender<Option<Box<str>>>;
+
+/// A request to kaspad, with the sender of its response for submits
+#[derive(Debug)]
+pub struct Command {
+    payload: Payload,
+    submit: Option<PendingSubmit>,
+}
+
+impl From<Payload> for Command {
+    fn from(payload: Payload) -> Self {
+        Command {
+            payload,
+            submit: None,
+        }
+    }
+}
+
+/// Who a submitted block came from, so kaspad's response can be logged
+/// with it
+#[derive(Clone, Debug, Default)]
+pub struct BlockOrigin {
+    pub hash: String,
+    pub worker: String,
+    /// Stratum job, none for blocks of a frontend
+    pub job_id: Option<String>,
+}
+
+impl fmt::Display for BlockOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} by {}", self.hash, self.worker)?;
+        match &self.job_id {
+            Some(job_id) => write!(f, " of job {job_id}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A block awaiting kaspad's response
+#[derive(Debug)]
+struct PendingSubmit {
+    reply: SubmitReply,
+    origin: BlockOrigin,
+}
 
 #[derive(Clone)]
-pub struct KaspadHandle(Send<Payload>);
+pub struct KaspadHandle(Send<Command>);
 
 impl KaspadHandle {
-    pub fn new() -> (Self, Recv<Payload>) {
+    pub fn new() -> (Self, Recv<Command>) {
         let (send, recv) = mpsc::unbounded_channel();
         (KaspadHandle(send), recv)
     }
 
-    pub fn submit_block(&self, block: RpcBlock) {
-        let _ = self.0.send(Payload::submit_block(block, false));
+    /// The result is dropped without a response if the kaspad connection
+    /// closes first
+    pub fn submit_block(&self, block: RpcBlock, origin: BlockOrigin) -> SubmitResult {
+        let (reply, result) = oneshot::channel();
+        let cmd = Command {
+            payload: Payload::submit_block(block, false),
+            submit: Some(PendingSubmit { reply, origin }),
+        };
+        if let Some(delay) = chaos::submit_delay() {
+            let send = self.0.clone();
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                let _ = send.send(cmd);
+            });
+            return result;
+        }
+        let _ = self.0.send(cmd);
+        result
     }
 }
 
 #[derive(Debug)]
 pub enum Message {
-    Info { version: String, synced: bool },
-    Template(RpcBlock),
+    /// Subscribed to a new kaspad connection, templates need to be requested
+    Online,
+    /// Lost the connection to kaspad, reconnecting in the background
+    Offline(String),
+    /// The client gave up reconnecting or failed, no more messages follow
+    Disconnected(String),
+    /// Kaspad answered a template request with an error
+    TemplateError(String),
+    Info {
+        version: String,
+    },
+    Template(Box<RpcBlock>),
     NewTemplate,
-    SubmitBlockResult(Option<Box<str>>),
+    /// The selected parent chain changed, see [`Client::watch_chain`]
+    ChainChanged {
+        removed: Vec<String>,
+        added: Vec<String>,
+    },
+    /// A block asked for with [`Client::request_block`], with verbose data
+    Block(Box<RpcBlock>),
+    /// The chain blocks added since a block asked for with
+    /// [`Client::request_chain`], or kaspad's error
+    ChainFrom(Result<Vec<String>, String>),
 }
 
+/// Delay before the first reconnect, doubled after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// A template request unanswered this long no longer holds back others
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(2);
+
 struct ClientTask {
     url: String,
+    /// Sent as bearer token, for a backend instance requiring one
+    token: Option<String>,
     send_msg: Send<Message>,
-    recv_cmd: Recv<Payload>,
+    recv_cmd: Recv<Command>,
     synced: bool,
+    /// Subscribed on the current connection
+    online: bool,
+    /// Consecutive failed connection attempts before giving up
+    max_reconnects: Option<u32>,
+    requests: Requests,
+    coalesce: Arc<AtomicBool>,
+    /// Template request held back until the one in flight is answered
+    deferred: Option<Command>,
+    /// Warnings that recur while kaspad is unreachable or syncing
+    repeated: Repeated,
+}
+
+/// When requests still waiting for their response entered the stream.
+/// Kaspad answers each kind of request in order, so submit responses are
+/// matched to their reply sender by position.
+#[derive(Default)]
+struct Requests {
+    templates: VecDeque<Instant>,
+    submits: VecDeque<(Instant, Option<PendingSubmit>)>,
+}
+
+impl Requests {
+    fn template_in_flight(&self) -> bool {
+        self.templates
+            .back()
+            .is_some_and(|sent| sent.elapsed() < COALESCE_TIMEOUT)
+    }
+
+    fn sent(&mut self, cmd: &mut Command) {
+        match cmd.payload {
+            Payload::GetBlockTemplateRequest(_) => self.templates.push_back(Instant::now()),
+            Payload::SubmitBlockRequest(_) => {
+                self.submits.push_back((Instant::now(), cmd.submit.take()))
+            }
+            _ => {}
+        }
+    }
+
+    /// Record the round trip, returning the block of a submit response
+    fn received(&mut self, payload: &Payload) -> Option<PendingSubmit> {
+        let (method, sent, submit) = match payload {
+            Payload::GetBlockTemplateResponse(_) => {
+                ("get_block_template", self.templates.pop_front(), None)
+            }
+            Payload::SubmitBlockResponse(_) => match self.submits.pop_front() {
+                Some((sent, submit)) => ("submit_block", Some(sent), submit),
+                None => ("submit_block", None, None),
+            },
+            _ => return None,
+        };
+        if let Some(sent) = sent {
+            metrics::KASPAD_RPC_DURATION
+                .with_label_values(&[method])
+                .observe(sent.elapsed().as_secs_f64());
+        }
+        submit
+    }
 }
 
 impl ClientTask {
-    async fn run(mut self) -> Result<()> {
-        let mut client = RpcClient::connect(self.url).await?;
+    /// Keep reconnecting to kaspad until the client is dropped or
+    /// `max_reconnects` consecutive attempts failed, returning why it stopped
+    async fn run(mut self) -> String {
+        let mut delay = RECONNECT_DELAY;
+        let mut attempts = 0;
+        loop {
+            let connected_at = Instant::now();
+            let reason = match self.connect().await {
+                Ok(_) => "connection closed".to_string(),
+                Err(e) => e.to_string(),
+            };
+            // Submits waiting for a response are answered with an error
+            self.requests = Requests::default();
+            self.deferred = None;
+            if std::mem::take(&mut self.online) {
+                attempts = 0;
+                if connected_at.elapsed() > MAX_RECONNECT_DELAY {
+                    delay = RECONNECT_DELAY;
+                }
+                if self.send_msg.send(Message::Offline(reason)).is_err() {
+                    return "client dropped".into();
+                }
+            } else {
+                attempts += 1;
+                if self.send_msg.is_closed() {
+                    return "client dropped".into();
+                }
+                if self.max_reconnects.is_some_and(|max| attempts > max) {
+                    return format!("{reason}, giving up after {attempts} attempts");
+                }
+                let message = format!("Unable to connect to kaspad: {reason}, retrying");
+                if let Some(line) = self.repeated.check(&message) {
+                    warn!("{line} in {delay:?}");
+                }
+            }
+            time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let channel = Endpoint::from_shared(self.url.clone())?.connect().await?;
+        let token: Option<MetadataValue<Ascii>> = self
+            .token
+            .as_ref()
+            .map(|t| format!("Bearer {t}").parse())
+            .transpose()?;
+        // The error type is given by tonic
+        #[allow(clippy::result_large_err)]
+        let mut client = RpcClient::with_interceptor(channel, move |mut req: tonic::Request<()>| {
+            if let Some(token) = &token {
+                req.metadata_mut().insert("authorization", token.clone());
+            }
+            Ok(req)
+        });
+        let (send, recv) = mpsc::unbounded_channel();
         let mut stream = client
-            .message_stream(
-                UnboundedReceiverStream::new(self.recv_cmd)
-                    .map(|p| KaspadMessage { payload: Some(p) }),
-            )
+            .message_stream(UnboundedReceiverStream::new(recv))
             .await?
             .into_inner();
+        for payload in [Payload::get_info(), Payload::notify_new_block_template()] {
+            send.send(KaspadMessage {
+                payload: Some(payload),
+            })?;
+        }
+        self.send_msg.send(Message::Online)?;
+        self.online = true;
 
-        while let Some(KaspadMessage { payload }) = stream.message().await? {
-            let msg = match payload {
-                Some(Payload::GetInfoResponse(info)) => {
-                    self.synced = info.is_synced;
-                    if !self.synced {
-                        warn!("Not yet synced");
-                    }
-                    Message::Info {
-                        version: info.server_version,
-                        synced: info.is_synced,
-                    }
-                }
-                Some(Payload::SubmitBlockResponse(res)) => {
-                    let res = match (RejectReason::from_i32(res.reject_reason), res.error) {
-                        (Some(RejectReason::None), None) => None,
-                        (_, Some(e)) => Some(e.message.into_boxed_str()),
-                        _ => Some("Unknown error".into()),
+        loop {
+            tokio::select! {
+                cmd = self.recv_cmd.recv() => {
+                    let mut cmd = match cmd {
+                        Some(c) => c,
+                        None => return Ok(()),
                     };
-                    Message::SubmitBlockResult(res)
-                }
-                Some(Payload::GetBlockTemplateResponse(res)) => {
-                    if let Some(e) = res.error {
-                        warn!("Error: {}", e.message);
+                    let template = matches!(cmd.payload, Payload::GetBlockTemplateRequest(_));
+                    let coalesce = self.coalesce.load(Ordering::Relaxed);
+                    if template && coalesce && self.requests.template_in_flight() {
+                        // The latest stands in for all requests meanwhile
+                        self.deferred = Some(cmd);
                         continue;
                     }
-                    if let Some(block) = res.block {
-                        if !self.synced && res.is_synced {
-                            info!("Node synced");
-                        }
-                        self.synced = res.is_synced;
-
-                        if block.header.is_none() {
-                            warn!("Template block is missing a header");
-                            continue;
+                    self.requests.sent(&mut cmd);
+                    send.send(KaspadMessage {
+                        payload: Some(cmd.payload),
+                    })?;
+                }
+                msg = stream.message() => match msg? {
+                    Some(KaspadMessage { payload }) => {
+                        self.handle(payload)?;
+                        if !self.requests.template_in_flight() {
+                            if let Some(mut cmd) = self.deferred.take() {
+                                self.requests.sent(&mut cmd);
+                                send.send(KaspadMessage {
+                                    payload: Some(cmd.payload),
+                                })?;
+                            }
                         }
-                        Message::Template(block)
-                    } else {
-                        continue;
                     }
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    fn warn_unsynced(&mut self) {
+        if let Some(line) = self.repeated.check("Not yet synced") {
+            warn!("{line}");
+        }
+    }
+
+    fn handle(&mut self, payload: Option<Payload>) -> Result<()> {
+        if chaos::drop_response() {
+            debug!("Chaos: dropping message from kaspad");
+            return Ok(());
+        }
+        let submit = match &payload {
+            Some(payload) => self.requests.received(payload),
+            None => None,
+        };
+        let msg = match payload {
+            Some(Payload::GetInfoResponse(info)) => {
+                self.synced = info.is_synced;
+                if !self.synced {
+                    self.warn_unsynced();
                 }
-                Some(Payload::NewBlockTemplateNotification(_)) => Message::NewTemplate,
-                Some(Payload::NotifyNewBlockTemplateResponse(res)) => match res.error {
-                    Some(e) => {
-                        warn!("Unable to subscribe to new templates: {}", e.message);
-                        break;
-                    }
+                Message::Info {
+                    version: info.server_version,
+                }
+            }
+            Some(Payload::SubmitBlockResponse(res)) => {
+                let res = match (RejectReason::from_i32(res.reject_reason), res.error) {
+                    (Some(RejectReason::None), None) => None,
+                    (_, Some(e)) => Some(e.message.into_boxed_str()),
+                    _ => Some("Unknown error".into()),
+                };
+                let submit = match submit {
+                    Some(s) => s,
                     None => {
-                        debug!("Subscribed to new templates");
-                        continue;
+                        debug!("Submit response without a pending submit");
+                        return Ok(());
                     }
-                },
-                _ => {
-                    debug!("Received unknown message");
-                    continue;
+                };
+                match &res {
+                    Some(e) => warn!("Kaspad rejected {}: {e}", submit.origin),
+                    None => debug!("Kaspad accepted {}", submit.origin),
                 }
-            };
-            self.send_msg.send(msg)?;
-        }
+                // The miner may have disconnected
+                let _ = submit.reply.send(res);
+                return Ok(());
+            }
+            Some(Payload::GetBlockTemplateResponse(res)) => {
+                if let Some(e) = res.error {
+                    self.send_msg.send(Message::TemplateError(e.message))?;
+                    return Ok(());
+                }
+                let block = match res.block {
+                    Some(b) => b,
+                    None => return Ok(()),
+                };
+                if !self.synced && res.is_synced {
+                    info!("Node synced");
+                }
+                if !res.is_synced {
+                    self.warn_unsynced();
+                }
+                self.synced = res.is_synced;
 
+                if block.header.is_none() {
+                    warn!("Template block is missing a header");
+                    return Ok(());
+                }
+                Message::Template(Box::new(block))
+            }
+            Some(Payload::NewBlockTemplateNotification(_)) => Message::NewTemplate,
+            Some(Payload::VirtualSelectedParentChainChangedNotification(n)) => {
+                Message::ChainChanged {
+                    removed: n.removed_chain_block_hashes,
+                    added: n.added_chain_block_hashes,
+                }
+            }
+            Some(Payload::NotifyVirtualSelectedParentChainChangedResponse(res)) => {
+                if let Some(e) = res.error {
+                    warn!("Unable to subscribe to chain changes: {}", e.message);
+                }
+                return Ok(());
+            }
+            Some(Payload::GetBlockResponse(res)) => match (res.block, res.error) {
+                (Some(block), None) => Message::Block(Box::new(block)),
+                (_, error) => {
+                    let error = error.map(|e| e.message).unwrap_or_default();
+                    debug!("Unable to get block: {error}");
+                    return Ok(());
+                }
+            },
+            Some(Payload::GetVirtualSelectedParentChainFromBlockResponse(res)) => {
+                Message::ChainFrom(match res.error {
+                    Some(e) => Err(e.message),
+                    None => Ok(res.added_chain_block_hashes),
+                })
+            }
+            Some(Payload::NotifyNewBlockTemplateResponse(res)) => match res.error {
+                Some(e) => {
+                    return Err(anyhow!(
+                        "Unable to subscribe to new templates: {}",
+                        e.message
+                    ))
+                }
+                None => {
+                    debug!("Subscribed to new templates");
+                    return Ok(());
+                }
+            },
+            _ => {
+                debug!("Received unknown message");
+                return Ok(());
+            }
+        };
+        self.send_msg.send(msg)?;
         Ok(())
     }
 }
 
 #[derive(Clone)]
 pub struct Client {
-    pay_address: String,
-    extra_data: String,
-    send_cmd: Send<Payload>,
+    pay_addresses: PayAddresses,
+    extra_data: ExtraData,
+    send_cmd: Send<Command>,
+    coalesce: Arc<AtomicBool>,
 }
 
 impl Client {
     pub fn new(
         url: &str,
-        pay_address: &str,
-        extra_data: &str,
+        token: Option<&str>,
+        pay_addresses: PayAddresses,
+        extra_data: ExtraData,
         handle: KaspadHandle,
-        recv_cmd: Recv<Payload>,
+        recv_cmd: Recv<Command>,
+        max_reconnects: Option<u32>,
     ) -> (Self, Recv<Message>) {
         let (send_msg, recv_msg) = mpsc::unbounded_channel();
 
-        let pay_address = if !pay_address.starts_with("kaspa") {
-            format!("kaspa:{}", pay_address)
-        } else {
-            pay_address.into()
-        };
-
         let url = if !url.starts_with("http") {
             format!("http://{}", url)
         } else {
@@ -145,42 +463,124 @@ impl Client {
         };
         let task = ClientTask {
             url,
+            token: token.map(Into::into),
             send_msg,
             recv_cmd,
             synced: false,
+            online: false,
+            max_reconnects,
+            requests: Default::default(),
+            coalesce: Default::default(),
+            deferred: None,
+            repeated: Repeated::default(),
         };
-
+        let coalesce = task.coalesce.clone();
+        let send_msg = task.send_msg.clone();
+        let task = tokio::spawn(task.run());
         tokio::spawn(async move {
-            match task.run().await {
-                Ok(_) => warn!("Kaspad connection closed"),
-                Err(e) => warn!("Kaspad connection closed: {e}"),
-            }
+            let reason = match task.await {
+                Ok(reason) => reason,
+                Err(e) => format!("client task failed: {e}"),
+            };
+            let _ = send_msg.send(Message::Disconnected(reason));
         });
 
-        let send_cmd = handle.0;
-        send_cmd.send(Payload::get_info()).unwrap();
-        send_cmd.send(Payload::notify_new_block_template()).unwrap();
+        let client = Client {
+            pay_addresses,
+            extra_data,
+            send_cmd: handle.0,
+            coalesce,
+        };
+        (client, recv_msg)
+    }
+
+    /// A client answering from a template file instead of a node
+    pub fn offline(
+        source: TemplateFile,
+        pay_addresses: PayAddresses,
+        extra_data: ExtraData,
+        handle: KaspadHandle,
+        recv_cmd: Recv<Command>,
+    ) -> (Self, Recv<Message>) {
+        let (send_msg, recv_msg) = mpsc::unbounded_channel();
+        let task = OfflineTask {
+            source,
+            send_msg: send_msg.clone(),
+            recv_cmd,
+        };
+        let task = tokio::spawn(task.run());
+        tokio::spawn(async move {
+            let reason = match task.await {
+                Ok(reason) => reason,
+                Err(e) => format!("client task failed: {e}"),
+            };
+            let _ = send_msg.send(Message::Disconnected(reason));
+        });
 
         let client = Client {
-            pay_address,
-            extra_data: extra_data.into(),
-            send_cmd,
+            pay_addresses,
+            extra_data,
+            send_cmd: handle.0,
+            coalesce: Default::default(),
         };
-        client.request_template();
         (client, recv_msg)
     }
 
+    /// Keep at most one template request in flight, those made meanwhile
+    /// are answered by a single one after it, as at several blocks a second
+    /// kaspad notifies faster than it answers
+    pub fn set_coalescing(&self, enabled: bool) {
+        self.coalesce.store(enabled, Ordering::Relaxed);
+    }
+
     pub fn request_template(&self) -> bool {
+        let pay_address = self.pay_addresses.next();
+        let extra_data = self.extra_data.render();
+        self.send_cmd
+            .send(Payload::get_block_template(pay_address, &extra_data).into())
+            .is_ok()
+    }
+
+    /// Subscribe to changes of the selected parent chain on the current
+    /// connection, reported as [`Message::ChainChanged`]
+    pub fn watch_chain(&self) -> bool {
+        self.send_cmd
+            .send(Payload::notify_chain_changed().into())
+            .is_ok()
+    }
+
+    /// Ask for a block with its merge set, answered with [`Message::Block`]
+    pub fn request_block(&self, hash: &str) -> bool {
+        self.send_cmd.send(Payload::get_block(hash).into()).is_ok()
+    }
+
+    /// Ask for the selected parent chain from a block to the virtual,
+    /// answered with [`Message::ChainFrom`]
+    pub fn request_chain(&self, start: &str) -> bool {
         self.send_cmd
-            .send(Payload::get_block_template(
-                &self.pay_address,
-                &self.extra_data,
-            ))
+            .send(Payload::get_chain_from_block(start).into())
             .is_ok()
     }
 }
 
+/// Reward of a template's block in sompi, read from its coinbase
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BlockReward {
+    pub subsidy: u64,
+    /// Estimated, as the block's own fees are paid by the coinbase of the
+    /// block merging it: the average the blocks this coinbase pays earned on
+    /// top of the subsidy
+    pub fees: u64,
+}
+
+impl BlockReward {
+    pub fn total(&self) -> u64 {
+        self.subsidy + self.fees
+    }
+}
+
 mod proto {
+    use super::{BlockReward, Header};
     use crate::pow;
     use crate::U256;
     use anyhow::Result;
@@ -211,6 +611,53 @@ mod proto {
         pub fn notify_new_block_template() -> Self {
             Payload::NotifyNewBlockTemplateRequest(super::NotifyNewBlockTemplateRequestMessage {})
         }
+
+        pub fn notify_chain_changed() -> Self {
+            Payload::NotifyVirtualSelectedParentChainChangedRequest(
+                NotifyVirtualSelectedParentChainChangedRequestMessage {
+                    include_accepted_transaction_ids: false,
+                },
+            )
+        }
+
+        pub fn get_block(hash: &str) -> Self {
+            Payload::GetBlockRequest(GetBlockRequestMessage {
+                hash: hash.into(),
+                include_transactions: false,
+            })
+        }
+
+        pub fn get_chain_from_block(start: &str) -> Self {
+            Payload::GetVirtualSelectedParentChainFromBlockRequest(
+                GetVirtualSelectedParentChainFromBlockRequestMessage {
+                    start_hash: start.into(),
+                    include_accepted_transaction_ids: false,
+                },
+            )
+        }
+    }
+
+    impl RpcBlock {
+        /// Block subsidy in sompi from the coinbase payload, which starts
+        /// with the little endian blue score and subsidy
+        pub fn subsidy(&self) -> Option<u64> {
+            let payload = hex::decode(&self.transactions.first()?.payload).ok()?;
+            let subsidy = payload.get(8..16)?.try_into().ok()?;
+            Some(u64::from_le_bytes(subsidy))
+        }
+
+        /// Subsidy and estimated fees, with one coinbase output per merged
+        /// block paying its subsidy and fees
+        pub fn reward(&self) -> Option<BlockReward> {
+            let subsidy = self.subsidy()?;
+            let outputs = &self.transactions.first()?.outputs;
+            let paid: u64 = outputs.iter().map(|o| o.amount).sum();
+            let fees = match outputs.len() as u64 {
+                0 => 0,
+                n => paid.saturating_sub(subsidy.saturating_mul(n)) / n,
+            };
+            Some(BlockReward { subsidy, fees })
+        }
     }
 
     impl RpcBlockHeader {
@@ -220,76 +667,64 @@ mod proto {
         }
 
         pub fn pre_pow(&self) -> Result<U256> {
-            let hash = self.hash(true)?;
-            let mut out = [0; 4];
-            for (o, c) in out.iter_mut().zip(hash.as_bytes().chunks_exact(8)) {
-                *o = u64::from_le_bytes(c.try_into().unwrap());
-            }
-            Ok(out.into())
+            Ok(Header::parse(self)?.pre_pow())
         }
 
         pub fn hash(&self, pre_pow: bool) -> Result<Hash> {
-            let mut state = blake2b_simd::Params::new()
-                .hash_length(32)
-                .key(b"BlockHash")
-                .to_state();
-
-            let version = self.version as u16;
-            state.update(&version.to_le_bytes());
-            let mut parents = self.parents.len() as u64;
-            state.update(&parents.to_le_bytes());
-
-            let mut hash = [0u8; 32];
-            for parent in &self.parents {
-                parents = parent.parent_hashes.len() as u64;
-                state.update(&parents.to_le_bytes());
-                for h in &parent.parent_hashes {
-                    hex::decode_to_slice(h, &mut hash)?;
-                    state.update(&hash);
-                }
-            }
-            hex::decode_to_slice(&self.hash_merkle_root, &mut hash)?;
-            state.update(&hash);
-            hex::decode_to_slice(&self.accepted_id_merkle_root, &mut hash)?;
-            state.update(&hash);
-            hex::decode_to_slice(&self.utxo_commitment, &mut hash)?;
-            state.update(&hash);
-
-            let (timestamp, nonce) = if pre_pow {
-                (0, 0)
-            } else {
-                (self.timestamp, self.nonce)
-            };
-
-            state
-                .update(&timestamp.to_le_bytes())
-                .update(&self.bits.to_le_bytes())
-                .update(&nonce.to_le_bytes())
-                .update(&self.daa_score.to_le_bytes())
-                .update(&self.blue_score.to_le_bytes());
-
-            let len = (self.blue_work.len() + 1) / 2;
-            if self.blue_work.len() % 2 == 0 {
-                hex::decode_to_slice(&self.blue_work, &mut hash[..len])?;
-            } else {
-                hex::decode_to_slice(format!("0{}", self.blue_work), &mut hash[..len])?;
-            }
-            state
-                .update(&(len as u64).to_le_bytes())
-                .update(&hash[..len]);
-
-            hex::decode_to_slice(&self.pruning_point, &mut hash)?;
-            state.update(&hash);
-
-            Ok(state.finalize())
+            let header = Header::parse(self)?;
+            Ok(match pre_pow {
+                true => header.hash_pre_pow(),
+                false => header.hash(self.nonce),
+            })
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{RpcBlockHeader, RpcBlockLevelParents};
-    use blake2b_simd::Hash;
+    use super::proto::kaspad_message::Payload;
+    use super::proto::SubmitBlockResponseMessage;
+    use super::{
+        BlockOrigin, BlockReward, Command, PendingSubmit, Requests, RpcBlock, RpcBlockHeader,
+        RpcBlockLevelParents, RpcTransaction, RpcTransactionOutput,
+    };
+    use crate::pow;
+    use crate::U256;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn attributes_submit_responses() {
+        let mut requests = Requests::default();
+        for worker in ["rig1", "rig2"] {
+            let (reply, _) = oneshot::channel();
+            let origin = BlockOrigin {
+                hash: "ab12".into(),
+                worker: worker.into(),
+                job_id: Some("2a".into()),
+            };
+            let mut cmd = Command {
+                payload: Payload::submit_block(RpcBlock::default(), false),
+                submit: Some(PendingSubmit { reply, origin }),
+            };
+            requests.sent(&mut cmd);
+        }
+        // Kaspad answers submits in order
+        let response = Payload::SubmitBlockResponse(SubmitBlockResponseMessage::default());
+        let first = requests.received(&response).unwrap();
+        assert_eq!(first.origin.to_string(), "block ab12 by rig1 of job 2a");
+        assert_eq!(requests.received(&response).unwrap().origin.worker, "rig2");
+        assert!(requests.received(&response).is_none());
+
+        let frontend = BlockOrigin {
+            hash: "cd34".into(),
+            worker: "frontend 10.0.0.2:40000".into(),
+            job_id: None,
+        };
+        assert_eq!(
+            frontend.to_string(),
+            "block cd34 by frontend 10.0.0.2:40000"
+        );
+    }
 
     #[test]
     fn header_hash() {
@@ -409,4 +844,213 @@ mod test {
 
         assert_eq!(header.hash(true).unwrap().as_bytes(), &expected_hash);
     }
+
+    /// Deterministic but irregular hash, as used to generate the vectors below
+    fn test_hash(seed: u8) -> String {
+        let bytes: Vec<u8> = (0..32u8)
+            .map(|i| i.wrapping_mul(37).wrapping_add(seed.wrapping_mul(101)) ^ 0x5a)
+            .collect();
+        hex::encode(bytes)
+    }
+
+    struct Vector {
+        name: &'static str,
+        version: u32,
+        levels: &'static [usize],
+        bits: u32,
+        timestamp: i64,
+        nonce: u64,
+        daa_score: u64,
+        blue_score: u64,
+        blue_work: &'static str,
+        hash: &'static str,
+        pre_pow: &'static str,
+        pow: &'static str,
+    }
+
+    #[test]
+    fn header_hash_vectors() {
+        // Generated with rusty-kaspa's consensus-core header hashing and kaspa-pow
+        let vectors = [
+            Vector {
+                name: "mainnet v0",
+                version: 0,
+                levels: &[3, 2, 1],
+                bits: 0x1e7fffff,
+                timestamp: 1662000000000,
+                nonce: 0x123456789abcdef0,
+                daa_score: 27000000,
+                blue_score: 25000000,
+                blue_work: "1c8d5a3c1f3b24e0a1c",
+                hash: "7b414d6060f40daf74c4392e16fdd5022700b16555034f158a098fa8b201f394",
+                pre_pow: "08ae2ddb50d592f3640629b16603b02efae2a44366b6ac922b8ddd0ab11ac190",
+                pow: "c922ff40a38ea9aff5ae83c5a54bd6b54dd0786cbcf0da6f5219791d67902ec2",
+            },
+            Vector {
+                name: "v1 single parent",
+                version: 1,
+                levels: &[1],
+                bits: 0x207fffff,
+                timestamp: 1700000000123,
+                nonce: 1,
+                daa_score: 1,
+                blue_score: 1,
+                blue_work: "1",
+                hash: "8c654478c48ac25163074513d9072c5054581a8ad16c00a302212e315ef96f5e",
+                pre_pow: "2cf9ef2a2ccfd86a5d7920cd9dbe8f580aaef009423242d5376ca6a1af819689",
+                pow: "e919a9be983c522b5f520a4c46f04078e4950ac484183def331a86883c51e709",
+            },
+            Vector {
+                name: "empty parents",
+                version: 1,
+                levels: &[],
+                bits: 0x1d00ffff,
+                timestamp: 0,
+                nonce: 0,
+                daa_score: 0,
+                blue_score: 0,
+                blue_work: "",
+                hash: "8c7e80e5a5ad99218449df9e706cd5257da2519b03656402b1e68c68acb73d3a",
+                pre_pow: "8c7e80e5a5ad99218449df9e706cd5257da2519b03656402b1e68c68acb73d3a",
+                pow: "2847165cff38b4086a87503002d1d82a12015fb67cc69fd6e7186719da8b38dd",
+            },
+            Vector {
+                name: "odd blue_work",
+                version: 1,
+                levels: &[2, 2],
+                bits: 0x1b0404cb,
+                timestamp: 1680000000000,
+                nonce: u64::MAX,
+                daa_score: u64::MAX,
+                blue_score: u64::MAX,
+                blue_work: "abc",
+                hash: "0cdea44ad9a28e722a1f4b930a8eaeaa509065ca50e0a2ef725ac7b35eb63e17",
+                pre_pow: "950c199ff5381e1f29630ca4102d3dad2d07d7bcfc3bd13c58d905ad29c02919",
+                pow: "4aa66bc7236c484ac269556b51d1889480d69981adca9d318b5b6bd3181bbbb0",
+            },
+            Vector {
+                name: "testnet-11",
+                version: 1,
+                levels: &[10, 10, 8, 5, 1],
+                bits: 0x1e21bc1c,
+                timestamp: 1699999999999,
+                nonce: 42,
+                daa_score: 60000000,
+                blue_score: 59000000,
+                blue_work: "3bc3ec5e1f2aa8d",
+                hash: "46045bde9f95965ecab9f1bc8bb2ed511c8b1dba69a91478a91c700ad9b975ee",
+                pre_pow: "1b2ed50e03583b9051726893e54844bc60c574d7190df03643bf708aa6e0e2f4",
+                pow: "85f0a14614b5a517aec6d3755c64f2633e72f10327e993b486b77a0a45a6e66c",
+            },
+            Vector {
+                name: "devnet",
+                version: 1,
+                levels: &[1, 1],
+                bits: 0x207fffff,
+                timestamp: 1650000000000,
+                nonce: 7,
+                daa_score: 100,
+                blue_score: 100,
+                blue_work: "64",
+                hash: "9ec61f85af3659855a896db89f8257b1a0f4dd274b4df67b36e27e5763a25e48",
+                pre_pow: "6a5505a8dade47c98f868572aff5f1712af5f9a532ce9629695362e95bbbde39",
+                pow: "e7f771381ba6a1436333cb1daa19d82ca42f54245e3c133bd675498c4d9c2927",
+            },
+        ];
+
+        for v in vectors {
+            let mut seed = 1u8;
+            let parents = v
+                .levels
+                .iter()
+                .map(|&n| RpcBlockLevelParents {
+                    parent_hashes: (0..n)
+                        .map(|_| {
+                            seed += 1;
+                            test_hash(seed)
+                        })
+                        .collect(),
+                })
+                .collect();
+            let header = RpcBlockHeader {
+                version: v.version,
+                parents,
+                hash_merkle_root: test_hash(200),
+                accepted_id_merkle_root: test_hash(201),
+                utxo_commitment: test_hash(202),
+                timestamp: v.timestamp,
+                bits: v.bits,
+                nonce: v.nonce,
+                daa_score: v.daa_score,
+                blue_work: v.blue_work.into(),
+                pruning_point: test_hash(203),
+                blue_score: v.blue_score,
+            };
+            let name = v.name;
+
+            let hash = header.hash(false).unwrap();
+            assert_eq!(hex::encode(hash.as_bytes()), v.hash, "{name}: hash");
+            let hash = header.hash(true).unwrap();
+            assert_eq!(
+                hex::encode(hash.as_bytes()),
+                v.pre_pow,
+                "{name}: pre_pow hash"
+            );
+
+            let pre_pow = header.pre_pow().unwrap();
+            assert_eq!(to_hex(pre_pow), v.pre_pow, "{name}: pre_pow");
+
+            let state = pow::State::new(pre_pow, v.timestamp as u64);
+            assert_eq!(to_hex(state.calculate_pow(v.nonce)), v.pow, "{name}: pow");
+        }
+    }
+
+    #[test]
+    fn coinbase_subsidy() {
+        let coinbase = |payload: &str| RpcBlock {
+            transactions: vec![RpcTransaction {
+                payload: payload.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // Blue score 1000, subsidy 12.3456789 KAS, then the script and extra data
+        let block = coinbase("e803000000000000d2029649000000000000020051");
+        assert_eq!(block.subsidy(), Some(1_234_567_890));
+        assert_eq!(coinbase("e803").subsidy(), None);
+        assert_eq!(RpcBlock::default().subsidy(), None);
+    }
+
+    #[test]
+    fn coinbase_reward() {
+        let output = |amount| RpcTransactionOutput {
+            amount,
+            ..Default::default()
+        };
+        let block = |outputs| RpcBlock {
+            transactions: vec![RpcTransaction {
+                payload: "e803000000000000d2029649000000000000020051".into(),
+                outputs,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // Two merged blocks with 1000 and 3000 sompi of fees
+        let reward = block(vec![output(1_234_568_890), output(1_234_570_890)]).reward();
+        assert_eq!(
+            reward,
+            Some(BlockReward {
+                subsidy: 1_234_567_890,
+                fees: 2000
+            })
+        );
+        assert_eq!(reward.unwrap().total(), 1_234_569_890);
+        assert_eq!(block(vec![]).reward().unwrap().fees, 0);
+        assert_eq!(RpcBlock::default().reward(), None);
+    }
+
+    fn to_hex(v: U256) -> String {
+        let bytes: Vec<u8> = v.as_slice().iter().flat_map(|w| w.to_le_bytes()).collect();
+        hex::encode(bytes)
+    }
 }
@@ -0,0 +1,344 @@
+use super::proto::kaspad_message::Payload;
+use super::proto::rpc_server::{Rpc, RpcServer};
+use super::proto::submit_block_response_message::RejectReason;
+use super::proto::*;
+use super::{BlockOrigin, Header, KaspadHandle, SubmitResult};
+use crate::admin::token_matches;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info};
+
+type Outgoing = mpsc::UnboundedSender<Result<KaspadMessage, Status>>;
+
+/// Start of the server version a backend reports to frontends
+const SERVER_NAME: &str = "kaspad-stratum";
+
+/// Whether the node reporting `version` is a backend instance, which serves
+/// templates and blocks but not the chain block acceptance is learned from
+pub fn is_backend(version: &str) -> bool {
+    version.starts_with(SERVER_NAME)
+}
+
+fn unsupported() -> Option<RpcError> {
+    Some(RpcError {
+        message: "Not supported by a backend instance, payouts need kaspad".into(),
+    })
+}
+
+/// Serves this instance's templates to frontend instances and forwards their
+/// blocks to kaspad. It speaks kaspad's gRPC protocol, so frontends use it as
+/// their node through `--rpc-url`.
+#[derive(Clone)]
+pub struct Backend {
+    handle: KaspadHandle,
+    templates: Arc<watch::Sender<Option<Arc<RpcBlock>>>>,
+    /// Bearer token frontends have to send
+    token: Option<Arc<str>>,
+}
+
+impl Backend {
+    pub fn new(handle: KaspadHandle, token: Option<&str>) -> Self {
+        let (templates, _) = watch::channel(None);
+        Backend {
+            handle,
+            templates: Arc::new(templates),
+            token: token.map(Into::into),
+        }
+    }
+
+    /// Hand out a new template, subscribed frontends are notified
+    pub fn publish(&self, template: &RpcBlock) {
+        self.templates
+            .send_replace(Some(Arc::new(template.clone())));
+    }
+
+    /// Kaspad is unreachable, frontends are disconnected until the next
+    /// template so they pause as well
+    pub fn pause(&self) {
+        self.templates.send_replace(None);
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+        info!("Serving frontends on {addr}");
+        let token = self.token.clone();
+        // The error type is given by tonic
+        #[allow(clippy::result_large_err)]
+        let authenticate = move |req: Request<()>| {
+            let token = match &token {
+                Some(t) => t,
+                None => return Ok(req),
+            };
+            let given = req
+                .metadata()
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            if token_matches(given, token) {
+                Ok(req)
+            } else {
+                Err(Status::unauthenticated("Invalid token"))
+            }
+        };
+        Server::builder()
+            .add_service(RpcServer::with_interceptor(self, authenticate))
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Rpc for Backend {
+    type MessageStreamStream = UnboundedReceiverStream<Result<KaspadMessage, Status>>;
+
+    async fn message_stream(
+        &self,
+        req: Request<Streaming<KaspadMessage>>,
+    ) -> Result<Response<Self::MessageStreamStream>, Status> {
+        let addr = req.remote_addr();
+        if let Some(addr) = addr {
+            info!("Frontend {addr} connected");
+        }
+        let (send, recv) = mpsc::unbounded_channel();
+        let frontend = Frontend {
+            submits: submit_queue(send.clone()),
+            send,
+            handle: self.handle.clone(),
+            templates: self.templates.subscribe(),
+            subscribed: false,
+            addr,
+        };
+        tokio::spawn(frontend.run(req.into_inner()));
+        Ok(Response::new(UnboundedReceiverStream::new(recv)))
+    }
+}
+
+/// Answers blocks in the order they were submitted, which is how kaspad
+/// clients match the responses
+fn submit_queue(send: Outgoing) -> mpsc::UnboundedSender<SubmitResult> {
+    let (submits, mut recv) = mpsc::unbounded_channel::<SubmitResult>();
+    tokio::spawn(async move {
+        while let Some(result) = recv.recv().await {
+            let error = result
+                .await
+                .unwrap_or_else(|_| Some("Kaspad connection lost".into()));
+            let reject_reason = match error {
+                Some(_) => RejectReason::BlockInvalid,
+                None => RejectReason::None,
+            };
+            let res = SubmitBlockResponseMessage {
+                reject_reason: reject_reason as i32,
+                error: error.map(|e| RpcError { message: e.into() }),
+            };
+            if send
+                .send(Ok(message(Payload::SubmitBlockResponse(res))))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    submits
+}
+
+fn block_hash(block: &RpcBlock) -> Option<String> {
+    let header = block.header.as_ref()?;
+    let hash = Header::parse(header).ok()?.hash(header.nonce);
+    Some(hex::encode(hash.as_bytes()))
+}
+
+fn message(payload: Payload) -> KaspadMessage {
+    KaspadMessage {
+        payload: Some(payload),
+    }
+}
+
+struct Frontend {
+    send: Outgoing,
+    submits: mpsc::UnboundedSender<SubmitResult>,
+    handle: KaspadHandle,
+    templates: watch::Receiver<Option<Arc<RpcBlock>>>,
+    /// Asked for new template notifications
+    subscribed: bool,
+    addr: Option<SocketAddr>,
+}
+
+impl Frontend {
+    async fn run(mut self, mut incoming: Streaming<KaspadMessage>) {
+        loop {
+            tokio::select! {
+                msg = incoming.message() => match msg {
+                    Ok(Some(KaspadMessage { payload: Some(payload) })) => {
+                        if self.handle(payload).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => return debug!("Frontend disconnected"),
+                    Err(e) => return debug!("Frontend stream failed: {e}"),
+                },
+                res = self.templates.changed() => {
+                    if res.is_err() || self.templates.borrow().is_none() {
+                        return debug!("Disconnecting frontend while paused");
+                    }
+                    if self.subscribed {
+                        let notification = NewBlockTemplateNotificationMessage {};
+                        if self.reply(Payload::NewBlockTemplateNotification(notification)).is_err() {
+                            return;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn reply(&self, payload: Payload) -> Result<()> {
+        self.send.send(Ok(message(payload)))?;
+        Ok(())
+    }
+
+    fn handle(&mut self, payload: Payload) -> Result<()> {
+        match payload {
+            Payload::GetInfoRequest(_) => {
+                let info = GetInfoResponseMessage {
+                    server_version: format!("{SERVER_NAME} {}", env!("CARGO_PKG_VERSION")),
+                    is_synced: self.templates.borrow().is_some(),
+                    ..Default::default()
+                };
+                self.reply(Payload::GetInfoResponse(info))
+            }
+            Payload::NotifyNewBlockTemplateRequest(_) => {
+                self.subscribed = true;
+                let res = NotifyNewBlockTemplateResponseMessage { error: None };
+                self.reply(Payload::NotifyNewBlockTemplateResponse(res))
+            }
+            Payload::GetBlockTemplateRequest(_) => {
+                // Templates pay to the backend's address
+                let template = self.templates.borrow().clone();
+                let res = match template {
+                    Some(block) => GetBlockTemplateResponseMessage {
+                        block: Some((*block).clone()),
+                        is_synced: true,
+                        error: None,
+                    },
+                    None => GetBlockTemplateResponseMessage {
+                        block: None,
+                        is_synced: false,
+                        error: Some(RpcError {
+                            message: "Backend has no template".into(),
+                        }),
+                    },
+                };
+                self.reply(Payload::GetBlockTemplateResponse(res))
+            }
+            Payload::SubmitBlockRequest(req) => {
+                let result = match req.block {
+                    Some(block) => {
+                        let origin = BlockOrigin {
+                            hash: block_hash(&block).unwrap_or_default(),
+                            worker: match self.addr {
+                                Some(addr) => format!("frontend {addr}"),
+                                None => "a frontend".into(),
+                            },
+                            job_id: None,
+                        };
+                        self.handle.submit_block(block, origin)
+                    }
+                    None => {
+                        let (reply, result) = oneshot::channel();
+                        let _ = reply.send(Some("Missing block".into()));
+                        result
+                    }
+                };
+                self.submits.send(result)?;
+                Ok(())
+            }
+            // Answered rather than dropped, so the frontend isn't left waiting
+            Payload::NotifyVirtualSelectedParentChainChangedRequest(_) => {
+                let res = NotifyVirtualSelectedParentChainChangedResponseMessage {
+                    error: unsupported(),
+                };
+                self.reply(Payload::NotifyVirtualSelectedParentChainChangedResponse(
+                    res,
+                ))
+            }
+            Payload::GetBlockRequest(_) => {
+                let res = GetBlockResponseMessage {
+                    block: None,
+                    error: unsupported(),
+                };
+                self.reply(Payload::GetBlockResponse(res))
+            }
+            Payload::GetVirtualSelectedParentChainFromBlockRequest(_) => {
+                let res = GetVirtualSelectedParentChainFromBlockResponseMessage {
+                    error: unsupported(),
+                    ..Default::default()
+                };
+                self.reply(Payload::GetVirtualSelectedParentChainFromBlockResponse(res))
+            }
+            _ => {
+                debug!("Unsupported request from frontend");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_backend, Backend, Frontend, Payload};
+    use super::{GetBlockRequestMessage, GetInfoRequestMessage};
+    use crate::kaspad::proto::NotifyVirtualSelectedParentChainChangedRequestMessage;
+    use crate::kaspad::KaspadHandle;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn refuses_chain_requests() {
+        let (handle, _commands) = KaspadHandle::new();
+        let backend = Backend::new(handle.clone(), None);
+        let (send, mut recv) = mpsc::unbounded_channel();
+        let mut frontend = Frontend {
+            submits: mpsc::unbounded_channel().0,
+            send,
+            handle,
+            templates: backend.templates.subscribe(),
+            subscribed: false,
+            addr: None,
+        };
+        let mut reply = |payload| {
+            frontend.handle(payload).unwrap();
+            recv.try_recv().unwrap().unwrap().payload.unwrap()
+        };
+        match reply(Payload::GetInfoRequest(GetInfoRequestMessage {})) {
+            Payload::GetInfoResponse(info) => assert!(is_backend(&info.server_version)),
+            _ => panic!("no info"),
+        }
+        let chain = NotifyVirtualSelectedParentChainChangedRequestMessage::default();
+        match reply(Payload::NotifyVirtualSelectedParentChainChangedRequest(
+            chain,
+        )) {
+            Payload::NotifyVirtualSelectedParentChainChangedResponse(res) => {
+                assert!(res.error.is_some())
+            }
+            _ => panic!("no chain response"),
+        }
+        let block = GetBlockRequestMessage::default();
+        match reply(Payload::GetBlockRequest(block)) {
+            Payload::GetBlockResponse(res) => assert!(res.block.is_none() && res.error.is_some()),
+            _ => panic!("no block response"),
+        }
+        match reply(Payload::get_chain_from_block("00")) {
+            Payload::GetVirtualSelectedParentChainFromBlockResponse(res) => {
+                assert!(res.added_chain_block_hashes.is_empty() && res.error.is_some())
+            }
+            _ => panic!("no chain from block response"),
+        }
+        assert!(!is_backend("v0.12.17"));
+    }
+}
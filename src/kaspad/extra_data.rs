@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// Template requests so far
+    Counter,
+}
+
+/// Extra data of the coinbase, with placeholders expanded for every template
+/// request so blocks of different instances can be told apart on-chain:
+/// `{version}`, `{counter}` counting the requests, and the variables given
+/// to [`ExtraData::parse`]. `{{` and `}}` are literal braces.
+#[derive(Clone, Debug)]
+pub struct ExtraData {
+    parts: Vec<Part>,
+    counter: Arc<AtomicU64>,
+}
+
+impl ExtraData {
+    pub fn parse(template: &str, vars: &[(String, String)]) -> Result<Self> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find(['{', '}']) {
+            text.push_str(&rest[..i]);
+            let brace = &rest[i..i + 1];
+            rest = &rest[i + 1..];
+            if let Some(after) = rest.strip_prefix(brace) {
+                text.push_str(brace);
+                rest = after;
+                continue;
+            }
+            if brace == "}" {
+                bail!("Unmatched }} in the extra data");
+            }
+            let (name, after) = match rest.split_once('}') {
+                Some(split) => split,
+                None => bail!("Unclosed {{ in the extra data"),
+            };
+            rest = after;
+            match name {
+                "version" => text.push_str(env!("CARGO_PKG_VERSION")),
+                "counter" => {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Counter);
+                }
+                _ => match vars.iter().find(|(var, _)| var == name) {
+                    Some((_, value)) => text.push_str(value),
+                    None => bail!("Unknown placeholder {{{name}}} in the extra data"),
+                },
+            }
+        }
+        text.push_str(rest);
+        parts.push(Part::Text(text));
+        parts.retain(|p| *p != Part::Text(String::new()));
+        Ok(ExtraData {
+            parts,
+            counter: Default::default(),
+        })
+    }
+
+    /// The extra data of the next template request
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Counter => {
+                    let n = self.counter.fetch_add(1, Ordering::Relaxed);
+                    out.push_str(&n.to_string());
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExtraData;
+
+    #[test]
+    fn expands_placeholders() {
+        let vars = [("region".to_string(), "eu".to_string())];
+        let plain = ExtraData::parse("kaspad-stratum", &vars).unwrap();
+        assert_eq!(plain.render(), "kaspad-stratum");
+
+        let extra = ExtraData::parse("pool/{region}/{counter} {{v{version}}}", &vars).unwrap();
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(extra.render(), format!("pool/eu/0 {{v{version}}}"));
+        // Clones share the counter
+        assert_eq!(extra.clone().render(), format!("pool/eu/1 {{v{version}}}"));
+
+        for invalid in ["{instance}", "a{region", "a}b", "{}"] {
+            assert!(ExtraData::parse(invalid, &vars).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use super::RpcBlockHeader;
+use crate::U256;
+use anyhow::Result;
+use blake2b_simd::Hash;
+
+type Hash32 = [u8; 32];
+
+/// A block header with its hex fields decoded, so hashing it for every
+/// submitted block doesn't parse the template again
+#[derive(Clone)]
+pub struct Header {
+    version: u16,
+    parents: Vec<Vec<Hash32>>,
+    hash_merkle_root: Hash32,
+    accepted_id_merkle_root: Hash32,
+    utxo_commitment: Hash32,
+    timestamp: i64,
+    bits: u32,
+    daa_score: u64,
+    blue_score: u64,
+    blue_work: Vec<u8>,
+    pruning_point: Hash32,
+}
+
+impl Header {
+    pub fn parse(header: &RpcBlockHeader) -> Result<Self> {
+        let blue_work = if header.blue_work.len().is_multiple_of(2) {
+            hex::decode(&header.blue_work)?
+        } else {
+            hex::decode(format!("0{}", header.blue_work))?
+        };
+        Ok(Header {
+            version: header.version as u16,
+            parents: header
+                .parents
+                .iter()
+                .map(|level| level.parent_hashes.iter().map(decode).collect())
+                .collect::<Result<_>>()?,
+            hash_merkle_root: decode(&header.hash_merkle_root)?,
+            accepted_id_merkle_root: decode(&header.accepted_id_merkle_root)?,
+            utxo_commitment: decode(&header.utxo_commitment)?,
+            timestamp: header.timestamp,
+            bits: header.bits,
+            daa_score: header.daa_score,
+            blue_score: header.blue_score,
+            blue_work,
+            pruning_point: decode(&header.pruning_point)?,
+        })
+    }
+
+    /// Pre-PoW hash as the little endian words miners expect
+    pub fn pre_pow(&self) -> U256 {
+        let hash = self.hash_pre_pow();
+        let mut out = [0; 4];
+        for (o, c) in out.iter_mut().zip(hash.as_bytes().chunks_exact(8)) {
+            *o = u64::from_le_bytes(c.try_into().unwrap());
+        }
+        out.into()
+    }
+
+    /// Hash without timestamp and nonce, which the miner combines with it
+    pub fn hash_pre_pow(&self) -> Hash {
+        self.hash_with(0, 0)
+    }
+
+    /// Block hash with the given nonce
+    pub fn hash(&self, nonce: u64) -> Hash {
+        self.hash_with(self.timestamp, nonce)
+    }
+
+    /// Block hash with the given nonce and the timestamp rolled by the miner
+    pub fn hash_with(&self, timestamp: i64, nonce: u64) -> Hash {
+        let mut state = blake2b_simd::Params::new()
+            .hash_length(32)
+            .key(b"BlockHash")
+            .to_state();
+
+        state.update(&self.version.to_le_bytes());
+        state.update(&(self.parents.len() as u64).to_le_bytes());
+        for level in &self.parents {
+            state.update(&(level.len() as u64).to_le_bytes());
+            for hash in level {
+                state.update(hash);
+            }
+        }
+        state
+            .update(&self.hash_merkle_root)
+            .update(&self.accepted_id_merkle_root)
+            .update(&self.utxo_commitment)
+            .update(&timestamp.to_le_bytes())
+            .update(&self.bits.to_le_bytes())
+            .update(&nonce.to_le_bytes())
+            .update(&self.daa_score.to_le_bytes())
+            .update(&self.blue_score.to_le_bytes())
+            .update(&(self.blue_work.len() as u64).to_le_bytes())
+            .update(&self.blue_work)
+            .update(&self.pruning_point);
+        state.finalize()
+    }
+}
+
+fn decode(hex: impl AsRef<[u8]>) -> Result<Hash32> {
+    let mut hash = [0; 32];
+    hex::decode_to_slice(hex, &mut hash)?;
+    Ok(hash)
+}
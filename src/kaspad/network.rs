@@ -0,0 +1,92 @@
+/// Kaspa network the node runs on, giving the defaults that differ between
+/// them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum Network {
+    Mainnet,
+    #[clap(name = "testnet-10")]
+    Testnet10,
+    /// 10 blocks per second
+    #[clap(name = "testnet-11")]
+    Testnet11,
+    Devnet,
+    Simnet,
+}
+
+impl Network {
+    /// Default gRPC port of kaspad
+    pub fn rpc_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 16110,
+            Network::Testnet10 | Network::Testnet11 => 16210,
+            Network::Simnet => 16510,
+            Network::Devnet => 16610,
+        }
+    }
+
+    /// Default stratum port, distinct per network so instances for several
+    /// can share a host
+    pub fn stratum_port(self) -> u16 {
+        match self {
+            Network::Mainnet => 6969,
+            Network::Testnet10 => 6970,
+            Network::Testnet11 => 6971,
+            Network::Devnet => 6972,
+            Network::Simnet => 6973,
+        }
+    }
+
+    /// Prefix of the addresses of the network
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Network::Mainnet => "kaspa",
+            Network::Testnet10 | Network::Testnet11 => "kaspatest",
+            Network::Devnet => "kaspadev",
+            Network::Simnet => "kaspasim",
+        }
+    }
+
+    /// Factor of the default share difficulties, following the hashrate
+    /// behind each block: 10 blocks per second split the work of testnet 11
+    /// tenfold, and devnet and simnet are mined by a CPU or two
+    pub fn difficulty_scale(self) -> f64 {
+        match self {
+            Network::Mainnet | Network::Testnet10 => 1.0,
+            Network::Testnet11 => 0.1,
+            Network::Devnet | Network::Simnet => 0.001,
+        }
+    }
+
+    /// URL of the node at `host`, or the local one, with the default port
+    /// added when it has none
+    pub fn rpc_url(self, host: Option<&str>) -> String {
+        let host = host.unwrap_or("127.0.0.1");
+        let address = host.split_once("://").map_or(host, |(_, rest)| rest);
+        // IPv6 addresses only have a port after the closing bracket
+        let has_port = match address.rsplit_once(']') {
+            Some((_, after)) => after.starts_with(':'),
+            None => address.contains(':'),
+        };
+        match has_port {
+            true => host.to_string(),
+            false => format!("{host}:{}", self.rpc_port()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Network;
+
+    #[test]
+    fn network_defaults() {
+        let testnet = Network::Testnet11;
+        assert_eq!(testnet.rpc_url(None), "127.0.0.1:16210");
+        assert_eq!(testnet.rpc_url(Some("node")), "node:16210");
+        assert_eq!(testnet.rpc_url(Some("http://node")), "http://node:16210");
+        assert_eq!(testnet.rpc_url(Some("node:17110")), "node:17110");
+        assert_eq!(testnet.rpc_url(Some("[::1]")), "[::1]:16210");
+        assert_eq!(testnet.rpc_url(Some("[::1]:17110")), "[::1]:17110");
+        assert_eq!(Network::Simnet.rpc_url(None), "127.0.0.1:16510");
+        assert_eq!(testnet.prefix(), "kaspatest");
+    }
+}
@@ -0,0 +1,190 @@
+use super::proto::kaspad_message::Payload;
+use super::{Command, Message, RpcBlock};
+use super::{Recv, Send};
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tracing::{debug, info, warn};
+
+/// Templates read from a file or stdin instead of a node, one JSON block in
+/// kaspad's shape per line, for protocol work and miner bring-up without
+/// one. Every template is announced like a new template of the node, and
+/// template requests are answered with the latest.
+#[derive(Clone, Debug)]
+pub struct TemplateFile {
+    /// `-` for stdin
+    pub path: PathBuf,
+    /// Blocks that would have been submitted are appended here, one JSON
+    /// block per line
+    pub submissions: Option<PathBuf>,
+}
+
+pub(super) struct OfflineTask {
+    pub source: TemplateFile,
+    pub send_msg: Send<Message>,
+    pub recv_cmd: Recv<Command>,
+}
+
+impl OfflineTask {
+    /// Serve templates until the client is dropped, returning why it stopped
+    pub async fn run(mut self) -> String {
+        match self.serve().await {
+            Ok(()) => "client dropped".into(),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    async fn serve(&mut self) -> Result<()> {
+        let path = &self.source.path;
+        let input: Box<dyn AsyncRead + std::marker::Send + Unpin> = match path.to_str() {
+            Some("-") => Box::new(tokio::io::stdin()),
+            _ => Box::new(
+                File::open(path)
+                    .await
+                    .with_context(|| format!("Unable to open {}", path.display()))?,
+            ),
+        };
+        let mut submissions = match &self.source.submissions {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Unable to open {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let mut lines = BufReader::new(input).lines();
+        let mut line_number = 0;
+        let mut reading = true;
+        let mut template: Option<RpcBlock> = None;
+        self.send_msg.send(Message::Online)?;
+
+        loop {
+            tokio::select! {
+                line = lines.next_line(), if reading => {
+                    let line = match line? {
+                        Some(line) => line,
+                        None => {
+                            info!("Read all templates, serving the last one");
+                            reading = false;
+                            continue;
+                        }
+                    };
+                    line_number += 1;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match parse_template(&line) {
+                        Ok(block) => {
+                            template = Some(block);
+                            self.send_msg.send(Message::NewTemplate)?;
+                        }
+                        Err(e) => warn!("Skipping template on line {line_number}: {e}"),
+                    }
+                }
+                cmd = self.recv_cmd.recv() => {
+                    let cmd = match cmd {
+                        Some(c) => c,
+                        None => return Ok(()),
+                    };
+                    match cmd.payload {
+                        Payload::GetBlockTemplateRequest(_) => {
+                            if let Some(template) = &template {
+                                let template = Box::new(template.clone());
+                                self.send_msg.send(Message::Template(template))?;
+                            }
+                        }
+                        Payload::SubmitBlockRequest(req) => {
+                            let block = req.block.unwrap_or_default();
+                            let submit = cmd.submit;
+                            let origin = submit.as_ref().map_or("a block".into(), |s| s.origin.to_string());
+                            info!("Would submit {origin} at DAA score {}", daa_score(&block));
+                            if let Some(file) = &mut submissions {
+                                let mut line = serde_json::to_string(&block)?;
+                                line.push('\n');
+                                file.write_all(line.as_bytes()).await?;
+                                file.flush().await?;
+                            }
+                            if let Some(submit) = submit {
+                                let _ = submit.reply.send(None);
+                            }
+                        }
+                        _ => debug!("Ignoring a request without a node"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_template(line: &str) -> Result<RpcBlock> {
+    let block: RpcBlock = serde_json::from_str(line)?;
+    if block.header.is_none() {
+        anyhow::bail!("missing a header");
+    }
+    Ok(block)
+}
+
+fn daa_score(block: &RpcBlock) -> u64 {
+    block.header.as_ref().map_or(0, |h| h.daa_score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_template, TemplateFile};
+    use crate::kaspad::{BlockOrigin, Client, ExtraData, KaspadHandle, Message, PayAddresses};
+
+    const TEMPLATE: &str = r#"{"header":{"version":1,"parents":[{"parentHashes":["aa"]}],"timestamp":1700000000000,"bits":453248203,"daaScore":60000000,"blueWork":"3bc3"},"transactions":[{"outputs":[{"amount":5000,"scriptPublicKey":{"scriptPublicKey":"20ab"}}]}]}"#;
+
+    #[test]
+    fn reads_kaspad_json() {
+        let block = parse_template(TEMPLATE).unwrap();
+        let header = block.header.as_ref().unwrap();
+        assert_eq!(header.daa_score, 60_000_000);
+        assert_eq!(header.parents[0].parent_hashes, ["aa"]);
+        assert_eq!(block.transactions[0].outputs[0].amount, 5000);
+        // Written back in the same shape
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(json["header"]["daaScore"], 60_000_000);
+
+        assert!(parse_template(r#"{"transactions":[]}"#).is_err());
+        assert!(parse_template("{").is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_templates_offline() {
+        let dir =
+            std::env::temp_dir().join(format!("kaspad-stratum-offline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("templates.jsonl");
+        std::fs::write(&path, format!("{TEMPLATE}\nnot json\n")).unwrap();
+        let source = TemplateFile {
+            path,
+            submissions: Some(dir.join("submitted.jsonl")),
+        };
+        let (handle, recv_cmd) = KaspadHandle::new();
+        let addresses = PayAddresses::new(vec![("qqa".into(), 1)], None).unwrap();
+        let extra_data = ExtraData::parse("", &[]).unwrap();
+        let (client, mut msgs) =
+            Client::offline(source, addresses, extra_data, handle.clone(), recv_cmd);
+
+        assert!(matches!(msgs.recv().await, Some(Message::Online)));
+        assert!(matches!(msgs.recv().await, Some(Message::NewTemplate)));
+        assert!(client.request_template());
+        let template = match msgs.recv().await {
+            Some(Message::Template(t)) => t,
+            _ => panic!("expected the template"),
+        };
+        assert_eq!(template.header.as_ref().unwrap().daa_score, 60_000_000);
+
+        let origin = BlockOrigin::default();
+        assert_eq!(handle.submit_block(*template, origin).await.unwrap(), None);
+        let submitted = std::fs::read_to_string(dir.join("submitted.jsonl")).unwrap();
+        assert_eq!(submitted.lines().count(), 1);
+        assert!(parse_template(submitted.trim()).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use std::sync::{Arc, Mutex};
+
+/// Addresses found blocks pay to, taking turns between template requests in
+/// proportion to their weights, so rewards are split across wallets without
+/// transfers
+#[derive(Clone, Debug)]
+pub struct PayAddresses {
+    addresses: Arc<[(String, u32)]>,
+    /// Credit of each address in the smooth weighted round robin, the
+    /// address with the most is next
+    credits: Arc<Mutex<Vec<i64>>>,
+}
+
+impl PayAddresses {
+    /// Addresses without prefix are taken as ones of the `network` prefix,
+    /// mainnet by default, and with a `network` all have to be of it
+    pub fn new(addresses: Vec<(String, u32)>, network: Option<&str>) -> Result<Self> {
+        if addresses.is_empty() {
+            bail!("No mining address");
+        }
+        let default = network.unwrap_or("kaspa");
+        let addresses: Vec<_> = addresses
+            .into_iter()
+            .map(|(address, weight)| match address.contains(':') {
+                true => (address, weight),
+                false => (format!("{default}:{address}"), weight),
+            })
+            .collect();
+        if let Some((address, _)) = addresses.iter().find(|(_, w)| *w == 0) {
+            bail!("Mining address {address} has a weight of 0");
+        }
+        let prefix = |address: &str| address.split(':').next().unwrap_or_default().to_string();
+        let first = prefix(&addresses[0].0);
+        if let Some(network) = network.filter(|n| *n != first) {
+            bail!(
+                "Mining address {} is not a {network} address",
+                addresses[0].0
+            );
+        }
+        if let Some((address, _)) = addresses.iter().find(|(a, _)| prefix(a) != first) {
+            bail!("Mining address {address} is of another network than {first}");
+        }
+        Ok(PayAddresses {
+            credits: Arc::new(Mutex::new(vec![0; addresses.len()])),
+            addresses: addresses.into(),
+        })
+    }
+
+    /// The address of the next template
+    pub fn next(&self) -> &str {
+        let mut credits = self.credits.lock().unwrap();
+        let total: i64 = self.addresses.iter().map(|(_, w)| *w as i64).sum();
+        for (credit, (_, weight)) in credits.iter_mut().zip(self.addresses.iter()) {
+            *credit += *weight as i64;
+        }
+        // The first of the largest on ties, so equal weights go in order
+        let (i, _) = credits
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, credit)| **credit)
+            .expect("there is an address");
+        credits[i] -= total;
+        &self.addresses[i].0
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = &str> {
+        self.addresses.iter().map(|(a, _)| a.as_str())
+    }
+
+    /// Address prefix of the network, such as `kaspa` or `kaspatest`
+    pub fn prefix(&self) -> &str {
+        self.addresses[0].0.split(':').next().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PayAddresses;
+
+    fn turns(addresses: &PayAddresses, n: usize) -> Vec<String> {
+        (0..n).map(|_| addresses.next().to_string()).collect()
+    }
+
+    #[test]
+    fn rotates_addresses() {
+        let single = PayAddresses::new(vec![("qqa".into(), 1)], None).unwrap();
+        assert_eq!(turns(&single, 2), ["kaspa:qqa", "kaspa:qqa"]);
+        assert_eq!(single.prefix(), "kaspa");
+
+        let even = PayAddresses::new(vec![("kaspa:qqa".into(), 1), ("kaspa:qqb".into(), 1)], None);
+        assert_eq!(
+            turns(&even.unwrap(), 4),
+            ["kaspa:qqa", "kaspa:qqb", "kaspa:qqa", "kaspa:qqb"]
+        );
+        let weighted =
+            PayAddresses::new(vec![("kaspa:qqa".into(), 3), ("kaspa:qqb".into(), 1)], None);
+        let turns = turns(&weighted.unwrap(), 8);
+        assert_eq!(turns.iter().filter(|a| *a == "kaspa:qqb").count(), 2);
+        // Spread out rather than in runs
+        assert_eq!(
+            turns[..4],
+            ["kaspa:qqa", "kaspa:qqa", "kaspa:qqb", "kaspa:qqa"]
+        );
+
+        assert!(PayAddresses::new(vec![], None).is_err());
+        assert!(PayAddresses::new(vec![("kaspa:qqa".into(), 0)], None).is_err());
+        let mixed = vec![("kaspa:qqa".into(), 1), ("kaspatest:qqb".into(), 1)];
+        assert!(PayAddresses::new(mixed, None).is_err());
+    }
+
+    #[test]
+    fn network_prefix() {
+        let testnet = Some("kaspatest");
+        let unprefixed = PayAddresses::new(vec![("qqa".into(), 1)], testnet).unwrap();
+        assert_eq!(unprefixed.next(), "kaspatest:qqa");
+        assert!(PayAddresses::new(vec![("kaspatest:qqa".into(), 1)], testnet).is_ok());
+        assert!(PayAddresses::new(vec![("kaspa:qqa".into(), 1)], testnet).is_err());
+    }
+}
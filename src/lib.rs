@@ -0,0 +1,24 @@
+pub mod access;
+pub mod admin;
+pub mod api;
+pub mod chaos;
+pub mod console;
+pub mod events;
+pub mod http;
+pub mod kaspad;
+pub mod metrics;
+pub mod mirror;
+pub mod payout;
+pub mod pow;
+mod redis;
+mod repeated;
+pub mod stratum;
+pub mod tui;
+mod uint;
+pub mod wallet;
+
+pub use crate::uint::U256;
+
+/// Log target of the lines `--quiet` keeps besides warnings and errors:
+/// the startup summary, connection summaries and found blocks
+pub const SUMMARY: &str = "kaspad_stratum::summary";
@@ -0,0 +1,266 @@
+use anyhow::Result;
+use clap::Args;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+#[derive(Args)]
+pub struct LoadtestArgs {
+    /// Address of the stratum server under test
+    #[clap(short, long, default_value = "127.0.0.1:6969")]
+    stratum_addr: String,
+    /// Number of simulated miners
+    #[clap(short, long, default_value = "100")]
+    connections: usize,
+    /// Shares per second submitted by each miner
+    #[clap(short = 'r', long, default_value = "0.2")]
+    share_rate: f64,
+    /// Test duration in seconds
+    #[clap(short = 't', long, default_value = "60")]
+    duration: u64,
+    /// Delay between opening consecutive connections in milliseconds
+    #[clap(long, default_value = "0")]
+    ramp_up: u64,
+    /// Password the miners authorize with, for servers requiring credentials
+    #[clap(long)]
+    password: Option<String>,
+}
+
+pub async fn run(args: LoadtestArgs) -> Result<()> {
+    info!(
+        "Starting load test against {} with {} miners submitting {} shares/s each",
+        args.stratum_addr, args.connections, args.share_rate
+    );
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let deadline = Instant::now() + Duration::from_secs(args.duration);
+    let mut tasks = Vec::with_capacity(args.connections);
+    for i in 0..args.connections {
+        let miner = SimulatedMiner {
+            addr: args.stratum_addr.clone(),
+            worker: format!("loadtest.{i}"),
+            password: args.password.clone(),
+            share_interval: Duration::from_secs_f64(1.0 / args.share_rate.max(0.001)),
+            stats: stats.clone(),
+            rng: 0x9e3779b97f4a7c15 ^ (i as u64 + 1),
+            deadline,
+        };
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = miner.run().await {
+                warn!("Miner {} failed: {e}", miner.worker);
+                miner.stats.lock().unwrap().failed += 1;
+            }
+        }));
+        if args.ramp_up > 0 {
+            time::sleep(Duration::from_millis(args.ramp_up)).await;
+        }
+    }
+
+    let mut report = time::interval(Duration::from_secs(10));
+    report.tick().await;
+    loop {
+        tokio::select! {
+            _ = report.tick() => stats.lock().unwrap().report(false),
+            _ = time::sleep_until(deadline.into()) => break,
+        }
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    stats.lock().unwrap().report(true);
+    Ok(())
+}
+
+struct SimulatedMiner {
+    addr: String,
+    worker: String,
+    password: Option<String>,
+    share_interval: Duration,
+    stats: Arc<Mutex<Stats>>,
+    rng: u64,
+    deadline: Instant,
+}
+
+impl SimulatedMiner {
+    async fn run(&self) -> Result<()> {
+        let mut conn = TcpStream::connect(&self.addr).await?;
+        let (reader, mut writer) = conn.split();
+        let mut lines = BufReader::new(reader).lines();
+        self.stats.lock().unwrap().connected += 1;
+
+        let mut rng = self.rng;
+        let mut next_id = 1u64;
+        let mut in_flight = HashMap::new();
+        let mut job: Option<String> = None;
+        let subscribed_at = Instant::now();
+        let mut first_notify = true;
+
+        write(
+            &mut writer,
+            next_id,
+            "mining.subscribe",
+            json!(["loadtest/0.1.0"]),
+        )
+        .await?;
+        next_id += 1;
+        // Servers without credentials accept shares without it as well
+        let authorize_id = next_id;
+        let params = match &self.password {
+            Some(password) => json!([self.worker, password]),
+            None => json!([self.worker]),
+        };
+        write(&mut writer, authorize_id, "mining.authorize", params).await?;
+        next_id += 1;
+        // Nonce bits fixed by the server
+        let mut extranonce = (0u64, 0u64);
+
+        let mut shares = time::interval(self.share_interval);
+        shares.tick().await;
+        let deadline = time::sleep_until(self.deadline.into());
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = shares.tick() => {
+                    let job = match &job {
+                        Some(j) => j.clone(),
+                        None => continue,
+                    };
+                    rng ^= rng << 13;
+                    rng ^= rng >> 7;
+                    rng ^= rng << 17;
+                    let nonce = extranonce.0 | (rng & !extranonce.1);
+                    let params = json!([self.worker, job, format!("0x{nonce:016x}")]);
+                    write(&mut writer, next_id, "mining.submit", params).await?;
+                    in_flight.insert(next_id, Instant::now());
+                    next_id += 1;
+                    self.stats.lock().unwrap().submitted += 1;
+                }
+                line = lines.next_line() => {
+                    let line = match line? {
+                        Some(l) => l,
+                        None => anyhow::bail!("connection closed by server"),
+                    };
+                    let msg: Value = serde_json::from_str(&line)?;
+                    match msg.get("method").and_then(Value::as_str) {
+                        Some("mining.notify") => {
+                            let now = Instant::now();
+                            let params = &msg["params"];
+                            let key = format!("{}/{}", params[0], params[2]);
+                            job = params[0].as_str().map(Into::into);
+
+                            let mut stats = self.stats.lock().unwrap();
+                            if first_notify {
+                                first_notify = false;
+                                stats.first_notify.push(now - subscribed_at);
+                            }
+                            let first_seen = *stats.jobs.entry(key).or_insert(now);
+                            stats.notify_spread.push(now - first_seen);
+                        }
+                        Some("set_extranonce" | "mining.set_extranonce") => {
+                            let prefix = msg["params"][0].as_str().unwrap_or_default();
+                            if let Ok(value) = u64::from_str_radix(prefix, 16) {
+                                let bits = 4 * prefix.len() as u32;
+                                let mask = u64::MAX.checked_shl(64 - bits).unwrap_or(0);
+                                extranonce = (value.checked_shl(64 - bits).unwrap_or(0), mask);
+                            }
+                        }
+                        Some(method) => debug!("Miner {} received {method}", self.worker),
+                        None if msg["id"] == json!(authorize_id) && !msg["error"].is_null() => {
+                            anyhow::bail!("authorize refused: {}", msg["error"]);
+                        }
+                        None => {
+                            let sent = match msg.get("id").and_then(Value::as_u64) {
+                                Some(id) => in_flight.remove(&id),
+                                None => None,
+                            };
+                            if let Some(sent) = sent {
+                                let mut stats = self.stats.lock().unwrap();
+                                stats.submit_rtt.push(sent.elapsed());
+                                if msg["result"] == json!(true) {
+                                    stats.accepted += 1;
+                                } else {
+                                    stats.rejected += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn write<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    id: u64,
+    method: &str,
+    params: Value,
+) -> Result<()> {
+    let mut data = serde_json::to_vec(&json!({ "id": id, "method": method, "params": params }))?;
+    data.push(b'\n');
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: u64,
+    failed: u64,
+    submitted: u64,
+    accepted: u64,
+    rejected: u64,
+    jobs: HashMap<String, Instant>,
+    first_notify: Samples,
+    notify_spread: Samples,
+    submit_rtt: Samples,
+}
+
+impl Stats {
+    fn report(&mut self, last: bool) {
+        let prefix = if last { "Final" } else { "Progress" };
+        info!(
+            "{prefix}: {} connected, {} failed, {} jobs, {} submitted, {} accepted, {} rejected",
+            self.connected,
+            self.failed,
+            self.jobs.len(),
+            self.submitted,
+            self.accepted,
+            self.rejected
+        );
+        info!("{prefix}: first notify {}", self.first_notify.summary());
+        info!("{prefix}: notify spread {}", self.notify_spread.summary());
+        info!("{prefix}: submit rtt {}", self.submit_rtt.summary());
+    }
+}
+
+#[derive(Default)]
+struct Samples(Vec<Duration>);
+
+impl Samples {
+    fn push(&mut self, d: Duration) {
+        self.0.push(d);
+    }
+
+    fn summary(&mut self) -> String {
+        if self.0.is_empty() {
+            return "n/a".into();
+        }
+        self.0.sort_unstable();
+        let at = |q: f64| self.0[((self.0.len() - 1) as f64 * q) as usize];
+        format!(
+            "n={} p50={:?} p90={:?} p99={:?} max={:?}",
+            self.0.len(),
+            at(0.5),
+            at(0.9),
+            at(0.99),
+            at(1.0)
+        )
+    }
+}
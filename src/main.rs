@@ -1,27 +1,423 @@
-mod kaspad;
-mod pow;
-mod stratum;
-mod uint;
-
-use crate::kaspad::KaspadHandle;
-pub use crate::uint::U256;
-use anyhow::Result;
-use clap::Parser;
-use kaspad::{Client, Message};
-use log::{debug, info, LevelFilter};
+mod ctl;
+mod loadtest;
+
+use anyhow::{Context, Result};
+use clap::{ArgEnum, Parser, Subcommand};
+use kaspad_stratum::access::{self, Access, Acl, Cidr};
+use kaspad_stratum::admin::{Admin, AuditLog, Reloadable};
+use kaspad_stratum::api::Api;
+use kaspad_stratum::console::Console;
+use kaspad_stratum::events::{ClockSkew, Event, Notifier, TemplateErrors, Webhook};
+use kaspad_stratum::kaspad::{
+    is_backend, Backend, Client, ExtraData, KaspadHandle, Message, Network, PayAddresses,
+    TemplateFile,
+};
+use kaspad_stratum::metrics::{self, Influx, InfluxVersion, StatsD};
+use kaspad_stratum::mirror::Mirror;
+use kaspad_stratum::payout::{Payouts, ReportFormat};
+use kaspad_stratum::stratum::{
+    self, Auth, ChainReplay, DialectConfig, ExtranonceMethod, ListenerConfig, NoncePrefix,
+    NotifyFormat, NotifyLimits, Overrides, PayoutConfig, Preset, Scheme, SecurityLog, SharedState,
+    SlowClient, SocketConfig, Tls, TlsConfig, UpstreamConfig, VarDiffConfig, SOMPI_PER_KAS,
+};
+use kaspad_stratum::tui::Dashboard;
+use kaspad_stratum::wallet::Wallet;
+use kaspad_stratum::SUMMARY;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time;
+use tracing::{debug, info, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+#[cfg(all(feature = "mimalloc", feature = "jemalloc"))]
+compile_error!("the mimalloc and jemalloc features are mutually exclusive");
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc")))]
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Job expiry of `--high-bps`, two seconds of blocks at 10 a second
+const HIGH_BPS_EXPIRY_DAA: u64 = 20;
 
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
+    /// kaspad gRPC address, the local node with --network, which also adds its port when missing
+    #[clap(short, long, required_unless_present_any = ["network", "template-file"])]
+    rpc_url: Option<String>,
+    /// Read templates from this file, or stdin as -, instead of a node, one JSON block per line
+    #[clap(long, conflicts_with = "rpc-url")]
+    template_file: Option<PathBuf>,
+    /// Append the blocks that would be submitted to this file, with --template-file
+    #[clap(long, requires = "template-file")]
+    submissions_file: Option<PathBuf>,
+    /// Stratum server address, 127.0.0.1 at the port of the --network by default
     #[clap(short, long)]
-    rpc_url: String,
-    #[clap(short, long, default_value = "127.0.0.1:6969")]
-    stratum_addr: String,
+    stratum_addr: Option<String>,
+    /// Network of the node, setting the default ports and difficulties and refusing mining addresses of others
+    #[clap(long, arg_enum)]
+    network: Option<Network>,
+    /// Coinbase extra data, with {version}, {counter} of the template requests and the --extra-data-var placeholders expanded
     #[clap(short, long, default_value = "kaspad-stratum")]
     extra_data: String,
-    #[clap(short, long)]
-    mining_addr: String,
-    #[clap(short, long)]
+    /// Placeholder of the extra data as NAME=VALUE, such as region=eu for {region}
+    #[clap(long, value_parser = parse_extra_data_var)]
+    extra_data_var: Vec<(String, String)>,
+    /// Address found blocks pay to, repeat to take turns between several as ADDRESS or ADDRESS=WEIGHT
+    #[clap(short, long, required = true, value_parser = parse_mining_addr)]
+    mining_addr: Vec<(String, u32)>,
+    #[clap(short, long, global = true)]
     debug: bool,
+    /// Only log warnings, errors, connection summaries and found blocks, for long running solo setups
+    #[clap(short, long, conflicts_with_all = &["debug", "pretty"])]
+    quiet: bool,
+    /// Print a line for every share and a banner for found blocks, colored on a terminal outside journald
+    #[clap(long)]
+    pretty: bool,
+    /// Show a terminal dashboard of the workers, hashrate, node and latest log lines instead of the log
+    #[clap(long, conflicts_with = "pretty")]
+    tui: bool,
+    /// Adjust each miner's share difficulty to its hashrate
+    #[clap(long)]
+    vardiff: bool,
+    /// Target seconds between shares of a miner with vardiff
+    #[clap(long, default_value = "5")]
+    share_time: f64,
+    /// Initial share difficulty with vardiff, 1 scaled to the --network by default
+    #[clap(long)]
+    start_difficulty: Option<f64>,
+    /// Start every miner at --start-difficulty, instead of known miners at the difficulty of their typical hashrate
+    #[clap(long)]
+    no_agent_difficulty: bool,
+    /// Seconds a reconnecting worker resumes its previous difficulty
+    #[clap(long, default_value = "600")]
+    difficulty_ttl: u64,
+    /// Protocol dialect spoken to every miner, detected from the agent of known miners by default
+    #[clap(long, arg_enum)]
+    dialect: Option<Preset>,
+    /// Another port on the stratum address speaking one dialect to every miner, as PORT=DIALECT
+    #[clap(long, value_parser = parse_dialect_port)]
+    dialect_port: Vec<(u16, Preset)>,
+    /// JSON file of ports on the stratum address with their own dialect, difficulties, TLS and extranonce size
+    #[clap(long)]
+    listeners: Option<PathBuf>,
+    /// Least milliseconds between two jobs sent to a miner, as PORT=MILLIS for the miners on a port or AGENT=MILLIS for agents starting with AGENT
+    #[clap(long, value_parser = parse_notify_interval)]
+    notify_interval: Vec<(String, u64)>,
+    /// Parse nonces without 0x prefix as decimal
+    #[clap(long)]
+    decimal_nonces: bool,
+    /// Send strict JSON-RPC 2.0 messages
+    #[clap(long)]
+    jsonrpc2: bool,
+    /// Send mining.set_difficulty before every mining.notify, for firmware that loses its difficulty
+    #[clap(long)]
+    resend_difficulty: bool,
+    /// Accept the timestamp miners rolled the job to after the nonce in mining.submit
+    #[clap(long)]
+    timestamp_rolling: bool,
+    /// Shape of the mining.notify params, overriding the dialect
+    #[clap(long, arg_enum)]
+    notify_format: Option<NotifyFormat>,
+    /// Method announcing the extranonce, overriding the dialect
+    #[clap(long, arg_enum)]
+    extranonce_method: Option<ExtranonceMethod>,
+    /// Whether to send the extranonce without mining.extranonce.subscribe, overriding the dialect
+    #[clap(long)]
+    unsolicited_extranonce: Option<bool>,
+    /// Handling of shares whose nonce doesn't start with the miner's extranonce, overriding the dialect
+    #[clap(long, arg_enum)]
+    nonce_prefix: Option<NoncePrefix>,
+    /// Seconds between console summaries, 0 to disable
+    #[clap(long, default_value = "60")]
+    summary_interval: u64,
+    /// Serve Prometheus metrics on this address
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(flatten)]
+    influx: InfluxArgs,
+    /// Send metrics to the StatsD daemon at this address
+    #[clap(long)]
+    statsd_addr: Option<String>,
+    /// Prefix of the StatsD metric names
+    #[clap(long, default_value = "kaspad_stratum")]
+    statsd_prefix: String,
+    /// Seconds between flushes to StatsD
+    #[clap(long, default_value = "10")]
+    statsd_interval: u64,
+    /// Report workers without shares for this many seconds
+    #[clap(long)]
+    worker_offline: Option<u64>,
+    /// POST events as JSON to this URL
+    #[clap(long)]
+    webhook_url: Option<String>,
+    /// Only let in workers listed with their password in this file, one `<worker> <password>` per line
+    #[clap(long)]
+    credentials: Option<PathBuf>,
+    /// Only let in workers this URL accepts with a 2xx status when POSTed their worker, password and IP
+    #[clap(long, conflicts_with = "credentials")]
+    auth_webhook: Option<String>,
+    /// Append auth failures, malformed messages and bans to this file, one line each for fail2ban
+    #[clap(long)]
+    security_log: Option<PathBuf>,
+    /// Allow and deny rules per listener, `<listener> <allow|deny> <cidr>` per line, reloaded when the file changes
+    #[clap(long)]
+    acl: Option<PathBuf>,
+    /// PEM certificate chain served on the TLS ports, reloaded when the file changes
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Another port on the stratum address, or a dialect or solo port, speaking stratum over TLS
+    #[clap(long, requires = "tls-cert")]
+    tls_port: Vec<u16>,
+    /// Serve the admin API, stats API and metrics over HTTPS with the TLS certificate
+    #[clap(long, requires = "tls-cert")]
+    http_tls: bool,
+    /// Shell command run for every found block
+    #[clap(long)]
+    on_block_found: Option<String>,
+    /// Handling of miners not reading their jobs in time
+    #[clap(long, arg_enum, default_value = "drop-jobs")]
+    slow_client: SlowClient,
+    /// Listeners accepting stratum connections in parallel, using SO_REUSEPORT
+    #[clap(long, default_value = "1")]
+    acceptors: usize,
+    /// Exit after this many consecutive failed attempts to reach kaspad, instead of retrying forever
+    #[clap(long)]
+    max_reconnects: Option<u32>,
+    /// Exit after kaspad answered this many template requests in a row with an error
+    #[clap(long)]
+    max_template_errors: Option<u32>,
+    /// Request a new template after this many seconds without one, 0 disables it
+    #[clap(long, default_value = "10")]
+    template_refresh: u64,
+    /// Warn when template timestamps differ from the local clock by more than this many seconds
+    #[clap(long, default_value = "10")]
+    max_clock_skew: u64,
+    /// Milliseconds shares for the previous job are still accepted after a new job
+    #[clap(long, default_value = "2000")]
+    job_grace_ms: u64,
+    /// Accept shares for jobs up to this many DAA scores behind the current one instead of the previous job within --job-grace-ms
+    #[clap(long)]
+    job_expiry_daa: Option<u64>,
+    /// Send job ids of two bytes, so late shares don't hit a newer job reusing their id
+    #[clap(long)]
+    wide_job_ids: bool,
+    /// Keep one template request in flight, answering the notifications meanwhile with a single request after it
+    #[clap(long)]
+    coalesce_templates: bool,
+    /// Settings for 10 blocks per second: --coalesce-templates, --wide-job-ids and --job-expiry-daa 20 unless given
+    #[clap(long)]
+    high_bps: bool,
+    /// Seconds a connection has to send mining.subscribe, 0 disables it
+    #[clap(long, default_value = "30")]
+    handshake_timeout: u64,
+    /// Share round work, workers and bans with other instances through Redis,
+    /// e.g. redis://:password@localhost:6379/0
+    #[clap(long)]
+    redis_url: Option<String>,
+    /// Prefix of the Redis keys
+    #[clap(long, default_value = "kaspad_stratum")]
+    redis_prefix: String,
+    /// Wait as hot standby until no other instance with the same Redis prefix serves miners
+    #[clap(long, requires = "redis-url")]
+    failover: bool,
+    /// Serve templates and block submissions on this address to frontend instances,
+    /// which use it as their --rpc-url
+    #[clap(long)]
+    backend_addr: Option<SocketAddr>,
+    /// Token frontends have to present to the backend
+    #[clap(long, requires = "backend-addr")]
+    backend_token: Option<String>,
+    /// Token presented to a backend instance given as --rpc-url
+    #[clap(long)]
+    rpc_token: Option<String>,
+    /// Instances splitting the extranonce prefixes between them, so their nonce ranges never overlap
+    #[clap(long, default_value = "1")]
+    instances: u16,
+    /// Extranonce partition of this instance, from 0, claimed through Redis when omitted
+    #[clap(long)]
+    instance: Option<u16>,
+    /// Check blocks but don't submit them to kaspad, can be toggled through the admin API
+    #[clap(long)]
+    dry_run: bool,
+    /// Serve the admin API on this address
+    #[clap(long)]
+    admin_addr: Option<SocketAddr>,
+    /// Bearer token required by the admin API
+    #[clap(long)]
+    admin_token: Option<String>,
+    /// Admins with their own token, one `<name> <token>` per line, named in the audit log
+    #[clap(long)]
+    admin_users: Option<PathBuf>,
+    /// Append every change made through the admin API to this file, one JSON object per line
+    #[clap(long)]
+    admin_audit_log: Option<PathBuf>,
+    /// Bearer token required by the metrics and stats API, the admin token works as well
+    #[clap(long)]
+    read_token: Option<String>,
+    /// Only answer HTTP requests from this network (CIDR or address), can be repeated
+    #[clap(long)]
+    http_allow: Vec<Cidr>,
+    /// Stream the jobs handed to miners as JSON on this address, read-only
+    #[clap(long)]
+    mirror_addr: Option<SocketAddr>,
+    /// Serve pool statistics as JSON on this address, read-only
+    #[clap(long)]
+    api_addr: Option<SocketAddr>,
+    /// Credit shares to the addresses workers log in with (address.worker) and split found blocks by this scheme
+    #[clap(long, arg_enum)]
+    payout_scheme: Option<Scheme>,
+    /// Another port on the stratum address, or a dialect port, where blocks are credited to their finder alone
+    #[clap(long, requires = "payout-scheme")]
+    solo_port: Vec<u16>,
+    /// PPLNS window as a multiple of the network difficulty
+    #[clap(long, default_value = "2")]
+    pplns_window: f64,
+    /// Smallest balance in KAS paid out, smaller ones carry over
+    #[clap(long, default_value = "1")]
+    payout_threshold: f64,
+    /// DAA score depth at which found blocks are credited, when their coinbase can be spent
+    #[clap(long, default_value = "1000")]
+    coinbase_maturity: u64,
+    /// Seconds between payouts
+    #[clap(long, default_value = "3600")]
+    payout_interval: u64,
+    /// Write a report of each payout to this directory without paying, leaving the balances pending
+    #[clap(long, requires = "payout-scheme")]
+    payout_preview: Option<PathBuf>,
+    /// Format of the payout reports
+    #[clap(long, arg_enum, default_value = "json")]
+    payout_report_format: ReportFormat,
+    /// Keep the rounds and balances in this file across restarts
+    #[clap(long, requires = "payout-scheme")]
+    payout_state: Option<PathBuf>,
+    /// Kaspawallet daemon (host:port) paying out from the mining address, unless previewing
+    #[clap(long, requires = "payout-scheme")]
+    wallet_url: Option<String>,
+    /// Password of the wallet's keys
+    #[clap(
+        long,
+        env = "KASPAD_STRATUM_WALLET_PASSWORD",
+        hide_env_values = true,
+        requires = "wallet-url"
+    )]
+    wallet_password: Option<String>,
+    /// Let a new process bind the stratum port while this one still runs, for upgrades without downtime
+    #[clap(long)]
+    reuse_port: bool,
+    /// Seconds to close connections over on SIGTERM or SIGINT, instead of all at once
+    #[clap(long, default_value = "0")]
+    drain_period: u64,
+    /// Stratum pool (host:port) miners are proxied to while kaspad is down
+    #[clap(long, requires = "fallback-user")]
+    fallback_pool: Option<String>,
+    /// Login at the fallback pool, usually the wallet address and a worker name
+    #[clap(long, requires = "fallback-pool")]
+    fallback_user: Option<String>,
+    /// Password at the fallback pool
+    #[clap(long, default_value = "x")]
+    fallback_password: String,
+    /// Seconds kaspad has to be down before miners move to the fallback pool
+    #[clap(long, default_value = "60")]
+    fallback_after: u64,
+    /// Leave Nagle's algorithm enabled on miner connections
+    #[clap(long)]
+    no_tcp_nodelay: bool,
+    /// Seconds of idle time before probing miner connections with TCP keepalives
+    #[clap(long)]
+    tcp_keepalive: Option<u64>,
+    /// Socket send buffer size in bytes
+    #[clap(long)]
+    send_buffer: Option<u32>,
+    /// Socket receive buffer size in bytes
+    #[clap(long)]
+    recv_buffer: Option<u32>,
+    #[cfg(feature = "chaos")]
+    #[clap(flatten)]
+    chaos: ChaosArgs,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Args)]
+struct InfluxArgs {
+    /// Push metrics to the InfluxDB at this URL
+    #[clap(long)]
+    influx_url: Option<String>,
+    /// InfluxDB 1.x database
+    #[clap(long, requires = "influx-url", conflicts_with = "influx-bucket")]
+    influx_db: Option<String>,
+    /// InfluxDB 1.x user:password
+    #[clap(long, requires = "influx-db")]
+    influx_credentials: Option<String>,
+    /// InfluxDB 2.x organization
+    #[clap(long, requires_all = &["influx-url", "influx-bucket", "influx-token"])]
+    influx_org: Option<String>,
+    /// InfluxDB 2.x bucket
+    #[clap(long, requires = "influx-org")]
+    influx_bucket: Option<String>,
+    /// InfluxDB 2.x API token
+    #[clap(long, requires = "influx-org")]
+    influx_token: Option<String>,
+    /// Seconds between pushes to InfluxDB
+    #[clap(long, default_value = "10")]
+    influx_interval: u64,
+}
+
+impl InfluxArgs {
+    fn influx(self) -> Result<Option<Influx>> {
+        let url = match self.influx_url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let version = match (self.influx_db, self.influx_org) {
+            (Some(database), _) => InfluxVersion::V1 {
+                database,
+                credentials: self.influx_credentials,
+            },
+            (None, Some(org)) => InfluxVersion::V2 {
+                org,
+                // Required by clap
+                bucket: self.influx_bucket.unwrap_or_default(),
+                token: self.influx_token.unwrap_or_default(),
+            },
+            (None, None) => anyhow::bail!("--influx-url needs --influx-db or --influx-org"),
+        };
+        let interval = Duration::from_secs(self.influx_interval);
+        Influx::new(&url, version, interval).map(Some)
+    }
+}
+
+#[cfg(feature = "chaos")]
+#[derive(clap::Args)]
+struct ChaosArgs {
+    /// Probability of dropping each message from kaspad
+    #[clap(long, default_value = "0")]
+    chaos_drop_response: f64,
+    /// Maximum random delay in milliseconds before each block submission
+    #[clap(long, default_value = "0")]
+    chaos_submit_delay: u64,
+    /// Probability of closing a client connection on each message
+    #[clap(long, default_value = "0")]
+    chaos_close_connection: f64,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Simulate miners against a running stratum server
+    Loadtest(loadtest::LoadtestArgs),
+    /// Manage a running instance through its admin API
+    Ctl(ctl::CtlArgs),
 }
 
 #[tokio::main]
@@ -29,52 +425,736 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     let level = if args.debug {
-        LevelFilter::Debug
+        LevelFilter::DEBUG
+    } else if args.quiet {
+        LevelFilter::WARN
     } else {
-        LevelFilter::Info
+        LevelFilter::INFO
     };
 
-    env_logger::Builder::new()
-        .filter_level(LevelFilter::Info)
-        .filter_module("kaspad_stratum", level)
-        .init();
+    let mut filter = EnvFilter::default()
+        .add_directive(level.min(LevelFilter::INFO).into())
+        .add_directive(format!("kaspad_stratum={level}").parse()?);
+    if args.quiet {
+        filter = filter.add_directive(format!("{SUMMARY}=info").parse()?);
+    }
+    let dashboard = args.tui.then(Dashboard::default);
+    match &dashboard {
+        Some(dashboard) => {
+            let dashboard = dashboard.clone();
+            tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .with_ansi(false)
+                .with_writer(move || dashboard.log_writer())
+                .init();
+        }
+        None => tracing_subscriber::fmt().with_env_filter(filter).init(),
+    }
+
+    match args.command {
+        Some(Command::Loadtest(args)) => return loadtest::run(args).await,
+        Some(Command::Ctl(args)) => return ctl::run(args).await,
+        None => {}
+    }
+    // Required by clap unless a subcommand, the network or a template file
+    // is given
+    let rpc_url = match args.network {
+        Some(network) => Some(network.rpc_url(args.rpc_url.as_deref())),
+        None => args.rpc_url.clone(),
+    };
+    let network = args.network.unwrap_or(Network::Mainnet);
+    let stratum_addr = args
+        .stratum_addr
+        .clone()
+        .unwrap_or_else(|| format!("127.0.0.1:{}", network.stratum_port()));
+    let start_difficulty = args
+        .start_difficulty
+        .unwrap_or_else(|| network.difficulty_scale());
+    let pay_addresses =
+        PayAddresses::new(args.mining_addr.clone(), args.network.map(Network::prefix))?;
+    let mining_addrs: Vec<String> = pay_addresses.addresses().map(String::from).collect();
+    // Checked before listening, so typos don't wait for the first template
+    let extra_data = ExtraData::parse(&args.extra_data, &args.extra_data_var)?;
+
+    #[cfg(feature = "chaos")]
+    kaspad_stratum::chaos::init(kaspad_stratum::chaos::Chaos {
+        drop_response: args.chaos.chaos_drop_response,
+        submit_delay: Duration::from_millis(args.chaos.chaos_submit_delay),
+        close_connection: args.chaos.chaos_close_connection,
+    });
+
+    validate(&args, start_difficulty)?;
+    let listeners = match &args.listeners {
+        Some(path) => ListenerConfig::load(path)?,
+        None => vec![],
+    };
+    if listeners.iter().any(|l| l.solo) && args.payout_scheme.is_none() {
+        anyhow::bail!("Solo listeners need --payout-scheme");
+    }
+    let node = match (&args.template_file, &rpc_url) {
+        (Some(path), _) => format!("templates from {}", path.display()),
+        (None, Some(url)) => format!("kaspad at {url}"),
+        (None, None) => unreachable!("required by clap"),
+    };
+    for line in summary(&args, &node, &stratum_addr, start_difficulty, &mining_addrs) {
+        info!(target: SUMMARY, "{line}");
+    }
+    let http_tls = match (&args.tls_cert, &args.tls_key, args.http_tls) {
+        (Some(cert), Some(key), true) => Some(Tls::new(TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            ports: vec![],
+        })?),
+        _ => None,
+    };
+    let config = stratum::Config {
+        vardiff: args.vardiff.then(|| VarDiffConfig {
+            share_time: Duration::from_secs_f64(args.share_time),
+            start_difficulty: stratum::from_stratum_difficulty(start_difficulty),
+            resume_ttl: Duration::from_secs(args.difficulty_ttl),
+            by_agent: !args.no_agent_difficulty,
+            ..Default::default()
+        }),
+        dialect: DialectConfig {
+            preset: args.dialect,
+            overrides: Overrides {
+                decimal_nonce: args.decimal_nonces,
+                jsonrpc2: args.jsonrpc2,
+                resend_difficulty: args.resend_difficulty,
+                timestamp_rolling: args.timestamp_rolling,
+                extranonce_method: args.extranonce_method,
+                nonce_prefix: args.nonce_prefix,
+                extranonce_unsolicited: args.unsolicited_extranonce,
+                notify_format: args.notify_format,
+            },
+        },
+        dialect_ports: args.dialect_port,
+        solo_ports: args.solo_port,
+        listeners,
+        slow_client: args.slow_client,
+        acceptors: args.acceptors,
+        socket: SocketConfig {
+            nodelay: !args.no_tcp_nodelay,
+            keepalive: args.tcp_keepalive.map(Duration::from_secs),
+            send_buffer: args.send_buffer,
+            recv_buffer: args.recv_buffer,
+            reuse_port: args.reuse_port,
+        },
+        worker_offline: args.worker_offline.map(Duration::from_secs),
+        job_grace: Duration::from_millis(args.job_grace_ms),
+        job_expiry_daa: args
+            .job_expiry_daa
+            .or(args.high_bps.then_some(HIGH_BPS_EXPIRY_DAA)),
+        wide_job_ids: args.wide_job_ids || args.high_bps,
+        handshake_timeout: (args.handshake_timeout > 0)
+            .then(|| Duration::from_secs(args.handshake_timeout)),
+        shared: args
+            .redis_url
+            .as_deref()
+            .map(|url| SharedState::new(url, &args.redis_prefix))
+            .transpose()?,
+        failover: args.failover,
+        instances: args.instances,
+        instance: args.instance,
+        dry_run: args.dry_run,
+        fallback: args.fallback_pool.map(|addr| UpstreamConfig {
+            addr,
+            user: args.fallback_user.unwrap_or_default(),
+            password: args.fallback_password,
+            after: Duration::from_secs(args.fallback_after),
+        }),
+        payout: args.payout_scheme.map(|scheme| PayoutConfig {
+            scheme,
+            window: args.pplns_window,
+            threshold: (args.payout_threshold * SOMPI_PER_KAS as f64) as u64,
+            maturity: args.coinbase_maturity,
+            // Kaspad only hands out templates for addresses of its network
+            prefix: pay_addresses.prefix().into(),
+            state: args.payout_state.clone(),
+        }),
+        notifier: Notifier {
+            webhook: args.webhook_url.as_deref().map(Webhook::new).transpose()?,
+            on_block_found: args.on_block_found,
+            console: args.pretty.then(Console::new),
+        },
+        auth: match (&args.credentials, &args.auth_webhook) {
+            (Some(path), _) => Some(Auth::from_file(path)?),
+            (_, Some(url)) => Some(Auth::webhook(url)?),
+            _ => None,
+        },
+        tls: match (args.tls_cert, args.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert,
+                key,
+                ports: args.tls_port,
+            }),
+            _ => None,
+        },
+        security_log: args
+            .security_log
+            .as_deref()
+            .map(SecurityLog::open)
+            .transpose()?,
+        acl: args.acl.map(Acl::load).transpose()?,
+        notify_limits: {
+            let mut limits = NotifyLimits::default();
+            for (key, millis) in args.notify_interval {
+                let interval = Duration::from_millis(millis);
+                match key.parse() {
+                    Ok(port) => limits.ports.push((port, interval)),
+                    Err(_) => limits.agents.push((key, interval)),
+                }
+            }
+            limits
+        },
+    };
+    let notifier = config.notifier.clone();
+    let acl = config.acl.clone();
+    // Webhooks have nothing to reload
+    let credentials = config
+        .auth
+        .clone()
+        .filter(|auth| matches!(auth, Auth::Credentials(_)));
+    let notify_format = config.dialect.initial().notify_format;
+
+    // Read-only listeners take either token, the admin API only its own
+    let admin_users = match &args.admin_users {
+        Some(path) => access::read_users(path)?,
+        None => vec![],
+    };
+    let read_access = match &args.read_token {
+        Some(token) => Access::new(
+            [token.clone()].into_iter().chain(args.admin_token.clone()),
+            &args.http_allow,
+        )
+        .with_users(admin_users.clone()),
+        None => Access::new(None, &args.http_allow),
+    };
+    if let Some(addr) = args.metrics_addr {
+        let access = read_access.clone().with_acl(acl.as_ref(), "metrics");
+        let tls = http_tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, access, tls).await {
+                warn!("Metrics server failed: {e}");
+            }
+        });
+    }
 
     let (handle, recv_cmd) = KaspadHandle::new();
-    let stratum = stratum::Stratum::new(&args.stratum_addr, handle.clone()).await?;
-
-    let (client, mut msgs) = Client::new(
-        &args.rpc_url,
-        &args.mining_addr,
-        &args.extra_data,
-        handle,
-        recv_cmd,
-    );
-    while let Some(msg) = msgs.recv().await {
+    let stratum = stratum::Stratum::new(&stratum_addr, handle.clone(), config).await?;
+    // Restores the terminal however main returns
+    let screen = dashboard
+        .clone()
+        .map(|dashboard| dashboard.show(stratum.stats().clone()));
+
+    if let Some(influx) = args.influx.influx()? {
+        tokio::spawn(influx.run(stratum.stats().clone()));
+    }
+
+    if let Some(addr) = &args.statsd_addr {
+        let interval = Duration::from_secs(args.statsd_interval);
+        let statsd = StatsD::new(addr, &args.statsd_prefix, interval).await?;
+        tokio::spawn(statsd.run(stratum.stats().clone()));
+    }
+
+    if args.summary_interval > 0 {
+        let stats = stratum.stats().clone();
+        let period = Duration::from_secs(args.summary_interval);
+        tokio::spawn(async move {
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                info!("{}", stats.summary());
+            }
+        });
+    }
+
+    // Asked for by the operator through the admin API
+    let refresh_requested = Arc::new(Notify::new());
+    if let Some(addr) = args.admin_addr {
+        let token = args.admin_token.clone().unwrap_or_default();
+        let access = Access::new([token], &args.http_allow)
+            .with_users(admin_users)
+            .with_acl(acl.as_ref(), "admin");
+        let audit = args
+            .admin_audit_log
+            .as_deref()
+            .map(AuditLog::open)
+            .transpose()?;
+        let mut admin = Admin::new(
+            stratum.control().clone(),
+            access,
+            refresh_requested.clone(),
+            audit,
+        );
+        let reloadable = [
+            ("acl", acl.clone().map(Reloadable::Acl)),
+            ("tls", stratum.tls().cloned().map(Reloadable::Tls)),
+            ("http-tls", http_tls.clone().map(Reloadable::Tls)),
+            ("credentials", credentials.map(Reloadable::Auth)),
+        ];
+        for (name, reloadable) in reloadable {
+            if let Some(reloadable) = reloadable {
+                admin = admin.with_reloadable(name, reloadable);
+            }
+        }
+        let tls = http_tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin.serve(addr, tls).await {
+                warn!("Admin API failed: {e}");
+            }
+        });
+    }
+
+    if let Some(addr) = args.mirror_addr {
+        let mirror = Mirror::new(stratum.watch_jobs(), notify_format);
+        tokio::spawn(async move {
+            if let Err(e) = mirror.serve(addr).await {
+                warn!("Job mirror failed: {e}");
+            }
+        });
+    }
+
+    if let Some(accounting) = stratum.accounting() {
+        let preview = args.payout_preview.is_some();
+        let payouts = Payouts {
+            accounting: accounting.clone(),
+            interval: Duration::from_secs(args.payout_interval),
+            report_dir: args.payout_preview.unwrap_or_default(),
+            format: args.payout_report_format,
+        };
+        if preview {
+            tokio::spawn(payouts.preview());
+        } else if let Some(url) = args.wallet_url {
+            let password = args.wallet_password.unwrap_or_default();
+            let wallet = Wallet::connect(&url, &password)
+                .await
+                .context("Unable to connect to the wallet daemon")?;
+            let balance = wallet.balance().await?;
+            info!(
+                "Paying out from {} through the wallet at {url}, {} sompi available",
+                mining_addrs.join(", "),
+                balance.available
+            );
+            tokio::spawn(payouts.pay(wallet, mining_addrs.clone()));
+        }
+    }
+
+    if let Some(addr) = args.api_addr {
+        let api = Api::new(
+            stratum.stats().clone(),
+            stratum.accounting().cloned(),
+            read_access.clone().with_acl(acl.as_ref(), "api"),
+        );
+        let tls = http_tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api.serve(addr, tls).await {
+                warn!("Stats API failed: {e}");
+            }
+        });
+    }
+
+    let backend = args.backend_addr.map(|addr| {
+        let backend = Backend::new(handle.clone(), args.backend_token.as_deref());
+        let server = backend.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(addr).await {
+                warn!("Backend server failed: {e}");
+            }
+        });
+        backend
+    });
+
+    // Timestamps of recorded templates say nothing about the local clock
+    let offline = args.template_file.is_some();
+    let (client, mut msgs) = match (args.template_file, rpc_url) {
+        (Some(path), _) => {
+            info!(
+                "Reading templates from {}, no blocks are submitted",
+                path.display()
+            );
+            let source = TemplateFile {
+                path,
+                submissions: args.submissions_file,
+            };
+            Client::offline(source, pay_addresses, extra_data, handle, recv_cmd)
+        }
+        (None, Some(rpc_url)) => Client::new(
+            &rpc_url,
+            args.rpc_token.as_deref(),
+            pay_addresses,
+            extra_data,
+            handle,
+            recv_cmd,
+            args.max_reconnects,
+        ),
+        (None, None) => unreachable!("required by clap"),
+    };
+    client.set_coalescing(args.coalesce_templates || args.high_bps);
+    // Oldest notification not yet followed by a broadcast
+    let mut notified = None;
+    let mut template_errors = TemplateErrors::default();
+    let mut clock = ClockSkew::new(Duration::from_secs(args.max_clock_skew));
+    // Keeps the timestamp and transactions fresh when notifications stall
+    let refresh_period = Duration::from_secs(args.template_refresh);
+    let refresh = time::sleep(Duration::ZERO);
+    tokio::pin!(refresh);
+    let mut online = false;
+    let mut replay = ChainReplay::default();
+    let shutdown = async {
+        match &screen {
+            Some(screen) => tokio::select! {
+                _ = shutdown_signal() => {}
+                _ = screen.quit() => {}
+            },
+            None => shutdown_signal().await,
+        }
+    };
+    tokio::pin!(shutdown);
+    let mut drain = None;
+    loop {
+        let msg = tokio::select! {
+            msg = msgs.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            reason = stratum.lease_lost() => {
+                anyhow::bail!(reason);
+            }
+            _ = &mut shutdown, if drain.is_none() => {
+                if args.drain_period == 0 {
+                    return Ok(());
+                }
+                drain = Some(stratum.drain(Duration::from_secs(args.drain_period)));
+                continue;
+            }
+            _ = async { drain.as_mut().unwrap().await }, if drain.is_some() => {
+                info!("All connections drained");
+                return Ok(());
+            }
+            _ = refresh_requested.notified(), if online => {
+                refresh.as_mut().reset(time::Instant::now() + refresh_period);
+                if !client.request_template() {
+                    debug!("Channel closed");
+                    break;
+                }
+                continue;
+            }
+            _ = &mut refresh, if online && !refresh_period.is_zero() => {
+                debug!("No template for {refresh_period:?}, refreshing");
+                refresh.as_mut().reset(time::Instant::now() + refresh_period);
+                if !client.request_template() {
+                    debug!("Channel closed");
+                    break;
+                }
+                continue;
+            }
+        };
         match msg {
-            Message::Info { version, .. } => {
+            Message::Online => {
+                debug!("Subscribed to kaspad, requesting template");
+                online = true;
+                if let Some(dashboard) = &dashboard {
+                    dashboard.node_online(true);
+                }
+                // Found blocks are credited once a chain block merges them
+                if stratum.accounting().is_some() && !client.watch_chain() {
+                    debug!("Channel closed");
+                    break;
+                }
+                refresh
+                    .as_mut()
+                    .reset(time::Instant::now() + refresh_period);
+                if !client.request_template() {
+                    debug!("Channel closed");
+                    break;
+                }
+            }
+            Message::Offline(reason) => {
+                warn!("Lost connection to kaspad ({reason}), pausing until it returns");
+                stratum.pause();
+                if let Some(backend) = &backend {
+                    backend.pause();
+                }
+                if let Some(dashboard) = &dashboard {
+                    dashboard.node_online(false);
+                }
+                notified = None;
+                online = false;
+                replay.reset();
+            }
+            Message::Disconnected(reason) => {
+                anyhow::bail!("Kaspad client stopped: {reason}");
+            }
+            Message::Info { version } => {
+                // Blocks would be orphaned for want of chain notifications
+                if is_backend(&version) && stratum.accounting().is_some() {
+                    anyhow::bail!(
+                        "--payout-scheme needs kaspad, the --rpc-url is a backend instance ({version})"
+                    );
+                }
                 info!("Connected to Kaspad {version}");
+                if let Some(dashboard) = &dashboard {
+                    dashboard.node_version(&version);
+                }
             }
             Message::NewTemplate => {
                 debug!("Requesting new template");
+                notified.get_or_insert_with(Instant::now);
                 if !client.request_template() {
                     debug!("Channel closed");
                     break;
                 }
             }
+            Message::TemplateError(error) => {
+                if let Some(event) = template_errors.error(error, Instant::now()) {
+                    notifier.emit(event);
+                }
+                let consecutive = template_errors.consecutive();
+                metrics::TEMPLATE_ERRORS.set(consecutive.into());
+                if args
+                    .max_template_errors
+                    .is_some_and(|max| consecutive >= max)
+                {
+                    anyhow::bail!("Kaspad failed {consecutive} template requests in a row");
+                }
+            }
+            Message::ChainChanged { removed, added } => {
+                let accounting = match stratum.accounting() {
+                    Some(a) => a,
+                    None => continue,
+                };
+                for round in accounting.chain_removed(&removed) {
+                    info!(
+                        "Block {} lost its acceptance to a reorg, checking the new chain",
+                        round.hash
+                    );
+                }
+                // Only the merge sets of new chain blocks tell their fate
+                if accounting.awaiting_acceptance() {
+                    for hash in &added {
+                        client.request_block(hash);
+                    }
+                }
+            }
+            Message::Block(block) => {
+                let (accounting, verbose) = match (stratum.accounting(), block.verbose_data) {
+                    (Some(a), Some(v)) => (a, v),
+                    _ => continue,
+                };
+                let orphaned = accounting.chain_block(
+                    &verbose.hash,
+                    &verbose.merge_set_blues_hashes,
+                    &verbose.merge_set_reds_hashes,
+                );
+                replay.block(accounting, &verbose.hash);
+                for round in orphaned {
+                    if round.reorged {
+                        notifier.emit(Event::BlockReverted {
+                            hash: round.hash,
+                            daa_score: round.daa_score,
+                            reward: round.reward,
+                            worker: round.worker,
+                        });
+                    } else {
+                        warn!("Block {} was merged as red, its reward is lost", round.hash);
+                    }
+                }
+            }
+            Message::ChainFrom(added) => {
+                if let Some(accounting) = stratum.accounting() {
+                    for hash in replay.chain(accounting, added, Instant::now()) {
+                        client.request_block(&hash);
+                    }
+                }
+            }
             Message::Template(template) => {
                 debug!("Received block template");
-                stratum.broadcast(template).await;
-            }
-            Message::SubmitBlockResult(error) => {
-                debug!("Resolve pending job");
-                match &error {
-                    Some(e) => debug!("Submitted invalid block: {e}"),
-                    None => info!("Found a block!"),
+                if let Some(event) = template_errors.template() {
+                    notifier.emit(event);
+                    metrics::TEMPLATE_ERRORS.set(0);
+                }
+                refresh
+                    .as_mut()
+                    .reset(time::Instant::now() + refresh_period);
+                if let Some(header) = template.header.as_ref().filter(|_| !offline) {
+                    let (skew, event) = clock.check(header.timestamp, unix_millis());
+                    if let Some(event) = event {
+                        notifier.emit(event);
+                    }
+                    metrics::CLOCK_SKEW.set(skew);
+                    metrics::CLOCK_SKEWED.set(clock.skewed().into());
+                }
+                if let Some(backend) = &backend {
+                    backend.publish(&template);
+                }
+                stratum.broadcast(*template).await;
+                if let Some(notified) = notified.take() {
+                    metrics::TEMPLATE_BROADCAST_DELAY.observe(notified.elapsed().as_secs_f64());
+                }
+                // Notifications missed while offline may hold their acceptance
+                let overdue = stratum
+                    .accounting()
+                    .and_then(|accounting| replay.next(accounting, Instant::now()));
+                if let Some(hash) = overdue {
+                    debug!("Block {hash} is due without acceptance, replaying the chain from it");
+                    client.request_chain(&hash);
                 }
-                stratum.resolve_pending_job(error).await;
             }
         }
     }
 
+    // Only reached when the client task is gone without a reason
+    anyhow::bail!("Kaspad client stopped")
+}
+
+/// Combinations of options that can't work, refused before anything starts
+fn validate(args: &Args, start_difficulty: f64) -> Result<()> {
+    if !(args.share_time > 0.0 && args.share_time.is_finite()) {
+        anyhow::bail!("--share-time must be positive");
+    }
+    if !(start_difficulty > 0.0 && start_difficulty.is_finite()) {
+        anyhow::bail!("--start-difficulty must be positive");
+    }
+    if !(args.pplns_window > 0.0 && args.pplns_window.is_finite()) {
+        anyhow::bail!("--pplns-window must be positive");
+    }
+    if !(args.payout_threshold >= 0.0 && args.payout_threshold.is_finite()) {
+        anyhow::bail!("--payout-threshold must not be negative");
+    }
+    if args.payout_scheme.is_some() && args.payout_interval == 0 {
+        anyhow::bail!("--payout-interval must be positive");
+    }
+    // Balances kept only in memory would be lost, or paid twice, by a restart
+    if args.wallet_url.is_some() && args.payout_preview.is_none() && args.payout_state.is_none() {
+        anyhow::bail!("--wallet-url needs --payout-state");
+    }
+    if args.admin_addr.is_some()
+        && args.admin_token.as_deref().unwrap_or_default().is_empty()
+        && args.admin_users.is_none()
+    {
+        anyhow::bail!("--admin-addr needs a non-empty --admin-token or --admin-users");
+    }
     Ok(())
 }
+
+/// The effective configuration, logged at startup
+fn summary(
+    args: &Args,
+    node: &str,
+    stratum_addr: &str,
+    start_difficulty: f64,
+    mining_addrs: &[String],
+) -> Vec<String> {
+    let network = args.network.unwrap_or(Network::Mainnet);
+    let network = network.to_possible_value().map_or("", |v| v.get_name());
+    let mut lines = vec![
+        format!("Node: {node} on {network}"),
+        format!("Paying to: {}", mining_addrs.join(", ")),
+        format!("Stratum: {stratum_addr}"),
+    ];
+    lines.push(match args.dialect {
+        Some(preset) => format!("Dialect: {preset:?}"),
+        None => "Dialect: by agent".into(),
+    });
+    lines.push(match args.vardiff {
+        true => format!(
+            "Difficulty: vardiff starting at {start_difficulty}{}, a share every {}s",
+            match args.no_agent_difficulty {
+                true => "",
+                false => " or the typical hashrate of known miners",
+            },
+            args.share_time
+        ),
+        false => "Difficulty: the network's".into(),
+    });
+    let features = [
+        ("TLS", args.tls_cert.is_some()),
+        ("payouts", args.payout_scheme.is_some()),
+        ("shared state", args.redis_url.is_some()),
+        ("failover", args.failover),
+        ("fallback pool", args.fallback_pool.is_some()),
+        (
+            "authentication",
+            args.credentials.is_some() || args.auth_webhook.is_some(),
+        ),
+        ("stats API", args.api_addr.is_some()),
+        ("admin API", args.admin_addr.is_some()),
+        ("metrics", args.metrics_addr.is_some()),
+        ("webhooks", args.webhook_url.is_some()),
+        ("job mirror", args.mirror_addr.is_some()),
+        ("backend", args.backend_addr.is_some()),
+        ("dry run", args.dry_run),
+    ];
+    let enabled: Vec<_> = features
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| *name)
+        .collect();
+    lines.push(match enabled.is_empty() {
+        true => "Features: none".into(),
+        false => format!("Features: {}", enabled.join(", ")),
+    });
+    lines
+}
+
+/// SIGINT, or SIGTERM as sent by service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn parse_dialect_port(s: &str) -> Result<(u16, Preset), String> {
+    let (port, dialect) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PORT=DIALECT, got {s}"))?;
+    let port = port
+        .parse()
+        .map_err(|e| format!("invalid port {port}: {e}"))?;
+    Ok((port, Preset::from_str(dialect, true)?))
+}
+
+fn parse_mining_addr(s: &str) -> Result<(String, u32), String> {
+    match s.split_once('=') {
+        Some((address, weight)) => {
+            let weight = weight
+                .parse()
+                .map_err(|e| format!("invalid weight {weight}: {e}"))?;
+            Ok((address.into(), weight))
+        }
+        None => Ok((s.into(), 1)),
+    }
+}
+
+fn parse_extra_data_var(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got {s}"))?;
+    Ok((name.into(), value.into()))
+}
+
+fn parse_notify_interval(s: &str) -> Result<(String, u64), String> {
+    let (key, millis) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PORT=MILLIS or AGENT=MILLIS, got {s}"))?;
+    let millis = millis
+        .parse()
+        .map_err(|e| format!("invalid interval {millis}: {e}"))?;
+    Ok((key.to_ascii_lowercase(), millis))
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
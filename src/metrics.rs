@@ -0,0 +1,178 @@
+mod influx;
+mod statsd;
+
+use crate::access::Access;
+use crate::http;
+use crate::stratum::Tls;
+use anyhow::Result;
+use hyper::service::service_fn;
+use hyper::{header, Body, Request, Response, StatusCode};
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter_vec, register_int_gauge, Encoder, Gauge, Histogram, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use tracing::info;
+
+pub use influx::{Influx, InfluxVersion};
+pub use statsd::StatsD;
+
+/// Hash difficulty of submitted shares relative to the difficulty assigned to the miner
+pub static SHARE_DIFFICULTY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "stratum_share_difficulty_ratio",
+        "Share hash difficulty relative to the assigned difficulty",
+        &["port"],
+        exponential_buckets(0.25, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+pub static ACCEPTED_SHARES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "stratum_accepted_shares_total",
+        "Accepted shares",
+        &["worker"]
+    )
+    .unwrap()
+});
+
+pub static REJECTED_SHARES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "stratum_rejected_shares_total",
+        "Rejected shares by reason",
+        &["worker", "reason"]
+    )
+    .unwrap()
+});
+
+/// Round trip of kaspad requests by method
+pub static KASPAD_RPC_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "kaspad_rpc_duration_seconds",
+        "Round trip time of kaspad RPC calls",
+        &["method"],
+        exponential_buckets(0.001, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+/// Time from a new template notification until its job is broadcast
+pub static TEMPLATE_BROADCAST_DELAY: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "stratum_template_broadcast_delay_seconds",
+        "Time from kaspad's new template notification until the job is broadcast",
+        exponential_buckets(0.001, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+/// Template requests failed in a row, 0 while kaspad hands out templates
+pub static TEMPLATE_ERRORS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "kaspad_consecutive_template_errors",
+        "Template requests kaspad answered with an error since the last template"
+    )
+    .unwrap()
+});
+
+/// Local time minus the timestamp of the latest template
+pub static CLOCK_SKEW: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "kaspad_template_clock_skew_seconds",
+        "Local time minus the timestamp of the latest template"
+    )
+    .unwrap()
+});
+
+/// 1 while the clock skew exceeds `--max-clock-skew`
+pub static CLOCK_SKEWED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "kaspad_clock_skewed",
+        "Whether the clock skew exceeds the allowed maximum"
+    )
+    .unwrap()
+});
+
+/// 1 while miners are proxied to the fallback pool
+pub static FALLBACK_ACTIVE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "stratum_fallback_active",
+        "Whether miners are proxied to the fallback pool"
+    )
+    .unwrap()
+});
+
+/// Share difficulty accumulated per found block relative to its network difficulty
+pub static BLOCK_EFFORT: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "stratum_block_effort_ratio",
+        "Round effort of found blocks",
+        vec![0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 5.0]
+    )
+    .unwrap()
+});
+
+/// Serve the metrics in the Prometheus text format. The health check only
+/// needs an allowed address, not the token.
+pub async fn serve(addr: SocketAddr, access: Access, tls: Option<Tls>) -> Result<()> {
+    info!("Serving metrics on {addr}");
+    http::serve(addr, tls, move |ip| {
+        let access = access.clone();
+        service_fn(move |req| handle(access.clone(), ip, req))
+    })
+    .await
+}
+
+async fn handle(
+    access: Access,
+    ip: IpAddr,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let refused = match req.uri().path() {
+        "/health" => (!access.allows(ip)).then_some(StatusCode::FORBIDDEN),
+        _ => access.check(ip, req.headers()),
+    };
+    if let Some(status) = refused {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = status;
+        return Ok(res);
+    }
+    if req.uri().path() == "/health" {
+        return Ok(health());
+    }
+    if req.uri().path() != "/metrics" {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    // Encoding only fails for invalid metric names
+    encoder.encode(&prometheus::gather(), &mut buf).unwrap();
+    let mut res = Response::new(Body::from(buf));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    Ok(res)
+}
+
+/// Unhealthy while kaspad fails to hand out templates or the clock is off
+fn health() -> Response<Body> {
+    let errors = TEMPLATE_ERRORS.get();
+    let reason = if errors > 0 {
+        format!("{errors} template errors in a row\n")
+    } else if CLOCK_SKEWED.get() > 0 {
+        format!("clock skew of {:.1}s\n", CLOCK_SKEW.get())
+    } else {
+        return Response::new(Body::from("ok\n"));
+    };
+    let mut res = Response::new(Body::from(reason));
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res
+}
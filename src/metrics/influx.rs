@@ -0,0 +1,207 @@
+use crate::http::{self, HttpClient};
+use crate::repeated::Repeated;
+use crate::stratum::{Stats, Summary};
+use anyhow::{anyhow, Result};
+use hyper::{header, Body, Method, Request, Uri};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::{debug, warn};
+
+pub enum InfluxVersion {
+    /// `/write` with an optional `user:password`
+    V1 {
+        database: String,
+        credentials: Option<String>,
+    },
+    /// `/api/v2/write` authenticated by token
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+/// Pushes the metrics and pool summary to InfluxDB on an interval
+pub struct Influx {
+    url: Uri,
+    auth: Option<String>,
+    client: HttpClient,
+    interval: Duration,
+}
+
+impl Influx {
+    pub fn new(url: &str, version: InfluxVersion, interval: Duration) -> Result<Self> {
+        let base = url.trim_end_matches('/');
+        let (url, auth) = match version {
+            InfluxVersion::V1 {
+                database,
+                credentials,
+            } => {
+                let mut url = format!("{base}/write?precision=s&db={database}");
+                if let Some(credentials) = credentials {
+                    let (user, password) = credentials
+                        .split_once(':')
+                        .ok_or_else(|| anyhow!("expected influx credentials as user:password"))?;
+                    write!(url, "&u={user}&p={password}")?;
+                }
+                (url, None)
+            }
+            InfluxVersion::V2 { org, bucket, token } => (
+                format!("{base}/api/v2/write?precision=s&org={org}&bucket={bucket}"),
+                Some(format!("Token {token}")),
+            ),
+        };
+        Ok(Influx {
+            url: url.parse()?,
+            auth,
+            client: http::client(),
+            interval,
+        })
+    }
+
+    pub async fn run(self, stats: Stats) {
+        let mut interval = time::interval(self.interval);
+        let mut repeated = Repeated::default();
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let body = lines(&prometheus::gather(), &stats.summary(), timestamp);
+            if let Err(e) = self.write(body).await {
+                if let Some(line) = repeated.check(&format!("Unable to write to InfluxDB: {e}")) {
+                    warn!("{line}");
+                }
+            }
+        }
+    }
+
+    async fn write(&self, body: String) -> Result<()> {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        if let Some(auth) = &self.auth {
+            req = req.header(header::AUTHORIZATION, auth);
+        }
+        let res = self.client.request(req.body(Body::from(body))?).await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("InfluxDB responded with {}", res.status()));
+        }
+        debug!("Wrote metrics to InfluxDB");
+        Ok(())
+    }
+}
+
+/// Render the metrics in the InfluxDB line protocol
+fn lines(families: &[MetricFamily], summary: &Summary, timestamp: u64) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "stratum workers={}i,hashrate={},shares_per_min={},blocks_found={}i",
+        summary.workers, summary.hashrate, summary.shares_per_min, summary.blocks_found
+    );
+    if let Some(daa_score) = summary.daa_score {
+        let _ = write!(
+            out,
+            ",daa_score={daa_score}i,reward={}i",
+            summary.reward.total()
+        );
+    }
+    let _ = writeln!(out, " {timestamp}");
+
+    for family in families {
+        for metric in family.get_metric() {
+            out.push_str(&escape(family.get_name(), ", "));
+            for label in metric.get_label() {
+                // Influx doesn't allow empty tag values
+                if !label.get_value().is_empty() {
+                    let _ = write!(
+                        out,
+                        ",{}={}",
+                        escape(label.get_name(), ",= "),
+                        escape(label.get_value(), ",= ")
+                    );
+                }
+            }
+            let _ = match family.get_field_type() {
+                MetricType::COUNTER => write!(out, " value={}", metric.get_counter().get_value()),
+                MetricType::GAUGE => write!(out, " value={}", metric.get_gauge().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    write!(
+                        out,
+                        " count={}i,sum={}",
+                        h.get_sample_count(),
+                        h.get_sample_sum()
+                    )
+                }
+                _ => {
+                    // Drop the measurement name and tags again
+                    out.truncate(out.rfind('\n').map(|i| i + 1).unwrap_or(0));
+                    continue;
+                }
+            };
+            let _ = writeln!(out, " {timestamp}");
+        }
+    }
+    out
+}
+
+fn escape(s: &str, special: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::lines;
+    use crate::kaspad::BlockReward;
+    use crate::stratum::Summary;
+    use prometheus::{CounterVec, Histogram, HistogramOpts, Opts, Registry};
+
+    #[test]
+    fn line_protocol() {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("shares", "help"), &["worker", "reason"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let histogram = Histogram::with_opts(HistogramOpts::new("rtt", "help")).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.with_label_values(&["rig 1,a", "stale"]).inc_by(2.0);
+        counter.with_label_values(&["", "duplicate"]).inc();
+        histogram.observe(0.5);
+        histogram.observe(1.5);
+
+        let summary = Summary {
+            workers: 2,
+            hashrate: 1.5e9,
+            shares_per_min: 12.0,
+            blocks_found: 1,
+            daa_score: Some(100),
+            template_age: None,
+            reward: BlockReward {
+                subsidy: 4_500_000_000,
+                fees: 1000,
+            },
+        };
+        assert_eq!(
+            lines(&registry.gather(), &summary, 1700000000),
+            concat!(
+                "stratum workers=2i,hashrate=1500000000,shares_per_min=12,blocks_found=1i,daa_score=100i,reward=4500001000i 1700000000\n",
+                "rtt count=2i,sum=2 1700000000\n",
+                "shares,reason=duplicate value=1 1700000000\n",
+                "shares,reason=stale,worker=rig\\ 1\\,a value=2 1700000000\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use crate::repeated::Repeated;
+use crate::stratum::{Stats, Summary};
+use anyhow::Result;
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::warn;
+
+/// Keeps datagrams below the usual Ethernet MTU
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends the metrics and pool summary to a StatsD daemon over UDP
+pub struct StatsD {
+    socket: UdpSocket,
+    prefix: String,
+    interval: Duration,
+}
+
+impl StatsD {
+    pub async fn new(addr: &str, prefix: &str, interval: Duration) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(StatsD {
+            socket,
+            prefix: prefix.trim_end_matches('.').into(),
+            interval,
+        })
+    }
+
+    pub async fn run(self, stats: Stats) {
+        // Counters are sent as increments since the previous flush
+        let mut sent = HashMap::new();
+        let mut repeated = Repeated::default();
+        let mut interval = time::interval(self.interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let lines = lines(
+                &self.prefix,
+                &prometheus::gather(),
+                &stats.summary(),
+                &mut sent,
+            );
+            for datagram in datagrams(&lines) {
+                if let Err(e) = self.socket.send(datagram.as_bytes()).await {
+                    if let Some(line) = repeated.check(&format!("Unable to send to StatsD: {e}")) {
+                        warn!("{line}");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Render the metrics as StatsD counters and gauges, label values become
+/// path components for Graphite
+fn lines(
+    prefix: &str,
+    families: &[MetricFamily],
+    summary: &Summary,
+    sent: &mut HashMap<String, f64>,
+) -> Vec<String> {
+    let mut out = vec![
+        format!("{prefix}.workers:{}|g", summary.workers),
+        format!("{prefix}.hashrate:{}|g", summary.hashrate),
+        format!("{prefix}.shares_per_min:{}|g", summary.shares_per_min),
+        format!("{prefix}.blocks_found:{}|g", summary.blocks_found),
+    ];
+    if let Some(daa_score) = summary.daa_score {
+        out.push(format!("{prefix}.daa_score:{daa_score}|g"));
+        out.push(format!("{prefix}.reward:{}|g", summary.reward.total()));
+    }
+
+    for family in families {
+        for metric in family.get_metric() {
+            let mut name = format!("{prefix}.{}", sanitize(family.get_name()));
+            for label in metric.get_label() {
+                name.push('.');
+                name.push_str(&sanitize(label.get_value()));
+            }
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    out.extend(increment(sent, name, value));
+                }
+                MetricType::GAUGE => {
+                    out.push(format!("{name}:{}|g", metric.get_gauge().get_value()));
+                }
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let count = h.get_sample_count() as f64;
+                    out.extend(increment(sent, format!("{name}.count"), count));
+                    out.extend(increment(sent, format!("{name}.sum"), h.get_sample_sum()));
+                }
+                _ => {}
+            }
+        }
+    }
+    out
+}
+
+/// Counter line for the increase since the last flush, idle counters are
+/// skipped
+fn increment(sent: &mut HashMap<String, f64>, name: String, value: f64) -> Option<String> {
+    let last = sent.insert(name.clone(), value).unwrap_or(0.0);
+    let delta = value - last;
+    (delta > 0.0).then(|| format!("{name}:{delta}|c"))
+}
+
+/// Graphite splits paths on dots and doesn't allow spaces
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Pack lines into newline separated datagrams
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for line in lines {
+        match out.last_mut() {
+            Some(d) if d.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                d.push('\n');
+                d.push_str(line);
+            }
+            _ => out.push(line.clone()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{datagrams, lines};
+    use crate::stratum::Summary;
+    use prometheus::{IntCounterVec, Opts, Registry};
+    use std::collections::HashMap;
+
+    #[test]
+    fn counters_and_gauges() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("shares", "help"), &["worker"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["rig.1"]).inc_by(3);
+        counter.with_label_values(&["rig 2"]).inc();
+
+        let summary = Summary {
+            workers: 2,
+            hashrate: 1.5e9,
+            shares_per_min: 12.0,
+            blocks_found: 0,
+            daa_score: None,
+            template_age: None,
+            reward: Default::default(),
+        };
+        let mut sent = HashMap::new();
+        assert_eq!(
+            lines("pool", &registry.gather(), &summary, &mut sent),
+            [
+                "pool.workers:2|g",
+                "pool.hashrate:1500000000|g",
+                "pool.shares_per_min:12|g",
+                "pool.blocks_found:0|g",
+                "pool.shares.rig_2:1|c",
+                "pool.shares.rig_1:3|c",
+            ]
+        );
+        counter.with_label_values(&["rig.1"]).inc_by(2);
+        assert_eq!(
+            lines("pool", &registry.gather(), &summary, &mut sent)[4..],
+            ["pool.shares.rig_1:2|c"]
+        );
+    }
+
+    #[test]
+    fn packs_datagrams() {
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!("pool.metric_{i:03}:1|c"))
+            .collect();
+        let packed = datagrams(&lines);
+        assert_eq!(packed.len(), 2);
+        assert!(packed.iter().all(|d| d.len() <= super::MAX_DATAGRAM));
+        assert_eq!(packed.join("\n"), lines.join("\n"));
+    }
+}
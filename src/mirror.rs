@@ -0,0 +1,70 @@
+use crate::stratum::jobs::JobParams;
+use crate::stratum::NotifyFormat;
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::watch;
+use tracing::info;
+
+/// Read-only view of the work handed to miners. `GET /jobs` streams the
+/// current job and every following one as newline delimited JSON, with the
+/// notify params, network difficulty and template metadata.
+#[derive(Clone)]
+pub struct Mirror {
+    jobs: watch::Receiver<Option<JobParams>>,
+    /// Shape of the notify params, as sent to miners
+    format: NotifyFormat,
+}
+
+impl Mirror {
+    pub fn new(jobs: watch::Receiver<Option<JobParams>>, format: NotifyFormat) -> Self {
+        Mirror { jobs, format }
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+        let make_svc = make_service_fn(move |_| {
+            let mirror = self.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(mirror.clone(), req))) }
+        });
+        let server = Server::try_bind(&addr)?.serve(make_svc);
+        info!("Mirroring jobs on {addr}");
+        server.await?;
+        Ok(())
+    }
+}
+
+async fn handle(mirror: Mirror, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/jobs" {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
+    let (mut sender, body) = Body::channel();
+    let Mirror { mut jobs, format } = mirror;
+    tokio::spawn(async move {
+        loop {
+            let line = jobs.borrow_and_update().as_ref().map(|job| {
+                let mut line = job.describe(format).to_string();
+                line.push('\n');
+                line
+            });
+            // Ends once the client is gone
+            if let Some(line) = line {
+                if sender.send_data(line.into()).await.is_err() {
+                    return;
+                }
+            }
+            if jobs.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+    let mut res = Response::new(body);
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/x-ndjson"),
+    );
+    Ok(res)
+}
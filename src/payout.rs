@@ -0,0 +1,403 @@
+use crate::stratum::{Accounting, Payment, Unconfirmed};
+use crate::wallet::{select_utxos, Refused, Wallet};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::{info, warn};
+
+/// Time a payment the wallet may have sent has to show up at its address,
+/// after which it's paid again
+const SETTLE_TIME: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+impl ReportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Json => "json",
+            ReportFormat::Csv => "csv",
+        }
+    }
+}
+
+/// A payout batch for the operator to audit. The checksum covers the
+/// payment lines, so a report can be matched against the batch sent later.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    /// Seconds since the epoch
+    pub time: u64,
+    /// Sompi
+    pub threshold: u64,
+    pub payments: Vec<Payment>,
+    /// Sompi
+    pub total: u64,
+    /// Blake2b-256 of the `address,amount` lines in hex
+    pub checksum: String,
+}
+
+impl Report {
+    pub fn new(time: u64, threshold: u64, payments: Vec<Payment>) -> Self {
+        let mut state = blake2b_simd::Params::new().hash_length(32).to_state();
+        for p in &payments {
+            state.update(format!("{},{}\n", p.address, p.amount).as_bytes());
+        }
+        Report {
+            time,
+            threshold,
+            total: payments.iter().map(|p| p.amount).sum(),
+            checksum: state.finalize().to_hex().to_string(),
+            payments,
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> Result<String> {
+        Ok(match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+            ReportFormat::Csv => {
+                let mut csv = String::from("address,amount\n");
+                for p in &self.payments {
+                    writeln!(csv, "{},{}", p.address, p.amount)?;
+                }
+                writeln!(csv, "# time,{}", self.time)?;
+                writeln!(csv, "# threshold,{}", self.threshold)?;
+                writeln!(csv, "# total,{}", self.total)?;
+                writeln!(csv, "# checksum,{}", self.checksum)?;
+                csv
+            }
+        })
+    }
+}
+
+/// Periodically works out the payout batch from the pending balances
+pub struct Payouts {
+    pub accounting: Accounting,
+    pub interval: Duration,
+    /// Previews are written to this directory
+    pub report_dir: PathBuf,
+    pub format: ReportFormat,
+}
+
+impl Payouts {
+    /// Preview every batch without paying it, the balances stay pending
+    pub async fn preview(self) {
+        let mut interval = time::interval_at(time::Instant::now() + self.interval, self.interval);
+        loop {
+            interval.tick().await;
+            let payments = self.accounting.preview_payments();
+            if let Err(e) = self.write_report(payments).await {
+                warn!("Unable to write the payout report: {e}");
+            }
+        }
+    }
+
+    /// Pay every batch through the wallet from the outputs of `from`, the
+    /// mining addresses. Payments the wallet refuses stay pending for the
+    /// next batch, those it fails to confirm wait until they're settled.
+    pub async fn pay(self, wallet: Wallet, from: Vec<String>) {
+        let mut interval = time::interval_at(time::Instant::now() + self.interval, self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.pay_batch(&wallet, &from).await {
+                warn!("Unable to pay out: {e}");
+            }
+        }
+    }
+
+    async fn pay_batch(&self, wallet: &Wallet, from: &[String]) -> Result<Option<Report>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.settle(wallet, now).await;
+        let payments = self.accounting.take_payments();
+        if payments.is_empty() {
+            return Ok(None);
+        }
+        let total: u64 = payments.iter().map(|p| p.amount).sum();
+        let mut utxos = vec![];
+        for address in from {
+            match wallet.spendable_utxos(address).await {
+                Ok(spendable) => utxos.extend(spendable),
+                Err(e) => {
+                    self.accounting.restore_payments(&payments);
+                    return Err(e);
+                }
+            }
+        }
+        if select_utxos(&utxos, total).is_none() {
+            self.accounting.restore_payments(&payments);
+            bail!("{} can't cover {total} sompi yet", from.join(", "));
+        }
+        let (mut paid, mut failed) = (vec![], vec![]);
+        for payment in payments {
+            // What the address held before, to recognize the payment if the
+            // wallet doesn't confirm it
+            let known = match wallet.spendable_utxos(&payment.address).await {
+                Ok(utxos) => utxos.iter().map(|u| u.outpoint()).collect(),
+                Err(e) => {
+                    warn!("Unable to read the outputs of {}: {e}", payment.address);
+                    failed.push(payment);
+                    continue;
+                }
+            };
+            match wallet.send(&payment.address, payment.amount, from).await {
+                Ok(txs) => {
+                    info!(
+                        "Paid {} sompi to {} in {}",
+                        payment.amount,
+                        payment.address,
+                        txs.join(", ")
+                    );
+                    paid.push(payment);
+                }
+                Err(e) if e.is::<Refused>() => {
+                    warn!(
+                        "Unable to pay {} sompi to {}: {e}",
+                        payment.amount, payment.address
+                    );
+                    failed.push(payment);
+                }
+                Err(e) => {
+                    warn!(
+                        "Unable to tell whether {} sompi reached {}: {e}, checking before paying it again",
+                        payment.amount, payment.address
+                    );
+                    self.accounting.unconfirmed_payment(payment, known, now);
+                }
+            }
+        }
+        self.accounting.restore_payments(&failed);
+        let report = Report::new(now, self.accounting.threshold(), paid);
+        info!(
+            "Paid {} sompi to {} addresses, checksum {}",
+            report.total,
+            report.payments.len(),
+            report.checksum
+        );
+        Ok(Some(report))
+    }
+
+    /// Settle the payments the wallet didn't confirm: paid once the address
+    /// holds a new output of the amount, pending again if it doesn't by the
+    /// settle time
+    async fn settle(&self, wallet: &Wallet, now: u64) {
+        for unconfirmed in self.accounting.unconfirmed() {
+            let Unconfirmed {
+                payment,
+                since,
+                known,
+            } = unconfirmed;
+            let utxos = match wallet.spendable_utxos(&payment.address).await {
+                Ok(utxos) => utxos,
+                Err(e) => {
+                    warn!("Unable to check the payment to {}: {e}", payment.address);
+                    continue;
+                }
+            };
+            let received = utxos
+                .iter()
+                .any(|u| u.amount == payment.amount && !known.contains(&u.outpoint()));
+            if received {
+                info!(
+                    "Confirmed the payment of {} sompi to {}",
+                    payment.amount, payment.address
+                );
+                self.accounting.settle_payment(&payment.address, true);
+            } else if now >= since + SETTLE_TIME.as_secs() {
+                warn!(
+                    "The payment of {} sompi to {} didn't arrive, paying it again",
+                    payment.amount, payment.address
+                );
+                self.accounting.settle_payment(&payment.address, false);
+            }
+        }
+    }
+
+    async fn write_report(&self, payments: Vec<Payment>) -> Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let report = Report::new(time, self.accounting.threshold(), payments);
+        let name = format!("payouts-{time}.{}", self.format.extension());
+        let path = self.report_dir.join(name);
+        tokio::fs::write(&path, report.render(self.format)?).await?;
+        info!(
+            "Payout preview of {} sompi to {} addresses written to {}",
+            report.total,
+            report.payments.len(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Payouts, Report, ReportFormat, SETTLE_TIME};
+    use crate::stratum::{Accounting, Payment, PayoutConfig};
+    use crate::wallet::mock::MockWallet;
+    use crate::wallet::Wallet;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_reports() {
+        let payments = vec![
+            Payment {
+                address: "kaspa:qqa".into(),
+                amount: 150,
+            },
+            Payment {
+                address: "kaspa:qqb".into(),
+                amount: 110,
+            },
+        ];
+        let report = Report::new(1_700_000_000, 100, payments);
+        assert_eq!(report.total, 260);
+        assert_eq!(report.checksum.len(), 64);
+        let csv = report.render(ReportFormat::Csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "address,amount",
+                "kaspa:qqa,150",
+                "kaspa:qqb,110",
+                "# time,1700000000"
+            ]
+        );
+        assert_eq!(lines[6], format!("# checksum,{}", report.checksum));
+        let json: serde_json::Value =
+            serde_json::from_str(&report.render(ReportFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["payments"][1]["amount"], 110);
+        assert_eq!(json["total"], 260);
+
+        // Any change to a payment shows in the checksum
+        let changed = Report::new(
+            1_700_000_000,
+            100,
+            vec![Payment {
+                address: "kaspa:qqa".into(),
+                amount: 151,
+            }],
+        );
+        assert_ne!(changed.checksum, report.checksum);
+    }
+
+    #[tokio::test]
+    async fn pays_through_the_wallet() {
+        let accounting = Accounting::new(PayoutConfig {
+            threshold: 100,
+            ..Default::default()
+        });
+        let payment = |address: &str, amount| Payment {
+            address: address.into(),
+            amount,
+        };
+        accounting.restore_payments(&[
+            payment("kaspa:qqa", 150),
+            payment("kaspa:qqb", 110),
+            payment("kaspa:qqc", 50),
+        ]);
+        let payouts = Payouts {
+            accounting: accounting.clone(),
+            interval: Duration::from_secs(3600),
+            report_dir: ".".into(),
+            format: ReportFormat::Json,
+        };
+        let pending = |address| accounting.balance(address).unwrap().pending;
+        let from = vec!["kaspa:pool".to_string()];
+
+        // Not enough funds on the mining address leaves everything pending
+        let mock = MockWallet {
+            utxos: vec![200],
+            failing: Some("kaspa:qqb".into()),
+            ..Default::default()
+        };
+        let sent = mock.sent.clone();
+        let wallet = Wallet::connect(&mock.clone().serve().await, "secret")
+            .await
+            .unwrap();
+        assert!(payouts.pay_batch(&wallet, &from).await.is_err());
+        assert_eq!((pending("kaspa:qqa"), pending("kaspa:qqb")), (150, 110));
+        assert!(sent.lock().unwrap().is_empty());
+
+        // A failed send is retried with the next batch
+        let mock = MockWallet {
+            utxos: vec![200, 100],
+            ..mock
+        };
+        let wallet = Wallet::connect(&mock.serve().await, "secret")
+            .await
+            .unwrap();
+        let report = payouts.pay_batch(&wallet, &from).await.unwrap();
+        assert_eq!(report.unwrap().payments, [payment("kaspa:qqa", 150)]);
+        assert_eq!(
+            sent.lock().unwrap()[..],
+            [("kaspa:qqa".into(), 150, vec!["kaspa:pool".into()])]
+        );
+        let balance = accounting.balance("kaspa:qqa").unwrap();
+        assert_eq!((balance.pending, balance.paid), (0, 150));
+        assert_eq!((pending("kaspa:qqb"), pending("kaspa:qqc")), (110, 50));
+
+        let report = payouts.pay_batch(&wallet, &from).await.unwrap();
+        assert!(report.unwrap().payments.is_empty());
+        assert_eq!(pending("kaspa:qqb"), 110);
+    }
+
+    #[tokio::test]
+    async fn settles_unconfirmed_payments() {
+        let accounting = Accounting::new(PayoutConfig {
+            threshold: 100,
+            ..Default::default()
+        });
+        let payment = |address: &str, amount| Payment {
+            address: address.into(),
+            amount,
+        };
+        accounting.restore_payments(&[payment("kaspa:qqa", 150), payment("kaspa:qqb", 110)]);
+        let payouts = Payouts {
+            accounting: accounting.clone(),
+            interval: Duration::from_secs(3600),
+            report_dir: ".".into(),
+            format: ReportFormat::Json,
+        };
+        let balance = |address| {
+            let b = accounting.balance(address).unwrap();
+            (b.pending, b.unconfirmed, b.paid)
+        };
+        let from = vec!["kaspa:pool".to_string()];
+        // The send to A goes out but the connection drops before the answer
+        let mock = MockWallet {
+            utxos: vec![1000],
+            lost: Some("kaspa:qqa".into()),
+            ..Default::default()
+        };
+        let sent = mock.sent.clone();
+        let wallet = Wallet::connect(&mock.clone().serve().await, "secret")
+            .await
+            .unwrap();
+        let report = payouts.pay_batch(&wallet, &from).await.unwrap();
+        assert_eq!(report.unwrap().payments, [payment("kaspa:qqb", 110)]);
+        assert_eq!(balance("kaspa:qqa"), (0, 150, 0));
+        assert_eq!(accounting.unconfirmed()[0].known.len(), 1);
+
+        // It isn't paid again while unconfirmed, and its output settles it
+        accounting.restore_payments(&[payment("kaspa:qqa", 100)]);
+        assert!(accounting.take_payments().is_empty());
+        let now = accounting.unconfirmed()[0].since;
+        payouts.settle(&wallet, now).await;
+        assert_eq!(balance("kaspa:qqa"), (100, 0, 150));
+        assert!(accounting.unconfirmed().is_empty());
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // A payment that never arrives is paid again after the settle time
+        accounting.unconfirmed_payment(payment("kaspa:qqc", 120), vec![], now);
+        payouts.settle(&wallet, now + 1).await;
+        assert_eq!(accounting.unconfirmed().len(), 1);
+        payouts.settle(&wallet, now + SETTLE_TIME.as_secs()).await;
+        assert!(accounting.unconfirmed().is_empty());
+        assert_eq!(balance("kaspa:qqc"), (120, 0, 0));
+    }
+}
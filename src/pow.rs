@@ -1,4 +1,62 @@
+mod matrix;
+
 use crate::uint::{BitArray, U256};
+use matrix::Matrix;
+use tiny_keccak::{CShake, Hasher};
+
+/// Precomputed per-template values needed to verify kHeavyHash shares
+pub struct State {
+    matrix: Matrix,
+    words: [u64; 4],
+    timestamp: u64,
+    // PRE_POW_HASH || TIME || 32 zero bytes, waiting for the nonce
+    hasher: CShake,
+}
+
+impl State {
+    pub fn new(pre_pow: U256, timestamp: u64) -> Self {
+        let words: [u64; 4] = pre_pow.as_slice().try_into().unwrap();
+        Self {
+            matrix: Matrix::generate(words),
+            words,
+            timestamp,
+            hasher: hasher(words, timestamp),
+        }
+    }
+
+    pub fn calculate_pow(&self, nonce: u64) -> U256 {
+        self.calculate_pow_at(self.timestamp, nonce)
+    }
+
+    /// PoW of the nonce with the timestamp rolled by the miner, the matrix
+    /// only depends on the pre-PoW hash
+    pub fn calculate_pow_at(&self, timestamp: u64, nonce: u64) -> U256 {
+        let mut hasher = match timestamp == self.timestamp {
+            true => self.hasher.clone(),
+            false => hasher(self.words, timestamp),
+        };
+        hasher.update(&nonce.to_le_bytes());
+        let mut hash = [0; 32];
+        hasher.finalize(&mut hash);
+
+        let hash = self.matrix.heavy_hash(hash);
+        let mut out = [0; 4];
+        for (o, c) in out.iter_mut().zip(hash.chunks_exact(8)) {
+            *o = u64::from_le_bytes(c.try_into().unwrap());
+        }
+        out.into()
+    }
+}
+
+fn hasher(words: [u64; 4], timestamp: u64) -> CShake {
+    let mut hasher = CShake::v256(&[], b"ProofOfWorkHash");
+    for w in words {
+        hasher.update(&w.to_le_bytes());
+    }
+    hasher.update(&timestamp.to_le_bytes());
+    hasher.update(&[0; 32]);
+    hasher
+}
 
 pub fn u256_from_compact_target(bits: u32) -> U256 {
     let (mant, expt) = {
@@ -23,3 +81,51 @@ pub fn difficulty(mut target: U256) -> u64 {
     target.increment();
     (!U256::zero() / target).low_u64()
 }
+
+/// Inverse of [`difficulty`]: the target a hash has to meet to be worth `difficulty` hashes
+pub fn target_from_difficulty(difficulty: u64) -> U256 {
+    !U256::zero() / U256::from_u64(difficulty.max(1)).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::State;
+    use crate::U256;
+
+    #[test]
+    fn heavy_hash() {
+        // Computed with rusty-kaspa's kaspa-pow
+        let cases = [
+            (
+                "5592d3d98aef2f55983b3a10049581b3ace2aee9a060ca3606e1408e6a006e89",
+                1657718600000,
+                8230160685758639177,
+                "13c1003f8347491b63ef3d36aee16efa842bb8d78634e0535ee821f25bd3eb72",
+            ),
+            (
+                "0707070707070707070707070707070707070707070707070707070707070707",
+                0,
+                0,
+                "01d7acc135f218b51d5a65a5e7b394633985b672bbc33fbba9b14d75d9753ea2",
+            ),
+        ];
+
+        for (pre_pow, timestamp, nonce, expected) in cases {
+            let pre_pow = hex::decode(pre_pow).unwrap();
+            let mut words = [0; 4];
+            for (w, c) in words.iter_mut().zip(pre_pow.chunks_exact(8)) {
+                *w = u64::from_le_bytes(c.try_into().unwrap());
+            }
+            let pow = State::new(U256::from(words), timestamp).calculate_pow(nonce);
+            let rolled = State::new(U256::from(words), timestamp ^ 1);
+            assert_eq!(rolled.calculate_pow_at(timestamp, nonce), pow);
+            assert_ne!(rolled.calculate_pow(nonce), pow);
+            let bytes: Vec<u8> = pow
+                .as_slice()
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            assert_eq!(hex::encode(bytes), expected);
+        }
+    }
+}
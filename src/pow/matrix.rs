@@ -0,0 +1,106 @@
+use tiny_keccak::{CShake, Hasher};
+
+pub struct Matrix([[u16; 64]; 64]);
+
+impl Matrix {
+    pub fn generate(pre_pow: [u64; 4]) -> Self {
+        let mut generator = XoShiRo256PlusPlus::new(pre_pow);
+        loop {
+            let matrix = Self::rand_matrix(&mut generator);
+            if matrix.rank() == 64 {
+                return matrix;
+            }
+        }
+    }
+
+    fn rand_matrix(generator: &mut XoShiRo256PlusPlus) -> Self {
+        let mut matrix = [[0u16; 64]; 64];
+        for row in matrix.iter_mut() {
+            for chunk in row.chunks_exact_mut(16) {
+                let val = generator.next_u64();
+                for (shift, v) in chunk.iter_mut().enumerate() {
+                    *v = ((val >> (4 * shift)) & 0x0F) as u16;
+                }
+            }
+        }
+        Matrix(matrix)
+    }
+
+    fn rank(&self) -> usize {
+        const EPS: f64 = 1e-9;
+        let mut m = [[0f64; 64]; 64];
+        for (row, src) in m.iter_mut().zip(&self.0) {
+            for (v, s) in row.iter_mut().zip(src) {
+                *v = *s as f64;
+            }
+        }
+
+        let mut rank = 0;
+        let mut selected = [false; 64];
+        for i in 0..64 {
+            let j = match (0..64).find(|&j| !selected[j] && m[j][i].abs() > EPS) {
+                Some(j) => j,
+                None => continue,
+            };
+            rank += 1;
+            selected[j] = true;
+            for p in (i + 1)..64 {
+                m[j][p] /= m[j][i];
+            }
+            for k in 0..64 {
+                if k != j && m[k][i].abs() > EPS {
+                    for p in (i + 1)..64 {
+                        m[k][p] -= m[j][p] * m[k][i];
+                    }
+                }
+            }
+        }
+        rank
+    }
+
+    pub fn heavy_hash(&self, hash: [u8; 32]) -> [u8; 32] {
+        let mut vec = [0u8; 64];
+        for (i, b) in hash.iter().enumerate() {
+            vec[2 * i] = b >> 4;
+            vec[2 * i + 1] = b & 0x0F;
+        }
+
+        // Multiply with the 4 bit vector and combine the upper bits of each row pair
+        let mut product = [0u8; 32];
+        for (i, p) in product.iter_mut().enumerate() {
+            let (mut sum1, mut sum2) = (0u16, 0u16);
+            for (j, v) in vec.iter().enumerate() {
+                sum1 += self.0[2 * i][j] * (*v as u16);
+                sum2 += self.0[2 * i + 1][j] * (*v as u16);
+            }
+            *p = (((sum1 >> 10) << 4) | (sum2 >> 10)) as u8 ^ hash[i];
+        }
+
+        let mut hasher = CShake::v256(&[], b"HeavyHash");
+        hasher.update(&product);
+        let mut out = [0u8; 32];
+        hasher.finalize(&mut out);
+        out
+    }
+}
+
+struct XoShiRo256PlusPlus([u64; 4]);
+
+impl XoShiRo256PlusPlus {
+    fn new(seed: [u64; 4]) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.0;
+        let res = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        res
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+/// Time an identical warning is held back after it was logged
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Collapses a warning logged over and over at one place, e.g. while the
+/// node syncs for hours, into a line per minute saying how often it came
+pub struct Repeated {
+    interval: Duration,
+    /// The last warning logged and when
+    last: Option<(String, Instant)>,
+    /// Held back since
+    count: u32,
+}
+
+impl Repeated {
+    /// The line to log for `message`, `None` while the same one was logged
+    /// less than a minute ago
+    pub fn check(&mut self, message: &str) -> Option<String> {
+        self.check_at(message, Instant::now())
+    }
+
+    fn check_at(&mut self, message: &str, now: Instant) -> Option<String> {
+        match &self.last {
+            Some((last, at)) if last == message && now < *at + self.interval => {
+                self.count += 1;
+                None
+            }
+            Some((last, _)) if last == message => {
+                self.last = Some((message.into(), now));
+                Some(match std::mem::take(&mut self.count) {
+                    0 => message.into(),
+                    count => format!("{message} (repeated {count} times)"),
+                })
+            }
+            _ => {
+                self.last = Some((message.into(), now));
+                self.count = 0;
+                Some(message.into())
+            }
+        }
+    }
+}
+
+impl Default for Repeated {
+    fn default() -> Self {
+        Repeated {
+            interval: INTERVAL,
+            last: None,
+            count: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Repeated;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn collapses_repeats() {
+        let mut repeated = Repeated::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            repeated.check_at("Not yet synced", at(0)).as_deref(),
+            Some("Not yet synced")
+        );
+        for secs in 1..60 {
+            assert_eq!(repeated.check_at("Not yet synced", at(secs)), None);
+        }
+        assert_eq!(
+            repeated.check_at("Not yet synced", at(60)).as_deref(),
+            Some("Not yet synced (repeated 59 times)")
+        );
+        // Other warnings come through right away
+        assert_eq!(
+            repeated.check_at("Node offline", at(61)).as_deref(),
+            Some("Node offline")
+        );
+        assert_eq!(
+            repeated.check_at("Not yet synced", at(62)).as_deref(),
+            Some("Not yet synced")
+        );
+    }
+}
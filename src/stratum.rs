@@ -1,18 +1,128 @@
-mod jobs;
+mod accounting;
+mod auth;
+mod control;
+mod dialect;
+mod params;
+mod reader;
+mod security;
+// Public for benchmarks
+#[doc(hidden)]
+pub mod jobs;
+mod listener;
 mod server;
+mod shared;
+mod stats;
+mod tls;
+mod upstream;
+mod vardiff;
+mod writer;
 
+use crate::access::Acl;
+use crate::events::Notifier;
+pub use accounting::{
+    Accounting, Balance, ChainReplay, Credit, Payment, PayoutConfig, Round, Scheme, Status,
+    Unconfirmed, SOMPI_PER_KAS,
+};
 use anyhow::Result;
+pub use auth::Auth;
+pub use control::{ConnectionInfo, Control};
+pub use dialect::{
+    Dialect, DialectConfig, ExtranonceMethod, NoncePrefix, NotifyFormat, NotifyLimits, Overrides,
+    Preset,
+};
+pub use listener::ListenerConfig;
+pub use security::SecurityLog;
 use serde::{de, Serializer};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-pub use server::Stratum;
+pub use server::{SocketConfig, Stratum};
+pub use shared::SharedState;
+pub use stats::{
+    format_hashrate, ChartPoint, FoundBlock, Hashrates, Share, Stats, Summary, Window,
+};
 use std::borrow::Cow;
 use std::fmt;
+use std::time::Duration;
+pub use tls::{Tls, TlsConfig};
+pub use upstream::UpstreamConfig;
+pub use vardiff::VarDiffConfig;
+pub use writer::SlowClient;
+
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Per connection share difficulty, otherwise shares are checked against
+    /// the network difficulty
+    pub vardiff: Option<VarDiffConfig>,
+    pub dialect: DialectConfig,
+    /// Further ports on the stratum address, each speaking one dialect to
+    /// every miner
+    pub dialect_ports: Vec<(u16, Preset)>,
+    /// Ports on the stratum address where found blocks are credited to
+    /// their finder alone, see [`Scheme::Solo`]. They may be dialect ports
+    /// as well.
+    pub solo_ports: Vec<u16>,
+    /// Ports with their own dialect, difficulties, TLS and extranonce size,
+    /// combined with the roles the other port options give them
+    pub listeners: Vec<ListenerConfig>,
+    pub slow_client: SlowClient,
+    /// Report workers without shares for this long
+    pub worker_offline: Option<Duration>,
+    pub notifier: Notifier,
+    /// Listeners sharing the stratum port, 0 is treated as 1
+    pub acceptors: usize,
+    pub socket: SocketConfig,
+    /// Shares for the previous job are accepted this long after a new one
+    pub job_grace: Duration,
+    /// Shares are accepted for jobs up to this many DAA scores behind the
+    /// current one instead of the previous job within `job_grace`
+    pub job_expiry_daa: Option<u64>,
+    /// Job ids of two bytes instead of one
+    pub wide_job_ids: bool,
+    /// Connections not subscribed within this time are closed
+    pub handshake_timeout: Option<Duration>,
+    /// Round work, workers and bans shared with other instances
+    pub shared: Option<SharedState>,
+    /// Only serve miners while holding the failover lease in the shared state
+    pub failover: bool,
+    /// Instances splitting the extranonce prefixes between them, 0 is
+    /// treated as 1
+    pub instances: u16,
+    /// Partition of this instance, claimed in the shared state when unset
+    pub instance: Option<u16>,
+    /// Found blocks are not sent to kaspad, can be changed through
+    /// [`Control`]
+    pub dry_run: bool,
+    /// Pool the miners are proxied to while kaspad is down
+    pub fallback: Option<UpstreamConfig>,
+    /// Credit shares to the addresses workers log in with, per instance
+    pub payout: Option<PayoutConfig>,
+    /// Only workers with valid credentials may mine
+    pub auth: Option<Auth>,
+    /// Certificate of the ports speaking stratum over TLS
+    pub tls: Option<TlsConfig>,
+    /// Auth failures, malformed messages and bans, for fail2ban
+    pub security_log: Option<SecurityLog>,
+    /// Rules of the `stratum` listener are checked for new connections
+    pub acl: Option<Acl>,
+    /// Per port and agent limits on how often a miner gets a new job
+    pub notify_limits: NotifyLimits,
+}
+
+/// Convert a difficulty in expected hashes to the unit used by stratum
+pub fn to_stratum_difficulty(difficulty: u64) -> f64 {
+    (difficulty as f64) / ((1u64 << 32) as f64)
+}
+
+pub fn from_stratum_difficulty(difficulty: f64) -> u64 {
+    (difficulty * ((1u64 << 32) as f64)) as u64
+}
 
 #[derive(Clone)]
 pub enum Id {
     Number(u64),
     Text(Box<str>),
+    /// Answers a request whose id couldn't be read
+    Null,
 }
 
 impl From<u64> for Id {
@@ -35,6 +145,13 @@ impl<'de> de::Visitor<'de> for IdVisitor {
         Ok(Id::Number(v))
     }
 
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Id::Text(v.into()))
+    }
+
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
     where
         E: de::Error,
@@ -48,7 +165,7 @@ impl<'de> Deserialize<'de> for Id {
     where
         D: de::Deserializer<'de>,
     {
-        d.deserialize_u64(IdVisitor)
+        d.deserialize_any(IdVisitor)
     }
 }
 
@@ -60,17 +177,30 @@ impl Serialize for Id {
         match self {
             Id::Number(v) => s.serialize_u64(*v),
             Id::Text(v) => s.serialize_str(v),
+            Id::Null => s.serialize_unit(),
         }
     }
 }
 
 #[derive(Deserialize, Serialize)]
-struct Request {
+pub struct Request {
     #[serde(default)]
-    id: Option<Id>,
-    method: Cow<'static, str>,
+    pub id: Option<Id>,
+    pub method: Cow<'static, str>,
     #[serde(default)]
-    params: Option<Value>,
+    pub params: Option<Value>,
+}
+
+impl Request {
+    /// JSON-RPC 2.0 form for strict clients
+    pub fn to_jsonrpc2(&self) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": self.id,
+            "method": self.method,
+            "params": self.params,
+        })
+    }
 }
 
 pub enum Response {
@@ -94,6 +224,23 @@ impl Response {
     }
 }
 
+impl Response {
+    /// JSON-RPC 2.0 form for strict clients, with both `result` and `error`
+    /// and the error as a `{code, message}` object
+    pub fn to_jsonrpc2(&self) -> Value {
+        let (id, result, error) = match self {
+            Response::Ok(r) => (&r.id, &r.result, Value::Null),
+            Response::Err(e) => (&e.id, &Value::Null, e.to_jsonrpc2()),
+        };
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": result,
+            "error": error,
+        })
+    }
+}
+
 impl Serialize for Response {
     fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
     where
@@ -117,3 +264,14 @@ pub struct ErrResponse {
     id: Id,
     error: Value,
 }
+
+impl ErrResponse {
+    /// The stratum `[code, message, data]` error as JSON-RPC 2.0 object,
+    /// errors relayed from a pool in another shape are left as they are
+    fn to_jsonrpc2(&self) -> Value {
+        match self.error.as_array().map(Vec::as_slice) {
+            Some([code, message, ..]) => json!({"code": code, "message": message}),
+            _ => self.error.clone(),
+        }
+    }
+}
@@ -0,0 +1,1203 @@
+use super::jobs::SubmittedBlock;
+use super::shared::SharedState;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Rounds kept in memory
+const MAX_ROUNDS: usize = 100;
+/// Chain blocks of a replay unanswered this long are asked for again
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+pub const SOMPI_PER_KAS: u64 = 100_000_000;
+
+/// How the reward of a found block is split between the miners
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    /// Pay per last N shares: the shares worth a multiple of the network
+    /// difficulty before the block, across rounds
+    Pplns,
+    /// Proportional: the shares of the round the block ended
+    Prop,
+    /// The finder's address earns the whole block, shares only count for
+    /// hashrate and vardiff
+    Solo,
+}
+
+#[derive(Clone, Debug)]
+pub struct PayoutConfig {
+    pub scheme: Scheme,
+    /// PPLNS window as a multiple of the network difficulty
+    pub window: f64,
+    /// Smallest balance in sompi included in a payout
+    pub threshold: u64,
+    /// DAA score depth at which the coinbase of a block can be spent, and its
+    /// reward is credited
+    pub maturity: u64,
+    /// Address prefix of kaspad's network, such as `kaspa` or `kaspatest`.
+    /// Addresses of other networks couldn't be paid, their workers are
+    /// refused.
+    pub prefix: String,
+    /// File keeping the rounds and balances across restarts
+    pub state: Option<PathBuf>,
+}
+
+impl Default for PayoutConfig {
+    fn default() -> Self {
+        PayoutConfig {
+            scheme: Scheme::Pplns,
+            window: 2.0,
+            threshold: SOMPI_PER_KAS,
+            maturity: 1000,
+            prefix: "kaspa".into(),
+            state: None,
+        }
+    }
+}
+
+/// Part of a round earned by one address
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Credit {
+    pub address: String,
+    /// Share difficulty counted for the address
+    pub work: u128,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Waiting to be merged as blue by a chain block and for the maturity
+    /// depth, the reward is credited as immature
+    Immature,
+    /// The reward was moved to the pending balances
+    Mature,
+    /// Merged as red or not merged by the maturity depth, the reward is lost
+    Orphaned,
+}
+
+/// Shares a found block's reward is split by
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Round {
+    pub hash: String,
+    pub daa_score: u64,
+    pub worker: String,
+    pub scheme: Scheme,
+    /// Seconds since the epoch
+    pub time: u64,
+    /// Sompi split between the credits
+    pub reward: u64,
+    /// Ordered by work, largest first
+    pub credits: Vec<Credit>,
+    pub status: Status,
+    /// Chain block merging the block as blue
+    pub accepted_by: Option<String>,
+    /// A reorg took back an earlier acceptance
+    pub reorged: bool,
+}
+
+impl Round {
+    /// Amounts of `reward` per address. Sompi lost to rounding go to the
+    /// largest credits, so the amounts add up to the reward.
+    pub fn split(&self, reward: u64) -> Vec<(String, u64)> {
+        let total: u128 = self.credits.iter().map(|c| c.work).sum();
+        if total == 0 {
+            return vec![];
+        }
+        let mut amounts: Vec<_> = self
+            .credits
+            .iter()
+            .map(|c| (c.address.clone(), (reward as u128 * c.work / total) as u64))
+            .collect();
+        let paid: u64 = amounts.iter().map(|(_, a)| a).sum();
+        for (_, amount) in amounts.iter_mut().take((reward - paid) as usize) {
+            *amount += 1;
+        }
+        amounts
+    }
+}
+
+/// Sompi earned by an address
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Credited for blocks that didn't mature yet
+    pub immature: u64,
+    /// Credited but not paid yet
+    pub pending: u64,
+    pub paid: u64,
+    /// Sent by a payment the wallet failed to confirm, until it's settled
+    #[serde(default)]
+    pub unconfirmed: u64,
+}
+
+/// Amount to send to an address
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payment {
+    pub address: String,
+    /// Sompi
+    pub amount: u64,
+}
+
+/// A payment the wallet may or may not have sent, settled by looking for
+/// its output at the address
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unconfirmed {
+    pub payment: Payment,
+    /// Seconds since the epoch
+    pub since: u64,
+    /// Outpoints the address held before the payment
+    pub known: Vec<String>,
+}
+
+/// Payout address of a worker logged in as `address.worker`, `None` for
+/// names without one
+pub fn address(worker: &str) -> Option<&str> {
+    let address = worker.split('.').next()?;
+    let (prefix, payload) = address.split_once(':')?;
+    let valid = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric());
+    (valid(prefix) && valid(payload)).then_some(address)
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Work per address since the last block
+    round: HashMap<String, u128>,
+    /// Latest shares, oldest first, bounded by the PPLNS window
+    shares: VecDeque<(Arc<str>, u64)>,
+    /// Sum of `shares`
+    shares_work: u128,
+    /// Network difficulty the window is sized by
+    difficulty: u64,
+    /// Latest rounds, all immature ones among them
+    rounds: VecDeque<Round>,
+    /// When a round was last added or changed
+    rounds_modified: Option<SystemTime>,
+    balances: HashMap<String, Balance>,
+    /// At most one per address, its balance isn't paid until it's settled
+    unconfirmed: Vec<Unconfirmed>,
+    /// DAA score of the latest template
+    daa_score: u64,
+    /// Overdue blocks kaspad's chain from them was replayed for, orphaned
+    /// by the next template unless a chain block merged them meanwhile
+    checked: HashSet<String>,
+}
+
+/// What the state file keeps, the shares of the running round start over
+#[derive(Default, Deserialize)]
+struct Saved {
+    rounds: VecDeque<Round>,
+    balances: HashMap<String, Balance>,
+    #[serde(default)]
+    unconfirmed: Vec<Unconfirmed>,
+}
+
+/// [`Saved`] as written from the accounts
+#[derive(Serialize)]
+struct Saving<'a> {
+    rounds: &'a VecDeque<Round>,
+    balances: &'a HashMap<String, Balance>,
+    unconfirmed: &'a [Unconfirmed],
+}
+
+/// Credits shares to the payout addresses of their workers and decides, for
+/// every found block, which addresses earned its reward
+#[derive(Clone)]
+pub struct Accounting {
+    config: PayoutConfig,
+    inner: Arc<Mutex<Inner>>,
+    /// The window or round all instances credit their shares to
+    shared: Option<SharedState>,
+}
+
+impl Accounting {
+    pub fn new(config: PayoutConfig) -> Self {
+        Accounting {
+            config,
+            inner: Default::default(),
+            shared: None,
+        }
+    }
+
+    /// Accounts that carry on from the rounds and balances of the state
+    /// file, if the config names one
+    pub fn open(config: PayoutConfig) -> Result<Self> {
+        let saved = match &config.state {
+            Some(path) => load(path)?,
+            None => Saved::default(),
+        };
+        let accounting = Accounting::new(config);
+        {
+            let mut inner = accounting.inner.lock().unwrap();
+            if !saved.rounds.is_empty() {
+                inner.rounds_modified = Some(SystemTime::now());
+            }
+            inner.rounds = saved.rounds;
+            inner.balances = saved.balances;
+            inner.unconfirmed = saved.unconfirmed;
+        }
+        Ok(accounting)
+    }
+
+    /// Write the rounds and balances to the state file. Failures are
+    /// logged, the accounts go on in memory.
+    fn save(&self, inner: &Inner) {
+        let path = match &self.config.state {
+            Some(path) => path,
+            None => return,
+        };
+        let state = Saving {
+            rounds: &inner.rounds,
+            balances: &inner.balances,
+            unconfirmed: &inner.unconfirmed,
+        };
+        if let Err(e) = save(path, &state) {
+            warn!(
+                "Unable to save the payout state to {}: {e:#}",
+                path.display()
+            );
+        }
+    }
+
+    /// Split found blocks by the shares of all instances sharing the state
+    pub fn with_shared(mut self, shared: Option<SharedState>) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        self.config.scheme
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.config.prefix
+    }
+
+    /// The address a worker logs in with if it is of another network
+    pub fn foreign_address<'a>(&self, worker: &'a str) -> Option<&'a str> {
+        address(worker).filter(|a| !a.starts_with(&format!("{}:", self.config.prefix)))
+    }
+
+    fn window(&self, difficulty: u64) -> u128 {
+        (difficulty as f64 * self.config.window) as u128
+    }
+
+    /// Shares of workers without an address are left to the pool
+    pub fn add_share(&self, worker: Option<&str>, difficulty: u64) {
+        let address = match worker.and_then(address) {
+            Some(a) => a,
+            None => return,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        match self.config.scheme {
+            Scheme::Solo => {}
+            Scheme::Prop => {
+                *inner.round.entry(address.into()).or_default() += difficulty as u128;
+            }
+            Scheme::Pplns => {
+                inner.shares.push_back((address.into(), difficulty));
+                inner.shares_work += difficulty as u128;
+                let window = self.window(inner.difficulty);
+                trim(&mut inner, window);
+            }
+        }
+        if let Some(shared) = &self.shared {
+            let window = self.window(inner.difficulty);
+            shared.add_credit(self.config.scheme, address, difficulty, window);
+        }
+    }
+
+    /// Size the PPLNS window by the latest network difficulty and credit
+    /// the blocks that matured by the template's DAA score. Blocks no chain
+    /// block merged by then are only orphaned once [`Accounting::checked`].
+    /// Returns the rounds that matured or were orphaned.
+    pub fn new_template(&self, difficulty: u64, daa_score: u64) -> Vec<Round> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.difficulty = difficulty;
+        inner.daa_score = daa_score;
+        let window = self.window(difficulty);
+        trim(&mut inner, window);
+        let Inner {
+            rounds,
+            balances,
+            checked,
+            ..
+        } = &mut *inner;
+        let mut changed = vec![];
+        for round in rounds.iter_mut() {
+            if round.status != Status::Immature
+                || daa_score < round.daa_score.saturating_add(self.config.maturity)
+            {
+                continue;
+            }
+            let mature = round.accepted_by.is_some();
+            if !checked.remove(&round.hash) && !mature {
+                continue;
+            }
+            for (address, amount) in round.split(round.reward) {
+                let balance = balances.entry(address).or_default();
+                balance.immature = balance.immature.saturating_sub(amount);
+                if mature {
+                    balance.pending += amount;
+                }
+            }
+            round.status = match mature {
+                true => Status::Mature,
+                false => Status::Orphaned,
+            };
+            changed.push(round.clone());
+        }
+        if !changed.is_empty() {
+            inner.rounds_modified = Some(SystemTime::now());
+            self.save(&inner);
+        }
+        changed
+    }
+
+    /// Whether blocks wait for a chain block to merge them
+    pub fn awaiting_acceptance(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .rounds
+            .iter()
+            .any(|r| r.status == Status::Immature && r.accepted_by.is_none())
+    }
+
+    /// Blocks past the maturity depth that no chain block merged, to
+    /// replay kaspad's chain from before they're orphaned: the notification
+    /// of the chain block merging them may have been missed while the
+    /// connection was down
+    pub fn overdue(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .rounds
+            .iter()
+            .filter(|r| r.status == Status::Immature && r.accepted_by.is_none())
+            .filter(|r| inner.daa_score >= r.daa_score.saturating_add(self.config.maturity))
+            .filter(|r| !inner.checked.contains(&r.hash))
+            .map(|r| r.hash.clone())
+            .collect()
+    }
+
+    /// Record that the chain from an overdue block was replayed, so the
+    /// next template orphans it unless a chain block merged it
+    pub fn checked(&self, hash: &str) {
+        self.inner.lock().unwrap().checked.insert(hash.into());
+    }
+
+    /// Record the merge set of a chain block. Blocks it merges as red are
+    /// orphaned and returned.
+    pub fn chain_block(&self, hash: &str, blues: &[String], reds: &[String]) -> Vec<Round> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            rounds,
+            balances,
+            checked,
+            ..
+        } = &mut *inner;
+        let mut orphaned = vec![];
+        let mut accepted = false;
+        for round in rounds.iter_mut() {
+            if round.status != Status::Immature {
+                continue;
+            }
+            if blues.contains(&round.hash) {
+                checked.remove(&round.hash);
+                round.accepted_by = Some(hash.into());
+                accepted = true;
+            } else if reds.contains(&round.hash) {
+                for (address, amount) in round.split(round.reward) {
+                    let balance = balances.entry(address).or_default();
+                    balance.immature = balance.immature.saturating_sub(amount);
+                }
+                round.status = Status::Orphaned;
+                round.accepted_by = None;
+                orphaned.push(round.clone());
+            }
+        }
+        if accepted || !orphaned.is_empty() {
+            inner.rounds_modified = Some(SystemTime::now());
+            self.save(&inner);
+        }
+        orphaned
+    }
+
+    /// Chain blocks left the selected chain, the blocks they merged wait
+    /// for another one. Returns the rounds that lost their acceptance.
+    pub fn chain_removed(&self, removed: &[String]) -> Vec<Round> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut reorged = vec![];
+        for round in inner.rounds.iter_mut() {
+            let by_removed = matches!(&round.accepted_by, Some(by) if removed.contains(by));
+            if round.status == Status::Immature && by_removed {
+                round.accepted_by = None;
+                round.reorged = true;
+                reorged.push(round.clone());
+            }
+        }
+        if !reorged.is_empty() {
+            inner.rounds_modified = Some(SystemTime::now());
+            self.save(&inner);
+        }
+        reorged
+    }
+
+    /// End the round, returning who earned the block. With shared state
+    /// the shares of every instance count, only this one's if Redis fails.
+    pub async fn block_found(&self, block: &SubmittedBlock) -> Round {
+        let shared = match (&self.shared, self.config.scheme) {
+            (Some(shared), Scheme::Prop | Scheme::Pplns) => shared,
+            _ => return self.end_round(block, self.config.scheme, None),
+        };
+        match shared.credits(self.config.scheme).await {
+            Ok(work) => self.end_round(block, self.config.scheme, Some(work)),
+            Err(e) => {
+                warn!(
+                    "Unable to read the shared credits: {e}, splitting block {} by this instance's shares",
+                    block.hash
+                );
+                self.end_round(block, self.config.scheme, None)
+            }
+        }
+    }
+
+    /// Credit a block found by a solo miner to its address, leaving the
+    /// pool's round running
+    pub fn solo_block_found(&self, block: &SubmittedBlock) -> Round {
+        self.end_round(block, Scheme::Solo, None)
+    }
+
+    /// `shared` is the work per address of all instances, replacing the
+    /// shares seen by this one
+    fn end_round(
+        &self,
+        block: &SubmittedBlock,
+        scheme: Scheme,
+        shared: Option<HashMap<String, u128>>,
+    ) -> Round {
+        let mut inner = self.inner.lock().unwrap();
+        let local: HashMap<String, u128> = match scheme {
+            Scheme::Solo => address(&block.worker)
+                .map(|a| (a.to_string(), block.difficulty as u128))
+                .into_iter()
+                .collect(),
+            Scheme::Prop => std::mem::take(&mut inner.round),
+            Scheme::Pplns => {
+                let window = self.window(block.difficulty);
+                trim(&mut inner, window);
+                let mut work = HashMap::new();
+                for (address, difficulty) in &inner.shares {
+                    *work.entry(address.to_string()).or_default() += *difficulty as u128;
+                }
+                work
+            }
+        };
+        let work = shared.unwrap_or(local);
+        let mut credits: Vec<_> = work
+            .into_iter()
+            .map(|(address, work)| Credit { address, work })
+            .collect();
+        credits.sort_by(|a, b| b.work.cmp(&a.work).then(a.address.cmp(&b.address)));
+        let round = Round {
+            hash: block.hash.clone(),
+            daa_score: block.daa_score,
+            worker: block.worker.clone(),
+            scheme,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            reward: block.reward,
+            credits,
+            status: Status::Immature,
+            accepted_by: None,
+            reorged: false,
+        };
+        for (address, amount) in round.split(round.reward) {
+            inner.balances.entry(address).or_default().immature += amount;
+        }
+        if inner.rounds.len() >= MAX_ROUNDS {
+            // Immature rounds are kept until they are settled
+            let settled = inner
+                .rounds
+                .iter()
+                .position(|r| r.status != Status::Immature);
+            if let Some(i) = settled {
+                inner.rounds.remove(i);
+            }
+        }
+        inner.rounds.push_back(round.clone());
+        inner.rounds_modified = Some(SystemTime::now());
+        self.save(&inner);
+        round
+    }
+
+    /// Latest rounds, oldest first
+    pub fn rounds(&self) -> Vec<Round> {
+        self.inner.lock().unwrap().rounds.iter().cloned().collect()
+    }
+
+    /// When a round was last added or changed, `None` before the first
+    pub fn rounds_modified(&self) -> Option<SystemTime> {
+        self.inner.lock().unwrap().rounds_modified
+    }
+
+    pub fn balances(&self) -> HashMap<String, Balance> {
+        self.inner.lock().unwrap().balances.clone()
+    }
+
+    pub fn balance(&self, address: &str) -> Option<Balance> {
+        self.inner.lock().unwrap().balances.get(address).cloned()
+    }
+
+    /// Smallest balance paid out in sompi
+    pub fn threshold(&self) -> u64 {
+        self.config.threshold.max(1)
+    }
+
+    /// Payments of the next payout, leaving the balances pending
+    pub fn preview_payments(&self) -> Vec<Payment> {
+        payments(&self.inner.lock().unwrap(), self.threshold())
+    }
+
+    /// Take the pending balances reaching the threshold for a payout, the
+    /// smaller ones and those of addresses with an unconfirmed payment carry
+    /// over to the next. Ordered by address.
+    pub fn take_payments(&self) -> Vec<Payment> {
+        let mut inner = self.inner.lock().unwrap();
+        let payments = payments(&inner, self.threshold());
+        for p in &payments {
+            if let Some(balance) = inner.balances.get_mut(&p.address) {
+                balance.pending -= p.amount;
+                balance.paid += p.amount;
+            }
+        }
+        if !payments.is_empty() {
+            self.save(&inner);
+        }
+        payments
+    }
+
+    /// Return payments that couldn't be sent to the pending balances
+    pub fn restore_payments(&self, payments: &[Payment]) {
+        let mut inner = self.inner.lock().unwrap();
+        for p in payments {
+            let balance = inner.balances.entry(p.address.clone()).or_default();
+            balance.paid = balance.paid.saturating_sub(p.amount);
+            balance.pending += p.amount;
+        }
+        if !payments.is_empty() {
+            self.save(&inner);
+        }
+    }
+
+    /// Hold back a payment the wallet may or may not have sent until it's
+    /// settled. `known` are the outpoints the address held before.
+    pub fn unconfirmed_payment(&self, payment: Payment, known: Vec<String>, since: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let balance = inner.balances.entry(payment.address.clone()).or_default();
+        balance.paid = balance.paid.saturating_sub(payment.amount);
+        balance.unconfirmed += payment.amount;
+        inner.unconfirmed.push(Unconfirmed {
+            payment,
+            since,
+            known,
+        });
+        self.save(&inner);
+    }
+
+    /// Payments waiting to be settled
+    pub fn unconfirmed(&self) -> Vec<Unconfirmed> {
+        self.inner.lock().unwrap().unconfirmed.clone()
+    }
+
+    /// Settle the unconfirmed payment to `address` as paid if it was
+    /// received, otherwise return it to the pending balance
+    pub fn settle_payment(&self, address: &str, received: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let i = match inner
+            .unconfirmed
+            .iter()
+            .position(|u| u.payment.address == address)
+        {
+            Some(i) => i,
+            None => return,
+        };
+        let payment = inner.unconfirmed.remove(i).payment;
+        let balance = inner.balances.entry(payment.address).or_default();
+        balance.unconfirmed = balance.unconfirmed.saturating_sub(payment.amount);
+        match received {
+            true => balance.paid += payment.amount,
+            false => balance.pending += payment.amount,
+        }
+        self.save(&inner);
+    }
+}
+
+/// Replays kaspad's chain from one [`Accounting::overdue`] block at a
+/// time, as kaspad's answer doesn't tell which block it started from
+#[derive(Default)]
+pub struct ChainReplay {
+    /// Overdue block whose chain was asked for
+    asked: Option<String>,
+    /// Overdue block, the chain blocks of its replay not yet recorded and
+    /// when they were asked for
+    replaying: Option<(String, HashSet<String>, Instant)>,
+}
+
+impl ChainReplay {
+    /// The overdue block to ask kaspad's chain from, none while another
+    /// one is replayed
+    pub fn next(&mut self, accounting: &Accounting, now: Instant) -> Option<String> {
+        if self.asked.is_some() {
+            return None;
+        }
+        if let Some((_, _, asked)) = &self.replaying {
+            // A chain block kaspad failed to send would hold it up forever
+            if now.duration_since(*asked) < REPLAY_TIMEOUT {
+                return None;
+            }
+            self.replaying = None;
+        }
+        let hash = accounting.overdue().into_iter().next()?;
+        self.asked = Some(hash.clone());
+        Some(hash)
+    }
+
+    /// Kaspad's answer to the chain asked for, returning the chain blocks
+    /// to ask for. Without any, the block is checked right away.
+    pub fn chain(
+        &mut self,
+        accounting: &Accounting,
+        added: Result<Vec<String>, String>,
+        now: Instant,
+    ) -> Vec<String> {
+        let hash = match self.asked.take() {
+            Some(hash) => hash,
+            None => return vec![],
+        };
+        match added {
+            Ok(added) if !added.is_empty() => {
+                let remaining = added.iter().cloned().collect();
+                self.replaying = Some((hash, remaining, now));
+                added
+            }
+            Ok(_) => {
+                accounting.checked(&hash);
+                vec![]
+            }
+            // Unknown to kaspad, so no chain block merges it
+            Err(e) => {
+                warn!("Unable to replay the chain from block {hash}: {e}");
+                accounting.checked(&hash);
+                vec![]
+            }
+        }
+    }
+
+    /// A chain block was recorded, the replay is done with its last one
+    pub fn block(&mut self, accounting: &Accounting, hash: &str) {
+        if let Some((overdue, remaining, _)) = &mut self.replaying {
+            remaining.remove(hash);
+            if remaining.is_empty() {
+                accounting.checked(overdue);
+                self.replaying = None;
+            }
+        }
+    }
+
+    /// The connection dropped, answers to the replay won't arrive
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Nothing to carry on from if the file doesn't exist yet
+fn load(path: &Path) -> Result<Saved> {
+    let text = match std::fs::read(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Saved::default()),
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {}", path.display())),
+    };
+    let saved: Saved = serde_json::from_slice(&text)
+        .with_context(|| format!("{} is not a payout state", path.display()))?;
+    info!(
+        "Loaded {} rounds and {} balances from {}",
+        saved.rounds.len(),
+        saved.balances.len(),
+        path.display()
+    );
+    Ok(saved)
+}
+
+/// Written next to the file and renamed over it, so a crash halfway
+/// leaves the previous state
+fn save(path: &Path, state: &Saving) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(state)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn payments(inner: &Inner, threshold: u64) -> Vec<Payment> {
+    let unconfirmed = |address: &String| {
+        inner
+            .unconfirmed
+            .iter()
+            .any(|u| &u.payment.address == address)
+    };
+    let mut payments: Vec<_> = inner
+        .balances
+        .iter()
+        .filter(|(address, b)| b.pending >= threshold && !unconfirmed(address))
+        .map(|(address, b)| Payment {
+            address: address.clone(),
+            amount: b.pending,
+        })
+        .collect();
+    payments.sort_by(|a, b| a.address.cmp(&b.address));
+    payments
+}
+
+/// Drop the oldest shares not needed to fill `window`
+fn trim(inner: &mut Inner, window: u128) {
+    if window == 0 {
+        return;
+    }
+    while let Some((_, oldest)) = inner.shares.front() {
+        let rest = inner.shares_work - *oldest as u128;
+        if rest < window {
+            break;
+        }
+        inner.shares_work = rest;
+        inner.shares.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{address, Accounting, ChainReplay, Payment, PayoutConfig, Round, Scheme, Status};
+    use crate::stratum::jobs::SubmittedBlock;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    const A: &str = "kaspa:qqa.rig1";
+    const B: &str = "kaspa:qqb.rig2";
+
+    fn block(difficulty: u64) -> SubmittedBlock {
+        SubmittedBlock {
+            hash: "00ff".into(),
+            daa_score: 1,
+            difficulty,
+            worker: A.into(),
+            reward: 0,
+            fees: 0,
+        }
+    }
+
+    /// [`Accounting::block_found`] without shared state
+    fn found(accounting: &Accounting, block: &SubmittedBlock) -> Round {
+        accounting.end_round(block, accounting.scheme(), None)
+    }
+
+    fn work(accounting: &Accounting, difficulty: u64) -> Vec<(String, u128)> {
+        let round = found(accounting, &block(difficulty));
+        round
+            .credits
+            .into_iter()
+            .map(|c| (c.address, c.work))
+            .collect()
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(address("kaspa:qqa.rig1"), Some("kaspa:qqa"));
+        assert_eq!(address("kaspatest:qqa"), Some("kaspatest:qqa"));
+        assert_eq!(address("rig1"), None);
+        assert_eq!(address("kaspa:.rig1"), None);
+        assert_eq!(address("kaspa:q q"), None);
+
+        let accounting = Accounting::new(PayoutConfig::default());
+        assert_eq!(accounting.foreign_address("kaspa:qqa.rig1"), None);
+        assert_eq!(accounting.foreign_address("rig1"), None);
+        assert_eq!(
+            accounting.foreign_address("kaspatest:qqa.rig1"),
+            Some("kaspatest:qqa")
+        );
+    }
+
+    #[test]
+    fn prop_counts_the_round() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Prop,
+            ..Default::default()
+        });
+        accounting.add_share(Some(A), 100);
+        accounting.add_share(Some(B), 300);
+        accounting.add_share(Some("rig3"), 500);
+        assert_eq!(
+            work(&accounting, 1000),
+            [("kaspa:qqb".into(), 300), ("kaspa:qqa".into(), 100)]
+        );
+        accounting.add_share(Some(A), 50);
+        assert_eq!(work(&accounting, 1000), [("kaspa:qqa".into(), 50)]);
+        assert_eq!(accounting.rounds().len(), 2);
+    }
+
+    #[test]
+    fn pplns_counts_the_window() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Pplns,
+            ..Default::default()
+        });
+        accounting.new_template(100, 0);
+        for _ in 0..5 {
+            accounting.add_share(Some(A), 50);
+        }
+        accounting.add_share(Some(B), 100);
+        // The last 200 of work
+        assert_eq!(
+            work(&accounting, 100),
+            [("kaspa:qqa".into(), 100), ("kaspa:qqb".into(), 100)]
+        );
+        // Shares count for the following block as well
+        accounting.add_share(Some(B), 50);
+        assert_eq!(
+            work(&accounting, 100),
+            [("kaspa:qqb".into(), 150), ("kaspa:qqa".into(), 50)]
+        );
+    }
+
+    #[test]
+    fn splits_by_shared_credits() {
+        let accounting = Accounting::new(PayoutConfig::default());
+        accounting.new_template(100, 0);
+        accounting.add_share(Some(A), 50);
+        // Shares of all instances, B mined on another one
+        let shared = HashMap::from([("kaspa:qqa".into(), 50), ("kaspa:qqb".into(), 150)]);
+        let round = accounting.end_round(&block(100), Scheme::Pplns, Some(shared));
+        let work: Vec<_> = round
+            .credits
+            .iter()
+            .map(|c| (&*c.address, c.work))
+            .collect();
+        assert_eq!(work, [("kaspa:qqb", 150), ("kaspa:qqa", 50)]);
+    }
+
+    #[test]
+    fn solo_credits_the_finder() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Prop,
+            ..Default::default()
+        });
+        accounting.add_share(Some(B), 100);
+        let round = accounting.solo_block_found(&block(1000));
+        assert_eq!(round.scheme, Scheme::Solo);
+        assert_eq!(round.split(10), [("kaspa:qqa".into(), 10)]);
+        // The pool's round goes on
+        assert_eq!(work(&accounting, 1000), [("kaspa:qqb".into(), 100)]);
+
+        let solo = Accounting::new(PayoutConfig {
+            scheme: Scheme::Solo,
+            ..Default::default()
+        });
+        solo.add_share(Some(B), 100);
+        assert_eq!(work(&solo, 1000), [("kaspa:qqa".into(), 1000)]);
+    }
+
+    #[test]
+    fn splits_rewards() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Prop,
+            ..Default::default()
+        });
+        accounting.add_share(Some(A), 2);
+        accounting.add_share(Some(B), 1);
+        let round = found(&accounting, &block(10));
+        assert_eq!(
+            round.split(100),
+            [("kaspa:qqa".into(), 67), ("kaspa:qqb".into(), 33)]
+        );
+        assert!(found(&accounting, &block(10)).split(100).is_empty());
+    }
+
+    #[test]
+    fn pays_balances_above_threshold() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Prop,
+            threshold: 100,
+            ..Default::default()
+        });
+        let find = |reward: u64| {
+            let hash = reward.to_string();
+            found(
+                &accounting,
+                &SubmittedBlock {
+                    hash: hash.clone(),
+                    reward,
+                    ..block(10)
+                },
+            );
+            accounting.chain_block("c", &[hash], &[]);
+            accounting.new_template(10, 1000 + 1);
+        };
+        accounting.add_share(Some(A), 3);
+        accounting.add_share(Some(B), 1);
+        find(200);
+        let payment = |address: &str, amount| Payment {
+            address: address.into(),
+            amount,
+        };
+        assert_eq!(accounting.preview_payments(), [payment("kaspa:qqa", 150)]);
+        assert_eq!(accounting.take_payments(), [payment("kaspa:qqa", 150)]);
+        // B's 50 carry over
+        accounting.add_share(Some(B), 1);
+        find(60);
+        let payments = accounting.take_payments();
+        assert_eq!(payments, [payment("kaspa:qqb", 110)]);
+        assert!(accounting.take_payments().is_empty());
+
+        accounting.restore_payments(&payments);
+        let b = accounting.balance("kaspa:qqb").unwrap();
+        assert_eq!((b.pending, b.paid), (110, 0));
+        let a = accounting.balance("kaspa:qqa").unwrap();
+        assert_eq!((a.pending, a.paid), (0, 150));
+    }
+
+    #[test]
+    fn credits_mature_blocks() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Solo,
+            maturity: 100,
+            ..Default::default()
+        });
+        let find = |hash: &str, daa_score| {
+            found(
+                &accounting,
+                &SubmittedBlock {
+                    hash: hash.into(),
+                    daa_score,
+                    reward: 10,
+                    ..block(1)
+                },
+            );
+        };
+        let balance = || {
+            let b = accounting.balance("kaspa:qqa").unwrap();
+            (b.immature, b.pending)
+        };
+        let statuses = |rounds: Vec<super::Round>| -> Vec<_> {
+            rounds.into_iter().map(|r| (r.hash, r.status)).collect()
+        };
+        find("blue", 1000);
+        find("red", 1000);
+        find("lost", 1000);
+        assert_eq!(balance(), (30, 0));
+        assert!(accounting.awaiting_acceptance());
+
+        let names = |names: &[&str]| -> Vec<String> { names.iter().map(|&n| n.into()).collect() };
+        let orphaned = accounting.chain_block("c1", &names(&["blue"]), &names(&["red"]));
+        assert_eq!(statuses(orphaned), [("red".into(), Status::Orphaned)]);
+        assert_eq!(balance(), (20, 0));
+        // A reorg takes back the acceptance until another chain block merges it
+        accounting.chain_removed(&names(&["c1"]));
+        accounting.chain_block("c2", &names(&["blue"]), &[]);
+        assert!(accounting.awaiting_acceptance());
+
+        assert!(accounting.new_template(1, 1099).is_empty());
+        assert!(accounting.overdue().is_empty());
+        // Blocks no chain block merged wait for the chain to be replayed
+        assert_eq!(
+            statuses(accounting.new_template(1, 1100)),
+            [("blue".into(), Status::Mature)]
+        );
+        assert_eq!(accounting.overdue(), ["lost"]);
+        accounting.checked("lost");
+        assert!(accounting.overdue().is_empty());
+        assert_eq!(
+            statuses(accounting.new_template(1, 1101)),
+            [("lost".into(), Status::Orphaned)]
+        );
+        assert_eq!(balance(), (0, 10));
+        assert!(!accounting.awaiting_acceptance());
+        assert!(accounting.new_template(1, 2000).is_empty());
+    }
+
+    #[test]
+    fn replays_missed_chain_blocks() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Solo,
+            maturity: 100,
+            ..Default::default()
+        });
+        found(
+            &accounting,
+            &SubmittedBlock {
+                hash: "missed".into(),
+                daa_score: 1000,
+                reward: 10,
+                ..block(1)
+            },
+        );
+        // The connection dropped, the chain block merging it went unnoticed
+        assert!(accounting.new_template(1, 1100).is_empty());
+        assert_eq!(accounting.overdue(), ["missed"]);
+        let mut replay = ChainReplay::default();
+        let now = Instant::now();
+        assert_eq!(replay.next(&accounting, now).as_deref(), Some("missed"));
+        assert!(replay.next(&accounting, now).is_none());
+        // Dropped again before kaspad answered, so it's asked once more
+        replay.reset();
+        assert_eq!(replay.next(&accounting, now).as_deref(), Some("missed"));
+        let chain = replay.chain(&accounting, Ok(vec!["c1".into(), "c2".into()]), now);
+        assert_eq!(chain, ["c1", "c2"]);
+        assert!(replay.next(&accounting, now).is_none());
+        // Replaying the chain from the block finds the chain block
+        accounting.chain_block("c1", &["missed".into()], &[]);
+        replay.block(&accounting, "c1");
+        accounting.chain_block("c2", &[], &[]);
+        replay.block(&accounting, "c2");
+        assert!(accounting.overdue().is_empty());
+        let matured = accounting.new_template(1, 1101);
+        assert_eq!(matured.len(), 1);
+        assert_eq!(matured[0].status, Status::Mature);
+        assert_eq!(matured[0].accepted_by.as_deref(), Some("c1"));
+        assert_eq!(accounting.balance("kaspa:qqa").unwrap().pending, 10);
+
+        // A block kaspad doesn't know is orphaned after the replay
+        found(
+            &accounting,
+            &SubmittedBlock {
+                hash: "unknown".into(),
+                daa_score: 1100,
+                reward: 10,
+                ..block(1)
+            },
+        );
+        assert!(accounting.new_template(1, 1200).is_empty());
+        assert_eq!(replay.next(&accounting, now).as_deref(), Some("unknown"));
+        let chain = replay.chain(&accounting, Err("block not found".into()), now);
+        assert!(chain.is_empty());
+        let orphaned = accounting.new_template(1, 1201);
+        assert_eq!(orphaned[0].status, Status::Orphaned);
+    }
+
+    #[test]
+    fn reverts_reorged_blocks() {
+        let accounting = Accounting::new(PayoutConfig {
+            scheme: Scheme::Solo,
+            maturity: 100,
+            ..Default::default()
+        });
+        for hash in ["kept", "reverted"] {
+            found(
+                &accounting,
+                &SubmittedBlock {
+                    hash: hash.into(),
+                    daa_score: 1000,
+                    reward: 10,
+                    ..block(1)
+                },
+            );
+        }
+        let names = |names: &[&str]| -> Vec<String> { names.iter().map(|&n| n.into()).collect() };
+        accounting.chain_block("c1", &names(&["kept", "reverted"]), &[]);
+        assert!(!accounting.awaiting_acceptance());
+
+        let reorged = accounting.chain_removed(&names(&["c1"]));
+        assert_eq!(reorged.len(), 2);
+        assert!(reorged.iter().all(|r| r.reorged && r.accepted_by.is_none()));
+        assert!(accounting.awaiting_acceptance());
+        // Removing chain blocks that merged nothing changes nothing
+        assert!(accounting.chain_removed(&names(&["c0"])).is_empty());
+
+        let orphaned = accounting.chain_block("c2", &names(&["kept"]), &names(&["reverted"]));
+        assert_eq!(orphaned.len(), 1);
+        assert_eq!(orphaned[0].hash, "reverted");
+        assert!(orphaned[0].reorged);
+        let b = accounting.balance("kaspa:qqa").unwrap();
+        assert_eq!((b.immature, b.pending), (10, 0));
+
+        accounting.new_template(1, 1100);
+        let b = accounting.balance("kaspa:qqa").unwrap();
+        assert_eq!((b.immature, b.pending), (0, 10));
+        let rounds = accounting.rounds();
+        assert_eq!(rounds[0].accepted_by.as_deref(), Some("c2"));
+        assert_eq!(rounds[1].status, Status::Orphaned);
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "kaspad-stratum-payouts-{}.json",
+            std::process::id()
+        ));
+        let config = PayoutConfig {
+            scheme: Scheme::Prop,
+            threshold: 100,
+            maturity: 100,
+            state: Some(path.clone()),
+            ..Default::default()
+        };
+        let accounting = Accounting::open(config.clone()).unwrap();
+        assert!(accounting.rounds().is_empty());
+        for (hash, daa_score) in [("paid", 1000), ("immature", 1050)] {
+            accounting.add_share(Some(A), 3);
+            accounting.add_share(Some(B), 1);
+            let block = SubmittedBlock {
+                hash: hash.into(),
+                daa_score,
+                reward: 200,
+                ..block(10)
+            };
+            found(&accounting, &block);
+        }
+        accounting.chain_block("c", &["paid".into(), "immature".into()], &[]);
+        accounting.new_template(10, 1100);
+        let mut payments = accounting.take_payments();
+        assert_eq!(payments.len(), 1);
+        // The wallet didn't confirm sending it
+        accounting.unconfirmed_payment(payments.remove(0), vec!["tx:0".into()], 1_700_000_000);
+
+        let restarted = Accounting::open(config).unwrap();
+        assert_eq!(restarted.balances(), accounting.balances());
+        assert_eq!(restarted.unconfirmed(), accounting.unconfirmed());
+        let statuses: Vec<_> = restarted
+            .rounds()
+            .into_iter()
+            .map(|r| (r.hash, r.status, r.accepted_by))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("paid".into(), Status::Mature, Some("c".into())),
+                ("immature".into(), Status::Immature, Some("c".into()))
+            ]
+        );
+        // The immature block carries on to maturity
+        let matured = restarted.new_template(10, 1150);
+        assert_eq!(matured[0].hash, "immature");
+        let a = restarted.balance("kaspa:qqa").unwrap();
+        assert_eq!((a.pending, a.unconfirmed, a.paid), (150, 150, 0));
+        restarted.settle_payment("kaspa:qqa", true);
+        let a = restarted.balance("kaspa:qqa").unwrap();
+        assert_eq!((a.pending, a.unconfirmed, a.paid), (150, 0, 150));
+        let b = restarted.balance("kaspa:qqb").unwrap();
+        assert_eq!((b.immature, b.pending), (0, 100));
+        assert!(restarted.rounds_modified().is_some());
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(Accounting::open(PayoutConfig {
+            state: Some(path.clone()),
+            ..Default::default()
+        })
+        .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::http::{self, HttpClient};
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{header, Body, Method, Request, Uri};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time;
+use tracing::info;
+
+/// Time the auth webhook has to answer before the worker is refused
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks the credentials of `mining.authorize`, for private farms that
+/// must not accept stray hashrate
+#[derive(Clone)]
+pub enum Auth {
+    /// Password of every worker allowed in
+    Credentials(Arc<Credentials>),
+    /// Asks a service, which accepts a worker with a 2xx status
+    Webhook(Arc<AuthWebhook>),
+}
+
+pub struct Credentials {
+    path: PathBuf,
+    passwords: RwLock<HashMap<String, String>>,
+}
+
+pub struct AuthWebhook {
+    url: Uri,
+    client: HttpClient,
+}
+
+impl Auth {
+    /// Read `<worker> <password>` lines, skipping those starting with `#`
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(Auth::Credentials(Arc::new(Credentials {
+            passwords: RwLock::new(read_credentials(path)?),
+            path: path.into(),
+        })))
+    }
+
+    /// Switch to the credentials in the file if they changed, whether they
+    /// did. Webhooks have nothing to reload.
+    pub fn reload(&self) -> Result<bool> {
+        let credentials = match self {
+            Auth::Credentials(credentials) => credentials,
+            Auth::Webhook(_) => return Ok(false),
+        };
+        let passwords = read_credentials(&credentials.path)?;
+        let mut current = credentials.passwords.write().unwrap();
+        if passwords == *current {
+            return Ok(false);
+        }
+        *current = passwords;
+        info!("Reloaded the credentials {}", credentials.path.display());
+        Ok(true)
+    }
+
+    pub fn webhook(url: &str) -> Result<Self> {
+        let url: Uri = url.parse()?;
+        if url.host().is_none() {
+            bail!("auth webhook url {url} has no host");
+        }
+        Ok(Auth::Webhook(Arc::new(AuthWebhook {
+            url,
+            client: http::client(),
+        })))
+    }
+
+    /// Whether the worker may mine, failures of the webhook refuse it
+    pub async fn check(&self, worker: &str, password: &str, ip: IpAddr) -> Result<bool> {
+        match self {
+            Auth::Credentials(credentials) => {
+                let passwords = credentials.passwords.read().unwrap();
+                Ok(passwords.get(worker).map(String::as_str) == Some(password))
+            }
+            Auth::Webhook(webhook) => {
+                let body = json!({ "worker": worker, "password": password, "ip": ip });
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri(webhook.url.clone())
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))?;
+                let res = time::timeout(WEBHOOK_TIMEOUT, webhook.client.request(req))
+                    .await
+                    .map_err(|_| anyhow!("auth webhook timed out"))??;
+                Ok(res.status().is_success())
+            }
+        }
+    }
+}
+
+fn read_credentials(path: &Path) -> Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    parse_credentials(&text).with_context(|| format!("{} is invalid", path.display()))
+}
+
+fn parse_credentials(text: &str) -> Result<HashMap<String, String>> {
+    let mut credentials = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((worker, password)) => {
+                credentials.insert(worker.into(), password.trim().into());
+            }
+            None => bail!("Line {} has no password", i + 1),
+        }
+    }
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_credentials, Auth};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[tokio::test]
+    async fn checks_credentials() {
+        let text = "# farm\nkaspa:qqa.rig1 hunter2\n\nkaspa:qqa.rig2\tpass #word\n";
+        let credentials = parse_credentials(text).unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials["kaspa:qqa.rig2"], "pass #word");
+        assert!(parse_credentials("rig3").is_err());
+
+        let path = std::env::temp_dir().join(format!("kaspad-stratum-auth-{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let auth = Auth::from_file(&path).unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(auth.check("kaspa:qqa.rig1", "hunter2", ip).await.unwrap());
+        assert!(!auth.check("kaspa:qqa.rig1", "x", ip).await.unwrap());
+        assert!(!auth.check("kaspa:qqb.rig1", "hunter2", ip).await.unwrap());
+
+        assert!(!auth.reload().unwrap());
+        std::fs::write(&path, "kaspa:qqa.rig1 hunter3\n").unwrap();
+        assert!(auth.reload().unwrap());
+        assert!(auth.check("kaspa:qqa.rig1", "hunter3", ip).await.unwrap());
+        assert!(!auth
+            .check("kaspa:qqa.rig2", "pass #word", ip)
+            .await
+            .unwrap());
+        // A broken edit keeps the credentials in effect
+        std::fs::write(&path, "rig3").unwrap();
+        assert!(auth.reload().is_err());
+        assert!(auth.check("kaspa:qqa.rig1", "hunter3", ip).await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use super::jobs::Jobs;
+use super::shared::Bans;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+const KICKED: &str = "Kicked by the operator";
+
+/// Requests from the operator to a connection
+pub(super) enum Command {
+    /// Close the connection for the given reason
+    Close(&'static str),
+    /// Share difficulty in expected hashes
+    SetDifficulty(u64),
+}
+
+/// A miner connection as listed by the admin API
+#[derive(Clone, Serialize)]
+pub struct ConnectionInfo {
+    /// Unique per process, as in the log lines of the connection
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub worker: Option<String>,
+    pub agent: Option<String>,
+    /// Current share difficulty in stratum units
+    pub difficulty: f64,
+    /// Unix time of the connection
+    pub connected: u64,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Live connections, so they can be inspected and managed at runtime
+#[derive(Clone, Default)]
+pub(super) struct Connections {
+    inner: Arc<Mutex<HashMap<u64, Entry>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Connections {
+    pub fn register(&self, addr: SocketAddr) -> (Registration, mpsc::UnboundedReceiver<Command>) {
+        // From 1, which reads better in logs than 0
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (commands, recv) = mpsc::unbounded_channel();
+        let connected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let info = ConnectionInfo {
+            id,
+            addr,
+            worker: None,
+            agent: None,
+            difficulty: 0.0,
+            connected,
+        };
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(id, Entry { info, commands });
+        let registration = Registration {
+            id,
+            connections: self.clone(),
+        };
+        (registration, recv)
+    }
+
+    /// Send a command to the matching connections, returning how many there
+    /// were
+    pub fn send(
+        &self,
+        matches: impl Fn(&ConnectionInfo) -> bool,
+        command: impl Fn() -> Command,
+    ) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .values()
+            .filter(|e| matches(&e.info))
+            .filter(|e| e.commands.send(command()).is_ok())
+            .count()
+    }
+}
+
+/// Entry of a connection in [`Connections`], removed when dropped
+pub(super) struct Registration {
+    id: u64,
+    connections: Connections,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut ConnectionInfo)) {
+        if let Some(e) = self.connections.inner.lock().unwrap().get_mut(&self.id) {
+            f(&mut e.info);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.inner.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Runtime management of a running server, used by the admin API
+#[derive(Clone)]
+pub struct Control {
+    pub(super) connections: Connections,
+    pub(super) bans: Bans,
+    pub(super) jobs: Jobs,
+}
+
+impl Control {
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let inner = self.connections.inner.lock().unwrap();
+        let mut list: Vec<_> = inner.values().map(|e| e.info.clone()).collect();
+        list.sort_by_key(|c| c.connected);
+        list
+    }
+
+    /// Close the connections of a worker, returning how many there were
+    pub fn kick_worker(&self, worker: &str) -> usize {
+        self.connections.send(
+            |c| c.worker.as_deref() == Some(worker),
+            || Command::Close(KICKED),
+        )
+    }
+
+    /// Close the connection with the id of its log lines
+    pub fn kick_connection(&self, id: u64) -> usize {
+        self.connections
+            .send(|c| c.id == id, || Command::Close(KICKED))
+    }
+
+    /// Close the connections from an address
+    pub fn kick_ip(&self, ip: IpAddr) -> usize {
+        self.connections
+            .send(|c| c.addr.ip() == ip, || Command::Close(KICKED))
+    }
+
+    /// Refuse an address for the ban time, longer for repeat offenders, and
+    /// close its connections
+    pub fn ban(&self, ip: IpAddr) -> usize {
+        self.bans.ban(ip, "operator");
+        self.kick_ip(ip)
+    }
+
+    /// Set the share difficulty of a worker's connections, vardiff keeps
+    /// adjusting it from there
+    pub fn set_difficulty(&self, worker: &str, difficulty: f64) -> usize {
+        let difficulty = super::from_stratum_difficulty(difficulty);
+        self.connections.send(
+            |c| c.worker.as_deref() == Some(worker),
+            || Command::SetDifficulty(difficulty),
+        )
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.jobs.dry_run()
+    }
+
+    /// Whether found blocks are kept from kaspad
+    pub fn set_dry_run(&self, enabled: bool) {
+        self.jobs.set_dry_run(enabled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, Connections};
+
+    #[test]
+    fn registers_connections() {
+        let connections = Connections::default();
+        let (a, mut a_recv) = connections.register("10.0.0.1:1000".parse().unwrap());
+        let (b, _b_recv) = connections.register("10.0.0.2:1000".parse().unwrap());
+        a.update(|c| c.worker = Some("rig1".into()));
+        let kicked = connections.send(
+            |c| c.worker.as_deref() == Some("rig1"),
+            || Command::Close(""),
+        );
+        assert_eq!(kicked, 1);
+        assert!(matches!(a_recv.try_recv(), Ok(Command::Close(_))));
+        assert_eq!((a.id(), b.id()), (1, 2));
+        let kicked = connections.send(|c| c.id == b.id(), || Command::Close(""));
+        assert_eq!(kicked, 1);
+        drop(b);
+        assert_eq!(connections.inner.lock().unwrap().len(), 1);
+    }
+}
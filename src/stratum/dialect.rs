@@ -0,0 +1,349 @@
+use std::time::Duration;
+
+/// Miner families with their own protocol quirks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum Preset {
+    /// The reference kaspa-miner, accepting the message shapes of other
+    /// miners too
+    KaspaMiner,
+    /// Exactly what the reference kaspa-miner sends and expects, without the
+    /// shims for other miners
+    KaspaMinerStrict,
+    /// Clients following the common stratum conventions
+    Stratum,
+    LolMiner,
+    #[clap(name = "gminer")]
+    GMiner,
+    /// SRBMiner-MULTI
+    #[clap(name = "srbminer")]
+    SrbMiner,
+    /// Goldshell KA-series firmware, selected per port
+    Goldshell,
+}
+
+/// Agent prefixes of `mining.subscribe` identifying a miner, lowercase
+const AGENTS: &[(&str, Preset)] = &[
+    ("kaspa-miner", Preset::KaspaMinerStrict),
+    ("lolminer", Preset::LolMiner),
+    ("gminer", Preset::GMiner),
+    ("srbminer", Preset::SrbMiner),
+];
+
+impl Preset {
+    /// The preset of a known miner from its agent
+    pub fn detect(agent: &str) -> Option<Preset> {
+        let agent = agent.trim().to_ascii_lowercase();
+        AGENTS
+            .iter()
+            .find(|(prefix, _)| agent.starts_with(prefix))
+            .map(|(_, preset)| *preset)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscribeResponse {
+    /// `true`
+    Bool,
+    /// `[[["mining.notify", id]], extranonce1, extranonce2_size]`
+    Standard,
+    /// `[true, "EthereumStratum/1.0.0"]`
+    EthereumStratum,
+}
+
+/// Shape of the `mining.notify` params
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum NotifyFormat {
+    /// `[job_id, [u64; 4], timestamp]`
+    Words,
+    /// `[job_id, hex(pre_pow), timestamp]`
+    Hex,
+    /// `[job_id, hex(pre_pow || timestamp_le)]`
+    Header,
+    /// `[job_id, hex(pre_pow)]`, for miners taking the timestamp from
+    /// elsewhere
+    PrePow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum ExtranonceMethod {
+    SetExtranonce,
+    MiningSetExtranonce,
+}
+
+impl ExtranonceMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExtranonceMethod::SetExtranonce => "set_extranonce",
+            ExtranonceMethod::MiningSetExtranonce => "mining.set_extranonce",
+        }
+    }
+}
+
+/// What happens to shares whose nonce doesn't start with the miner's
+/// extranonce
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+pub enum NoncePrefix {
+    /// Rejected as invalid
+    Strict,
+    /// The extranonce is written over the prefix before checking the share
+    Rewrite,
+    /// Checked as submitted, for firmware rolling the whole nonce
+    Off,
+}
+
+/// Protocol variations between miner implementations
+#[derive(Clone, Debug)]
+pub struct Dialect {
+    pub subscribe_response: SubscribeResponse,
+    /// Nonces without `0x` prefix are decimal instead of hex
+    pub decimal_nonce: bool,
+    pub extranonce_method: ExtranonceMethod,
+    /// Send the extranonce right after subscribing, otherwise only after
+    /// `mining.extranonce.subscribe`
+    pub extranonce_unsolicited: bool,
+    pub notify_format: NotifyFormat,
+    /// Add `jsonrpc` and always both `result` and `error` to messages
+    pub jsonrpc2: bool,
+    /// Factor of the difficulty sent in `mining.set_difficulty`, for miners
+    /// deriving their share target differently
+    pub difficulty_scale: f64,
+    /// Send a changed `mining.set_difficulty` ahead of the notify instead of
+    /// after it
+    pub difficulty_first: bool,
+    /// Send `mining.set_difficulty` ahead of every notify, for firmware that
+    /// loses its difficulty
+    pub resend_difficulty: bool,
+    /// Nonces may leave out the extranonce, which is then put in front
+    pub short_nonce: bool,
+    /// Only accept messages shaped like the reference kaspa-miner's, see
+    /// [`Preset::KaspaMinerStrict`]
+    pub strict: bool,
+    /// Accept `mining.login` in place of `mining.authorize`
+    pub mining_login: bool,
+    /// Nonces are submitted as their little endian bytes
+    pub little_endian_nonce: bool,
+    pub nonce_prefix: NoncePrefix,
+    /// Submits may carry the timestamp the miner rolled the job to after the
+    /// nonce, so a job lasts without a new notify
+    pub timestamp_rolling: bool,
+}
+
+impl Dialect {
+    pub fn new(preset: Preset) -> Self {
+        match preset {
+            Preset::KaspaMinerStrict => Dialect {
+                strict: true,
+                ..Dialect::new(Preset::KaspaMiner)
+            },
+            Preset::KaspaMiner => Dialect {
+                subscribe_response: SubscribeResponse::Bool,
+                decimal_nonce: false,
+                extranonce_method: ExtranonceMethod::SetExtranonce,
+                extranonce_unsolicited: true,
+                notify_format: NotifyFormat::Words,
+                jsonrpc2: false,
+                difficulty_scale: 1.0,
+                difficulty_first: false,
+                resend_difficulty: false,
+                short_nonce: false,
+                strict: false,
+                mining_login: false,
+                little_endian_nonce: false,
+                nonce_prefix: NoncePrefix::Strict,
+                timestamp_rolling: false,
+            },
+            Preset::Stratum => Dialect {
+                subscribe_response: SubscribeResponse::Standard,
+                decimal_nonce: false,
+                // The subscribe response already carries the extranonce
+                extranonce_method: ExtranonceMethod::MiningSetExtranonce,
+                extranonce_unsolicited: false,
+                notify_format: NotifyFormat::Words,
+                jsonrpc2: false,
+                difficulty_scale: 1.0,
+                difficulty_first: false,
+                resend_difficulty: false,
+                short_nonce: false,
+                strict: false,
+                mining_login: false,
+                little_endian_nonce: false,
+                nonce_prefix: NoncePrefix::Strict,
+                timestamp_rolling: false,
+            },
+            Preset::LolMiner => Dialect {
+                subscribe_response: SubscribeResponse::EthereumStratum,
+                decimal_nonce: false,
+                extranonce_method: ExtranonceMethod::SetExtranonce,
+                extranonce_unsolicited: true,
+                notify_format: NotifyFormat::Words,
+                jsonrpc2: false,
+                // lolMiner takes difficulty 1 as the bitcoin target
+                // 0xffff << 208 instead of 1 << 224
+                difficulty_scale: 65535.0 / 65536.0,
+                difficulty_first: false,
+                resend_difficulty: false,
+                short_nonce: false,
+                strict: false,
+                mining_login: false,
+                little_endian_nonce: false,
+                nonce_prefix: NoncePrefix::Strict,
+                timestamp_rolling: false,
+            },
+            Preset::GMiner => Dialect {
+                // Jobs before the first difficulty are dropped
+                difficulty_first: true,
+                ..Dialect::new(Preset::Stratum)
+            },
+            Preset::SrbMiner => Dialect {
+                // Only the nonce bytes left to the miner are submitted
+                short_nonce: true,
+                ..Dialect::new(Preset::Stratum)
+            },
+            Preset::Goldshell => Dialect {
+                extranonce_unsolicited: true,
+                notify_format: NotifyFormat::Header,
+                mining_login: true,
+                little_endian_nonce: true,
+                ..Dialect::new(Preset::Stratum)
+            },
+        }
+    }
+
+    fn with(mut self, overrides: &Overrides) -> Self {
+        self.decimal_nonce |= overrides.decimal_nonce;
+        self.jsonrpc2 |= overrides.jsonrpc2;
+        self.resend_difficulty |= overrides.resend_difficulty;
+        self.timestamp_rolling |= overrides.timestamp_rolling;
+        if let Some(method) = overrides.extranonce_method {
+            self.extranonce_method = method;
+        }
+        if let Some(format) = overrides.notify_format {
+            self.notify_format = format;
+        }
+        if let Some(unsolicited) = overrides.extranonce_unsolicited {
+            self.extranonce_unsolicited = unsolicited;
+        }
+        if let Some(policy) = overrides.nonce_prefix {
+            self.nonce_prefix = policy;
+        }
+        self
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self::new(Preset::KaspaMiner)
+    }
+}
+
+/// Settings given by the operator, applied over every preset
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub decimal_nonce: bool,
+    pub jsonrpc2: bool,
+    pub resend_difficulty: bool,
+    pub timestamp_rolling: bool,
+    pub extranonce_method: Option<ExtranonceMethod>,
+    pub extranonce_unsolicited: Option<bool>,
+    pub notify_format: Option<NotifyFormat>,
+    pub nonce_prefix: Option<NoncePrefix>,
+}
+
+/// How the dialect of a connection is chosen
+#[derive(Clone, Debug, Default)]
+pub struct DialectConfig {
+    /// Spoken to every miner, otherwise detected from the agent in
+    /// `mining.subscribe`
+    pub preset: Option<Preset>,
+    pub overrides: Overrides,
+}
+
+impl DialectConfig {
+    /// Dialect until the miner's agent is known
+    pub fn initial(&self) -> Dialect {
+        Dialect::new(self.preset.unwrap_or(Preset::KaspaMiner)).with(&self.overrides)
+    }
+
+    /// Dialect of a miner announcing `agent`, `None` to keep the initial one
+    pub fn detect(&self, agent: &str) -> Option<Dialect> {
+        match self.preset {
+            Some(_) => None,
+            None => Preset::detect(agent).map(|p| Dialect::new(p).with(&self.overrides)),
+        }
+    }
+}
+
+/// Least time between two jobs sent to one miner, for firmware choking on
+/// frequent `mining.notify`. Jobs arriving sooner are held back and only the
+/// latest is sent once the interval passed.
+#[derive(Clone, Debug, Default)]
+pub struct NotifyLimits {
+    pub ports: Vec<(u16, Duration)>,
+    /// By lowercase agent prefix of `mining.subscribe`, taking precedence
+    /// over the port's
+    pub agents: Vec<(String, Duration)>,
+}
+
+impl NotifyLimits {
+    pub fn for_port(&self, port: u16) -> Option<Duration> {
+        self.ports.iter().find(|(p, _)| *p == port).map(|(_, i)| *i)
+    }
+
+    pub fn for_agent(&self, agent: &str) -> Option<Duration> {
+        let agent = agent.trim().to_ascii_lowercase();
+        self.agents
+            .iter()
+            .find(|(prefix, _)| agent.starts_with(prefix.as_str()))
+            .map(|(_, i)| *i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DialectConfig, NotifyFormat, NotifyLimits, Overrides, Preset};
+    use std::time::Duration;
+
+    #[test]
+    fn detects_agents() {
+        assert_eq!(Preset::detect("lolMiner 1.88"), Some(Preset::LolMiner));
+        assert_eq!(Preset::detect(" LOLMINER/1.76"), Some(Preset::LolMiner));
+        assert_eq!(Preset::detect("GMiner/3.44"), Some(Preset::GMiner));
+        assert_eq!(
+            Preset::detect("SRBMiner-MULTI/2.4.6"),
+            Some(Preset::SrbMiner)
+        );
+        assert_eq!(
+            Preset::detect("kaspa-miner/0.2.1"),
+            Some(Preset::KaspaMinerStrict)
+        );
+        assert_eq!(Preset::detect("BzMiner/v17.0.0"), None);
+
+        let config = DialectConfig {
+            preset: None,
+            overrides: Overrides {
+                notify_format: Some(NotifyFormat::Hex),
+                ..Default::default()
+            },
+        };
+        let dialect = config.detect("lolMiner 1.88").unwrap();
+        assert_eq!(dialect.notify_format, NotifyFormat::Hex);
+        let fixed = DialectConfig {
+            preset: Some(Preset::KaspaMiner),
+            ..config
+        };
+        assert!(fixed.detect("lolMiner 1.88").is_none());
+    }
+
+    #[test]
+    fn notify_limits() {
+        let second = Duration::from_secs(1);
+        let limits = NotifyLimits {
+            ports: vec![(5556, second)],
+            agents: vec![("goldshell".into(), second * 2)],
+        };
+        assert_eq!(limits.for_port(5556), Some(second));
+        assert_eq!(limits.for_port(5555), None);
+        assert_eq!(limits.for_agent("Goldshell/KA-Box"), Some(second * 2));
+        assert_eq!(limits.for_agent("lolMiner 1.88"), None);
+    }
+}
@@ -1,145 +1,753 @@
-use super::{Id, Response};
-use crate::kaspad::{KaspadHandle, RpcBlock};
+use super::writer::{self, RawParams};
+use super::{to_stratum_difficulty, Id, NotifyFormat, Response};
+use crate::kaspad::{BlockOrigin, BlockReward, Header, KaspadHandle, RpcBlock};
+use crate::pow;
 use crate::U256;
 use anyhow::Result;
-use log::debug;
-use serde_json::json;
-use std::collections::VecDeque;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::task;
+use tracing::{debug, info};
+
+/// How far ahead of its clock kaspad accepts block timestamps, in ms
+const FUTURE_TOLERANCE: u64 = 132_000;
+
+/// Jobs kept for late shares, at their id modulo the slots
+const SLOTS: usize = 256;
+/// Blocks of a connection waiting for kaspad's response, the miner is
+/// answered right away for further blocks
+pub const MAX_PENDING_SUBMITS: usize = 8;
+/// Nonces kept per job for spotting duplicates, further shares for the job
+/// are stale
+const MAX_NONCES: usize = 65_536;
+
+/// How long shares for replaced jobs are still accepted
+#[derive(Clone, Copy, Debug)]
+pub enum Expiry {
+    /// The previous job for this long after it was replaced, older jobs are
+    /// stale
+    Grace(Duration),
+    /// Jobs whose template is at most this many DAA scores behind the
+    /// current one's, for networks producing several blocks a second
+    DaaScore(u64),
+}
 
 #[derive(Clone)]
 pub struct Jobs {
     inner: Arc<RwLock<JobsInner>>,
-    pending: Arc<Mutex<VecDeque<Pending>>>,
+    /// Found blocks are not sent to kaspad
+    dry_run: Arc<AtomicBool>,
+    /// DAA score of the newest job, templates arriving out of order with a
+    /// lower one are dropped
+    daa_score: Arc<AtomicU64>,
 }
 
 impl Jobs {
-    pub fn new(handle: KaspadHandle) -> Self {
+    /// With `wide_ids` job ids take two bytes instead of one, so ids of
+    /// late shares aren't already taken again when jobs change many times a
+    /// second
+    pub fn new(handle: KaspadHandle, expiry: Expiry, wide_ids: bool) -> Self {
         Self {
             inner: Arc::new(RwLock::new(JobsInner {
                 next: 0,
-                jobs: Vec::with_capacity(256),
+                created: 0,
+                jobs: Vec::with_capacity(SLOTS),
                 handle,
+                replaced: None,
+                expiry,
+                wide_ids,
             })),
-            pending: Arc::new(Mutex::new(VecDeque::with_capacity(64))),
+            dry_run: Default::default(),
+            daa_score: Default::default(),
         }
     }
 
-    pub async fn insert(&self, template: RpcBlock) -> Option<JobParams> {
-        let header = template.header.as_ref()?;
-        let pre_pow = header.pre_pow().ok()?;
-        let difficulty = header.difficulty();
-        let timestamp = header.timestamp as u64;
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::Relaxed)
+    }
 
-        let mut w = self.inner.write().await;
-        let len = w.jobs.len();
-        let id = if len < 256 {
-            w.jobs.push(template);
-            len as u8
-        } else {
-            w.next
-        };
-        w.next = id.wrapping_add(1);
+    pub fn set_dry_run(&self, enabled: bool) {
+        self.dry_run.store(enabled, Ordering::Relaxed);
+    }
 
-        Some(JobParams {
-            id,
-            pre_pow,
+    /// Take the next template whatever its DAA score, as another node may
+    /// be behind the last one
+    pub fn forget_daa_score(&self) {
+        self.daa_score.store(0, Ordering::Relaxed);
+    }
+
+    pub async fn insert(&self, template: RpcBlock) -> Option<JobParams> {
+        let rpc_header = template.header.as_ref()?;
+        let header = Header::parse(rpc_header).ok()?;
+        let pre_pow = header.pre_pow();
+        let difficulty = rpc_header.difficulty();
+        let timestamp = rpc_header.timestamp as u64;
+        let info = TemplateInfo {
+            daa_score: rpc_header.daa_score,
+            blue_score: rpc_header.blue_score,
+            bits: rpc_header.bits,
+            transactions: template.transactions.len(),
+            reward: template.reward().unwrap_or_default(),
+        };
+        let mut job = Job {
+            id: 0,
+            daa_score: info.daa_score,
+            header,
+            target: pow::u256_from_compact_target(rpc_header.bits),
             difficulty,
+            pow: pow::State::new(pre_pow, timestamp),
             timestamp,
-        })
+            nonces: Default::default(),
+            block: template,
+        };
+
+        let mut w = self.inner.write().await;
+        if info.daa_score < self.daa_score.load(Ordering::Relaxed) {
+            debug!(
+                "Dropping template with DAA score {} older than the job",
+                info.daa_score
+            );
+            return None;
+        }
+        self.daa_score.store(info.daa_score, Ordering::Relaxed);
+        w.created += 1;
+        let created = w.created;
+        let id = w.next;
+        job.id = id;
+        let slot = id as usize % SLOTS;
+        match w.jobs.get_mut(slot) {
+            Some(old) => *old = Arc::new(job),
+            None => w.jobs.push(Arc::new(job)),
+        }
+        w.next = w.after(id);
+        w.replaced = Some(Instant::now());
+        let id = w.format_id(id);
+
+        JobParams::new(id, created, pre_pow, difficulty, timestamp, info).ok()
     }
 
+    /// Submit a nonce for a job. The PoW is checked locally on the blocking
+    /// pool and only nonces meeting the block target are sent to kaspad, the
+    /// rest are shares or rejected against the share target. Blocks are
+    /// answered once kaspad responds unless `pending` of the connection's
+    /// await it already.
     pub async fn submit(
         &self,
-        rpc_id: Id,
-        job_id: u8,
-        nonce: u64,
+        share: Share<'_>,
+        pending: usize,
         send: mpsc::UnboundedSender<PendingResult>,
-    ) -> bool {
-        let (mut block, handle) = {
+    ) -> SubmitResult {
+        let Share {
+            id: rpc_id,
+            job_id,
+            nonce,
+            timestamp,
+            target: share_target,
+            worker,
+        } = share;
+        let (job, handle, job_name) = {
             let r = self.inner.read().await;
-            let block = match r.jobs.get(job_id as usize) {
-                Some(b) => b.clone(),
-                None => return false,
-            };
-            (block, r.handle.clone())
+            if !r.is_active(job_id, Instant::now()) {
+                return SubmitResult::Stale;
+            }
+            match r.get(job_id) {
+                Some(j) => (j.clone(), r.handle.clone(), r.format_id(job_id)),
+                None => return SubmitResult::Stale,
+            }
         };
-        if let Some(header) = &mut block.header {
-            {
-                // Keep the lock on the pending jobs while we submit the block
-                // to guarantee that the ordering matches up
-                let mut pending = self.pending.lock().await;
-                pending.push_back(Pending { id: rpc_id, send });
-
-                header.nonce = nonce;
-                handle.submit_block(block);
+        let timestamp = timestamp.unwrap_or(job.timestamp);
+        if !valid_timestamp(job.timestamp, timestamp, unix_millis()) {
+            return SubmitResult::BadTimestamp;
+        }
+        // Kaspad refuses a block sent twice, shares can't be told apart
+        // from duplicates once the job's nonces are full
+        let recorded = {
+            let mut nonces = job.nonces.lock().unwrap();
+            match nonces.len() < MAX_NONCES {
+                true => Some(nonces.insert((timestamp, nonce))),
+                false => None,
             }
-
-            true
-        } else {
-            false
+        };
+        if recorded == Some(false) {
+            return SubmitResult::Duplicate;
         }
-    }
-
-    pub async fn resolve_pending(&self, error: Option<Box<str>>) {
-        if let Some(pending) = self.pending.lock().await.pop_front() {
-            pending.resolve(error);
+        // kHeavyHash would hold up every connection on this worker thread
+        let pow = {
+            let job = job.clone();
+            task::spawn_blocking(move || job.pow.calculate_pow_at(timestamp, nonce)).await
+        };
+        let pow = match pow {
+            Ok(p) => p,
+            Err(_) => return SubmitResult::Invalid,
+        };
+        debug!(
+            "Job {job_name} nonce {nonce:016x} from {worker}: pow {pow:?}, share target {share_target:?}, block target {:?}",
+            job.target
+        );
+        if pow > job.target {
+            let difficulty = pow::difficulty(pow);
+            return if pow > share_target {
+                SubmitResult::LowDifficulty(difficulty)
+            } else if recorded.is_none() {
+                SubmitResult::Stale
+            } else {
+                SubmitResult::Share(difficulty)
+            };
+        }
+        // Still sent to kaspad, only the miner's answer doesn't wait
+        let answered = pending >= MAX_PENDING_SUBMITS;
+        let (mut block, difficulty) = (job.block.clone(), job.difficulty);
+        let reward = block.reward().unwrap_or_default();
+        let header = match &mut block.header {
+            Some(h) => h,
+            None => return SubmitResult::Invalid,
+        };
+        header.nonce = nonce;
+        header.timestamp = timestamp as i64;
+        let submitted = SubmittedBlock {
+            hash: hex::encode(job.header.hash_with(timestamp as i64, nonce).as_bytes()),
+            daa_score: header.daa_score,
+            difficulty,
+            worker: worker.into(),
+            reward: reward.subsidy,
+            fees: reward.fees,
+        };
+        let result = if self.dry_run() {
+            info!("Dry run, not submitting block {}", submitted.hash);
+            let (reply, result) = oneshot::channel();
+            let _ = reply.send(Some("Dry run, block not submitted".into()));
+            result
         } else {
-            debug!("Resolve: nothing is pending");
+            let origin = BlockOrigin {
+                hash: submitted.hash.clone(),
+                worker: submitted.worker.clone(),
+                job_id: Some(job_name),
+            };
+            handle.submit_block(block, origin)
+        };
+        let sent = Instant::now();
+        tokio::spawn(async move {
+            let error = result
+                .await
+                .unwrap_or_else(|_| Some("Kaspad connection lost".into()));
+            let _ = send.send(PendingResult {
+                id: rpc_id,
+                block: submitted,
+                error,
+                round_trip: sent.elapsed(),
+                answered,
+            });
+        });
+        match answered {
+            true => SubmitResult::Answered,
+            false => SubmitResult::Block,
         }
     }
 }
 
+/// A nonce a miner submitted for a job
+pub struct Share<'a> {
+    /// Of the miner's request
+    pub id: Id,
+    pub job_id: u16,
+    pub nonce: u64,
+    /// Rolled by the miner, the job's if `None`
+    pub timestamp: Option<u64>,
+    /// Of the difficulty assigned to the miner
+    pub target: U256,
+    pub worker: &'a str,
+}
+
 struct JobsInner {
-    next: u8,
+    /// Id of the next job
+    next: u16,
+    /// Jobs created so far
+    created: u64,
     handle: KaspadHandle,
-    jobs: Vec<RpcBlock>,
+    /// The latest jobs at their id modulo [`SLOTS`]
+    jobs: Vec<Arc<Job>>,
+    /// When the previous job was replaced by the current one
+    replaced: Option<Instant>,
+    expiry: Expiry,
+    wide_ids: bool,
+}
+
+impl JobsInner {
+    /// Id as sent to miners
+    fn format_id(&self, id: u16) -> String {
+        match self.wide_ids {
+            true => format!("{id:04x}"),
+            false => format!("{id:02x}"),
+        }
+    }
+
+    fn get(&self, id: u16) -> Option<&Arc<Job>> {
+        self.jobs.get(id as usize % SLOTS).filter(|j| j.id == id)
+    }
+
+    /// Id of the job following `id`
+    fn after(&self, id: u16) -> u16 {
+        match self.wide_ids {
+            true => id.wrapping_add(1),
+            false => id.wrapping_add(1) & 0xff,
+        }
+    }
+
+    /// Whether shares for the job are accepted, the current job always and
+    /// older ones until they expire
+    fn is_active(&self, id: u16, now: Instant) -> bool {
+        let (job, current) = match (self.get(id), self.current()) {
+            (Some(job), Some(current)) => (job, current),
+            _ => return false,
+        };
+        if job.id == current.id {
+            return true;
+        }
+        match self.expiry {
+            Expiry::Grace(grace) => {
+                self.after(job.id) == current.id
+                    && self
+                        .replaced
+                        .is_some_and(|t| now.saturating_duration_since(t) <= grace)
+            }
+            Expiry::DaaScore(depth) => job.daa_score + depth >= current.daa_score,
+        }
+    }
+
+    fn current(&self) -> Option<&Arc<Job>> {
+        let id = match self.wide_ids {
+            true => self.next.wrapping_sub(1),
+            false => self.next.wrapping_sub(1) & 0xff,
+        };
+        self.get(id)
+    }
+}
+
+/// Whether kaspad takes a block with the timestamp rolled from the
+/// template's: not before it, which already follows the past median time,
+/// nor beyond the tolerance ahead of the clock
+fn valid_timestamp(template: u64, rolled: u64, now: u64) -> bool {
+    rolled >= template && rolled <= now.max(template) + FUTURE_TOLERANCE
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+struct Job {
+    id: u16,
+    daa_score: u64,
+    block: RpcBlock,
+    /// The block header decoded for hashing
+    header: Header,
+    pow: pow::State,
+    /// Of the template, in ms
+    timestamp: u64,
+    target: U256,
+    difficulty: u64,
+    /// Timestamps and nonces submitted so far
+    nonces: std::sync::Mutex<HashSet<(u64, u64)>>,
+}
+
+pub enum SubmitResult {
+    /// Sent to kaspad, the response arrives through the pending channel
+    Block,
+    /// Meets the share target but not the block target, with the
+    /// difficulty of its hash
+    Share(u64),
+    LowDifficulty(u64),
+    /// The job is unknown or was replaced, beyond the grace window for the
+    /// previous job
+    Stale,
+    Duplicate,
+    /// The rolled timestamp is outside what kaspad accepts
+    BadTimestamp,
+    /// A block sent to kaspad while [`MAX_PENDING_SUBMITS`] of the
+    /// connection's await its response, to be answered right away. Kaspad's
+    /// response still arrives through the pending channel.
+    Answered,
+    Invalid,
+}
+
+/// Template metadata of a job
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TemplateInfo {
+    pub daa_score: u64,
+    pub blue_score: u64,
+    /// Compact network target
+    pub bits: u32,
+    pub transactions: usize,
+    pub reward: BlockReward,
 }
 
 pub struct JobParams {
-    id: u8,
+    /// In hex as sent to miners
+    id: String,
+    /// Position in the order jobs were created
+    seq: u64,
     pre_pow: U256,
     difficulty: u64,
     timestamp: u64,
+    template: TemplateInfo,
+    /// Notify params serialized once per format when the job is created,
+    /// indexed by [`NotifyFormat`]
+    notify: [RawParams; 4],
 }
 
 impl JobParams {
+    fn new(
+        id: String,
+        seq: u64,
+        pre_pow: U256,
+        difficulty: u64,
+        timestamp: u64,
+        template: TemplateInfo,
+    ) -> Result<Self> {
+        let notify = |format| writer::to_raw(&notify_value(&id, pre_pow, timestamp, format));
+        let notify = [
+            notify(NotifyFormat::Words)?,
+            notify(NotifyFormat::Hex)?,
+            notify(NotifyFormat::Header)?,
+            notify(NotifyFormat::PrePow)?,
+        ];
+        Ok(JobParams {
+            id,
+            seq,
+            pre_pow,
+            difficulty,
+            timestamp,
+            template,
+            notify,
+        })
+    }
+
     pub fn difficulty(&self) -> u64 {
         self.difficulty
     }
 
-    pub fn to_value(&self) -> serde_json::Value {
-        json!([
-            hex::encode([self.id]),
-            self.pre_pow.as_slice(),
-            self.timestamp
-        ])
+    /// Hand the job to the connections unless a newer one got there first,
+    /// which happens when broadcasts overtake each other between creating
+    /// their job and sending it
+    pub fn publish(self, send: &watch::Sender<Option<JobParams>>) -> bool {
+        send.send_if_modified(|current| match current {
+            Some(newer) if newer.seq > self.seq => {
+                debug!("Dropping job {} overtaken by a newer one", self.id);
+                false
+            }
+            _ => {
+                *current = Some(self);
+                true
+            }
+        })
     }
-}
 
-pub struct Pending {
-    id: Id,
-    send: mpsc::UnboundedSender<PendingResult>,
+    pub fn notify(&self, format: NotifyFormat) -> RawParams {
+        self.notify[format as usize].clone()
+    }
+
+    pub fn to_value(&self, format: NotifyFormat) -> serde_json::Value {
+        notify_value(&self.id, self.pre_pow, self.timestamp, format)
+    }
+
+    /// The job as handed to miners along with its template, for monitoring
+    pub fn describe(&self, format: NotifyFormat) -> serde_json::Value {
+        json!({
+            "job_id": self.id,
+            "notify": self.to_value(format),
+            "network_difficulty": to_stratum_difficulty(self.difficulty),
+            "timestamp": self.timestamp,
+            "template": self.template,
+        })
+    }
 }
 
-impl Pending {
-    pub fn resolve(self, error: Option<Box<str>>) {
-        let result = PendingResult { id: self.id, error };
-        let _ = self.send.send(result);
+fn notify_value(
+    id: &str,
+    pre_pow: U256,
+    timestamp: u64,
+    format: NotifyFormat,
+) -> serde_json::Value {
+    let pre_pow_bytes = || -> Vec<u8> {
+        pre_pow
+            .as_slice()
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect()
+    };
+    match format {
+        NotifyFormat::Words => json!([id, pre_pow.as_slice(), timestamp]),
+        NotifyFormat::Hex => json!([id, hex::encode(pre_pow_bytes()), timestamp]),
+        NotifyFormat::Header => {
+            let mut header = pre_pow_bytes();
+            header.extend(timestamp.to_le_bytes());
+            json!([id, hex::encode(header)])
+        }
+        NotifyFormat::PrePow => json!([id, hex::encode(pre_pow_bytes())]),
     }
 }
 
+/// `mining.notify` params of another pool in `format`, whichever format
+/// the pool sent. `None` if the pre_pow and timestamp can't be read from
+/// them, as from pools leaving out the timestamp.
+pub fn convert_notify(params: &Value, format: NotifyFormat) -> Option<Value> {
+    let bytes = |text: &str, len: usize| hex::decode(text).ok().filter(|b| b.len() == len);
+    let pre_pow = |bytes: &[u8]| {
+        let mut words = [0; 4];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().ok()?);
+        }
+        Some(U256::from(words))
+    };
+    let (pre_pow, timestamp) = match params.as_array()?.as_slice() {
+        [_, Value::Array(words), timestamp] => {
+            let words: Vec<_> = words.iter().map(Value::as_u64).collect::<Option<_>>()?;
+            (
+                U256::from(<[u64; 4]>::try_from(words).ok()?),
+                timestamp.as_u64()?,
+            )
+        }
+        [_, Value::String(hex), timestamp] => (pre_pow(&bytes(hex, 32)?)?, timestamp.as_u64()?),
+        [_, Value::String(header)] => {
+            let header = bytes(header, 40)?;
+            let timestamp = u64::from_le_bytes(header[32..].try_into().ok()?);
+            (pre_pow(&header[..32])?, timestamp)
+        }
+        _ => return None,
+    };
+    // The pool's job id as it sent it, shares quote it back
+    let mut notify = notify_value("", pre_pow, timestamp, format);
+    notify[0] = params[0].clone();
+    Some(notify)
+}
+
+/// A block sent to kaspad
+#[derive(Clone, Debug)]
+pub struct SubmittedBlock {
+    pub hash: String,
+    pub daa_score: u64,
+    /// Network difficulty
+    pub difficulty: u64,
+    pub worker: String,
+    /// Block subsidy in sompi, 0 if the coinbase couldn't be read
+    pub reward: u64,
+    /// Estimated fees in sompi, see [`BlockReward::fees`]
+    pub fees: u64,
+}
+
+/// Kaspad's response to a block, routed back to the submitting connection
 pub struct PendingResult {
     id: Id,
+    block: SubmittedBlock,
     error: Option<Box<str>>,
+    /// Until kaspad's response
+    round_trip: Duration,
+    /// The miner was answered on submitting, see [`SubmitResult::Answered`]
+    answered: bool,
 }
 
 impl PendingResult {
-    pub fn into_response(self) -> Result<Response> {
+    /// The block if kaspad accepted it
+    pub fn accepted(&self) -> Option<&SubmittedBlock> {
         match self.error {
-            Some(e) => Response::err(self.id, 20, e),
-            None => Response::ok(self.id, true),
+            Some(_) => None,
+            None => Some(&self.block),
         }
     }
+
+    /// Kaspad's error if it refused the block
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn round_trip(&self) -> Duration {
+        self.round_trip
+    }
+
+    /// The answer to the miner, if it wasn't answered on submitting
+    pub fn into_response(self) -> Result<Option<Response>> {
+        if self.answered {
+            return Ok(None);
+        }
+        let res = match self.error {
+            Some(e) => Response::err(self.id, 20, e)?,
+            None => Response::ok(self.id, true)?,
+        };
+        Ok(Some(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_notify, valid_timestamp, Expiry, JobParams, Jobs, FUTURE_TOLERANCE};
+    use crate::kaspad::{KaspadHandle, RpcBlock, RpcBlockHeader};
+    use crate::stratum::NotifyFormat;
+    use crate::U256;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+    use tokio::sync::watch;
+
+    fn template(daa_score: u64) -> RpcBlock {
+        let hash = || "00".repeat(32);
+        RpcBlock {
+            header: Some(RpcBlockHeader {
+                hash_merkle_root: hash(),
+                accepted_id_merkle_root: hash(),
+                utxo_commitment: hash(),
+                pruning_point: hash(),
+                bits: 0x1b0404cb,
+                daa_score,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn notify_formats() {
+        let pre_pow = U256::from([1, 2, 3, 0x0102030405060708]);
+        let job = JobParams::new("2a".into(), 1, pre_pow, 1, 0x1122, Default::default()).unwrap();
+        let pre_pow = concat!(
+            "0100000000000000",
+            "0200000000000000",
+            "0300000000000000",
+            "0807060504030201"
+        );
+        assert_eq!(
+            job.to_value(NotifyFormat::Words),
+            json!(["2a", [1, 2, 3, 0x0102030405060708u64], 0x1122])
+        );
+        assert_eq!(
+            job.to_value(NotifyFormat::Hex),
+            json!(["2a", pre_pow, 0x1122])
+        );
+        assert_eq!(
+            job.to_value(NotifyFormat::Header),
+            json!(["2a", format!("{pre_pow}2211000000000000")])
+        );
+        assert_eq!(job.to_value(NotifyFormat::PrePow), json!(["2a", pre_pow]));
+        for format in [
+            NotifyFormat::Words,
+            NotifyFormat::Hex,
+            NotifyFormat::Header,
+            NotifyFormat::PrePow,
+        ] {
+            let raw: serde_json::Value = serde_json::from_str(job.notify(format).get()).unwrap();
+            assert_eq!(raw, job.to_value(format));
+        }
+        let described = job.describe(NotifyFormat::Hex);
+        assert_eq!(described["job_id"], "2a");
+        assert_eq!(described["notify"], job.to_value(NotifyFormat::Hex));
+    }
+
+    #[test]
+    fn converts_pool_notify() {
+        let pre_pow = U256::from([1, 2, 3, 0x0102030405060708]);
+        let job = JobParams::new("2a".into(), 1, pre_pow, 1, 0x1122, Default::default()).unwrap();
+        let formats = [NotifyFormat::Words, NotifyFormat::Hex, NotifyFormat::Header];
+        for from in formats {
+            for to in formats {
+                let converted = convert_notify(&job.to_value(from), to);
+                assert_eq!(converted, Some(job.to_value(to)), "{from:?} to {to:?}");
+            }
+        }
+        let numeric_id = json!([7, [1, 2, 3, 4], 5]);
+        let converted = convert_notify(&numeric_id, NotifyFormat::Hex).unwrap();
+        assert_eq!(converted[0], 7);
+        // Without a timestamp there's nothing to convert from
+        assert_eq!(
+            convert_notify(&job.to_value(NotifyFormat::PrePow), NotifyFormat::Words),
+            None
+        );
+        assert_eq!(
+            convert_notify(&json!(["2a", "zz", 1]), NotifyFormat::Words),
+            None
+        );
+    }
+
+    /// Jobs for templates of the DAA scores, returning the id of the last
+    async fn insert_all(expiry: Expiry, wide_ids: bool, scores: &[u64]) -> (Jobs, String) {
+        let jobs = Jobs::new(KaspadHandle::new().0, expiry, wide_ids);
+        let mut id = String::new();
+        for &score in scores {
+            id = jobs.insert(template(score)).await.unwrap().id;
+        }
+        (jobs, id)
+    }
+
+    #[tokio::test]
+    async fn previous_job_grace() {
+        // The ids wrap around to 0
+        let (jobs, id) = insert_all(Expiry::Grace(Duration::from_secs(2)), false, &[1; 257]).await;
+        assert_eq!(id, "00");
+        let mut jobs = jobs.inner.write().await;
+        let replaced = Instant::now();
+        jobs.replaced = Some(replaced);
+        assert!(jobs.is_active(0, replaced + Duration::from_secs(60)));
+        assert!(jobs.is_active(255, replaced + Duration::from_secs(2)));
+        assert!(!jobs.is_active(255, replaced + Duration::from_secs(3)));
+        assert!(!jobs.is_active(254, replaced));
+        assert!(!jobs.is_active(256, replaced));
+    }
+
+    #[tokio::test]
+    async fn wide_ids_and_daa_expiry() {
+        let (jobs, id) = insert_all(Expiry::Grace(Duration::ZERO), true, &[1; 257]).await;
+        assert_eq!(id, "0100");
+        let jobs = jobs.inner.read().await;
+        assert!(jobs.is_active(0x100, Instant::now()));
+        // Its slot went to the new job
+        assert!(!jobs.is_active(0, Instant::now()));
+
+        let scores: Vec<_> = (100..=110).collect();
+        let (jobs, id) = insert_all(Expiry::DaaScore(5), false, &scores).await;
+        assert_eq!(id, "0a");
+        let jobs = jobs.inner.read().await;
+        assert!(jobs.is_active(10, Instant::now()));
+        assert!(jobs.is_active(5, Instant::now()));
+        assert!(!jobs.is_active(4, Instant::now()));
+        assert!(!jobs.is_active(11, Instant::now()));
+    }
+
+    #[test]
+    fn rolled_timestamps() {
+        let (template, now) = (1_700_000_000_000, 1_700_000_005_000);
+        assert!(valid_timestamp(template, template, now));
+        assert!(valid_timestamp(template, now + FUTURE_TOLERANCE, now));
+        assert!(!valid_timestamp(template, template - 1, now));
+        assert!(!valid_timestamp(template, now + FUTURE_TOLERANCE + 1, now));
+        // A template from a node clock ahead of ours is still mined
+        assert!(valid_timestamp(now + 1000, now + 1000, now));
+    }
+
+    #[tokio::test]
+    async fn newest_job_wins() {
+        let jobs = Jobs::new(KaspadHandle::new().0, Expiry::Grace(Duration::ZERO), false);
+        let older = jobs.insert(template(100)).await.unwrap();
+        let newer = jobs.insert(template(101)).await.unwrap();
+        // A template answering an earlier request arrives last
+        assert!(jobs.insert(template(100)).await.is_none());
+        let refreshed = jobs.insert(template(101)).await.unwrap();
+
+        // Broadcasts publishing out of order leave the newest job in place
+        let (send, mut recv) = watch::channel(None);
+        assert!(newer.publish(&send));
+        assert!(recv.has_changed().unwrap());
+        recv.borrow_and_update();
+        assert!(!older.publish(&send));
+        assert!(!recv.has_changed().unwrap());
+        assert_eq!(recv.borrow().as_ref().unwrap().id, "01");
+        assert!(refreshed.publish(&send));
+        assert_eq!(recv.borrow_and_update().as_ref().unwrap().id, "02");
+
+        // Another node may be behind after reconnecting
+        jobs.forget_daa_score();
+        assert!(jobs.insert(template(99)).await.is_some());
+    }
 }
@@ -0,0 +1,265 @@
+use super::tls::{Tls, TlsConfig};
+use super::{from_stratum_difficulty, to_stratum_difficulty, Config, Preset, VarDiffConfig};
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Extranonce bytes of connections unless the listener sets them
+pub const DEFAULT_EXTRANONCE_SIZE: u8 = 2;
+/// Instances splitting the one byte extranonces, each needs a prefix of its
+/// own besides 0, which is never handed out
+const MAX_ONE_BYTE_INSTANCES: u16 = 128;
+
+/// Settings of one port on the stratum address, as given in the listeners
+/// file. Unset fields follow the process wide options.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub port: u16,
+    /// Spoken to every miner instead of detecting it from the agent
+    #[serde(default, deserialize_with = "preset")]
+    pub dialect: Option<Preset>,
+    /// Whether difficulties follow the hashrate, otherwise shares are
+    /// checked against the network difficulty
+    pub vardiff: Option<bool>,
+    /// In stratum units, like the difficulty fields below
+    pub start_difficulty: Option<f64>,
+    pub min_difficulty: Option<f64>,
+    pub max_difficulty: Option<f64>,
+    /// Seconds between shares of a miner with vardiff
+    pub share_time: Option<f64>,
+    /// Speak stratum over TLS, with the process wide certificate unless
+    /// `tls_cert` and `tls_key` are given
+    #[serde(default)]
+    pub tls: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Credit blocks found on this port to their finder alone
+    #[serde(default)]
+    pub solo: bool,
+    /// Bytes of the nonce fixed per connection, from 1 for proxies like
+    /// NiceHash splitting the rest between their rigs, to 3
+    pub extranonce_size: Option<u8>,
+}
+
+impl ListenerConfig {
+    /// Read a JSON array of listeners
+    pub fn load(path: &Path) -> Result<Vec<Self>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid {}", path.display()))
+    }
+}
+
+fn preset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Preset>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    clap::ArgEnum::from_str(&name, true)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// A port with its settings resolved against the process wide ones
+#[derive(Clone)]
+pub(super) struct Listener {
+    pub port: u16,
+    pub preset: Option<Preset>,
+    pub solo: bool,
+    pub tls: Option<Tls>,
+    pub vardiff: Option<VarDiffConfig>,
+    pub extranonce_size: u8,
+}
+
+impl Listener {
+    /// A port following the process wide options
+    pub fn new(port: u16, config: &Config) -> Self {
+        Listener {
+            port,
+            preset: None,
+            solo: false,
+            tls: None,
+            vardiff: config.vardiff.clone(),
+            extranonce_size: DEFAULT_EXTRANONCE_SIZE,
+        }
+    }
+
+    /// Every port given a role by the listeners file, the dialect, solo and
+    /// TLS ports. Ports with several roles are listed once.
+    pub fn resolve(config: &Config, tls: Option<&Tls>) -> Result<Vec<Self>> {
+        let tls_ports = config.tls.as_ref().map_or(&[][..], |t| &t.ports[..]);
+        let mut ports: Vec<u16> = config.listeners.iter().map(|l| l.port).collect();
+        ports.extend(config.dialect_ports.iter().map(|&(port, _)| port));
+        ports.extend(&config.solo_ports);
+        ports.extend(tls_ports);
+        let mut seen = vec![];
+        ports.retain(|port| match seen.contains(port) {
+            true => false,
+            false => {
+                seen.push(*port);
+                true
+            }
+        });
+
+        let mut listeners = vec![];
+        for port in ports {
+            let mut profiles = config.listeners.iter().filter(|l| l.port == port);
+            let profile = profiles.next().cloned().unwrap_or_default();
+            if profiles.next().is_some() {
+                bail!("Port {port} is listed twice in the listeners");
+            }
+            let mut listener = Listener::new(port, config);
+            listener.preset = profile.dialect.or_else(|| {
+                let dialect = config.dialect_ports.iter().find(|&&(p, _)| p == port);
+                dialect.map(|&(_, preset)| preset)
+            });
+            listener.solo = profile.solo || config.solo_ports.contains(&port);
+            listener.tls = match (profile.tls_cert, profile.tls_key) {
+                (Some(cert), Some(key)) => Some(Tls::new(TlsConfig {
+                    cert,
+                    key,
+                    ports: vec![port],
+                })?),
+                (None, None) if profile.tls || tls_ports.contains(&port) => match tls {
+                    Some(tls) => Some(tls.clone()),
+                    None => bail!(
+                        "Port {port} speaks TLS without a certificate, give --tls-cert and \
+                         --tls-key or its tls_cert and tls_key"
+                    ),
+                },
+                (None, None) => None,
+                _ => bail!("Port {port} needs both tls_cert and tls_key"),
+            };
+            listener.vardiff = match profile.vardiff.unwrap_or(config.vardiff.is_some()) {
+                true => {
+                    let mut vardiff = config.vardiff.clone().unwrap_or_default();
+                    if let Some(difficulty) = profile.start_difficulty {
+                        vardiff.start_difficulty = from_stratum_difficulty(difficulty);
+                    }
+                    if let Some(difficulty) = profile.min_difficulty {
+                        vardiff.min_difficulty = from_stratum_difficulty(difficulty);
+                    }
+                    if let Some(difficulty) = profile.max_difficulty {
+                        vardiff.max_difficulty = from_stratum_difficulty(difficulty);
+                    }
+                    if let Some(secs) = profile.share_time {
+                        if !(secs > 0.0 && secs.is_finite()) {
+                            bail!("Port {port} needs a positive share time");
+                        }
+                        vardiff.share_time = Duration::from_secs_f64(secs);
+                    }
+                    if vardiff.min_difficulty > vardiff.max_difficulty {
+                        bail!("Port {port} has a minimum difficulty above the maximum");
+                    }
+                    Some(vardiff)
+                }
+                false => None,
+            };
+            listener.extranonce_size = profile.extranonce_size.unwrap_or(DEFAULT_EXTRANONCE_SIZE);
+            if !(1..=3).contains(&listener.extranonce_size) {
+                bail!("Port {port} needs an extranonce size of 1 to 3 bytes");
+            }
+            if listener.extranonce_size == 1 && config.instances > MAX_ONE_BYTE_INSTANCES {
+                bail!(
+                    "Port {port} has too few 1-byte extranonces for {} instances, at most \
+                     {MAX_ONE_BYTE_INSTANCES} can share them",
+                    config.instances
+                );
+            }
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// What the port does, for the startup summary
+    pub fn describe(&self) -> String {
+        let mut parts = vec![match self.preset {
+            Some(preset) => format!("{preset:?} dialect"),
+            None => "dialect by agent".into(),
+        }];
+        parts.push(match &self.vardiff {
+            Some(v) => format!(
+                "vardiff starting at {}, a share every {:?}",
+                to_stratum_difficulty(v.start_difficulty),
+                v.share_time
+            ),
+            None => "network difficulty".into(),
+        });
+        if self.tls.is_some() {
+            parts.push("TLS".into());
+        }
+        if self.solo {
+            parts.push("solo".into());
+        }
+        parts.push(format!("{}-byte extranonce", self.extranonce_size));
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Listener, ListenerConfig};
+    use crate::stratum::{from_stratum_difficulty, Config, Preset, VarDiffConfig};
+
+    #[test]
+    fn merges_port_options() {
+        let profiles: Vec<ListenerConfig> = serde_json::from_str(
+            r#"[
+                {"port": 5555, "dialect": "gminer", "start_difficulty": 8, "extranonce_size": 1},
+                {"port": 6666, "vardiff": false, "solo": true}
+            ]"#,
+        )
+        .unwrap();
+        let config = Config {
+            vardiff: Some(VarDiffConfig::default()),
+            dialect_ports: vec![(6666, Preset::Goldshell), (7777, Preset::LolMiner)],
+            listeners: profiles,
+            ..Default::default()
+        };
+        let listeners = Listener::resolve(&config, None).unwrap();
+        let ports: Vec<_> = listeners.iter().map(|l| l.port).collect();
+        assert_eq!(ports, [5555, 6666, 7777]);
+
+        let gpu = &listeners[0];
+        assert_eq!(gpu.preset, Some(Preset::GMiner));
+        let start = gpu.vardiff.as_ref().unwrap().start_difficulty;
+        assert_eq!(start, from_stratum_difficulty(8.0));
+        assert_eq!(gpu.extranonce_size, 1);
+        assert_eq!(
+            gpu.describe(),
+            "GMiner dialect, vardiff starting at 8, a share every 5s, 1-byte extranonce"
+        );
+        // Flags for the port still apply
+        assert_eq!(listeners[1].preset, Some(Preset::Goldshell));
+        assert!(listeners[1].solo && listeners[1].vardiff.is_none());
+        assert_eq!(
+            listeners[1].describe(),
+            "Goldshell dialect, network difficulty, solo, 2-byte extranonce"
+        );
+        assert_eq!(listeners[2].extranonce_size, 2);
+        assert!(listeners[2].vardiff.is_some());
+
+        for invalid in [
+            r#"[{"port": 5555, "tls": true}]"#,
+            r#"[{"port": 5555, "extranonce_size": 4}]"#,
+            r#"[{"port": 5555, "tls_cert": "cert.pem"}]"#,
+            r#"[{"port": 5555}, {"port": 5555}]"#,
+        ] {
+            let config = Config {
+                listeners: serde_json::from_str(invalid).unwrap(),
+                ..Default::default()
+            };
+            assert!(Listener::resolve(&config, None).is_err(), "{invalid}");
+        }
+        // Partitions of 1-byte extranonces would overlap or be empty
+        let config = |instances| Config {
+            listeners: serde_json::from_str(r#"[{"port": 5555, "extranonce_size": 1}]"#).unwrap(),
+            instances,
+            ..Default::default()
+        };
+        assert!(Listener::resolve(&config(128), None).is_ok());
+        assert!(Listener::resolve(&config(129), None).is_err());
+        assert!(Listener::resolve(&config(300), None).is_err());
+        let unknown = r#"[{"port": 5555, "dialect": "cgminer"}]"#;
+        assert!(serde_json::from_str::<Vec<ListenerConfig>>(unknown).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use super::Dialect;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+pub struct Submit {
+    pub worker: String,
+    pub job_id: u16,
+    pub nonce: u64,
+    /// The nonce read as decimal, for strings of digits that may be either
+    pub decimal_nonce: Option<u64>,
+    /// Timestamp the miner rolled the job to, in ms
+    pub timestamp: Option<u64>,
+}
+
+impl Submit {
+    pub fn parse(params: Value, dialect: &Dialect) -> Result<Self> {
+        if dialect.strict {
+            return Self::parse_strict(&params, dialect.timestamp_rolling);
+        }
+        let (worker, job_id, nonce) = match &params {
+            Value::Array(p) if p.len() >= 3 => (&p[0], &p[1], &p[2]),
+            Value::Object(p) => (
+                field(p, &["worker", "user", "login"]).unwrap_or(&Value::Null),
+                field(p, &["job_id", "jobId", "job", "id"])
+                    .ok_or_else(|| anyhow!("missing job id"))?,
+                field(p, &["nonce"]).ok_or_else(|| anyhow!("missing nonce"))?,
+            ),
+            _ => bail!("expected [worker, job_id, nonce]"),
+        };
+        let worker = match worker {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            v => v.to_string(),
+        };
+        let job_id = parse_u64(job_id, false)?;
+        let job_id = u16::try_from(job_id).map_err(|_| anyhow!("job id {job_id} out of range"))?;
+        let (nonce, decimal_nonce) = match dialect.little_endian_nonce {
+            true => (parse_u64(nonce, dialect.decimal_nonce)?.swap_bytes(), None),
+            false => parse_nonce(nonce, dialect.decimal_nonce)?,
+        };
+        let timestamp = match &params {
+            _ if !dialect.timestamp_rolling => None,
+            Value::Array(p) => p.get(3),
+            Value::Object(p) => field(p, &["timestamp", "time", "ntime"]),
+            _ => None,
+        };
+        // Decimal like the timestamp of the notify unless `0x` prefixed
+        let timestamp = timestamp.map(|t| parse_u64(t, true)).transpose()?;
+
+        Ok(Submit {
+            worker,
+            job_id,
+            nonce,
+            decimal_nonce,
+            timestamp,
+        })
+    }
+
+    /// Exactly `[worker, job_id, "0x" nonce]` as the reference kaspa-miner
+    /// sends it, with the timestamp after it if rolling. The `0x` may be
+    /// left out.
+    fn parse_strict(params: &Value, rolling: bool) -> Result<Self> {
+        let (worker, job_id, nonce, timestamp) = match params.as_array().map(Vec::as_slice) {
+            Some([Value::String(w), Value::String(j), Value::String(n)]) => (w, j, n, None),
+            Some([Value::String(w), Value::String(j), Value::String(n), Value::Number(t)])
+                if rolling =>
+            {
+                let t = t.as_u64().ok_or_else(|| anyhow!("invalid timestamp {t}"))?;
+                (w, j, n, Some(t))
+            }
+            _ => bail!("expected [worker, job_id, nonce] strings"),
+        };
+        let job_id = u16::from_str_radix(job_id, 16)
+            .map_err(|e| anyhow!("invalid job id {job_id:?}: {e}"))?;
+        let hex = nonce.strip_prefix("0x").unwrap_or(nonce);
+        let nonce =
+            u64::from_str_radix(hex, 16).map_err(|e| anyhow!("invalid nonce {nonce:?}: {e}"))?;
+        Ok(Submit {
+            worker: worker.clone(),
+            job_id,
+            nonce,
+            decimal_nonce: None,
+            timestamp,
+        })
+    }
+}
+
+pub struct Subscribe {
+    pub agent: Option<String>,
+    /// Some miners name the worker here instead of authorizing
+    pub worker: Option<String>,
+}
+
+impl Subscribe {
+    /// `[agent, worker]`, with a protocol such as `EthereumStratum/1.0.0`
+    /// in place of the worker for others
+    pub fn parse(params: Option<&Value>) -> Self {
+        let param = |i| params.and_then(|p| p.get(i)).and_then(Value::as_str);
+        let worker = param(1).filter(|w| !w.is_empty() && !w.contains('/'));
+        Subscribe {
+            agent: param(0).map(Into::into),
+            worker: worker.map(Into::into),
+        }
+    }
+}
+
+pub struct Authorize {
+    pub worker: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Authorize {
+    /// Never fails, credentials are only checked if configured
+    pub fn parse(params: Option<&Value>) -> Self {
+        let (worker, password) = match params {
+            Some(Value::Array(p)) => (p.first(), p.get(1)),
+            Some(Value::Object(p)) => (
+                field(p, &["worker", "user", "login", "username"]),
+                field(p, &["password", "pass"]),
+            ),
+            _ => (None, None),
+        };
+        let string = |v: Option<&Value>| v.and_then(|v| v.as_str()).map(Into::into);
+        Authorize {
+            worker: string(worker),
+            password: string(password),
+        }
+    }
+}
+
+/// First of the alternative names a client used for a field
+fn field<'a>(params: &'a Map<String, Value>, names: &[&str]) -> Option<&'a Value> {
+    names.iter().find_map(|n| params.get(*n))
+}
+
+/// Like [`parse_u64`], but digits that are too long for hex are decimal,
+/// which some ASIC firmware sends. Digits that are valid either way are
+/// read as hex with the decimal value as the alternative.
+fn parse_nonce(v: &Value, decimal: bool) -> Result<(u64, Option<u64>)> {
+    let digits = match v.as_str().map(str::trim) {
+        Some(s) if !decimal && !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) => s,
+        _ => return Ok((parse_u64(v, decimal)?, None)),
+    };
+    match (u64::from_str_radix(digits, 16), digits.parse::<u64>()) {
+        (Ok(hex), Ok(dec)) if hex != dec => Ok((hex, Some(dec))),
+        (Ok(hex), _) => Ok((hex, None)),
+        (Err(_), Ok(dec)) => Ok((dec, None)),
+        (Err(e), Err(_)) => bail!("invalid value {digits:?}: {e}"),
+    }
+}
+
+/// Parse a hex string with or without `0x` prefix in any case and padding.
+/// JSON numbers are taken as is, strings only as decimal if `decimal` is set.
+fn parse_u64(v: &Value, decimal: bool) -> Result<u64> {
+    match v {
+        Value::Number(n) => n.as_u64().ok_or_else(|| anyhow!("invalid number {n}")),
+        Value::String(s) => {
+            let s = s.trim();
+            let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None if decimal => s.parse(),
+                None => u64::from_str_radix(s, 16),
+            };
+            res.map_err(|e| anyhow!("invalid value {s:?}: {e}"))
+        }
+        _ => bail!("expected a string or number, got {v}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Authorize, Submit, Subscribe};
+    use crate::stratum::{Dialect, Preset};
+    use serde_json::json;
+
+    #[test]
+    fn submit_formats() {
+        let dialect = Dialect::default();
+        let cases = [
+            (json!(["w", "01", "0x00000000000000ff"]), 1, 0xff),
+            (json!(["w", "0x1A", "0X00FF"]), 0x1a, 0xff),
+            (json!(["w", "a", "ABCDEF"]), 0xa, 0xabcdef),
+            (json!(["w", 7, 255]), 7, 255),
+            (json!(["w", "0100", "0x10"]), 0x100, 0x10),
+            (json!(["w", " ff ", "ffffffffffffffff"]), 0xff, u64::MAX),
+            (
+                json!({"worker": "w", "job_id": "01", "nonce": "0x10"}),
+                1,
+                0x10,
+            ),
+            (json!({"id": "02", "nonce": 16}), 2, 16),
+        ];
+        for (params, job_id, nonce) in cases {
+            let submit = Submit::parse(params.clone(), &dialect).unwrap();
+            assert_eq!((submit.job_id, submit.nonce), (job_id, nonce), "{params}");
+        }
+
+        for params in [
+            json!(["w", "10000", "00"]),
+            json!(["w", "01", "0x"]),
+            json!(["w", "01", "1ffffffffffffffff"]),
+            json!(["w", "01"]),
+            json!({"nonce": "00"}),
+            json!({"job_id": "00"}),
+            json!("w"),
+        ] {
+            assert!(Submit::parse(params.clone(), &dialect).is_err(), "{params}");
+        }
+    }
+
+    #[test]
+    fn decimal_nonce() {
+        let dialect = Dialect {
+            decimal_nonce: true,
+            ..Default::default()
+        };
+        let submit = Submit::parse(json!(["w", "10", "1000"]), &dialect).unwrap();
+        assert_eq!((submit.job_id, submit.nonce), (0x10, 1000));
+        let submit = Submit::parse(json!(["w", "10", "0x1000"]), &dialect).unwrap();
+        assert_eq!(submit.nonce, 0x1000);
+    }
+
+    #[test]
+    fn rolled_timestamp() {
+        let plain = Submit::parse(json!(["w", "01", "0x10", 1000]), &Dialect::default()).unwrap();
+        assert_eq!(plain.timestamp, None);
+
+        let rolling = Dialect {
+            timestamp_rolling: true,
+            ..Default::default()
+        };
+        for (params, timestamp) in [
+            (json!(["w", "01", "0x10", 1000]), Some(1000)),
+            (json!(["w", "01", "0x10", "1000"]), Some(1000)),
+            (json!(["w", "01", "0x10", "0x3e8"]), Some(1000)),
+            (json!({"id": "01", "nonce": 16, "ntime": 1000}), Some(1000)),
+            (json!(["w", "01", "0x10"]), None),
+        ] {
+            let submit = Submit::parse(params.clone(), &rolling).unwrap();
+            assert_eq!(submit.timestamp, timestamp, "{params}");
+        }
+        assert!(Submit::parse(json!(["w", "01", "0x10", "x"]), &rolling).is_err());
+
+        let strict = Dialect {
+            timestamp_rolling: true,
+            ..Dialect::new(Preset::KaspaMinerStrict)
+        };
+        let submit = Submit::parse(json!(["w", "01", "0x10", 1000]), &strict).unwrap();
+        assert_eq!(submit.timestamp, Some(1000));
+        let strict = Dialect::new(Preset::KaspaMinerStrict);
+        assert!(Submit::parse(json!(["w", "01", "0x10", 1000]), &strict).is_err());
+    }
+
+    #[test]
+    fn detects_decimal_nonces() {
+        let dialect = Dialect::default();
+        let nonce = |n: &str| {
+            let submit = Submit::parse(json!(["w", "01", n]), &dialect).unwrap();
+            (submit.nonce, submit.decimal_nonce)
+        };
+        // Too long for hex
+        assert_eq!(nonce("18446744073709551615"), (u64::MAX, None));
+        assert_eq!(nonce("12345678901234567"), (12345678901234567, None));
+        // Either
+        assert_eq!(nonce("1000"), (0x1000, Some(1000)));
+        assert_eq!(nonce("0x1000"), (0x1000, None));
+        assert_eq!(nonce("7"), (7, None));
+        assert!(Submit::parse(json!(["w", "01", "99999999999999999999"]), &dialect).is_err());
+    }
+
+    #[test]
+    fn little_endian_nonce() {
+        let dialect = Dialect::new(Preset::Goldshell);
+        let submit = Submit::parse(json!(["w", "01", "0807060504030201"]), &dialect).unwrap();
+        assert_eq!(submit.nonce, 0x0102030405060708);
+    }
+
+    #[test]
+    fn strict_submit() {
+        let dialect = Dialect::new(Preset::KaspaMinerStrict);
+        let submit = Submit::parse(json!(["w", "2a", "0x00000000000000ff"]), &dialect).unwrap();
+        assert_eq!((submit.job_id, submit.nonce), (0x2a, 0xff));
+        let submit = Submit::parse(json!(["w", "2a", "00000000000000ff"]), &dialect).unwrap();
+        assert_eq!(submit.nonce, 0xff);
+        for params in [
+            json!(["w", "2a", "0xzz"]),
+            json!(["w", "2a", 255]),
+            json!(["w", "2a", "0x00000000000000ff", "extra"]),
+            json!({"worker": "w", "job_id": "2a", "nonce": "0xff"}),
+        ] {
+            assert!(Submit::parse(params.clone(), &dialect).is_err(), "{params}");
+        }
+    }
+
+    #[test]
+    fn subscribe_formats() {
+        let cases = [
+            (json!(["kaspa-miner/0.2.1"]), None),
+            (
+                json!(["BzMiner/v17", "kaspa:qz0000.rig1"]),
+                Some("kaspa:qz0000.rig1"),
+            ),
+            (json!(["lolMiner 1.88", "EthereumStratum/1.0.0"]), None),
+            (json!(["agent", ""]), None),
+        ];
+        for (params, worker) in cases {
+            let subscribe = Subscribe::parse(Some(&params));
+            assert_eq!(subscribe.worker.as_deref(), worker, "{params}");
+            assert!(subscribe.agent.is_some());
+        }
+        assert!(Subscribe::parse(None).agent.is_none());
+    }
+
+    #[test]
+    fn authorize_formats() {
+        let cases = [
+            (json!(["w.1", "x"]), Some("w.1")),
+            (json!({"user": "w.1", "pass": "x"}), Some("w.1")),
+            (json!({"login": "w.1"}), Some("w.1")),
+            (json!([]), None),
+            (json!({"pass": "x"}), None),
+        ];
+        for (params, worker) in cases {
+            let authorize = Authorize::parse(Some(&params));
+            assert_eq!(authorize.worker.as_deref(), worker, "{params}");
+        }
+        assert_eq!(Authorize::parse(None).worker, None);
+        let password = |params| Authorize::parse(Some(&params)).password;
+        assert_eq!(password(json!(["w.1", "x"])).as_deref(), Some("x"));
+        assert_eq!(
+            password(json!({"user": "w.1", "pass": "x"})).as_deref(),
+            Some("x")
+        );
+        assert_eq!(password(json!(["w.1"])), None);
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use std::fmt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Longest line accepted from a client, stratum messages are far shorter
+pub const MAX_LINE: usize = 16 * 1024;
+
+/// A line longer than [`MAX_LINE`], the client is answered before closing
+#[derive(Debug)]
+pub struct LineTooLong;
+
+impl fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line exceeds {MAX_LINE} bytes")
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+/// Reads newline terminated messages without buffering more than
+/// [`MAX_LINE`] bytes, so an unterminated line can't grow without limit
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader {
+            reader: BufReader::new(reader),
+            line: Vec::with_capacity(1024),
+        }
+    }
+
+    /// Next line without its line ending, `None` at the end of the stream.
+    /// It isn't checked for UTF-8, that is left to the JSON parser. Cancel
+    /// safe, a partial line is kept for the next call.
+    pub async fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            if buf.is_empty() {
+                // A final line without newline is still a message
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(self.take()));
+            }
+            let (chunk, done) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (&buf[..i], i + 1),
+                None => (buf, buf.len()),
+            };
+            if self.line.len() + chunk.len() > MAX_LINE {
+                return Err(LineTooLong.into());
+            }
+            self.line.extend_from_slice(chunk);
+            let complete = chunk.len() < done;
+            self.reader.consume(done);
+            if complete {
+                return Ok(Some(self.take()));
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        std::mem::take(&mut self.line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LineReader, LineTooLong, MAX_LINE};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn splits_lines() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = LineReader::new(server);
+        tokio::spawn(async move {
+            for part in ["{\"id\":1}\r\n{\"id\"", ":2}\n", "{\"id\":3}"] {
+                client.write_all(part.as_bytes()).await.unwrap();
+            }
+        });
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), b"{\"id\":1}");
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), b"{\"id\":2}");
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), b"{\"id\":3}");
+        assert!(reader.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_long_lines() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = LineReader::new(server);
+        tokio::spawn(async move {
+            let line = vec![b'a'; MAX_LINE + 1];
+            let _ = client.write_all(&line).await;
+        });
+        let e = reader.next_line().await.unwrap_err();
+        assert!(e.is::<LineTooLong>());
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Abuse of the stratum ports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityEvent<'a> {
+    /// Credentials refused on `mining.authorize`
+    AuthFailure { worker: &'a str },
+    /// A line that isn't a stratum message
+    Malformed,
+    /// The address is refused for `secs`, its `offense`th ban in a row
+    Banned {
+        secs: u64,
+        offense: u32,
+        reason: &'a str,
+    },
+}
+
+/// Appends security events to a file, one line each with the client address
+/// after `from`, for fail2ban and similar tools:
+///
+/// ```text
+/// 2024-05-01T12:00:00Z kaspad-stratum[4321]: auth_failure from 203.0.113.7 worker="kaspa:qq.rig1"
+/// ```
+#[derive(Clone)]
+pub struct SecurityLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl SecurityLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        Ok(SecurityLog {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, ip: IpAddr, event: SecurityEvent) {
+        let line = format_line(SystemTime::now(), ip, event);
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Unable to write the security log: {e}");
+        }
+    }
+}
+
+fn format_line(time: SystemTime, ip: IpAddr, event: SecurityEvent) -> String {
+    let pid = std::process::id();
+    // Mapped IPv4 clients of dual stack listeners are banned by their IPv4
+    let ip = ip.to_canonical();
+    let mut line = format!("{} kaspad-stratum[{pid}]: ", timestamp(time));
+    match event {
+        SecurityEvent::AuthFailure { worker } => {
+            let _ = write!(line, "auth_failure from {ip} worker={worker:?}");
+        }
+        SecurityEvent::Malformed => {
+            let _ = write!(line, "malformed from {ip}");
+        }
+        SecurityEvent::Banned {
+            secs,
+            offense,
+            reason,
+        } => {
+            let _ = write!(
+                line,
+                "banned from {ip} secs={secs} offense={offense} reason={reason:?}"
+            );
+        }
+    }
+    line.push('\n');
+    line
+}
+
+/// RFC 3339 in UTC, which fail2ban recognizes without a date pattern
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch, after Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_line, timestamp, SecurityEvent};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn formats_lines() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(timestamp(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(at(951_782_400)), "2000-02-29T00:00:00Z");
+        assert_eq!(timestamp(at(1_714_564_800)), "2024-05-01T12:00:00Z");
+
+        let ip = "::ffff:203.0.113.7".parse().unwrap();
+        let line = format_line(
+            at(1_714_564_800),
+            ip,
+            SecurityEvent::AuthFailure {
+                worker: "kaspa:qq.\"rig1\"",
+            },
+        );
+        let pid = std::process::id();
+        assert_eq!(
+            line,
+            format!("2024-05-01T12:00:00Z kaspad-stratum[{pid}]: auth_failure from 203.0.113.7 worker=\"kaspa:qq.\\\"rig1\\\"\"\n")
+        );
+        let banned = SecurityEvent::Banned {
+            secs: 1200,
+            offense: 2,
+            reason: "refused logins",
+        };
+        assert!(format_line(at(0), ip, banned)
+            .ends_with("banned from 203.0.113.7 secs=1200 offense=2 reason=\"refused logins\"\n"));
+    }
+}
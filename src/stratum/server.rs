@@ -1,232 +1,1673 @@
-use super::jobs::{JobParams, Jobs, PendingResult};
-use super::{Id, Request, Response};
+use super::accounting::{Accounting, Status};
+use super::auth::Auth;
+use super::control::{Command, Connections, Control, Registration};
+use super::dialect::{Dialect, DialectConfig, NoncePrefix, NotifyLimits, SubscribeResponse};
+use super::jobs::{
+    convert_notify, Expiry, JobParams, Jobs, PendingResult, Share, SubmitResult, SubmittedBlock,
+};
+use super::listener::{Listener, DEFAULT_EXTRANONCE_SIZE};
+use super::params::{Authorize, Submit, Subscribe};
+use super::reader::{LineReader, LineTooLong};
+use super::security::SecurityEvent;
+use super::shared::{self, Bans, Lease, SharedState};
+use super::stats::{format_hashrate, Stats};
+use super::tls::Tls;
+use super::upstream::{Relay, Upstream, UpstreamConfig};
+use super::vardiff::{DifficultyCache, SystemClock, VarDiff};
+use super::writer::{self, Job, Message, Tiers, Writer};
+use super::{Config, Id, Request, Response};
+use crate::chaos;
+use crate::console::Outcome;
+use crate::events::{Event, Notifier};
 use crate::kaspad::{KaspadHandle, RpcBlock};
+use crate::metrics;
+use crate::pow;
+use crate::SUMMARY;
 use anyhow::Result;
-use log::{debug, info, warn};
-use serde::Serialize;
-use serde_json::json;
-use std::num::Wrapping;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
-use tokio::net::TcpListener;
+use prometheus::Histogram;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use socket2::{SockRef, TcpKeepalive};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{self, TcpListener, TcpSocket, TcpStream};
 use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::field::Empty;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 
-const NEW_LINE: &'static str = "\n";
+/// Misbehaviour tolerated before a connection is closed
+const MAX_BAN_SCORE: u32 = 10;
+/// Time a client on a TLS port has to complete the handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Reading half of a plain or TLS connection
+type ConnReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Accepts connections on one listener, several of them share a port
+/// when sharding
+#[derive(Clone)]
 struct StratumTask {
-    listener: TcpListener,
     recv: watch::Receiver<Option<JobParams>>,
     jobs: Jobs,
+    config: Config,
+    difficulties: DifficultyCache,
+    share_difficulty: Histogram,
+    stats: Stats,
+    accounting: Option<Accounting>,
+    /// Blocks found on this listener are credited to their finder alone
+    solo: bool,
+    tiers: Tiers,
+    /// Connections accepted so far, shared by all acceptors
+    next_worker: Arc<AtomicU32>,
+    /// First extranonce prefix of this instance and how many it has
+    prefixes: (u32, u32),
+    online: Arc<AtomicBool>,
+    bans: Bans,
+    connections: Connections,
+    /// Set once the listeners should close
+    draining: watch::Receiver<bool>,
+    fallback: Option<Fallback>,
+    /// Connections start with a TLS handshake
+    tls: Option<Tls>,
+    /// Ports with their own settings
+    listeners: Arc<[Listener]>,
+    /// Bytes of the nonce fixed per connection
+    extranonce_size: u8,
+}
+
+/// The fallback pool and whether miners should use it
+#[derive(Clone)]
+struct Fallback {
+    config: UpstreamConfig,
+    active: watch::Receiver<bool>,
 }
 
 impl StratumTask {
-    async fn run(self) {
-        let mut worker = Wrapping(0u16);
+    /// Extranonce for a new connection, never 0
+    fn next_worker(&self) -> Extranonce {
+        let size = self.extranonce_size;
+        let (start, count) = self.prefixes;
+        // One byte takes the top byte of the partition's prefixes, longer
+        // ones are padded with zeros
+        let (start, count, shift) = match size {
+            1 => (start >> 8, (count >> 8).max(1), 0),
+            _ => (start, count, 8 * (size as u32 - 2)),
+        };
         loop {
-            worker += &1;
-            if worker.0 == 0 {
-                worker += &1;
+            let n = self.next_worker.fetch_add(1, Ordering::Relaxed) % count;
+            let worker = start + n;
+            if worker != 0 {
+                let value = (worker as u64) << shift;
+                return Extranonce { value, size };
+            }
+        }
+    }
+
+    /// Take this instance's share of the extranonce prefixes, claiming it
+    /// from the shared state unless it was configured
+    async fn partition(&mut self, lost: &Arc<watch::Sender<Option<&'static str>>>) {
+        let count = self.config.instances.max(1);
+        let index = match (self.config.instance, &self.config.shared) {
+            _ if count == 1 => return,
+            (Some(index), _) => index,
+            (None, Some(state)) => {
+                let (index, lease) = shared::claim_partition(state, count).await;
+                let lost = lost.clone();
+                tokio::spawn(async move {
+                    lease.hold().await;
+                    let _ = lost.send(Some("Lost the extranonce partition to another instance"));
+                });
+                index
+            }
+            // Checked by Stratum::new
+            (None, None) => return,
+        };
+        info!("Using extranonce partition {index} of {count}");
+        self.prefixes = partition(index, count);
+    }
+
+    /// Resume the difficulties other instances saved for their workers
+    async fn restore_difficulties(&self) {
+        let shared = match (&self.config.shared, &self.config.vardiff) {
+            (Some(s), Some(_)) => s,
+            _ => return,
+        };
+        match shared.difficulties().await {
+            Ok(saved) => {
+                for (worker, difficulty, age) in saved {
+                    self.difficulties.restore(worker, difficulty, age);
+                }
             }
+            Err(e) => warn!("Unable to restore difficulties: {e}"),
+        }
+    }
 
-            match self.listener.accept().await {
-                Ok((mut conn, addr)) => {
-                    info!("New connection from {addr}");
+    /// Listeners of the stratum address and the ports with their own
+    /// settings, each with the task serving it. Sockets passed by systemd
+    /// take the place of all of them, with the settings of their port.
+    fn listen(&self, addr: SocketAddr) -> Result<Vec<(StratumTask, TcpListener)>> {
+        let main = self.listeners.iter().find(|l| l.port == addr.port());
+        let main = main
+            .cloned()
+            .unwrap_or_else(|| Listener::new(addr.port(), &self.config));
+        #[cfg(unix)]
+        if let Some(listeners) = systemd_listeners()? {
+            info!(
+                "Using {} sockets passed by systemd instead of {addr}",
+                listeners.len()
+            );
+            let mut tasks = std::collections::HashMap::new();
+            return listeners
+                .into_iter()
+                .map(|socket| {
+                    let port = socket.local_addr()?.port();
+                    let listener = match self.listeners.iter().find(|l| l.port == port) {
+                        Some(listener) => listener,
+                        None if port == main.port => &main,
+                        None => anyhow::bail!(
+                            "Socket passed by systemd on port {port} is neither the port of \
+                             {addr} nor one with its own settings"
+                        ),
+                    };
+                    let task = tasks.entry(port).or_insert_with(|| self.on(listener));
+                    Ok((task.clone(), socket))
+                })
+                .collect();
+        }
+        let main = self.on(&main);
+        let count = self.config.acceptors.max(1);
+        let mut listeners: Vec<_> = bind(addr, count, &self.config.socket)?
+            .into_iter()
+            .map(|l| (main.clone(), l))
+            .collect();
+        for listener in self.listeners.iter().filter(|l| l.port != addr.port()) {
+            let task = self.on(listener);
+            let addr = SocketAddr::new(addr.ip(), listener.port);
+            for socket in bind(addr, count, &self.config.socket)? {
+                listeners.push((task.clone(), socket));
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// The task serving a port with the settings of `listener`
+    fn on(&self, listener: &Listener) -> StratumTask {
+        let port = listener.port;
+        let mut task = self.clone();
+        info!("Port {port}: {}", listener.describe());
+        task.tls = listener.tls.clone();
+        if let Some(preset) = listener.preset {
+            task.config.dialect.preset = Some(preset);
+        }
+        task.solo = listener.solo;
+        task.config.vardiff = listener.vardiff.clone();
+        task.extranonce_size = listener.extranonce_size;
+        task.share_difficulty = metrics::SHARE_DIFFICULTY.with_label_values(&[&port.to_string()]);
+        task
+    }
+
+    async fn run(self, listener: TcpListener) {
+        let mut draining = self.draining.clone();
+        loop {
+            let accepted = tokio::select! {
+                res = listener.accept() => res,
+                _ = draining.changed() => return info!("Stopped accepting connections"),
+            };
+            match accepted {
+                Ok((conn, addr)) => {
+                    let acl = self.config.acl.as_ref();
+                    if acl.is_some_and(|acl| !acl.allows("stratum", addr.ip())) {
+                        debug!("Refusing {addr} by the ACL");
+                        continue;
+                    }
+                    let (registration, commands) = self.connections.register(addr);
+                    // Kept by --quiet, for its connection summaries
+                    let span = info_span!(
+                        target: SUMMARY,
+                        "conn",
+                        id = registration.id(),
+                        %addr,
+                        worker = Empty,
+                        agent = Empty
+                    );
+                    info!(parent: &span, "New connection");
+                    if let Err(e) = self.config.socket.apply(&conn) {
+                        warn!(parent: &span, "Unable to set socket options: {e}");
+                    }
                     let recv = self.recv.clone();
                     let jobs = self.jobs.clone();
-                    let worker = worker.0.to_be_bytes();
+                    let worker = self.next_worker();
                     let (pending_send, pending_recv) = mpsc::unbounded_channel();
+                    let vardiff = self
+                        .config
+                        .vardiff
+                        .clone()
+                        .map(|c| VarDiff::new(c, SystemClock));
+                    let dialects = self.config.dialect.clone();
+                    let notify_limits = self.config.notify_limits.clone();
+                    let notify_interval = conn
+                        .local_addr()
+                        .ok()
+                        .and_then(|a| notify_limits.for_port(a.port()));
+                    let dialect = dialects.initial();
+                    let difficulties = self.difficulties.clone();
+                    let slow_client = self.config.slow_client;
+                    let share_difficulty = self.share_difficulty.clone();
+                    let stats = self.stats.clone();
+                    let accounting = self.accounting.clone();
+                    let solo = self.solo;
+                    let notifier = self.config.notifier.clone();
+                    let auth = self.config.auth.clone();
+                    let tiers = self.tiers.clone();
+                    let online = self.online.clone();
+                    let handshake_timeout = self.config.handshake_timeout;
+                    let shared = self.config.shared.clone();
+                    let bans = self.bans.clone();
+                    let fallback = self.fallback.clone();
+                    let tls = self.tls.clone();
+
+                    tokio::spawn(
+                        async move {
+                            if bans.is_banned(addr.ip()).await {
+                                return info!("Refusing banned address");
+                            }
+                            let (reader, writer) = match tls {
+                                Some(tls) => {
+                                    let accept = tls.acceptor().accept(conn);
+                                    match time::timeout(TLS_HANDSHAKE_TIMEOUT, accept).await {
+                                        Ok(Ok(stream)) => split(tokio::io::split(stream)),
+                                        Ok(Err(e)) => return info!("TLS handshake failed: {e}"),
+                                        Err(_) => return info!("TLS handshake timed out"),
+                                    }
+                                }
+                                None => split(conn.into_split()),
+                            };
+                            let conn = StratumConn {
+                                addr,
+                                reader: LineReader::new(reader),
+                                writer: Writer::new(writer, slow_client, dialect.jsonrpc2),
+                                recv,
+                                jobs,
+                                pending_send,
+                                pending_recv,
+                                pending: 0,
+                                worker,
+                                state: State::Connected,
+                                difficulty: 0,
+                                vardiff,
+                                dialect,
+                                dialects,
+                                notify_limits,
+                                notify_interval,
+                                last_notify: None,
+                                submit_received: Instant::now(),
+                                connected: Instant::now(),
+                                accepted_shares: 0,
+                                rejected_shares: 0,
+                                notify_due: None,
+                                difficulties,
+                                worker_name: None,
+                                extranonce_sent: false,
+                                extranonce_requested: false,
+                                share_difficulty,
+                                stats,
+                                accounting,
+                                solo,
+                                notifier,
+                                auth,
+                                tiers,
+                                online,
+                                ban_score: 0,
+                                registration,
+                                commands,
+                                fixed_difficulty: None,
+                                fallback,
+                                upstream: None,
+                                handshake_timeout,
+                                shared,
+                                bans,
+                            };
 
-                    tokio::spawn(async move {
-                        let (reader, writer) = conn.split();
-                        let conn = StratumConn {
-                            // addr,
-                            reader: BufReader::new(reader).lines(),
-                            writer,
-                            recv,
-                            jobs,
-                            pending_send,
-                            pending_recv,
-                            worker,
-                            id: 0,
-                            subscribed: false,
-                            difficulty: 0,
-                        };
-
-                        match conn.run().await {
-                            Ok(_) => info!("Connection {addr} closed"),
-                            Err(e) => warn!("Connection {addr} closed: {e}"),
+                            conn.run().await;
                         }
-                    });
+                        .instrument(span),
+                    );
                 }
                 Err(e) => {
-                    warn!("Error: {e}");
+                    // Out of file descriptors, say
+                    warn!("Unable to accept: {e}");
+                    time::sleep(Duration::from_secs(1)).await;
                 }
             }
         }
     }
 }
 
+/// Options of accepted miner connections
+#[derive(Clone, Debug)]
+pub struct SocketConfig {
+    /// Disable Nagle's algorithm so jobs go out immediately
+    pub nodelay: bool,
+    /// Idle time before keepalive probes, and the interval between them
+    pub keepalive: Option<Duration>,
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    /// Let another process bind the same port, e.g. a new version taking
+    /// over while this one drains
+    pub reuse_port: bool,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            nodelay: true,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: None,
+            reuse_port: false,
+        }
+    }
+}
+
+impl SocketConfig {
+    /// Options that accepted sockets don't inherit from the listener
+    fn apply(&self, conn: &TcpStream) -> Result<()> {
+        conn.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time).with_interval(time);
+            SockRef::from(conn).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bind `count` listeners to `addr`. With more than one, the kernel spreads
+/// incoming connections over them through SO_REUSEPORT.
+fn bind(addr: SocketAddr, count: usize, config: &SocketConfig) -> Result<Vec<TcpListener>> {
+    #[cfg(not(unix))]
+    if count > 1 || config.reuse_port {
+        anyhow::bail!("Multiple acceptors need SO_REUSEPORT, which is only available on unix");
+    }
+    (0..count)
+        .map(|_| {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            #[cfg(unix)]
+            socket.set_reuseport(count > 1 || config.reuse_port)?;
+            // Inherited by accepted sockets
+            if let Some(size) = config.send_buffer {
+                socket.set_send_buffer_size(size)?;
+            }
+            if let Some(size) = config.recv_buffer {
+                socket.set_recv_buffer_size(size)?;
+            }
+            socket.bind(addr)?;
+            Ok(socket.listen(1024)?)
+        })
+        .collect()
+}
+
+/// Leading nonce bytes of a connection's shares
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Extranonce {
+    value: u64,
+    /// Bytes, from 1 to 3
+    size: u8,
+}
+
+impl Extranonce {
+    fn hex(self) -> String {
+        format!("{:0width$x}", self.value, width = 2 * self.size as usize)
+    }
+
+    /// Bytes of the nonce left to the miner
+    fn extranonce2_size(self) -> u64 {
+        8 - self.size as u64
+    }
+
+    /// Bits of the nonce below the extranonce
+    fn shift(self) -> u32 {
+        64 - 8 * self.size as u32
+    }
+
+    fn matches(self, nonce: u64) -> bool {
+        nonce >> self.shift() == self.value
+    }
+
+    /// `nonce` with the extranonce written over its leading bytes
+    fn apply(self, nonce: u64) -> u64 {
+        nonce & (u64::MAX >> (8 * self.size)) | self.value << self.shift()
+    }
+}
+
+/// Prefixes of the `index`th of `count` equal extranonce partitions
+fn partition(index: u16, count: u16) -> (u32, u32) {
+    let size = 0x10000 / count.max(1) as u32;
+    (index as u32 * size, size)
+}
+
+/// Listening sockets of systemd socket activation, see sd_listen_fds(3)
+#[cfg(unix)]
+fn systemd_listeners() -> Result<Option<Vec<TcpListener>>> {
+    use std::os::unix::io::FromRawFd;
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count = match var("LISTEN_FDS") {
+        Some(n) if n > 0 => n as i32,
+        _ => return Ok(None),
+    };
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passed these descriptors to this process, nothing
+            // else owns them
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
 pub struct Stratum {
     send: watch::Sender<Option<JobParams>>,
     jobs: Jobs,
+    stats: Stats,
+    accounting: Option<Accounting>,
+    /// Whether kaspad is reachable, otherwise submits are refused
+    online: Arc<AtomicBool>,
+    /// Why another instance may have taken over
+    lease_lost: watch::Receiver<Option<&'static str>>,
+    control: Control,
+    draining: watch::Sender<bool>,
+    tls: Option<Tls>,
 }
 
 impl Stratum {
-    pub async fn new(host: &str, handle: KaspadHandle) -> Result<Self> {
+    pub async fn new(host: &str, handle: KaspadHandle, config: Config) -> Result<Self> {
         let (send, recv) = watch::channel(None);
-        let listener = TcpListener::bind(host).await?;
-        info!("Listening on {host}");
+        let (draining, draining_recv) = watch::channel(false);
+        let addr = match net::lookup_host(host).await?.next() {
+            Some(a) => a,
+            None => anyhow::bail!("{host} did not resolve to an address"),
+        };
+        let count = config.instances.max(1);
+        match config.instance {
+            Some(index) if index >= count => {
+                anyhow::bail!("Instance {index} is out of range for {count} instances")
+            }
+            None if count > 1 && config.shared.is_none() => {
+                anyhow::bail!(
+                    "Partitioning the extranonces needs an instance index or shared state"
+                )
+            }
+            _ => {}
+        }
+        let port = addr.port().to_string();
+        let share_difficulty = metrics::SHARE_DIFFICULTY.with_label_values(&[&port]);
 
-        let jobs = Jobs::new(handle);
-        let task = StratumTask {
-            listener,
+        let expiry = match config.job_expiry_daa {
+            Some(depth) => Expiry::DaaScore(depth),
+            None => Expiry::Grace(config.job_grace),
+        };
+        let jobs = Jobs::new(handle, expiry, config.wide_job_ids);
+        jobs.set_dry_run(config.dry_run);
+        let stats = Stats::default();
+        let accounting = config
+            .payout
+            .clone()
+            .map(Accounting::open)
+            .transpose()?
+            .map(|a| a.with_shared(config.shared.clone()));
+        let tls = config.tls.clone().map(Tls::new).transpose()?;
+        let listeners = Listener::resolve(&config, tls.as_ref())?;
+        let online = Arc::new(AtomicBool::new(false));
+        if let Some(threshold) = config.worker_offline {
+            tokio::spawn(watch_workers(
+                stats.clone(),
+                threshold,
+                config.notifier.clone(),
+            ));
+        }
+        let ttl = config
+            .vardiff
+            .as_ref()
+            .map(|v| v.resume_ttl)
+            .unwrap_or_default();
+        let mut task = StratumTask {
             recv,
             jobs: jobs.clone(),
+            difficulties: DifficultyCache::new(ttl),
+            share_difficulty,
+            stats: stats.clone(),
+            accounting: accounting.clone(),
+            solo: false,
+            tiers: Tiers::default(),
+            next_worker: Default::default(),
+            prefixes: partition(0, 1),
+            online: online.clone(),
+            bans: Bans::new(config.shared.clone(), config.security_log.clone()),
+            connections: Connections::default(),
+            draining: draining_recv,
+            fallback: None,
+            tls: None,
+            listeners: listeners.into(),
+            extranonce_size: DEFAULT_EXTRANONCE_SIZE,
+            config,
+        };
+        if let Some(config) = &task.config.fallback {
+            let (active, recv) = watch::channel(false);
+            tokio::spawn(watch_node(online.clone(), config.after, active));
+            task.fallback = Some(Fallback {
+                config: config.clone(),
+                active: recv,
+            });
+        }
+        let control = Control {
+            connections: task.connections.clone(),
+            bans: task.bans.clone(),
+            jobs: jobs.clone(),
+        };
+        let (lost_send, lease_lost) = watch::channel(None);
+        let lost_send = Arc::new(lost_send);
+        let failover = match (&task.config.shared, task.config.failover) {
+            (Some(shared), true) => Lease::new(shared.clone(), "primary"),
+            (None, true) => anyhow::bail!("Failover needs shared state"),
+            (_, false) => {
+                task.partition(&lost_send).await;
+                task.restore_difficulties().await;
+                let listeners = task.listen(addr)?;
+                info!("Listening on {host}");
+                for (task, listener) in listeners {
+                    tokio::spawn(task.run(listener));
+                }
+                return Ok(Stratum {
+                    send,
+                    jobs,
+                    stats,
+                    accounting,
+                    online,
+                    lease_lost,
+                    control,
+                    draining,
+                    tls,
+                });
+            }
         };
-        tokio::spawn(task.run());
-        Ok(Stratum { send, jobs })
+        let host = host.to_string();
+        tokio::spawn(async move {
+            info!("Standing by until the failover lease is free");
+            failover.acquire().await;
+            info!("Took over the failover lease");
+            task.partition(&lost_send).await;
+            task.restore_difficulties().await;
+            let listeners = loop {
+                match task.listen(addr) {
+                    Ok(l) => break l,
+                    Err(e) => warn!("Unable to listen on {host}: {e}, retrying"),
+                }
+                time::sleep(Duration::from_secs(1)).await;
+            };
+            info!("Listening on {host}");
+            for (task, listener) in listeners {
+                tokio::spawn(task.run(listener));
+            }
+            failover.hold().await;
+            let _ = lost_send.send(Some("Lost the failover lease to another instance"));
+        });
+        Ok(Stratum {
+            send,
+            jobs,
+            stats,
+            accounting,
+            online,
+            lease_lost,
+            control,
+            draining,
+            tls,
+        })
+    }
+
+    /// Resolves once another instance may have taken over the failover
+    /// lease or the extranonce partition, never without shared state
+    pub async fn lease_lost(&self) -> &'static str {
+        let mut lost = self.lease_lost.clone();
+        loop {
+            if let Some(reason) = *lost.borrow() {
+                return reason;
+            }
+            if lost.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
     }
 
     pub async fn broadcast(&self, template: RpcBlock) {
+        let daa_score = template.header.as_ref().map(|h| h.daa_score);
+        if let Some(daa_score) = daa_score {
+            self.stats
+                .new_template(daa_score, template.reward().unwrap_or_default());
+        }
         if let Some(job) = self.jobs.insert(template).await {
-            let _ = self.send.send(Some(job));
+            self.online.store(true, Ordering::Relaxed);
+            if let (Some(accounting), Some(daa_score)) = (&self.accounting, daa_score) {
+                for round in accounting.new_template(job.difficulty(), daa_score) {
+                    match round.status {
+                        Status::Mature => info!(
+                            "Block {} matured, crediting {} sompi",
+                            round.hash, round.reward
+                        ),
+                        _ => warn!(
+                            "Block {} was not merged as blue by the maturity depth, its reward is lost",
+                            round.hash
+                        ),
+                    }
+                }
+            }
+            job.publish(&self.send);
         }
     }
 
-    pub async fn resolve_pending_job(&self, error: Option<Box<str>>) {
-        self.jobs.resolve_pending(error).await
+    /// Refuse submits until the next template, miners stay connected
+    pub fn pause(&self) {
+        self.online.store(false, Ordering::Relaxed);
+        self.jobs.forget_daa_score();
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Set when shares are credited to payout addresses
+    pub fn accounting(&self) -> Option<&Accounting> {
+        self.accounting.as_ref()
+    }
+
+    pub fn control(&self) -> &Control {
+        &self.control
+    }
+
+    /// Certificate of the TLS ports
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Jobs as they are broadcast to the miners
+    pub fn watch_jobs(&self) -> watch::Receiver<Option<JobParams>> {
+        self.send.subscribe()
+    }
+
+    /// Stop accepting connections and close the open ones evenly spread
+    /// over `period`, so their miners move to another process without all
+    /// reconnecting at once. Jobs and submits are served until each closes.
+    /// The task ends once all connections are gone.
+    pub fn drain(&self, period: Duration) -> JoinHandle<()> {
+        let _ = self.draining.send(true);
+        let control = self.control.clone();
+        tokio::spawn(async move {
+            let open = control.connections();
+            info!("Draining {} connections over {period:?}", open.len());
+            let pause = period / open.len().max(1) as u32;
+            for conn in open {
+                time::sleep(pause).await;
+                control
+                    .connections
+                    .send(|c| c.addr == conn.addr, || Command::Close("Shutting down"));
+            }
+            while !control.connections().is_empty() {
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        })
     }
 }
 
-struct StratumConn<'a> {
-    // addr: SocketAddr,
-    reader: Lines<BufReader<ReadHalf<'a>>>,
-    writer: WriteHalf<'a>,
+/// Handshake progress of a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Connected,
+    /// Authorized before subscribing, which some miners do
+    Authorized,
+    Subscribed,
+    /// Subscribed and authorized
+    Ready,
+}
+
+impl State {
+    fn subscribed(self) -> bool {
+        matches!(self, State::Subscribed | State::Ready)
+    }
+}
+
+struct StratumConn {
+    addr: SocketAddr,
+    reader: LineReader<ConnReader>,
+    writer: Writer,
     recv: watch::Receiver<Option<JobParams>>,
     jobs: Jobs,
     pending_send: mpsc::UnboundedSender<PendingResult>,
     pending_recv: mpsc::UnboundedReceiver<PendingResult>,
-    worker: [u8; 2],
-    id: u64,
-    subscribed: bool,
+    /// Blocks sent to kaspad without a response yet
+    pending: usize,
+    worker: Extranonce,
+    state: State,
     difficulty: u64,
+    vardiff: Option<VarDiff>,
+    dialect: Dialect,
+    notify_limits: NotifyLimits,
+    /// Least time between two jobs
+    notify_interval: Option<Duration>,
+    last_notify: Option<time::Instant>,
+    /// When a held back job is sent
+    notify_due: Option<time::Instant>,
+    /// When the share being handled arrived
+    submit_received: Instant,
+    connected: Instant,
+    accepted_shares: u64,
+    /// Including blocks kaspad refused
+    rejected_shares: u64,
+    /// Chooses the dialect once the agent is known
+    dialects: DialectConfig,
+    difficulties: DifficultyCache,
+    worker_name: Option<String>,
+    /// The miner was told to prefix its nonces with `worker`
+    extranonce_sent: bool,
+    /// `mining.extranonce.subscribe` arrived before subscribing
+    extranonce_requested: bool,
+    share_difficulty: Histogram,
+    stats: Stats,
+    accounting: Option<Accounting>,
+    /// Connected to a solo port
+    solo: bool,
+    notifier: Notifier,
+    /// Credentials checked on `mining.authorize`
+    auth: Option<Auth>,
+    tiers: Tiers,
+    online: Arc<AtomicBool>,
+    /// Malformed messages and refused logins so far, see [`MAX_BAN_SCORE`]
+    ban_score: u32,
+    /// Time to send `mining.subscribe` after connecting
+    handshake_timeout: Option<Duration>,
+    shared: Option<SharedState>,
+    bans: Bans,
+    /// Entry in the connection list of the admin API
+    registration: Registration,
+    commands: mpsc::UnboundedReceiver<Command>,
+    /// Set by the operator when there is no vardiff
+    fixed_difficulty: Option<u64>,
+    fallback: Option<Fallback>,
+    /// Connection to the fallback pool while it is active
+    upstream: Option<Upstream>,
 }
 
-impl<'a> StratumConn<'a> {
-    async fn write_template(&mut self) -> Result<()> {
+impl StratumConn {
+    fn write_template(&mut self) -> Result<()> {
+        if self.upstream.is_some() {
+            // Jobs come from the fallback pool
+            return Ok(());
+        }
+        if let (Some(interval), Some(last)) = (self.notify_interval, self.last_notify) {
+            if time::Instant::now() < last + interval {
+                debug!("Holding back the template");
+                self.notify_due = Some(last + interval);
+                return Ok(());
+            }
+        }
         debug!("Sending template");
-        let (difficulty, params) = {
-            let borrow = self.recv.borrow();
+        let (difficulty, notify) = {
+            // Seen, so the notification of this job doesn't send it again
+            let borrow = self.recv.borrow_and_update();
             match borrow.as_ref() {
-                Some(j) => (j.difficulty(), j.to_value()),
+                Some(j) => (j.difficulty(), j.notify(self.dialect.notify_format)),
                 None => return Ok(()),
             }
         };
-        self.write_request("mining.notify", Some(params)).await?;
+        // Share difficulties above the block difficulty would hide blocks
+        let difficulty = match (&self.vardiff, self.fixed_difficulty) {
+            (Some(v), _) => v.difficulty().min(difficulty),
+            (None, Some(fixed)) => fixed.min(difficulty),
+            (None, None) => difficulty,
+        };
+        self.difficulty = difficulty;
+        let difficulty = super::to_stratum_difficulty(difficulty);
+        self.registration.update(|c| c.difficulty = difficulty);
+        let scaled = difficulty * self.dialect.difficulty_scale;
+        self.last_notify = Some(time::Instant::now());
+        self.notify_due = None;
+        self.writer.send_job(Job {
+            notify,
+            difficulty,
+            set_difficulty: self.tiers.set_difficulty(scaled)?,
+            difficulty_first: self.dialect.difficulty_first,
+            resend_difficulty: self.dialect.resend_difficulty,
+        })
+    }
+
+    fn write_extranonce(&mut self) -> Result<()> {
+        let params = json!([self.worker.hex(), self.worker.extranonce2_size()]);
+        let method = self.dialect.extranonce_method.name();
+        self.extranonce_sent = true;
+        self.writer.send(Message::Request(method, Some(params)))
+    }
 
-        if self.difficulty != difficulty {
-            self.difficulty = difficulty;
-            let difficulty = (difficulty as f64) / ((1u64 << 32) as f64);
-            self.write_request("mining.set_difficulty", Some(json!([difficulty])))
-                .await?;
+    fn write_response<T: Serialize>(&mut self, id: Id, result: Option<T>) -> Result<()> {
+        let res = Response::ok(id, result)?;
+        self.writer.send(Message::Response(res))
+    }
+
+    fn write_error_response(&mut self, id: Id, code: u64, message: Box<str>) -> Result<()> {
+        let res = Response::err(id, code, message)?;
+        self.writer.send(Message::Response(res))
+    }
+
+    /// Remember the worker name, resuming the difficulty it had before a
+    /// reconnect. Returns whether the difficulty changed.
+    fn set_worker_name(&mut self, name: &str) -> bool {
+        if self.worker_name.is_some() || name.is_empty() {
+            return false;
+        }
+        self.worker_name = Some(name.into());
+        Span::current().record("worker", &name);
+        self.registration.update(|c| c.worker = Some(name.into()));
+        match (&mut self.vardiff, self.difficulties.get(name)) {
+            (Some(v), Some(difficulty)) if v.difficulty() != difficulty => {
+                debug!("Resuming difficulty of {name}");
+                v.set_difficulty(difficulty);
+                true
+            }
+            _ => false,
         }
+    }
 
-        Ok(())
+    /// Mirror the difficulty to the shared state for other instances
+    fn save_difficulty(&self) {
+        if let (Some(shared), Some(name), Some(v)) =
+            (&self.shared, &self.worker_name, &self.vardiff)
+        {
+            shared.save_difficulty(name, v.difficulty());
+        }
     }
 
-    async fn write_request(
-        &mut self,
-        method: &'static str,
-        params: Option<serde_json::Value>,
-    ) -> Result<()> {
-        self.id += 1;
-        let req = Request {
-            id: Some(self.id.into()),
-            method: method.into(),
-            params,
-        };
-        self.write(&req).await
+    fn retargeted(&self) {
+        if let Some(v) = &self.vardiff {
+            debug!(
+                "Difficulty now {:.4}, for {}",
+                super::to_stratum_difficulty(v.difficulty()),
+                format_hashrate(v.hashrate())
+            );
+        }
+        self.save_difficulty();
     }
 
-    async fn write_response<T: Serialize>(&mut self, id: Id, result: Option<T>) -> Result<()> {
-        let res = Response::ok(id, result)?;
-        self.write(&res).await
+    fn worker_label(&self) -> &str {
+        self.worker_name.as_deref().unwrap_or_default()
     }
 
-    async fn write_error_response(&mut self, id: Id, code: u64, message: Box<str>) -> Result<()> {
-        let res = Response::err(id, code, message)?;
-        self.write(&res).await
+    fn count_rejected(&mut self, reason: Reject) {
+        self.rejected_shares += 1;
+        metrics::REJECTED_SHARES
+            .with_label_values(&[self.worker_label(), reason.label()])
+            .inc();
     }
 
-    async fn write<T: Serialize>(&mut self, data: &T) -> Result<()> {
-        let data = serde_json::to_vec(data)?;
-        self.writer.write_all(&data).await?;
-        self.writer.write_all(NEW_LINE.as_ref()).await?;
-        Ok(())
+    /// Print the share on the console, if there is one
+    fn show_share(&self, elapsed: Duration, outcome: Outcome) {
+        if let Some(console) = &self.notifier.console {
+            let difficulty = super::to_stratum_difficulty(self.difficulty);
+            console.share(self.worker_label(), difficulty, elapsed, outcome);
+        }
     }
 
-    async fn run(mut self) -> Result<()> {
+    fn reject(&mut self, id: Id, reason: Reject, message: Box<str>) -> Result<()> {
+        self.count_rejected(reason);
+        self.show_share(
+            self.submit_received.elapsed(),
+            Outcome::Rejected(reason.label()),
+        );
+        self.write_error_response(id, reason.code(), message)
+    }
+
+    async fn run(mut self) {
+        let res = self.serve().await;
+        self.save_difficulty();
+        let summary = format!(
+            "after {}s, {} shares accepted and {} rejected",
+            self.connected.elapsed().as_secs(),
+            self.accepted_shares,
+            self.rejected_shares
+        );
+        match res {
+            Ok(()) => info!(target: SUMMARY, "Connection closed {summary}"),
+            Err(e) => warn!(target: SUMMARY, "Connection closed {summary}: {e}"),
+        }
+        if let (Some(name), Some(v)) = (self.worker_name.take(), &self.vardiff) {
+            self.difficulties.insert(name, v.difficulty());
+        }
+    }
+
+    fn block_found(&self, block: SubmittedBlock) {
+        let effort = self.stats.block_found(block.difficulty);
+        if let Some(accounting) = self.accounting.clone() {
+            let (block, solo) = (block.clone(), self.solo);
+            // The credits of a shared round are read from Redis
+            tokio::spawn(
+                async move {
+                    let round = match solo {
+                        true => accounting.solo_block_found(&block),
+                        false => accounting.block_found(&block).await,
+                    };
+                    info!(
+                        "Credited block {} to {} addresses by {:?}",
+                        round.hash,
+                        round.credits.len(),
+                        round.scheme
+                    );
+                }
+                .in_current_span(),
+            );
+        }
+        let notifier = self.notifier.clone();
+        let report = move |effort| {
+            metrics::BLOCK_EFFORT.observe(effort);
+            notifier.emit(Event::BlockFound {
+                hash: block.hash,
+                daa_score: block.daa_score,
+                difficulty: block.difficulty,
+                effort,
+                worker: block.worker,
+                reward: block.reward,
+                fees: block.fees,
+            });
+        };
+        let shared = match &self.shared {
+            Some(s) => s.clone(),
+            None => return report(effort),
+        };
+        // The round spans the shares of all instances
+        tokio::spawn(async move {
+            match shared.take_round().await {
+                Ok(work) => report(work / block.difficulty.max(1) as f64),
+                Err(e) => {
+                    warn!("Unable to read the shared round: {e}");
+                    report(effort)
+                }
+            }
+        });
+    }
+
+    async fn serve(&mut self) -> Result<()> {
+        let mut retarget = time::interval(Duration::from_secs(5));
+        let handshake = time::sleep(self.handshake_timeout.unwrap_or_default());
+        tokio::pin!(handshake);
         loop {
             tokio::select! {
+                _ = &mut handshake, if self.handshake_timeout.is_some() && !self.state.subscribed() => {
+                    anyhow::bail!("No mining.subscribe within {:?}", self.handshake_timeout.unwrap_or_default());
+                },
+                _ = time::sleep_until(self.notify_due.unwrap_or_else(time::Instant::now)), if self.notify_due.is_some() => {
+                    self.write_template()?;
+                },
+                _ = retarget.tick(), if self.vardiff.is_some() => {
+                    let changed = self.vardiff.as_mut().and_then(|v| v.tick());
+                    if changed.is_some() {
+                        self.retargeted();
+                    }
+                    if changed.is_some() && self.state.subscribed() {
+                        self.write_template()?;
+                    }
+                },
                 res = self.recv.changed() => match res {
                     Err(_) => {
                         // Shutdown
                         break;
                     }
                     Ok(_) => {
-                        if self.subscribed {
-                            self.write_template().await?;
+                        if self.state.subscribed() {
+                            self.write_template()?;
                         }
                     }
                 },
                 item = self.pending_recv.recv() => {
-                    let res = item.expect("channel is always open").into_response()?;
-                    self.write(&res).await?;
+                    let item = item.expect("channel is always open");
+                    self.pending -= 1;
+                    let outcome = match item.error() {
+                        Some(error) => Outcome::BlockRejected(error),
+                        None => Outcome::Block,
+                    };
+                    self.show_share(item.round_trip(), outcome);
+                    match item.accepted() {
+                        Some(block) => self.block_found(block.clone()),
+                        None => self.count_rejected(Reject::BlockRejected),
+                    }
+                    if let Some(res) = item.into_response()? {
+                        self.writer.send(Message::Response(res))?;
+                    }
                 },
+                Some(command) = self.commands.recv() => self.command(command)?,
+                _ = fallback_changed(&mut self.fallback) => self.switch_upstream().await?,
+                relay = next_relay(&mut self.upstream) => match relay {
+                    Ok(Some(relay)) => self.relay(relay)?,
+                    Ok(None) => {}
+                    // The miner starts over on the next connection
+                    Err(e) => anyhow::bail!("Fallback pool failed: {e}"),
+                },
+                e = self.writer.failed() => return Err(e),
                 res = read(&mut self.reader) => match res {
-                    Ok(Some(msg)) => {
-                        match (msg.id, &*msg.method, msg.params) {
-                            (Some(id), "mining.subscribe", _) => {
-                                debug!("Worker subscribed");
-                                self.subscribed = true;
-                                self.write_response(id, Some(true)).await?;
-
-                                self.write_request(
-                                    "set_extranonce",
-                                    Some(json!([hex::encode(&self.worker), 6u64]))
-                                ).await?;
-                                self.write_template().await?;
-                            }
-                            (Some(i), "mining.submit", Some(p)) => {
-                                let (_, id, nonce): (String, String, String) = serde_json::from_value(p)?;
-                                let id = u8::from_str_radix(&id, 16)?;
-                                let nonce = u64::from_str_radix(nonce.trim_start_matches("0x"), 16)?;
-                                if self.jobs.submit(i.clone(), id, nonce, self.pending_send.clone()).await {
-                                    debug!("Submit new block");
-                                }
-                                else {
-                                    debug!("Unable to submit new block");
-                                    self.write_error_response(i, 20, "Unable to submit block".into()).await?;
-                                }
-                            }
-                            _ => {
-                                debug!("Got unknown {}", msg.method);
-                            }
-                        }
+                    Ok(Some(_)) if chaos::close_connection() => {
+                        anyhow::bail!("Chaos: closing connection");
                     }
+                    Ok(Some(Ok(msg))) => self.handle(msg).await?,
+                    Ok(Some(Err(malformed))) => self.malformed(malformed)?,
                     Ok(None) => break,
+                    Err(e) if e.is::<LineTooLong>() => {
+                        // Tell the miner why before closing, the id was cut off
+                        self.write_error_response(Id::Null, 20, "Line too long".into())?;
+                        return Err(e);
+                    }
                     Err(e) => return Err(e),
                 },
             }
         }
         Ok(())
     }
+
+    /// Connect to the fallback pool while it is active, or return to the
+    /// own jobs once it isn't anymore
+    async fn switch_upstream(&mut self) -> Result<()> {
+        let fallback = match &self.fallback {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let active = *fallback.active.borrow() && self.state.subscribed();
+        match (active, self.upstream.is_some()) {
+            (true, false) => {
+                // Miners stay paused if the pool is unreachable as well
+                match Upstream::connect(&fallback.config).await {
+                    Ok(upstream) => {
+                        info!("Mining on the fallback pool");
+                        self.upstream = Some(upstream);
+                    }
+                    Err(e) => warn!("Unable to reach the fallback pool: {e}"),
+                }
+            }
+            (false, true) => {
+                info!("Leaving the fallback pool");
+                self.upstream = None;
+                // Replace the extranonce the pool assigned
+                self.write_extranonce()?;
+                self.write_template()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn relay(&mut self, relay: Relay) -> Result<()> {
+        match relay {
+            Relay::Job(notify, difficulty) => {
+                // Converted like local jobs, the miner speaks its own dialect
+                let notify =
+                    convert_notify(&notify, self.dialect.notify_format).unwrap_or_else(|| {
+                        debug!("Relaying a job of the pool as sent");
+                        notify
+                    });
+                let scaled = difficulty * self.dialect.difficulty_scale;
+                self.writer.send_job(Job {
+                    notify: writer::to_raw(&notify)?,
+                    difficulty,
+                    set_difficulty: self.tiers.set_difficulty(scaled)?,
+                    difficulty_first: self.dialect.difficulty_first,
+                    resend_difficulty: self.dialect.resend_difficulty,
+                })
+            }
+            Relay::Extranonce(extranonce, size) => {
+                let method = self.dialect.extranonce_method.name();
+                let params = json!([extranonce, size]);
+                self.writer.send(Message::Request(method, Some(params)))
+            }
+            Relay::Response(res) => self.writer.send(Message::Response(res)),
+        }
+    }
+
+    fn command(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Close(reason) => anyhow::bail!(reason),
+            Command::SetDifficulty(difficulty) => {
+                info!("Difficulty set by the operator");
+                match &mut self.vardiff {
+                    Some(v) => v.set_difficulty(difficulty),
+                    None => self.fixed_difficulty = Some(difficulty),
+                }
+                self.save_difficulty();
+                if self.state.subscribed() {
+                    self.write_template()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Answer an unparseable line if it has an id, the connection is only
+    /// closed once the ban score is exceeded
+    fn malformed(&mut self, malformed: Malformed) -> Result<()> {
+        warn!("Malformed message: {}", malformed.error);
+        self.bans.report(self.addr.ip(), SecurityEvent::Malformed);
+        self.ban_score += 1;
+        if self.ban_score > MAX_BAN_SCORE {
+            self.bans.ban(self.addr.ip(), "malformed messages");
+            anyhow::bail!("Too many malformed messages");
+        }
+        match malformed.id {
+            Some(id) => {
+                let message = format!("Parse error: {}", malformed.error);
+                self.write_error_response(id, 20, message.into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn handle(&mut self, msg: Request) -> Result<()> {
+        match (msg.id, &*msg.method, msg.params) {
+            (Some(id), "mining.subscribe", p) => self.subscribed(id, p).await,
+            (Some(id), "mining.extranonce.subscribe", _) => {
+                self.write_response(id, Some(true))?;
+                if self.state.subscribed() {
+                    self.write_extranonce()
+                } else {
+                    // Announced after the subscribe response, in the dialect
+                    // of the miner's agent
+                    self.extranonce_requested = true;
+                    Ok(())
+                }
+            }
+            (Some(id), "mining.authorize", p) => self.authorize(id, p).await,
+            (Some(id), "mining.login", p) if self.dialect.mining_login => {
+                self.authorize(id, p).await
+            }
+            (Some(id), "mining.submit", p) => self.submit(id, p.unwrap_or_default()).await,
+            (Some(id), method, _) => {
+                debug!("Got unknown {method}");
+                self.write_error_response(id, 20, format!("Unknown method {method}").into())
+            }
+            (None, method, _) => {
+                debug!("Got unknown notification {method}");
+                Ok(())
+            }
+        }
+    }
+
+    fn subscribe(&mut self, id: Id, params: Option<Value>) -> Result<()> {
+        self.state = match self.state {
+            State::Connected => State::Subscribed,
+            State::Authorized => State::Ready,
+            State::Subscribed | State::Ready => {
+                return self.write_error_response(id, 20, "Already subscribed".into());
+            }
+        };
+        let subscribe = Subscribe::parse(params.as_ref());
+        if let Some(name) = &subscribe.worker {
+            if self.foreign_worker(name).is_none() {
+                debug!("Worker {name} named in the subscription");
+                self.set_worker_name(name);
+                // Nothing left to authorize without credentials
+                if self.auth.is_none() {
+                    self.state = State::Ready;
+                }
+            }
+        }
+        if let Some(agent) = subscribe.agent.as_deref() {
+            Span::current().record("agent", &agent);
+            self.registration.update(|c| c.agent = Some(agent.into()));
+            if let Some(dialect) = self.dialects.detect(agent) {
+                debug!("Speaking the dialect of {agent}");
+                self.writer.set_jsonrpc2(dialect.jsonrpc2);
+                self.dialect = dialect;
+            }
+            if let Some(interval) = self.notify_limits.for_agent(agent) {
+                debug!("Sending {agent} at most one job every {interval:?}");
+                self.notify_interval = Some(interval);
+            }
+            // Unless the worker already resumed its own difficulty
+            let resumed = self
+                .worker_name
+                .as_deref()
+                .and_then(|n| self.difficulties.get(n))
+                .is_some();
+            if let (Some(v), false) = (&mut self.vardiff, resumed) {
+                if v.start_for_agent(agent) {
+                    debug!("Starting at the difficulty of {agent}");
+                }
+            }
+        }
+        debug!("Worker subscribed");
+        let extranonce = self.worker.hex();
+        match self.dialect.subscribe_response {
+            SubscribeResponse::Bool => self.write_response(id, Some(true))?,
+            SubscribeResponse::Standard => {
+                let result = json!([
+                    [["mining.notify", extranonce]],
+                    extranonce,
+                    self.worker.extranonce2_size()
+                ]);
+                self.extranonce_sent = true;
+                self.write_response(id, Some(result))?
+            }
+            SubscribeResponse::EthereumStratum => {
+                self.write_response(id, Some(json!([true, "EthereumStratum/1.0.0"])))?
+            }
+        }
+
+        if self.dialect.extranonce_unsolicited || self.extranonce_requested {
+            self.write_extranonce()?;
+        }
+        self.write_template()
+    }
+
+    /// Complete the subscription, moving to the fallback pool if it is
+    /// active
+    async fn subscribed(&mut self, id: Id, params: Option<Value>) -> Result<()> {
+        self.subscribe(id, params)?;
+        self.switch_upstream().await
+    }
+
+    async fn authorize(&mut self, id: Id, params: Option<Value>) -> Result<()> {
+        let authorize = Authorize::parse(params.as_ref());
+        if let Some(auth) = &self.auth {
+            let worker = authorize.worker.as_deref().unwrap_or_default();
+            let password = authorize.password.as_deref().unwrap_or_default();
+            let allowed = match auth.check(worker, password, self.addr.ip()).await {
+                Ok(allowed) => allowed,
+                Err(e) => {
+                    warn!("Unable to check the credentials of {worker}: {e}");
+                    false
+                }
+            };
+            if !allowed {
+                info!("Refused worker {worker:?}");
+                self.bans
+                    .report(self.addr.ip(), SecurityEvent::AuthFailure { worker });
+                self.ban_score += 1;
+                if self.ban_score > MAX_BAN_SCORE {
+                    self.bans.ban(self.addr.ip(), "refused logins");
+                    anyhow::bail!("Too many refused logins");
+                }
+                return self.write_error_response(id, 24, "Unauthorized worker".into());
+            }
+        }
+        let worker = authorize.worker.as_deref().unwrap_or_default();
+        if let Some(message) = self.foreign_worker(worker) {
+            return self.write_error_response(id, 24, message.into());
+        }
+        self.state = match self.state {
+            State::Connected | State::Authorized => State::Authorized,
+            State::Subscribed | State::Ready => State::Ready,
+        };
+        if let Some(name) = authorize.worker {
+            debug!("Worker {name} authorized");
+            if self.set_worker_name(&name) && self.state.subscribed() {
+                self.write_template()?;
+            }
+        }
+        self.write_response(id, Some(true))
+    }
+
+    /// Why a worker is refused whose address is of another network, rewards
+    /// of which couldn't be paid
+    fn foreign_worker(&self, worker: &str) -> Option<String> {
+        let accounting = self.accounting.as_ref()?;
+        let address = accounting.foreign_address(worker)?;
+        let prefix = accounting.prefix();
+        info!("Refused worker {worker:?} with an address of another network than {prefix}");
+        Some(format!(
+            "Address {address} is not a {prefix}: address of the pool's network"
+        ))
+    }
+
+    /// Put the extranonce in front of nonces the dialect submits without it
+    fn full_nonce(&self, nonce: u64) -> u64 {
+        match self.dialect.short_nonce && nonce >> self.worker.shift() == 0 {
+            true => self.worker.apply(nonce),
+            false => nonce,
+        }
+    }
+
+    async fn submit(&mut self, id: Id, params: Value) -> Result<()> {
+        self.submit_received = Instant::now();
+        match self.state {
+            State::Connected | State::Authorized => {
+                return self.write_error_response(id, 25, "Not subscribed".into());
+            }
+            // Only credentials make authorizing a must
+            State::Subscribed if self.auth.is_some() => {
+                return self.write_error_response(id, 24, "Unauthorized worker".into());
+            }
+            State::Subscribed | State::Ready => {}
+        }
+        if let Some(upstream) = &mut self.upstream {
+            debug!("Forwarding share to the fallback pool");
+            return upstream.submit(id, params).await;
+        }
+        if !self.online.load(Ordering::Relaxed) {
+            return self.reject(id, Reject::NodeOffline, "Pool paused, node offline".into());
+        }
+        let mut submit = match Submit::parse(params, &self.dialect) {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("Malformed submit: {e}");
+                let message = format!("Malformed share: {e}");
+                return self.reject(id, Reject::Malformed, message.into());
+            }
+        };
+        let worker = self.worker;
+        submit.nonce = self.full_nonce(submit.nonce);
+        if let Some(decimal) = submit.decimal_nonce.map(|n| self.full_nonce(n)) {
+            // Only the right reading carries the extranonce
+            if self.extranonce_sent && !worker.matches(submit.nonce) && worker.matches(decimal) {
+                debug!("Reading the nonce as decimal");
+                submit.nonce = decimal;
+            }
+        }
+        if self.extranonce_sent && !worker.matches(submit.nonce) {
+            match self.dialect.nonce_prefix {
+                NoncePrefix::Strict => {
+                    debug!("Rejected share with foreign extranonce");
+                    return self.reject(id, Reject::BadExtranonce, "Invalid extranonce".into());
+                }
+                NoncePrefix::Rewrite => {
+                    debug!("Writing the extranonce over the nonce");
+                    submit.nonce = worker.apply(submit.nonce);
+                }
+                NoncePrefix::Off => {}
+            }
+        }
+        // This share was still mined at the current difficulty
+        let assigned = self.difficulty;
+        let target = pow::target_from_difficulty(assigned);
+        if self.state == State::Subscribed {
+            if let Some(message) = self.foreign_worker(&submit.worker) {
+                return self.write_error_response(id, 24, message.into());
+            }
+        }
+        if self.set_worker_name(&submit.worker) {
+            self.write_template()?;
+        }
+        let share = Share {
+            id: id.clone(),
+            job_id: submit.job_id,
+            nonce: submit.nonce,
+            timestamp: submit.timestamp,
+            target,
+            worker: &submit.worker,
+        };
+        let result = self
+            .jobs
+            .submit(share, self.pending, self.pending_send.clone())
+            .await;
+        if let SubmitResult::Share(d) | SubmitResult::LowDifficulty(d) = result {
+            self.share_difficulty
+                .observe(d as f64 / assigned.max(1) as f64);
+        }
+        let accepted = match result {
+            SubmitResult::Block => {
+                debug!("Submit new block");
+                self.pending += 1;
+                true
+            }
+            SubmitResult::Share(_) => {
+                debug!("Accepted share");
+                self.write_response(id, Some(true))?;
+                self.show_share(self.submit_received.elapsed(), Outcome::Accepted);
+                true
+            }
+            SubmitResult::LowDifficulty(_) => {
+                debug!("Rejected low difficulty share");
+                self.reject(id, Reject::LowDifficulty, "Low difficulty share".into())?;
+                false
+            }
+            SubmitResult::Stale => {
+                debug!("Rejected stale share");
+                self.reject(id, Reject::Stale, "Stale job".into())?;
+                false
+            }
+            SubmitResult::Duplicate => {
+                debug!("Rejected duplicate share");
+                self.reject(id, Reject::Duplicate, "Duplicate share".into())?;
+                false
+            }
+            SubmitResult::BadTimestamp => {
+                debug!("Rejected share with rolled timestamp out of bounds");
+                self.reject(id, Reject::BadTimestamp, "Invalid timestamp".into())?;
+                false
+            }
+            SubmitResult::Answered => {
+                debug!("Submit new block with {} blocks pending", self.pending);
+                self.pending += 1;
+                self.write_response(id, Some(true))?;
+                true
+            }
+            SubmitResult::Invalid => {
+                debug!("Unable to submit new block");
+                self.reject(id, Reject::Invalid, "Unable to submit block".into())?;
+                false
+            }
+        };
+        if accepted {
+            self.accepted_shares += 1;
+            self.stats.add_share(self.worker_name.as_deref(), assigned);
+            if let Some(shared) = &self.shared {
+                shared.add_share(self.worker_name.as_deref(), assigned);
+            }
+            // Solo miners don't take part in the pool's rounds
+            match &self.accounting {
+                Some(accounting) if !self.solo => {
+                    accounting.add_share(self.worker_name.as_deref(), assigned)
+                }
+                _ => {}
+            }
+            metrics::ACCEPTED_SHARES
+                .with_label_values(&[self.worker_label()])
+                .inc();
+        }
+        let changed = match &mut self.vardiff {
+            Some(v) if accepted => v.on_share(),
+            _ => None,
+        };
+        if changed.is_some() {
+            self.retargeted();
+            self.write_template()?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a share was rejected
+#[derive(Clone, Copy, Debug)]
+enum Reject {
+    Malformed,
+    BadExtranonce,
+    Stale,
+    Duplicate,
+    LowDifficulty,
+    /// The rolled timestamp is outside the consensus bounds
+    BadTimestamp,
+    /// Kaspad refused the block
+    BlockRejected,
+    NodeOffline,
+    /// The PoW couldn't be checked or the template has no header
+    Invalid,
+}
+
+impl Reject {
+    fn label(self) -> &'static str {
+        match self {
+            Reject::Malformed => "malformed",
+            Reject::BadExtranonce => "bad_extranonce",
+            Reject::Stale => "stale",
+            Reject::Duplicate => "duplicate",
+            Reject::LowDifficulty => "low_difficulty",
+            Reject::BadTimestamp => "bad_timestamp",
+            Reject::BlockRejected => "block_rejected",
+            Reject::NodeOffline => "node_offline",
+            Reject::Invalid => "invalid",
+        }
+    }
+
+    fn code(self) -> u64 {
+        match self {
+            Reject::Stale => 21,
+            Reject::Duplicate => 22,
+            Reject::LowDifficulty => 23,
+            Reject::Malformed
+            | Reject::BadExtranonce
+            | Reject::BadTimestamp
+            | Reject::BlockRejected
+            | Reject::NodeOffline
+            | Reject::Invalid => 20,
+        }
+    }
+}
+
+/// Activate the fallback pool once kaspad was unreachable for `after`, and
+/// leave it with the first template after kaspad returned
+async fn watch_node(online: Arc<AtomicBool>, after: Duration, active: watch::Sender<bool>) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut online_at = time::Instant::now();
+    loop {
+        interval.tick().await;
+        let now = time::Instant::now();
+        if online.load(Ordering::Relaxed) {
+            online_at = now;
+            if *active.borrow() {
+                info!("Kaspad is back, moving miners off the fallback pool");
+                active.send_replace(false);
+                metrics::FALLBACK_ACTIVE.set(0);
+            }
+        } else if now - online_at >= after && !*active.borrow() {
+            warn!("Kaspad down for {after:?}, moving miners to the fallback pool");
+            active.send_replace(true);
+            metrics::FALLBACK_ACTIVE.set(1);
+        }
+    }
 }
 
-async fn read(r: &mut Lines<BufReader<ReadHalf<'_>>>) -> Result<Option<Request>> {
+async fn watch_workers(stats: Stats, threshold: Duration, notifier: Notifier) {
+    let mut interval = time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        for event in stats.check_workers(threshold) {
+            notifier.emit(event);
+        }
+    }
+}
+
+/// Resolves when the fallback pool is activated or deactivated, never
+/// without one
+async fn fallback_changed(fallback: &mut Option<Fallback>) {
+    if let Some(f) = fallback {
+        if f.active.changed().await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+async fn next_relay(upstream: &mut Option<Upstream>) -> Result<Option<Relay>> {
+    match upstream {
+        Some(u) => u.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A line that isn't a valid request
+struct Malformed {
+    /// The request id if the line is JSON with a usable id
+    id: Option<Id>,
+    error: String,
+}
+
+/// Box the halves of a connection, so plain and TLS ones are served alike
+fn split<R, W>((reader, writer): (R, W)) -> (ConnReader, Box<dyn AsyncWrite + Send + Unpin>)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    (Box::new(reader), Box::new(writer))
+}
+
+/// Next request, only reading failures are errors
+async fn read(r: &mut LineReader<ConnReader>) -> Result<Option<Result<Request, Malformed>>> {
     let line = match r.next_line().await? {
         Some(l) => l,
         None => return Ok(None),
     };
-    Ok(Some(serde_json::from_str(&line)?))
+    Ok(Some(serde_json::from_slice(&line).map_err(|e| Malformed {
+        id: recover_id(&line),
+        error: e.to_string(),
+    })))
+}
+
+fn recover_id(line: &[u8]) -> Option<Id> {
+    #[derive(Deserialize)]
+    struct WithId {
+        id: Option<Id>,
+    }
+    serde_json::from_slice::<WithId>(line).ok()?.id
+}
+
+#[cfg(test)]
+mod test {
+    use super::{partition, recover_id, Extranonce};
+    use crate::stratum::Id;
+
+    #[test]
+    fn recovers_ids() {
+        let id = |line: &[u8]| match recover_id(line) {
+            Some(Id::Number(n)) => Some(n.to_string()),
+            Some(Id::Text(t)) => Some(t.into()),
+            Some(Id::Null) | None => None,
+        };
+        assert_eq!(id(br#"{"id":7,"method":5}"#), Some("7".into()));
+        assert_eq!(id(br#"{"id":"a","params":{"#), None);
+        assert_eq!(id(b"{\"id\":\"a\",\"method\":\"\xff\"}"), Some("a".into()));
+        assert_eq!(id(br#"{"id":null,"method":5}"#), None);
+        assert_eq!(id(b"garbage"), None);
+    }
+
+    #[test]
+    fn sized_extranonces() {
+        let one = Extranonce {
+            value: 0x2a,
+            size: 1,
+        };
+        assert_eq!(one.hex(), "2a");
+        assert_eq!(one.extranonce2_size(), 7);
+        assert!(one.matches(0x2a00_0000_0000_0001));
+        assert!(!one.matches(0x2b00_0000_0000_0001));
+        assert_eq!(one.apply(0xffff_0000_0000_0001), 0x2aff_0000_0000_0001);
+        let three = Extranonce {
+            value: 0x01_0000,
+            size: 3,
+        };
+        assert_eq!(three.hex(), "010000");
+        assert_eq!(three.apply(u64::MAX), 0x0100_00ff_ffff_ffff);
+    }
+
+    #[test]
+    fn partitions_extranonces() {
+        assert_eq!(partition(0, 1), (0, 0x10000));
+        assert_eq!(partition(1, 4), (0x4000, 0x4000));
+        // The remainder of uneven splits is left unused
+        let (start, size) = partition(2, 3);
+        assert_eq!(start + size, 0xffff);
+    }
 }
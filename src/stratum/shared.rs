@@ -0,0 +1,501 @@
+use super::accounting::Scheme;
+use super::security::{SecurityEvent, SecurityLog};
+use crate::redis::{Redis, Reply};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::warn;
+
+/// How long misbehaving addresses are refused the first time, doubling
+/// with every ban that follows within [`OFFENSE_MEMORY`]
+pub const BAN_TIME: Duration = Duration::from_secs(600);
+/// Longest ban of a repeat offender
+pub const MAX_BAN_TIME: Duration = Duration::from_secs(86400);
+/// Addresses without a ban for this long start over at [`BAN_TIME`]
+const OFFENSE_MEMORY: Duration = Duration::from_secs(86400);
+/// Ban lookups slower than this let the connection through
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+/// The holder of a lease, such as the primary of a failover group, is
+/// replaced this long after it stopped renewing it
+const LEASE_TTL: Duration = Duration::from_secs(5);
+const LEASE_RENEW: Duration = Duration::from_secs(1);
+/// Renews the lease if `ARGV[1]` holds it, otherwise takes it if it's free
+const LEASE_SCRIPT: &str = "\
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('pexpire', KEYS[1], ARGV[2])
+end
+if redis.call('set', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0";
+/// Appends a `difficulty address` share to the PPLNS window `KEYS[1]`, adds
+/// it to the address' work in `KEYS[2]` and the total in `KEYS[3]`, then
+/// drops the oldest shares not needed to fill the window of `ARGV[3]`
+const PPLNS_SCRIPT: &str = "\
+redis.call('rpush', KEYS[1], ARGV[2] .. ' ' .. ARGV[1])
+redis.call('hincrbyfloat', KEYS[2], ARGV[1], ARGV[2])
+local total = tonumber(redis.call('incrbyfloat', KEYS[3], ARGV[2]))
+local window = tonumber(ARGV[3])
+while window > 0 do
+    local oldest = redis.call('lindex', KEYS[1], 0)
+    if not oldest then break end
+    local space = string.find(oldest, ' ')
+    local work = tonumber(string.sub(oldest, 1, space - 1))
+    if total - work < window then break end
+    local address = string.sub(oldest, space + 1)
+    redis.call('lpop', KEYS[1])
+    total = tonumber(redis.call('incrbyfloat', KEYS[3], -work))
+    if tonumber(redis.call('hincrbyfloat', KEYS[2], address, -work)) < 1 then
+        redis.call('hdel', KEYS[2], address)
+    end
+end
+return 0";
+/// Returns and clears the work per address of the round `KEYS[1]`
+const TAKE_SCRIPT: &str = "\
+local credits = redis.call('hgetall', KEYS[1])
+redis.call('del', KEYS[1])
+return credits";
+
+/// Pool state kept in Redis, so several instances behind a load balancer
+/// share the worker registry, bans, the work of the running round and the
+/// credits the found blocks are split by
+#[derive(Clone)]
+pub struct SharedState {
+    redis: Redis,
+    prefix: String,
+}
+
+impl SharedState {
+    /// Connect to `redis://[[user]:password@]host[:port][/db]`, keys are
+    /// prefixed with `prefix:`
+    pub fn new(url: &str, prefix: &str) -> Result<Self> {
+        Ok(SharedState {
+            redis: Redis::new(url)?,
+            prefix: prefix.trim_end_matches(':').into(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{name}", self.prefix)
+    }
+
+    /// Count an accepted share toward the round and record when the worker
+    /// was last seen
+    pub fn add_share(&self, worker: Option<&str>, difficulty: u64) {
+        // Floats, the work of a long round can exceed Redis' 64 bit integers
+        self.redis.send([
+            "INCRBYFLOAT",
+            &self.key("round_work"),
+            &difficulty.to_string(),
+        ]);
+        if let Some(worker) = worker {
+            let now = unix_secs().to_string();
+            self.redis
+                .send(["HSET", &self.key("workers"), worker, &now]);
+        }
+    }
+
+    /// Credit a share to its payout address, in the PPLNS window or the
+    /// PROP round all instances add to. `window` is the work the PPLNS
+    /// window holds.
+    pub fn add_credit(&self, scheme: Scheme, address: &str, difficulty: u64, window: u128) {
+        match scheme {
+            Scheme::Solo => {}
+            Scheme::Prop => self.redis.send([
+                "HINCRBYFLOAT",
+                &self.key("prop:credits"),
+                address,
+                &difficulty.to_string(),
+            ]),
+            Scheme::Pplns => self.redis.send([
+                "EVAL",
+                PPLNS_SCRIPT,
+                "3",
+                &self.key("pplns:shares"),
+                &self.key("pplns:credits"),
+                &self.key("pplns:work"),
+                address,
+                &difficulty.to_string(),
+                &window.to_string(),
+            ]),
+        }
+    }
+
+    /// Work per address a found block is split by. The PPLNS window carries
+    /// over to the next block, the PROP round starts over.
+    pub async fn credits(&self, scheme: Scheme) -> Result<HashMap<String, u128>> {
+        let reply = match scheme {
+            Scheme::Solo => return Ok(HashMap::new()),
+            Scheme::Prop => {
+                let key = self.key("prop:credits");
+                self.redis.query(["EVAL", TAKE_SCRIPT, "1", &key]).await?
+            }
+            Scheme::Pplns => {
+                let key = self.key("pplns:credits");
+                self.redis.query(["HGETALL", &key]).await?
+            }
+        };
+        parse_credits(reply)
+    }
+
+    /// End the round, returning the work all instances accumulated in it
+    pub async fn take_round(&self) -> Result<f64> {
+        match self
+            .redis
+            .query(["GETDEL", &self.key("round_work")])
+            .await?
+        {
+            Reply::Nil => Ok(0.0),
+            Reply::Data(work) => Ok(String::from_utf8(work)?.parse()?),
+            reply => bail!("Unexpected round work {reply:?}"),
+        }
+    }
+
+    /// Mirror a worker's difficulty, so it is resumed by whichever instance
+    /// it connects to next
+    pub fn save_difficulty(&self, worker: &str, difficulty: u64) {
+        let value = format!("{difficulty}:{}", unix_secs());
+        self.redis
+            .send(["HSET", &self.key("difficulties"), worker, &value]);
+    }
+
+    /// Mirrored difficulties with the time since they were saved
+    pub async fn difficulties(&self) -> Result<Vec<(String, u64, Duration)>> {
+        let items = match self
+            .redis
+            .query(["HGETALL", &self.key("difficulties")])
+            .await?
+        {
+            Reply::Array(items) => items,
+            reply => bail!("Unexpected difficulties {reply:?}"),
+        };
+        let now = unix_secs();
+        let mut out = vec![];
+        for pair in items.chunks_exact(2) {
+            let (Reply::Data(worker), Reply::Data(value)) = (&pair[0], &pair[1]) else {
+                continue;
+            };
+            let value = String::from_utf8_lossy(value);
+            let parsed = value
+                .split_once(':')
+                .and_then(|(d, t)| Some((d.parse().ok()?, t.parse::<u64>().ok()?)));
+            if let Some((difficulty, saved)) = parsed {
+                let age = Duration::from_secs(now.saturating_sub(saved));
+                out.push((String::from_utf8_lossy(worker).into(), difficulty, age));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Take or renew the lease `key` for `id`, returning whether it holds it
+    async fn lease(&self, key: &str, id: &str) -> Result<bool> {
+        let ttl = LEASE_TTL.as_millis().to_string();
+        match self
+            .redis
+            .query(["EVAL", LEASE_SCRIPT, "1", key, id, &ttl])
+            .await?
+        {
+            Reply::Int(n) => Ok(n > 0),
+            reply => bail!("Unexpected lease reply {reply:?}"),
+        }
+    }
+
+    fn ban(&self, ip: IpAddr, duration: Duration) {
+        let key = self.key(&format!("ban:{ip}"));
+        let secs = duration.as_secs().to_string();
+        self.redis.send(["SET", key.as_str(), "1", "EX", &secs]);
+    }
+
+    async fn is_banned(&self, ip: IpAddr) -> Result<bool> {
+        let key = self.key(&format!("ban:{ip}"));
+        match self.redis.query(["EXISTS", &key]).await? {
+            Reply::Int(n) => Ok(n > 0),
+            reply => bail!("Unexpected ban lookup {reply:?}"),
+        }
+    }
+}
+
+/// `HGETALL` pairs of address and work, stored as floats
+fn parse_credits(reply: Reply) -> Result<HashMap<String, u128>> {
+    let items = match reply {
+        Reply::Array(items) => items,
+        reply => bail!("Unexpected credits {reply:?}"),
+    };
+    let mut credits = HashMap::new();
+    for pair in items.chunks_exact(2) {
+        let (Reply::Data(address), Reply::Data(work)) = (&pair[0], &pair[1]) else {
+            continue;
+        };
+        let work: f64 = String::from_utf8_lossy(work).parse()?;
+        if work >= 1.0 {
+            credits.insert(
+                String::from_utf8_lossy(address).into(),
+                work.round() as u128,
+            );
+        }
+    }
+    Ok(credits)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Key in the shared state held by one instance at a time, such as the
+/// failover lease of the primary
+#[derive(Clone)]
+pub struct Lease {
+    shared: SharedState,
+    key: String,
+    id: String,
+}
+
+impl Lease {
+    pub fn new(shared: SharedState, name: &str) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        Lease {
+            key: shared.key(name),
+            shared,
+            id: format!("{}-{started}", std::process::id()),
+        }
+    }
+
+    /// Take or renew the lease, returning whether this instance holds it.
+    /// Gives up once the next renewal is due, Redis may be unreachable
+    pub async fn try_acquire(&self) -> Result<bool> {
+        match time::timeout(LEASE_RENEW, self.shared.lease(&self.key, &self.id)).await {
+            Ok(res) => res,
+            Err(_) => bail!("Redis didn't answer within {LEASE_RENEW:?}"),
+        }
+    }
+
+    /// Wait until this instance holds the lease
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire().await {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => warn!("Unable to take the {} lease: {e}", self.key),
+            }
+            time::sleep(LEASE_RENEW).await;
+        }
+    }
+
+    /// Keep renewing the lease, returning once it may have passed to another
+    /// instance
+    pub async fn hold(&self) {
+        let mut renewed = Instant::now();
+        let mut interval = time::interval(LEASE_RENEW);
+        loop {
+            interval.tick().await;
+            // The expiry counts from when Redis ran the renewal, at the
+            // earliest when it was sent
+            let sent = Instant::now();
+            match self.try_acquire().await {
+                Ok(true) => renewed = sent,
+                Ok(false) => return,
+                Err(e) => warn!("Unable to renew the {} lease: {e}", self.key),
+            }
+            if renewed.elapsed() >= LEASE_TTL {
+                warn!(
+                    "The {} lease wasn't renewed for {LEASE_TTL:?}, stepping down",
+                    self.key
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Claim the first free of `count` extranonce partitions, waiting while all
+/// of them are held by other instances
+pub async fn claim_partition(shared: &SharedState, count: u16) -> (u16, Lease) {
+    loop {
+        for index in 0..count {
+            let lease = Lease::new(shared.clone(), &format!("partition:{index}"));
+            match lease.try_acquire().await {
+                Ok(true) => return (index, lease),
+                Ok(false) => {}
+                Err(e) => warn!("Unable to claim an extranonce partition: {e}"),
+            }
+        }
+        warn!("All {count} extranonce partitions are taken, retrying");
+        time::sleep(LEASE_RENEW).await;
+    }
+}
+
+/// Addresses refused for misbehaving, shared with the other instances when
+/// there is shared state
+#[derive(Clone, Default)]
+pub struct Bans {
+    local: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    /// Bans in a row and when the last one started, kept by each instance
+    offenses: Arc<Mutex<HashMap<IpAddr, (u32, Instant)>>>,
+    shared: Option<SharedState>,
+    security: Option<SecurityLog>,
+}
+
+impl Bans {
+    pub fn new(shared: Option<SharedState>, security: Option<SecurityLog>) -> Self {
+        Bans {
+            shared,
+            security,
+            ..Default::default()
+        }
+    }
+
+    /// Refuse the address for a time growing with its previous bans,
+    /// returning it
+    pub fn ban(&self, ip: IpAddr, reason: &str) -> Duration {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let offense = {
+            let mut offenses = self.offenses.lock().unwrap();
+            offenses.retain(|_, (_, last)| now.duration_since(*last) < OFFENSE_MEMORY);
+            let (count, last) = offenses.entry(ip).or_insert((0, now));
+            *count += 1;
+            *last = now;
+            *count
+        };
+        let duration = ban_time(offense);
+        self.local.lock().unwrap().insert(ip, now + duration);
+        if let Some(shared) = &self.shared {
+            shared.ban(ip, duration);
+        }
+        self.report(
+            ip,
+            SecurityEvent::Banned {
+                secs: duration.as_secs(),
+                offense,
+                reason,
+            },
+        );
+        duration
+    }
+
+    /// Record abuse in the security log, if there is one
+    pub fn report(&self, ip: IpAddr, event: SecurityEvent) {
+        if let Some(security) = &self.security {
+            security.record(ip, event);
+        }
+    }
+
+    pub async fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        {
+            let mut local = self.local.lock().unwrap();
+            let now = Instant::now();
+            local.retain(|_, until| *until > now);
+            if local.contains_key(&ip) {
+                return true;
+            }
+        }
+        let shared = match &self.shared {
+            Some(s) => s,
+            None => return false,
+        };
+        match time::timeout(LOOKUP_TIMEOUT, shared.is_banned(ip)).await {
+            Ok(Ok(banned)) => banned,
+            Ok(Err(e)) => {
+                warn!("Unable to look up ban: {e}");
+                false
+            }
+            Err(_) => {
+                warn!("Ban lookup timed out");
+                false
+            }
+        }
+    }
+}
+
+/// [`BAN_TIME`] doubled for every offense after the first, up to
+/// [`MAX_BAN_TIME`]
+fn ban_time(offense: u32) -> Duration {
+    let factor = 1u32
+        .checked_shl(offense.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    BAN_TIME.saturating_mul(factor).min(MAX_BAN_TIME)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ban_time, parse_credits, Bans, Lease, SharedState, BAN_TIME, LEASE_TTL, MAX_BAN_TIME,
+    };
+    use crate::redis::Reply;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn lease_expires_while_redis_hangs() {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let shared = SharedState::new(&url, "test").unwrap();
+        let lease = Lease::new(shared, "primary");
+        assert!(lease.try_acquire().await.is_err());
+
+        let start = Instant::now();
+        time::timeout(LEASE_TTL + Duration::from_secs(2), lease.hold())
+            .await
+            .expect("primary kept the lease");
+        assert!(start.elapsed() >= LEASE_TTL);
+    }
+
+    #[test]
+    fn parses_credits() {
+        let data = |s: &str| Reply::Data(s.as_bytes().to_vec());
+        let reply = Reply::Array(vec![
+            data("kaspa:qqa"),
+            data("1.5e+18"),
+            data("kaspa:qqb"),
+            data("300"),
+            // Left over from float rounding once the last share went
+            data("kaspa:qqc"),
+            data("0.000001"),
+        ]);
+        let credits = parse_credits(reply).unwrap();
+        assert_eq!(credits.len(), 2);
+        assert_eq!(credits["kaspa:qqa"], 1_500_000_000_000_000_000);
+        assert_eq!(credits["kaspa:qqb"], 300);
+        assert!(parse_credits(Reply::Nil).is_err());
+    }
+
+    #[tokio::test]
+    async fn local_bans() {
+        let bans = Bans::default();
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(!bans.is_banned(ip).await);
+        assert_eq!(bans.ban(ip, "test"), BAN_TIME);
+        assert!(bans.is_banned(ip).await);
+        assert!(bans.is_banned("::ffff:10.0.0.1".parse().unwrap()).await);
+        assert!(!bans.is_banned("10.0.0.2".parse().unwrap()).await);
+        // Repeat offenders are banned longer
+        assert_eq!(bans.ban(ip, "test"), BAN_TIME * 2);
+        assert_eq!(bans.ban(ip, "test"), BAN_TIME * 4);
+        assert_eq!(bans.ban("10.0.0.2".parse().unwrap(), "test"), BAN_TIME);
+    }
+
+    #[test]
+    fn escalates_ban_time() {
+        assert_eq!(ban_time(1), Duration::from_secs(600));
+        assert_eq!(ban_time(2), Duration::from_secs(1200));
+        assert_eq!(ban_time(8), Duration::from_secs(76800));
+        assert_eq!(ban_time(9), MAX_BAN_TIME);
+        assert_eq!(ban_time(40), MAX_BAN_TIME);
+    }
+}
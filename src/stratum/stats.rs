@@ -0,0 +1,603 @@
+use super::SOMPI_PER_KAS;
+use crate::events::Event;
+use crate::kaspad::BlockReward;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Found blocks kept in memory
+const MAX_BLOCKS: usize = 100;
+/// Accepted shares kept in memory for the API
+const MAX_SHARES: usize = 1000;
+/// Shares the pool hashrate is estimated from
+const HASHRATE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Spans of the per-worker share windows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Window {
+    FiveMinutes,
+    Hour,
+    Day,
+}
+
+impl Window {
+    pub const ALL: [Window; 3] = [Window::FiveMinutes, Window::Hour, Window::Day];
+
+    pub fn span(self) -> Duration {
+        self.bucket() * self.buckets() as u32
+    }
+
+    /// Resolution of the window
+    pub fn bucket(self) -> Duration {
+        Duration::from_secs(match self {
+            Window::FiveMinutes => 10,
+            Window::Hour => 60,
+            Window::Day => 900,
+        })
+    }
+
+    fn buckets(self) -> usize {
+        match self {
+            Window::FiveMinutes => 30,
+            Window::Hour => 60,
+            Window::Day => 96,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Window::FiveMinutes => "5m",
+            Window::Hour => "1h",
+            Window::Day => "24h",
+        }
+    }
+}
+
+/// Share difficulty summed per bucket of a sliding window, in a ring that
+/// takes the same memory however many shares arrive
+#[derive(Clone)]
+struct ShareWindow {
+    bucket: u64,
+    sums: Box<[u128]>,
+    /// Number of the bucket holding the latest share
+    latest: u64,
+}
+
+impl ShareWindow {
+    fn new(window: Window) -> Self {
+        ShareWindow {
+            bucket: window.bucket().as_secs(),
+            sums: vec![0; window.buckets()].into_boxed_slice(),
+            latest: 0,
+        }
+    }
+
+    /// Add a share `secs` seconds after the start of the stats
+    fn add(&mut self, secs: u64, difficulty: u64) {
+        let len = self.sums.len() as u64;
+        let number = (secs / self.bucket).max(self.latest);
+        // Buckets skipped since the latest share are stale
+        for stale in (self.latest + 1..=number).take(self.sums.len()) {
+            self.sums[(stale % len) as usize] = 0;
+        }
+        self.latest = number;
+        self.sums[(number % len) as usize] += difficulty as u128;
+    }
+
+    /// Sums of the buckets at `secs`, oldest first
+    fn series(&self, secs: u64) -> Vec<u128> {
+        let len = self.sums.len() as u64;
+        let now = secs / self.bucket;
+        (0..len)
+            .rev()
+            .map(|age| match now.checked_sub(age) {
+                Some(number) if number <= self.latest && self.latest - number < len => {
+                    self.sums[(number % len) as usize]
+                }
+                _ => 0,
+            })
+            .collect()
+    }
+
+    /// Hashes per second over the window at `secs`, or over the time since
+    /// the start if shorter
+    fn hashrate(&self, secs: u64) -> f64 {
+        let work: u128 = self.series(secs).iter().sum();
+        let span = self.sums.len() as u64 * self.bucket;
+        work as f64 / span.min(secs + 1) as f64
+    }
+}
+
+/// Hashes per second of a worker over each window
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Hashrates {
+    #[serde(rename = "5m")]
+    pub five_minutes: f64,
+    #[serde(rename = "1h")]
+    pub hour: f64,
+    #[serde(rename = "24h")]
+    pub day: f64,
+}
+
+/// An accepted share
+#[derive(Clone, Debug, Serialize)]
+pub struct Share {
+    /// Seconds since the epoch
+    pub time: u64,
+    pub worker: Option<String>,
+    pub difficulty: u64,
+}
+
+/// Hashrate of a bucket for charts
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChartPoint {
+    /// Start of the bucket in seconds since the epoch
+    pub time: u64,
+    pub hashrate: f64,
+}
+
+#[derive(Clone)]
+pub struct FoundBlock {
+    pub time: SystemTime,
+    pub difficulty: u64,
+    /// Share difficulty accumulated during the round relative to the
+    /// block's network difficulty
+    pub effort: f64,
+}
+
+struct Worker {
+    last_share: Instant,
+    /// Time of the last share before the worker went silent
+    offline_since: Option<Instant>,
+    /// Indexed like [`Window::ALL`]
+    windows: [ShareWindow; 3],
+}
+
+#[derive(Default)]
+struct StatsInner {
+    /// Share difficulty accepted since the last block
+    round_work: u128,
+    blocks_found: u64,
+    /// Latest found blocks
+    blocks: VecDeque<FoundBlock>,
+    workers: HashMap<String, Worker>,
+    /// Time and difficulty of the shares within [`HASHRATE_WINDOW`]
+    recent: VecDeque<(Instant, u64)>,
+    /// DAA score and arrival of the latest template
+    template: Option<(u64, Instant)>,
+    /// Of the latest template
+    reward: BlockReward,
+    /// First share, the share windows count from it
+    start: Option<Instant>,
+    /// Latest shares, bounded by [`MAX_SHARES`]
+    shares: VecDeque<Share>,
+    /// When the latest share arrived
+    shares_modified: Option<SystemTime>,
+}
+
+impl StatsInner {
+    fn secs(&self, now: Instant) -> u64 {
+        self.start
+            .map_or(0, |start| now.saturating_duration_since(start).as_secs())
+    }
+}
+
+/// Snapshot of the pool for the console
+pub struct Summary {
+    pub workers: usize,
+    /// Hashes per second
+    pub hashrate: f64,
+    pub shares_per_min: f64,
+    pub blocks_found: u64,
+    pub daa_score: Option<u64>,
+    pub template_age: Option<Duration>,
+    pub reward: BlockReward,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} workers, {}, {:.1} shares/min, {} blocks found",
+            self.workers,
+            format_hashrate(self.hashrate),
+            self.shares_per_min,
+            self.blocks_found
+        )?;
+        match (self.daa_score, self.template_age) {
+            (Some(score), Some(age)) => {
+                write!(
+                    f,
+                    ", DAA score {score}, reward {:.2} KAS, template {:.1}s old",
+                    self.reward.total() as f64 / SOMPI_PER_KAS as f64,
+                    age.as_secs_f64()
+                )
+            }
+            _ => write!(f, ", no template yet"),
+        }
+    }
+}
+
+/// Hashes per second with the unit that keeps it below 1000 and three
+/// significant digits, the one way logs, the dashboard and the API show them
+pub fn format_hashrate(hashrate: f64) -> String {
+    const UNITS: [&str; 7] = ["H/s", "KH/s", "MH/s", "GH/s", "TH/s", "PH/s", "EH/s"];
+    if !(hashrate > 0.0 && hashrate.is_finite()) {
+        return "0 H/s".into();
+    }
+    let mut value = hashrate;
+    let mut unit = 0;
+    while value >= 999.5 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    let unit = UNITS[unit];
+    match value {
+        v if v >= 99.95 => format!("{v:.0} {unit}"),
+        v if v >= 9.995 => format!("{v:.1} {unit}"),
+        v => format!("{v:.2} {unit}"),
+    }
+}
+
+/// Pool wide share accounting
+#[derive(Clone, Default)]
+pub struct Stats {
+    inner: Arc<Mutex<StatsInner>>,
+}
+
+impl Stats {
+    pub fn add_share(&self, worker: Option<&str>, difficulty: u64) {
+        self.add_share_at(worker, difficulty, Instant::now())
+    }
+
+    fn add_share_at(&self, worker: Option<&str>, difficulty: u64, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.round_work += difficulty as u128;
+        inner.recent.push_back((now, difficulty));
+        inner.start.get_or_insert(now);
+        let secs = inner.secs(now);
+        if inner.shares.len() == MAX_SHARES {
+            inner.shares.pop_front();
+        }
+        let time = SystemTime::now();
+        inner.shares.push_back(Share {
+            time: time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            worker: worker.map(Into::into),
+            difficulty,
+        });
+        inner.shares_modified = Some(time);
+        if let Some(worker) = worker {
+            let w = inner
+                .workers
+                .entry(worker.into())
+                .or_insert_with(|| Worker {
+                    last_share: now,
+                    offline_since: None,
+                    windows: Window::ALL.map(ShareWindow::new),
+                });
+            w.last_share = now;
+            for window in &mut w.windows {
+                window.add(secs, difficulty);
+            }
+        }
+    }
+
+    /// Hashrate of every worker over the share windows
+    pub fn worker_hashrates(&self) -> HashMap<String, Hashrates> {
+        self.worker_hashrates_at(Instant::now())
+    }
+
+    fn worker_hashrates_at(&self, now: Instant) -> HashMap<String, Hashrates> {
+        let inner = self.inner.lock().unwrap();
+        let secs = inner.secs(now);
+        inner
+            .workers
+            .iter()
+            .map(|(name, w)| {
+                let [five_minutes, hour, day] = w.windows.each_ref().map(|w| w.hashrate(secs));
+                let hashrates = Hashrates {
+                    five_minutes,
+                    hour,
+                    day,
+                };
+                (name.clone(), hashrates)
+            })
+            .collect()
+    }
+
+    /// Hashrate of a worker per bucket of a window, oldest first, `None`
+    /// for unknown workers
+    pub fn worker_chart(&self, worker: &str, window: Window) -> Option<Vec<ChartPoint>> {
+        self.worker_chart_at(worker, window, Instant::now(), SystemTime::now())
+    }
+
+    fn worker_chart_at(
+        &self,
+        worker: &str,
+        window: Window,
+        now: Instant,
+        time: SystemTime,
+    ) -> Option<Vec<ChartPoint>> {
+        let inner = self.inner.lock().unwrap();
+        let secs = inner.secs(now);
+        let w = inner.workers.get(worker)?;
+        let i = Window::ALL.iter().position(|&w| w == window)?;
+        let bucket = window.bucket().as_secs();
+        let time = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+            .saturating_sub(secs % bucket);
+        let series = w.windows[i].series(secs);
+        let len = series.len() as u64;
+        Some(
+            series
+                .into_iter()
+                .enumerate()
+                .map(|(age, work)| ChartPoint {
+                    time: time.saturating_sub((len - 1 - age as u64) * bucket),
+                    hashrate: work as f64 / bucket as f64,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn new_template(&self, daa_score: u64, reward: BlockReward) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.template = Some((daa_score, Instant::now()));
+        inner.reward = reward;
+    }
+
+    /// DAA score and reward of the latest template
+    pub fn reward(&self) -> Option<(u64, BlockReward)> {
+        let inner = self.inner.lock().unwrap();
+        inner.template.map(|(score, _)| (score, inner.reward))
+    }
+
+    pub fn summary(&self) -> Summary {
+        self.summary_at(Instant::now())
+    }
+
+    fn summary_at(&self, now: Instant) -> Summary {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some((at, _)) = inner.recent.front() {
+            if now.saturating_duration_since(*at) <= HASHRATE_WINDOW {
+                break;
+            }
+            inner.recent.pop_front();
+        }
+        // Shares are worth their difficulty in hashes
+        let work: u128 = inner.recent.iter().map(|(_, d)| *d as u128).sum();
+        let window = HASHRATE_WINDOW.as_secs_f64();
+        Summary {
+            workers: inner
+                .workers
+                .values()
+                .filter(|w| now.saturating_duration_since(w.last_share) <= HASHRATE_WINDOW)
+                .count(),
+            hashrate: work as f64 / window,
+            shares_per_min: inner.recent.len() as f64 * 60.0 / window,
+            blocks_found: inner.blocks_found,
+            daa_score: inner.template.map(|(score, _)| score),
+            template_age: inner
+                .template
+                .map(|(_, at)| now.saturating_duration_since(at)),
+            reward: inner.reward,
+        }
+    }
+
+    /// Workers that went silent for longer than `threshold` or resumed
+    /// submitting since the last check
+    pub fn check_workers(&self, threshold: Duration) -> Vec<Event> {
+        self.check_workers_at(threshold, Instant::now())
+    }
+
+    fn check_workers_at(&self, threshold: Duration, now: Instant) -> Vec<Event> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut events = vec![];
+        for (name, w) in inner.workers.iter_mut() {
+            let silent = now.saturating_duration_since(w.last_share);
+            match w.offline_since {
+                None if silent > threshold => {
+                    w.offline_since = Some(w.last_share);
+                    events.push(Event::WorkerOffline {
+                        worker: name.clone(),
+                        silent_secs: silent.as_secs(),
+                    });
+                }
+                Some(since) if silent <= threshold => {
+                    w.offline_since = None;
+                    events.push(Event::WorkerOnline {
+                        worker: name.clone(),
+                        offline_secs: (w.last_share - since).as_secs(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// End the round, returning its effort
+    pub fn block_found(&self, difficulty: u64) -> f64 {
+        let mut inner = self.inner.lock().unwrap();
+        let effort = inner.round_work as f64 / difficulty.max(1) as f64;
+        inner.round_work = 0;
+        inner.blocks_found += 1;
+        if inner.blocks.len() == MAX_BLOCKS {
+            inner.blocks.pop_front();
+        }
+        inner.blocks.push_back(FoundBlock {
+            time: SystemTime::now(),
+            difficulty,
+            effort,
+        });
+        effort
+    }
+
+    /// Effort of the running round against a network difficulty
+    pub fn round_effort(&self, difficulty: u64) -> f64 {
+        self.inner.lock().unwrap().round_work as f64 / difficulty.max(1) as f64
+    }
+
+    pub fn blocks_found(&self) -> u64 {
+        self.inner.lock().unwrap().blocks_found
+    }
+
+    pub fn latest_blocks(&self) -> Vec<FoundBlock> {
+        self.inner.lock().unwrap().blocks.iter().cloned().collect()
+    }
+
+    /// Latest shares, oldest first
+    pub fn latest_shares(&self) -> Vec<Share> {
+        self.inner.lock().unwrap().shares.iter().cloned().collect()
+    }
+
+    /// When the latest share arrived, `None` before the first
+    pub fn shares_modified(&self) -> Option<SystemTime> {
+        self.inner.lock().unwrap().shares_modified
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_hashrate, ChartPoint, Stats, Window};
+    use crate::events::Event;
+    use crate::kaspad::BlockReward;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    #[test]
+    fn round_effort() {
+        let stats = Stats::default();
+        for _ in 0..3 {
+            stats.add_share(None, 100);
+        }
+        assert_eq!(stats.round_effort(200), 1.5);
+        assert_eq!(stats.block_found(200), 1.5);
+        assert_eq!(stats.round_effort(200), 0.0);
+        stats.add_share(None, 50);
+        assert_eq!(stats.block_found(200), 0.25);
+        assert_eq!(stats.blocks_found(), 2);
+        assert_eq!(stats.latest_shares().len(), 4);
+        assert!(stats.shares_modified().is_some());
+    }
+
+    #[test]
+    fn worker_offline() {
+        let stats = Stats::default();
+        let threshold = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        stats.add_share_at(Some("a"), 1, at(0));
+        stats.add_share_at(Some("b"), 1, at(0));
+        assert!(stats.check_workers_at(threshold, at(30)).is_empty());
+
+        stats.add_share_at(Some("b"), 1, at(50));
+        let events = stats.check_workers_at(threshold, at(90));
+        assert!(matches!(
+            &events[..],
+            [Event::WorkerOffline { worker, silent_secs: 90 }] if worker == "a"
+        ));
+        // Reported once
+        assert!(stats.check_workers_at(threshold, at(100)).is_empty());
+
+        stats.add_share_at(Some("a"), 1, at(300));
+        let events = stats.check_workers_at(threshold, at(310));
+        let [a, b] = &events[..] else {
+            panic!("expected two events, got {events:?}");
+        };
+        let (online, offline) = match a {
+            Event::WorkerOnline { .. } => (a, b),
+            _ => (b, a),
+        };
+        assert!(matches!(
+            online,
+            Event::WorkerOnline { worker, offline_secs: 300 } if worker == "a"
+        ));
+        assert!(matches!(
+            offline,
+            Event::WorkerOffline { worker, silent_secs: 260 } if worker == "b"
+        ));
+    }
+
+    #[test]
+    fn summary() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        // Dropped from the window
+        stats.add_share_at(Some("a"), 600_000, at(0));
+        for i in 0..60 {
+            stats.add_share_at(Some("b"), 1_000_000, at(100 + i));
+        }
+        let summary = stats.summary_at(at(700));
+        assert_eq!(summary.workers, 1);
+        assert_eq!(summary.hashrate, 100_000.0);
+        assert_eq!(summary.shares_per_min, 6.0);
+        assert_eq!(summary.daa_score, None);
+        assert_eq!(format_hashrate(summary.hashrate), "100 KH/s");
+        assert_eq!(format_hashrate(1.234e12), "1.23 TH/s");
+        assert_eq!(format_hashrate(12.34e15), "12.3 PH/s");
+        // Rounding up to the next unit
+        assert_eq!(format_hashrate(999.7e6), "1.00 GH/s");
+        assert_eq!(format_hashrate(0.5), "0.50 H/s");
+        assert_eq!(format_hashrate(f64::NAN), "0 H/s");
+
+        let reward = BlockReward {
+            subsidy: 4_500_000_000,
+            fees: 12_000_000,
+        };
+        stats.new_template(60_000_000, reward);
+        assert_eq!(stats.reward(), Some((60_000_000, reward)));
+        let summary = stats.summary();
+        assert!(summary
+            .to_string()
+            .contains("DAA score 60000000, reward 45.12 KAS, template"));
+    }
+
+    #[test]
+    fn share_windows() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        // One share of 600 per minute for two hours
+        for minute in 0..120 {
+            stats.add_share_at(Some("a"), 600, at(minute * 60));
+        }
+        stats.add_share_at(Some("b"), 3000, at(7170));
+        let hashrates = stats.worker_hashrates_at(at(7199));
+        let a = &hashrates["a"];
+        assert_eq!(a.five_minutes, 3000.0 / 300.0);
+        assert_eq!(a.hour, 10.0);
+        assert_eq!(a.day, 72_000.0 / 7200.0);
+        assert_eq!(hashrates["b"].five_minutes, 10.0);
+
+        // Silent for six minutes, only the longer windows remember
+        let hashrates = stats.worker_hashrates_at(at(7559));
+        assert_eq!(hashrates["a"].five_minutes, 0.0);
+        assert_eq!(hashrates["a"].hour, 54.0 * 600.0 / 3600.0);
+        // Back after two days, the old buckets are gone
+        stats.add_share_at(Some("a"), 900, at(180_000));
+        let hashrates = stats.worker_hashrates_at(at(180_000));
+        assert_eq!(hashrates["a"].day, 900.0 / 86_400.0);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_005);
+        let chart = stats
+            .worker_chart_at("a", Window::FiveMinutes, at(180_005), time)
+            .unwrap();
+        assert_eq!(chart.len(), 30);
+        assert_eq!(
+            chart[29],
+            ChartPoint {
+                time: 1_700_000_000,
+                hashrate: 90.0
+            }
+        );
+        assert_eq!(chart[0].time, 1_700_000_000 - 290);
+        assert!(chart[..29].iter().all(|p| p.hashrate == 0.0));
+        assert!(stats
+            .worker_chart_at("c", Window::Hour, at(0), time)
+            .is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+/// Time between checks of the certificate files
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate chain and key in PEM files, served on the TLS ports
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Ports on the stratum address speaking stratum over TLS, they may be
+    /// dialect or solo ports as well
+    pub ports: Vec<u16>,
+}
+
+/// Hands the latest certificate to new handshakes, connections keep the
+/// one they started with
+struct Resolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Certificate files as last loaded
+#[derive(PartialEq, Eq)]
+struct Files {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl Files {
+    fn read(config: &TlsConfig) -> Result<Self> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))
+        };
+        Ok(Files {
+            cert: read(&config.cert)?,
+            key: read(&config.key)?,
+        })
+    }
+
+    fn load(&self) -> Result<CertifiedKey> {
+        let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(&self.cert[..]))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            bail!("No certificate found");
+        }
+        let key = rustls_pemfile::read_all(&mut BufReader::new(&self.key[..]))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::RSAKey(k)
+                | rustls_pemfile::Item::PKCS8Key(k)
+                | rustls_pemfile::Item::ECKey(k) => Some(PrivateKey(k)),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No private key found"))?;
+        let key = sign::any_supported_type(&key).map_err(|e| anyhow!("Invalid key: {e}"))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+
+/// Accepts TLS connections with the certificate of the files, reloading it
+/// when they change so renewals don't need a restart
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    resolver: Arc<Resolver>,
+    config: Arc<TlsConfig>,
+    /// Contents of the files the served certificate was loaded from
+    files: Arc<Mutex<Files>>,
+}
+
+impl Tls {
+    /// Load the certificate and watch its files, the certificate has to be
+    /// valid at start
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let files = Files::read(&config)?;
+        let key = files.load().context("Unable to load the TLS certificate")?;
+        let resolver = Arc::new(Resolver {
+            current: RwLock::new(Arc::new(key)),
+        });
+        let server = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        let tls = Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            resolver,
+            config: Arc::new(config),
+            files: Arc::new(Mutex::new(files)),
+        };
+        tokio::spawn(tls.clone().watch());
+        Ok(tls)
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+
+    async fn watch(self) {
+        let mut interval =
+            time::interval_at(time::Instant::now() + RELOAD_INTERVAL, RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Renewals may be halfway written, the next check retries
+            if let Err(e) = self.reload() {
+                warn!("Unable to reload the TLS certificate: {e:#}");
+            }
+        }
+    }
+
+    /// Switch to the certificate in the files if they changed, whether they
+    /// did
+    pub fn reload(&self) -> Result<bool> {
+        let current = Files::read(&self.config)?;
+        let mut files = self.files.lock().unwrap();
+        if current == *files {
+            return Ok(false);
+        }
+        let key = current.load()?;
+        *self.resolver.current.write().unwrap() = Arc::new(key);
+        *files = current;
+        info!(
+            "Reloaded the TLS certificate {}",
+            self.config.cert.display()
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Tls, TlsConfig};
+    use std::path::PathBuf;
+
+    fn data(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("kaspad-stratum-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            ports: vec![],
+        };
+        let install = |cert: &str, key: &str| {
+            std::fs::copy(data(cert), &config.cert).unwrap();
+            std::fs::copy(data(key), &config.key).unwrap();
+        };
+        install("cert.pem", "key.pem");
+        let tls = Tls::new(config.clone()).unwrap();
+        let served = || tls.resolver.current.read().unwrap().cert[0].clone();
+        let original = served();
+
+        assert!(!tls.reload().unwrap());
+
+        // A certificate without its key yet is skipped
+        std::fs::write(&config.key, "").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(served(), original);
+
+        install("renewed_cert.pem", "renewed_key.pem");
+        assert!(tls.reload().unwrap());
+        assert_ne!(served(), original);
+        assert!(!tls.reload().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
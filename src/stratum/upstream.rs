@@ -0,0 +1,225 @@
+use super::reader::LineReader;
+use super::{ErrResponse, Id, OkResponse, Response};
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::debug;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Ids below are used by the handshake
+const FIRST_SUBMIT_ID: u64 = 3;
+
+/// Stratum pool the miners are proxied to while kaspad is down
+#[derive(Clone, Debug)]
+pub struct UpstreamConfig {
+    /// `host:port` of the pool
+    pub addr: String,
+    /// Login at the pool, usually a wallet address with a worker name
+    pub user: String,
+    pub password: String,
+    /// Kaspad is down this long before miners are moved to the pool
+    pub after: Duration,
+}
+
+/// What the pool sends on to the miner
+pub(super) enum Relay {
+    /// `mining.notify` params with the difficulty the pool set
+    Job(Value, f64),
+    /// Extranonce and the number of nonce bytes left to the miner
+    Extranonce(String, u64),
+    Response(Response),
+}
+
+/// Connection to the fallback pool on behalf of one miner. The miner's
+/// shares are forwarded under the pool login and the pool's jobs,
+/// difficulty and extranonce are relayed back.
+pub(super) struct Upstream {
+    reader: LineReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    user: String,
+    next_id: u64,
+    /// Miner ids of the shares waiting for the pool's answer
+    submits: HashMap<u64, Id>,
+    difficulty: f64,
+}
+
+impl Upstream {
+    pub async fn connect(config: &UpstreamConfig) -> Result<Self> {
+        let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&config.addr)).await??;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let mut upstream = Upstream {
+            reader: LineReader::new(reader),
+            writer,
+            user: config.user.clone(),
+            next_id: FIRST_SUBMIT_ID,
+            submits: HashMap::new(),
+            difficulty: 1.0,
+        };
+        let agent = concat!("kaspad-stratum/", env!("CARGO_PKG_VERSION"));
+        upstream
+            .write(json!({"id": 1, "method": "mining.subscribe", "params": [agent]}))
+            .await?;
+        let login = [&config.user, &config.password];
+        upstream
+            .write(json!({"id": 2, "method": "mining.authorize", "params": login}))
+            .await?;
+        Ok(upstream)
+    }
+
+    async fn write(&mut self, msg: Value) -> Result<()> {
+        let mut line = serde_json::to_vec(&msg)?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        Ok(())
+    }
+
+    /// Forward a share with the worker replaced by the pool login
+    pub async fn submit(&mut self, id: Id, mut params: Value) -> Result<()> {
+        if let Some(worker) = params.as_array_mut().and_then(|p| p.first_mut()) {
+            *worker = self.user.as_str().into();
+        }
+        let upstream_id = self.next_id;
+        self.next_id += 1;
+        self.submits.insert(upstream_id, id);
+        self.write(json!({"id": upstream_id, "method": "mining.submit", "params": params}))
+            .await
+    }
+
+    /// Next message for the miner, `None` for those handled here. Cancel
+    /// safe.
+    pub async fn next(&mut self) -> Result<Option<Relay>> {
+        let line = match self.reader.next_line().await? {
+            Some(l) => l,
+            None => bail!("Pool closed the connection"),
+        };
+        let msg: Value = serde_json::from_slice(&line)?;
+        let params = &msg["params"];
+        let relay = match msg["method"].as_str() {
+            Some("mining.notify") => Some(Relay::Job(params.clone(), self.difficulty)),
+            Some("mining.set_difficulty") => {
+                if let Some(difficulty) = params[0].as_f64() {
+                    self.difficulty = difficulty;
+                }
+                None
+            }
+            Some("set_extranonce" | "mining.set_extranonce") => extranonce(params),
+            Some(method) => {
+                debug!("Ignoring {method} from the pool");
+                None
+            }
+            None => self.response(&msg)?,
+        };
+        Ok(relay)
+    }
+
+    fn response(&mut self, msg: &Value) -> Result<Option<Relay>> {
+        let (result, error) = (&msg["result"], &msg["error"]);
+        match msg["id"].as_u64() {
+            // The standard subscribe response carries the extranonce
+            Some(1) => Ok(extranonce(&json!([result[1], result[2]]))),
+            Some(2) if !error.is_null() || result == &Value::Bool(false) => {
+                bail!("Pool refused the login: {error}")
+            }
+            Some(id) => Ok(self.submits.remove(&id).map(|id| {
+                Relay::Response(if error.is_null() {
+                    Response::Ok(OkResponse {
+                        id,
+                        result: result.clone(),
+                    })
+                } else {
+                    Response::Err(ErrResponse {
+                        id,
+                        error: error.clone(),
+                    })
+                })
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `[extranonce, size]` params
+fn extranonce(params: &Value) -> Option<Relay> {
+    let extranonce = params[0].as_str()?;
+    // Kaspa nonces are 8 bytes
+    let size = params[1]
+        .as_u64()
+        .unwrap_or_else(|| 8u64.saturating_sub(extranonce.len() as u64 / 2));
+    Some(Relay::Extranonce(extranonce.into(), size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Relay, Upstream, UpstreamConfig};
+    use crate::stratum::{Id, Response};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn relays_pool_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = UpstreamConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            user: "kaspa:pool.rig1".into(),
+            password: "x".into(),
+            after: Duration::ZERO,
+        };
+        let pool = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = conn.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            for _ in 0..2 {
+                lines.next_line().await.unwrap();
+            }
+            let replies = concat!(
+                r#"{"id":1,"result":true,"error":null}"#,
+                "\n",
+                r#"{"id":2,"result":true,"error":null}"#,
+                "\n",
+                r#"{"id":null,"method":"set_extranonce","params":["a1b2",6]}"#,
+                "\n",
+                r#"{"id":null,"method":"mining.set_difficulty","params":[4]}"#,
+                "\n",
+                r#"{"id":null,"method":"mining.notify","params":["7",[1,2,3,4],5]}"#,
+                "\n",
+            );
+            writer.write_all(replies.as_bytes()).await.unwrap();
+            let submit = lines.next_line().await.unwrap().unwrap();
+            writer
+                .write_all(b"{\"id\":3,\"result\":true,\"error\":null}\n")
+                .await
+                .unwrap();
+            submit
+        });
+
+        let mut upstream = Upstream::connect(&config).await.unwrap();
+        let mut relays = vec![];
+        while relays.len() < 2 {
+            if let Some(relay) = upstream.next().await.unwrap() {
+                relays.push(relay);
+            }
+        }
+        assert!(matches!(&relays[0], Relay::Extranonce(e, 6) if e == "a1b2"));
+        assert!(matches!(&relays[1], Relay::Job(_, d) if *d == 4.0));
+
+        let params = serde_json::json!(["kaspa:miner.rig", "7", "0xa1b2000000000001"]);
+        upstream.submit(Id::Number(42), params).await.unwrap();
+        let relay = loop {
+            if let Some(relay) = upstream.next().await.unwrap() {
+                break relay;
+            }
+        };
+        assert!(
+            matches!(relay, Relay::Response(Response::Ok(r)) if matches!(r.id, Id::Number(42)))
+        );
+        let submit = pool.await.unwrap();
+        assert!(submit.contains(r#""params":["kaspa:pool.rig1","7","0xa1b2000000000001"]"#));
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest factor a single retarget may change the difficulty by
+const MAX_STEP: f64 = 4.0;
+/// Windows that stay within the variance keep accumulating shares up to this
+/// many retarget periods, after which the estimate starts over
+const MAX_WINDOWS: u32 = 4;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<T: Clock> Clock for &T {
+    fn now(&self) -> Instant {
+        (*self).now()
+    }
+}
+
+/// Typical hashrates by lowercase agent substring, checked in order. Unknown
+/// IceRiver models start as the smallest, which a burst of shares corrects
+/// quickly.
+const AGENT_HASHRATES: &[(&str, f64)] = &[
+    ("ks5", 21e12),
+    ("ks3", 9.4e12),
+    ("ks0", 100e9),
+    ("iceriver", 100e9),
+    ("bzminer", 1e9),
+    ("gminer", 1e9),
+    ("kaspa-miner", 1e9),
+    ("lolminer", 1e9),
+    ("srbminer", 1e9),
+];
+
+#[derive(Clone)]
+pub struct VarDiffConfig {
+    /// Desired average time between shares
+    pub share_time: Duration,
+    /// Minimum time between regular retargets
+    pub retarget_time: Duration,
+    /// Relative deviation from `share_time` tolerated before retargeting
+    pub variance: f64,
+    pub start_difficulty: u64,
+    pub min_difficulty: u64,
+    pub max_difficulty: u64,
+    /// How long a worker's difficulty is remembered after it disconnects
+    pub resume_ttl: Duration,
+    /// Known miners start at the difficulty of their typical hashrate
+    /// instead of `start_difficulty`
+    pub by_agent: bool,
+}
+
+impl VarDiffConfig {
+    /// Starting difficulty of a miner announcing `agent`, if it is known
+    pub fn agent_difficulty(&self, agent: &str) -> Option<u64> {
+        if !self.by_agent {
+            return None;
+        }
+        let agent = agent.to_ascii_lowercase();
+        let (_, hashrate) = AGENT_HASHRATES
+            .iter()
+            .find(|(name, _)| agent.contains(name))?;
+        Some((hashrate * self.share_time.as_secs_f64()) as u64)
+    }
+}
+
+impl Default for VarDiffConfig {
+    fn default() -> Self {
+        Self {
+            share_time: Duration::from_secs(5),
+            retarget_time: Duration::from_secs(90),
+            variance: 0.4,
+            start_difficulty: 1 << 32,
+            min_difficulty: 1 << 20,
+            max_difficulty: u64::MAX,
+            resume_ttl: Duration::from_secs(600),
+            by_agent: true,
+        }
+    }
+}
+
+/// Per connection share difficulty controller. Difficulties are expressed as
+/// the expected number of hashes per share, like the network difficulty.
+pub struct VarDiff<C: Clock = SystemClock> {
+    config: VarDiffConfig,
+    clock: C,
+    difficulty: u64,
+    window_start: Instant,
+    shares: u32,
+}
+
+impl<C: Clock> VarDiff<C> {
+    pub fn new(config: VarDiffConfig, clock: C) -> Self {
+        let difficulty = config
+            .start_difficulty
+            .clamp(config.min_difficulty, config.max_difficulty);
+        let window_start = clock.now();
+        Self {
+            config,
+            clock,
+            difficulty,
+            window_start,
+            shares: 0,
+        }
+    }
+
+    pub fn difficulty(&self) -> u64 {
+        self.difficulty
+    }
+
+    /// Hashes per second the difficulty is set for
+    pub fn hashrate(&self) -> f64 {
+        self.difficulty as f64 / self.config.share_time.as_secs_f64()
+    }
+
+    /// Start at the difficulty of a known miner's typical hashrate, returns
+    /// whether it is known
+    pub fn start_for_agent(&mut self, agent: &str) -> bool {
+        match self.config.agent_difficulty(agent) {
+            Some(difficulty) => {
+                self.set_difficulty(difficulty);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Override the difficulty, e.g. with the one a worker had before it
+    /// reconnected, and start a new estimate from there
+    pub fn set_difficulty(&mut self, difficulty: u64) {
+        self.difficulty = difficulty.clamp(self.config.min_difficulty, self.config.max_difficulty);
+        self.reset();
+    }
+
+    /// Record a share accepted at the current difficulty. Returns the new
+    /// difficulty if it changed.
+    pub fn on_share(&mut self) -> Option<u64> {
+        self.shares += 1;
+        let elapsed = self.clock.now() - self.window_start;
+
+        // Shares coming in far faster than intended are retargeted right away,
+        // e.g. an ASIC starting at a GPU difficulty
+        let burst = self.config.retarget_time.as_secs_f64() / self.config.share_time.as_secs_f64();
+        if elapsed >= self.config.retarget_time || self.shares as f64 >= MAX_STEP * burst {
+            self.retarget(elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// Should be called periodically, so the difficulty also drops for
+    /// miners that stopped finding shares at all
+    pub fn tick(&mut self) -> Option<u64> {
+        let elapsed = self.clock.now() - self.window_start;
+        if elapsed >= self.config.retarget_time {
+            self.retarget(elapsed)
+        } else {
+            None
+        }
+    }
+
+    fn retarget(&mut self, elapsed: Duration) -> Option<u64> {
+        // Without any shares, act as if one just came in: the real share time
+        // is at least as long as the window
+        let shares = self.shares.max(1) as f64;
+        let elapsed_secs = elapsed.as_secs_f64().max(1e-3);
+        let ratio = shares * self.config.share_time.as_secs_f64() / elapsed_secs;
+
+        if (ratio - 1.0).abs() <= self.config.variance {
+            if elapsed >= self.config.retarget_time * MAX_WINDOWS {
+                self.reset();
+            }
+            return None;
+        }
+
+        let ratio = ratio.clamp(1.0 / MAX_STEP, MAX_STEP);
+        let difficulty = ((self.difficulty as f64) * ratio) as u64;
+        let difficulty = difficulty.clamp(self.config.min_difficulty, self.config.max_difficulty);
+        self.reset();
+        if difficulty == self.difficulty {
+            return None;
+        }
+        self.difficulty = difficulty;
+        Some(difficulty)
+    }
+
+    fn reset(&mut self) {
+        self.window_start = self.clock.now();
+        self.shares = 0;
+    }
+}
+
+/// Last difficulty of disconnected workers, keyed by worker name
+#[derive(Clone)]
+pub struct DifficultyCache {
+    ttl: Duration,
+    inner: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+}
+
+impl DifficultyCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn get(&self, worker: &str) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        match inner.get(worker) {
+            Some((difficulty, at)) if at.elapsed() < self.ttl => Some(*difficulty),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, worker: String, difficulty: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        inner.insert(worker, (difficulty, Instant::now()));
+    }
+
+    /// Insert a difficulty saved `age` ago, e.g. by another instance
+    pub fn restore(&self, worker: String, difficulty: u64, age: Duration) {
+        if let Some(at) = Instant::now().checked_sub(age) {
+            if age < self.ttl {
+                self.inner.lock().unwrap().insert(worker, (difficulty, at));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, VarDiff, VarDiffConfig, MAX_STEP};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    struct ManualClock(Cell<Instant>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            Self(Cell::new(Instant::now()))
+        }
+
+        fn advance(&self, d: Duration) {
+            self.0.set(self.0.get() + d);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    /// Deterministic miner finding shares as a Poisson process
+    struct Miner {
+        hashrate: f64,
+        rng: u64,
+    }
+
+    impl Miner {
+        fn new(hashrate: f64, seed: u64) -> Self {
+            Self {
+                hashrate,
+                rng: seed.wrapping_mul(0x9e3779b97f4a7c15) | 1,
+            }
+        }
+
+        fn uniform(&mut self) -> f64 {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            ((self.rng >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+        }
+
+        fn next_share(&mut self, difficulty: u64) -> Duration {
+            let mean = difficulty as f64 / self.hashrate;
+            Duration::from_secs_f64(-self.uniform().ln() * mean)
+        }
+
+        /// Mine for `duration`, ticking the controller every second
+        fn run(
+            &mut self,
+            vardiff: &mut VarDiff<&ManualClock>,
+            clock: &ManualClock,
+            duration: Duration,
+        ) {
+            let end = clock.now() + duration;
+            let mut next = clock.now() + self.next_share(vardiff.difficulty());
+            while clock.now() < end {
+                let tick = clock.now() + Duration::from_secs(1);
+                let changed = if next <= tick {
+                    clock.advance(next - clock.now());
+                    vardiff.on_share()
+                } else {
+                    clock.advance(tick - clock.now());
+                    vardiff.tick()
+                };
+                if changed.is_some() || next <= clock.now() {
+                    next = clock.now() + self.next_share(vardiff.difficulty());
+                }
+            }
+        }
+    }
+
+    fn ideal(config: &VarDiffConfig, hashrate: f64) -> f64 {
+        hashrate * config.share_time.as_secs_f64()
+    }
+
+    fn assert_near(difficulty: u64, ideal: f64) {
+        let ratio = difficulty as f64 / ideal;
+        assert!(
+            (0.5..=2.0).contains(&ratio),
+            "difficulty {difficulty} is {ratio}x the ideal"
+        );
+    }
+
+    #[test]
+    fn converges_from_low_start() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig::default();
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        // An ASIC at 10 TH/s starting on the default difficulty
+        let mut miner = Miner::new(10e12, 1);
+        miner.run(&mut vardiff, &clock, Duration::from_secs(30 * 60));
+        assert_near(vardiff.difficulty(), ideal(&config, 10e12));
+    }
+
+    #[test]
+    fn converges_from_high_start() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig {
+            start_difficulty: 1 << 50,
+            ..Default::default()
+        };
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        // A 100 MH/s GPU would need months for a share at the start difficulty
+        let mut miner = Miner::new(100e6, 2);
+        miner.run(&mut vardiff, &clock, Duration::from_secs(60 * 60));
+        assert_near(vardiff.difficulty(), ideal(&config, 100e6));
+    }
+
+    #[test]
+    fn follows_hashrate_steps() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig::default();
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        let mut miner = Miner::new(1e12, 3);
+        for hashrate in [1e12, 1e11, 4e12] {
+            miner.hashrate = hashrate;
+            miner.run(&mut vardiff, &clock, Duration::from_secs(30 * 60));
+            assert_near(vardiff.difficulty(), ideal(&config, hashrate));
+        }
+    }
+
+    #[test]
+    fn stable_for_bursty_miner() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig::default();
+        let hashrate = 1e12;
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        Miner::new(hashrate, 4).run(&mut vardiff, &clock, Duration::from_secs(30 * 60));
+
+        // Shares reported in batches of 8, as some firmware does
+        let mut retargets = 0;
+        for _ in 0..60 {
+            let batch = vardiff.difficulty() as f64 * 8.0 / hashrate;
+            clock.advance(Duration::from_secs_f64(batch));
+            for _ in 0..8 {
+                retargets += vardiff.on_share().is_some() as u32;
+            }
+            retargets += vardiff.tick().is_some() as u32;
+            assert_near(vardiff.difficulty(), ideal(&config, hashrate));
+        }
+        assert!(
+            retargets <= 2,
+            "{retargets} retargets for a steady hashrate"
+        );
+    }
+
+    #[test]
+    fn reconnect_resettles() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig::default();
+        let hashrate = 5e12;
+        let mut miner = Miner::new(hashrate, 5);
+        for _ in 0..3 {
+            // Every reconnect starts a fresh controller
+            let mut vardiff = VarDiff::new(config.clone(), &clock);
+            miner.run(&mut vardiff, &clock, Duration::from_secs(20 * 60));
+            assert_near(vardiff.difficulty(), ideal(&config, hashrate));
+            clock.advance(Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn reconnect_resumes() {
+        let clock = ManualClock::new();
+        let config = VarDiffConfig::default();
+        let hashrate = 5e12;
+        let mut miner = Miner::new(hashrate, 6);
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        miner.run(&mut vardiff, &clock, Duration::from_secs(20 * 60));
+        let settled = vardiff.difficulty();
+
+        // Resuming at the settled difficulty doesn't go through the retarget cycle again
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        vardiff.set_difficulty(settled);
+        let mut max_ratio = 1f64;
+        for _ in 0..10 {
+            miner.run(&mut vardiff, &clock, Duration::from_secs(60));
+            let ratio = vardiff.difficulty() as f64 / settled as f64;
+            max_ratio = max_ratio.max(ratio.max(1.0 / ratio));
+        }
+        assert!(
+            max_ratio < 2.0,
+            "difficulty moved {max_ratio}x after resuming"
+        );
+    }
+
+    #[test]
+    fn bounded_steps() {
+        let config = VarDiffConfig {
+            min_difficulty: 1 << 24,
+            max_difficulty: 1 << 48,
+            ..Default::default()
+        };
+        for seed in 0..50 {
+            let clock = ManualClock::new();
+            let mut vardiff = VarDiff::new(config.clone(), &clock);
+            let mut miner = Miner::new(1.0, seed);
+            for _ in 0..500 {
+                let before = vardiff.difficulty();
+                clock.advance(Duration::from_secs_f64(miner.uniform() * 120.0));
+                let after = if miner.uniform() < 0.8 {
+                    vardiff.on_share()
+                } else {
+                    vardiff.tick()
+                };
+                if let Some(after) = after {
+                    let ratio = after as f64 / before as f64;
+                    assert!((1.0 / MAX_STEP / 1.0001..=MAX_STEP * 1.0001).contains(&ratio));
+                    assert!((config.min_difficulty..=config.max_difficulty).contains(&after));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn starts_by_agent() {
+        let config = VarDiffConfig::default();
+        assert_eq!(config.agent_difficulty("IceRiver KS3M"), Some(47e12 as u64));
+        assert_eq!(config.agent_difficulty("lolMiner 1.88"), Some(5e9 as u64));
+        assert_eq!(config.agent_difficulty("unknown/1.0"), None);
+
+        let clock = ManualClock::new();
+        let mut vardiff = VarDiff::new(config.clone(), &clock);
+        assert!(vardiff.start_for_agent("iceriver-ks0pro"));
+        assert_eq!(vardiff.difficulty(), 500e9 as u64);
+        let config = VarDiffConfig {
+            by_agent: false,
+            ..config
+        };
+        assert_eq!(config.agent_difficulty("IceRiver KS3M"), None);
+    }
+}
@@ -0,0 +1,520 @@
+use super::{Request, Response};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, Instrument};
+
+/// Messages buffered for a client before it counts as stalled
+const QUEUE_SIZE: usize = 64;
+/// Difficulty tiers kept before unused ones are dropped
+const MAX_TIERS: usize = 1024;
+/// Time a job may wait for a client before [`SlowClient::Disconnect`]
+/// closes it, bursts of templates replace jobs much faster
+const MAX_JOB_DELAY: Duration = Duration::from_secs(10);
+/// Time a single write may block before the client counts as gone, so a
+/// peer that stopped reading doesn't keep the task and socket forever
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with a client that doesn't read jobs as fast as they are sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ArgEnum)]
+pub enum SlowClient {
+    /// Skip jobs the client didn't receive yet in favor of the newest one,
+    /// and messages that don't fit the queue
+    #[default]
+    DropJobs,
+    /// Close connections with a job waiting longer than 10 seconds
+    Disconnect,
+}
+
+pub enum Message {
+    /// Request to the client, the id is assigned by the writer
+    Request(&'static str, Option<Value>),
+    Response(Response),
+}
+
+/// Params serialized once and shared by every connection sending them
+pub type RawParams = Arc<RawValue>;
+
+pub fn to_raw<T: Serialize + ?Sized>(params: &T) -> Result<RawParams> {
+    Ok(serde_json::value::to_raw_value(params)?.into())
+}
+
+pub struct Job {
+    pub notify: RawParams,
+    /// Share difficulty in stratum units
+    pub difficulty: f64,
+    pub set_difficulty: RawParams,
+    /// Send a changed difficulty ahead of the notify instead of after it
+    pub difficulty_first: bool,
+    /// Send the difficulty ahead of the notify even if it didn't change
+    pub resend_difficulty: bool,
+}
+
+/// `mining.set_difficulty` params of the difficulties in use, so
+/// connections on the same difficulty share one serialization
+#[derive(Clone, Default)]
+pub struct Tiers {
+    inner: Arc<Mutex<HashMap<u64, RawParams>>>,
+}
+
+impl Tiers {
+    /// Params for a difficulty in stratum units
+    pub fn set_difficulty(&self, difficulty: f64) -> Result<RawParams> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(params) = inner.get(&difficulty.to_bits()) {
+            return Ok(params.clone());
+        }
+        if inner.len() >= MAX_TIERS {
+            // Only referenced by the map
+            inner.retain(|_, params| Arc::strong_count(params) > 1);
+        }
+        let params = to_raw(&[difficulty])?;
+        inner.insert(difficulty.to_bits(), params.clone());
+        Ok(params)
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    /// The job to write next and since when the client has one waiting
+    job: Mutex<Option<(Job, Instant)>>,
+    notify: Notify,
+}
+
+/// Queues writes to a client on a separate task, so a stalled peer can't
+/// block reading its submits.
+pub struct Writer {
+    queue: mpsc::Sender<Message>,
+    slot: Arc<Slot>,
+    task: JoinHandle<Result<()>>,
+    slow_client: SlowClient,
+    jsonrpc2: Arc<AtomicBool>,
+}
+
+impl Writer {
+    pub fn new<W>(writer: W, slow_client: SlowClient, jsonrpc2: bool) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_timeout(writer, slow_client, jsonrpc2, WRITE_TIMEOUT)
+    }
+
+    fn with_timeout<W>(
+        writer: W,
+        slow_client: SlowClient,
+        jsonrpc2: bool,
+        timeout: Duration,
+    ) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (queue, recv) = mpsc::channel(QUEUE_SIZE);
+        let slot = Arc::new(Slot::default());
+        let jsonrpc2 = Arc::new(AtomicBool::new(jsonrpc2));
+        let task = WriterTask {
+            writer,
+            recv,
+            slot: slot.clone(),
+            id: 0,
+            difficulty: None,
+            jsonrpc2: jsonrpc2.clone(),
+            timeout,
+        };
+        Writer {
+            queue,
+            slot,
+            task: tokio::spawn(task.run().in_current_span()),
+            slow_client,
+            jsonrpc2,
+        }
+    }
+
+    /// Switch to or from JSON-RPC 2.0 once the client's dialect is known
+    pub fn set_jsonrpc2(&self, jsonrpc2: bool) {
+        self.jsonrpc2.store(jsonrpc2, Ordering::Relaxed);
+    }
+
+    pub fn send(&self, message: Message) -> Result<()> {
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) if self.slow_client == SlowClient::DropJobs => {
+                debug!("Dropping message, write queue full");
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => bail!("Write queue full"),
+            Err(TrySendError::Closed(_)) => bail!("Writer stopped"),
+        }
+    }
+
+    /// Replace the job waiting to be written
+    pub fn send_job(&self, job: Job) -> Result<()> {
+        self.send_job_at(job, Instant::now())
+    }
+
+    fn send_job_at(&self, job: Job, now: Instant) -> Result<()> {
+        {
+            let mut slot = self.slot.job.lock().unwrap();
+            let since = match slot.take() {
+                Some((_, since)) => {
+                    let waited = now.saturating_duration_since(since);
+                    if self.slow_client == SlowClient::Disconnect && waited > MAX_JOB_DELAY {
+                        bail!("Client didn't read jobs for {waited:?}");
+                    }
+                    debug!("Dropping job not yet sent");
+                    since
+                }
+                None => now,
+            };
+            *slot = Some((job, since));
+        }
+        self.slot.notify.notify_one();
+        Ok(())
+    }
+
+    /// Completes with the error when writing to the client fails
+    pub async fn failed(&mut self) -> anyhow::Error {
+        match (&mut self.task).await {
+            Ok(Err(e)) => e,
+            Ok(Ok(_)) => anyhow::anyhow!("Writer stopped"),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+struct WriterTask<W> {
+    writer: W,
+    recv: mpsc::Receiver<Message>,
+    slot: Arc<Slot>,
+    id: u64,
+    /// Last difficulty sent to the client
+    difficulty: Option<f64>,
+    jsonrpc2: Arc<AtomicBool>,
+    timeout: Duration,
+}
+
+impl<W: AsyncWrite + Unpin> WriterTask<W> {
+    async fn run(mut self) -> Result<()> {
+        loop {
+            // Queued messages go first so a job never overtakes the
+            // subscribe response
+            tokio::select! {
+                biased;
+                message = self.recv.recv() => match message {
+                    Some(Message::Request(method, params)) => self.write_request(method, params).await?,
+                    Some(Message::Response(res)) => self.write_response(res).await?,
+                    None => break,
+                },
+                _ = self.slot.notify.notified() => self.flush_job().await?,
+            }
+        }
+        self.flush_job().await
+    }
+
+    async fn flush_job(&mut self) -> Result<()> {
+        let job = self.slot.job.lock().unwrap().take();
+        match job {
+            Some((job, _)) => self.write_job(job).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The notify and a changed difficulty go out in a single write
+    async fn write_job(&mut self, job: Job) -> Result<()> {
+        let mut data = Vec::with_capacity(job.notify.get().len() + 128);
+        let changed = self.difficulty.replace(job.difficulty) != Some(job.difficulty);
+        let first = job.resend_difficulty || changed && job.difficulty_first;
+        if first {
+            self.encode_raw_request(&mut data, "mining.set_difficulty", &job.set_difficulty)?;
+        }
+        self.encode_raw_request(&mut data, "mining.notify", &job.notify)?;
+        if changed && !first {
+            self.encode_raw_request(&mut data, "mining.set_difficulty", &job.set_difficulty)?;
+        }
+        self.write_all(&data).await
+    }
+
+    /// Append a request with pre-serialized params as a line
+    fn encode_raw_request(
+        &mut self,
+        data: &mut Vec<u8>,
+        method: &'static str,
+        params: &RawValue,
+    ) -> Result<()> {
+        self.id += 1;
+        let req = RawRequest {
+            jsonrpc: self.jsonrpc2().then_some("2.0"),
+            id: self.id,
+            method,
+            params,
+        };
+        serde_json::to_writer(&mut *data, &req)?;
+        data.push(b'\n');
+        Ok(())
+    }
+
+    async fn write_request(&mut self, method: &'static str, params: Option<Value>) -> Result<()> {
+        self.id += 1;
+        let req = Request {
+            id: Some(self.id.into()),
+            method: method.into(),
+            params,
+        };
+        match self.jsonrpc2() {
+            true => self.write(&req.to_jsonrpc2()).await,
+            false => self.write(&req).await,
+        }
+    }
+
+    async fn write_response(&mut self, res: Response) -> Result<()> {
+        match self.jsonrpc2() {
+            true => self.write(&res.to_jsonrpc2()).await,
+            false => self.write(&res).await,
+        }
+    }
+
+    async fn write<T: Serialize>(&mut self, data: &T) -> Result<()> {
+        let mut data = serde_json::to_vec(data)?;
+        data.push(b'\n');
+        self.write_all(&data).await
+    }
+
+    fn jsonrpc2(&self) -> bool {
+        self.jsonrpc2.load(Ordering::Relaxed)
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match time::timeout(self.timeout, self.writer.write_all(data)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => bail!("Client didn't read for {:?}", self.timeout),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RawRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jsonrpc: Option<&'static str>,
+    id: u64,
+    method: &'static str,
+    params: &'a RawValue,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_raw, Job, Message, SlowClient, Tiers, Writer};
+    use crate::stratum::Response;
+    use serde_json::{json, Value};
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncBufReadExt, AsyncWrite, BufReader};
+
+    #[tokio::test]
+    async fn drops_intermediate_jobs() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let writer = Writer::new(server, SlowClient::DropJobs, false);
+        let job = |n: u64, difficulty: f64| Job {
+            notify: to_raw(&[n]).unwrap(),
+            difficulty,
+            set_difficulty: to_raw(&[difficulty]).unwrap(),
+            difficulty_first: false,
+            resend_difficulty: false,
+        };
+        // Nothing runs until the test yields, so all but the last job are dropped
+        writer
+            .send(Message::Response(Response::ok(1.into(), true).unwrap()))
+            .unwrap();
+        writer.send_job(job(1, 1.0)).unwrap();
+        writer.send_job(job(2, 2.0)).unwrap();
+        writer.send_job(job(3, 2.0)).unwrap();
+        drop(writer);
+
+        let mut lines = BufReader::new(client).lines();
+        let mut methods = vec![];
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            methods.push((msg["method"].clone(), msg["params"].clone()));
+        }
+        assert_eq!(
+            methods,
+            [
+                (Value::Null, Value::Null),
+                (json!("mining.notify"), json!([3])),
+                (json!("mining.set_difficulty"), json!([2.0])),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resends_difficulty() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let writer = Writer::new(server, SlowClient::DropJobs, false);
+        let mut lines = BufReader::new(client).lines();
+        for n in 0..2 {
+            writer
+                .send_job(Job {
+                    notify: to_raw(&[n]).unwrap(),
+                    difficulty: 1.0,
+                    set_difficulty: to_raw(&[1.0]).unwrap(),
+                    difficulty_first: false,
+                    resend_difficulty: true,
+                })
+                .unwrap();
+            for method in ["mining.set_difficulty", "mining.notify"] {
+                let line = lines.next_line().await.unwrap().unwrap();
+                let msg: Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(msg["method"], method);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnects_slow_client() {
+        let (_client, server) = tokio::io::duplex(1 << 16);
+        let writer = Writer::new(server, SlowClient::Disconnect, false);
+        let job = || Job {
+            notify: to_raw(&json!([])).unwrap(),
+            difficulty: 1.0,
+            set_difficulty: to_raw(&[1.0]).unwrap(),
+            difficulty_first: false,
+            resend_difficulty: false,
+        };
+        // A burst of templates replaces jobs faster than any client reads
+        let start = Instant::now();
+        for _ in 0..100 {
+            writer.send_job_at(job(), start).unwrap();
+        }
+        let at = |secs| start + Duration::from_secs(secs);
+        writer.send_job_at(job(), at(10)).unwrap();
+        assert!(writer.send_job_at(job(), at(11)).is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_slow_client() {
+        let (_client, server) = tokio::io::duplex(16);
+        let writer = Writer::new(server, SlowClient::DropJobs, false);
+        // Far more than the queue takes while the client reads nothing
+        for id in 0..1000 {
+            let res = Response::ok(id.into(), true).unwrap();
+            writer.send(Message::Response(res)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_writes() {
+        let (_client, server) = tokio::io::duplex(16);
+        let timeout = Duration::from_millis(50);
+        let mut writer = Writer::with_timeout(server, SlowClient::DropJobs, false, timeout);
+        let res = Response::ok(1.into(), true).unwrap();
+        writer.send(Message::Response(res)).unwrap();
+        let e = tokio::time::timeout(Duration::from_secs(5), writer.failed())
+            .await
+            .unwrap();
+        assert!(e.to_string().contains("didn't read"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn jsonrpc2() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let writer = Writer::new(server, SlowClient::DropJobs, false);
+        // As when the dialect detected at subscribe asks for it
+        writer.set_jsonrpc2(true);
+        let ok = Response::ok(1.into(), true).unwrap();
+        let err = Response::err(2.into(), 21, "Job not found".into()).unwrap();
+        writer.send(Message::Response(ok)).unwrap();
+        writer.send(Message::Response(err)).unwrap();
+        writer
+            .send(Message::Request("set_extranonce", None))
+            .unwrap();
+        drop(writer);
+
+        let mut lines = BufReader::new(client).lines();
+        let mut messages = vec![];
+        while let Some(line) = lines.next_line().await.unwrap() {
+            messages.push(serde_json::from_str::<Value>(&line).unwrap());
+        }
+        assert_eq!(
+            messages,
+            [
+                json!({"jsonrpc": "2.0", "id": 1, "result": true, "error": null}),
+                json!({"jsonrpc": "2.0", "id": 2, "result": null, "error": {"code": 21, "message": "Job not found"}}),
+                json!({"jsonrpc": "2.0", "id": 1, "method": "set_extranonce", "params": null}),
+            ]
+        );
+    }
+
+    #[test]
+    fn shares_tiers() {
+        let tiers = Tiers::default();
+        let a = tiers.set_difficulty(0.5).unwrap();
+        let b = tiers.set_difficulty(0.5).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&a, &b));
+        assert_eq!(a.get(), "[0.5]");
+        assert_eq!(tiers.set_difficulty(2.0).unwrap().get(), "[2.0]");
+    }
+
+    /// Records the chunks passed to each write call
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn job_in_one_write() {
+        let recorder = Recorder::default();
+        let writes = recorder.0.clone();
+        let writer = Writer::new(recorder, SlowClient::DropJobs, false);
+        writer
+            .send_job(Job {
+                notify: to_raw(&[1]).unwrap(),
+                difficulty: 1.0,
+                set_difficulty: to_raw(&[1.0]).unwrap(),
+                difficulty_first: false,
+                resend_difficulty: false,
+            })
+            .unwrap();
+        drop(writer.queue);
+        writer.task.await.unwrap().unwrap();
+
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(
+            String::from_utf8_lossy(&writes[0]),
+            concat!(
+                r#"{"id":1,"method":"mining.notify","params":[1]}"#,
+                "\n",
+                r#"{"id":2,"method":"mining.set_difficulty","params":[1.0]}"#,
+                "\n"
+            )
+        );
+    }
+}
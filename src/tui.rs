@@ -0,0 +1,508 @@
+use crate::stratum::{format_hashrate, Hashrates, Stats, Summary, Window, SOMPI_PER_KAS};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal, TerminalOptions, Viewport};
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time;
+use tokio_stream::StreamExt;
+
+/// Log lines kept for the events pane
+const MAX_EVENTS: usize = 200;
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+/// Used when the terminal size is unknown
+const DEFAULT_SIZE: (u16, u16) = (100, 30);
+/// Event lines scrolled by Page Up and Page Down
+const PAGE: usize = 10;
+
+/// State of the node as last reported
+#[derive(Clone, Debug, Default)]
+struct Node {
+    online: bool,
+    version: Option<String>,
+}
+
+/// Terminal dashboard with the workers, the pool hashrate, the node and the
+/// latest log lines, redrawn every second in place of the log
+#[derive(Clone, Default)]
+pub struct Dashboard {
+    events: Arc<Mutex<VecDeque<String>>>,
+    node: Arc<Mutex<Node>>,
+    /// Event lines scrolled back from the latest
+    scroll: Arc<AtomicUsize>,
+}
+
+impl Dashboard {
+    pub fn node_online(&self, online: bool) {
+        self.node.lock().unwrap().online = online;
+    }
+
+    pub fn node_version(&self, version: &str) {
+        self.node.lock().unwrap().version = Some(version.into());
+    }
+
+    /// Writer for the log, putting its lines in the events pane
+    pub fn log_writer(&self) -> LogWriter {
+        LogWriter {
+            events: self.events.clone(),
+            line: vec![],
+        }
+    }
+
+    /// Switch to the dashboard until the returned screen is dropped. Keys
+    /// are read from a terminal on stdin, the terminal is restored on
+    /// panics as well.
+    pub fn show(self, stats: Stats) -> Screen {
+        let stopped = Arc::new(AtomicBool::new(false));
+        // Without a terminal to read keys from, Ctrl-C is left to the shell
+        let raw = io::stdin().is_terminal() && terminal::enable_raw_mode().is_ok();
+        let _ = execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide);
+        let screen = Screen {
+            stopped: stopped.clone(),
+            quit: Arc::new(Notify::new()),
+            raw,
+        };
+        let previous = std::panic::take_hook();
+        let restore = stopped.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            leave(&restore, raw);
+            previous(info);
+        }));
+        let redraw = Arc::new(Notify::new());
+        if raw {
+            tokio::spawn(self.clone().read_keys(screen.quit.clone(), redraw.clone()));
+        }
+        let viewport = match terminal::size() {
+            Ok((width, height)) if width > 0 && height > 0 => Viewport::Fullscreen,
+            _ => Viewport::Fixed(Rect::new(0, 0, DEFAULT_SIZE.0, DEFAULT_SIZE.1)),
+        };
+        let backend = CrosstermBackend::new(io::stdout());
+        let mut terminal = match Terminal::with_options(backend, TerminalOptions { viewport }) {
+            Ok(terminal) => terminal,
+            Err(_) => return screen,
+        };
+        tokio::spawn(async move {
+            let mut interval = time::interval(REDRAW_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = redraw.notified() => {}
+                }
+                let view = self.view(&stats);
+                let _out = io::stdout().lock();
+                // Checked under the lock, so nothing is drawn after the
+                // screen was restored
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let _ = terminal.draw(|frame| view.draw(frame));
+            }
+        });
+        screen
+    }
+
+    async fn read_keys(self, quit: Arc<Notify>, redraw: Arc<Notify>) {
+        let mut events = EventStream::new();
+        while let Some(Ok(event)) = events.next().await {
+            let key = match event {
+                Event::Key(event) => match key(event) {
+                    Some(key) => key,
+                    None => continue,
+                },
+                Event::Resize(..) => {
+                    redraw.notify_one();
+                    continue;
+                }
+                _ => continue,
+            };
+            let events = self.events.lock().unwrap().len();
+            let scroll = self.scroll.load(Ordering::Relaxed);
+            let scroll = match key {
+                Key::Quit => {
+                    quit.notify_one();
+                    return;
+                }
+                Key::Up => scroll + 1,
+                Key::Down => scroll.saturating_sub(1),
+                Key::PageUp => scroll + PAGE,
+                Key::PageDown => scroll.saturating_sub(PAGE),
+                Key::End => 0,
+            };
+            self.scroll.store(scroll.min(events), Ordering::Relaxed);
+            redraw.notify_one();
+        }
+    }
+
+    fn view(&self, stats: &Stats) -> View {
+        let mut workers: Vec<_> = stats.worker_hashrates().into_iter().collect();
+        workers.sort_by(|(a, x), (b, y)| y.five_minutes.total_cmp(&x.five_minutes).then(a.cmp(b)));
+        // The pool's hashrate is the sum of its workers'
+        let mut chart: Vec<f64> = vec![];
+        for (worker, _) in &workers {
+            let points = stats.worker_chart(worker, Window::FiveMinutes);
+            for (i, point) in points.unwrap_or_default().iter().enumerate() {
+                match chart.get_mut(i) {
+                    Some(total) => *total += point.hashrate,
+                    None => chart.push(point.hashrate),
+                }
+            }
+        }
+        let node = self.node.lock().unwrap().clone();
+        let events: Vec<String> = self.events.lock().unwrap().iter().cloned().collect();
+        View {
+            summary: stats.summary(),
+            node,
+            workers,
+            chart,
+            events,
+            scroll: self.scroll.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The dashboard on screen, the terminal is restored when dropped
+pub struct Screen {
+    stopped: Arc<AtomicBool>,
+    quit: Arc<Notify>,
+    /// Keys are read from stdin in raw mode
+    raw: bool,
+}
+
+impl Screen {
+    /// Completes once Q or Ctrl-C was pressed
+    pub async fn quit(&self) {
+        self.quit.notified().await
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        leave(&self.stopped, self.raw);
+    }
+}
+
+/// Stop drawing and give the terminal back as it was
+fn leave(stopped: &AtomicBool, raw: bool) {
+    let mut out = io::stdout().lock();
+    stopped.store(true, Ordering::Relaxed);
+    let _ = execute!(out, cursor::Show, terminal::LeaveAlternateScreen);
+    if raw {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Quit,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    End,
+}
+
+/// The dashboard's key of a key press. Raw mode turns Ctrl-C into a key, so
+/// it quits as well.
+fn key(event: KeyEvent) -> Option<Key> {
+    if event.kind == KeyEventKind::Release {
+        return None;
+    }
+    let key = match event.code {
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Key::Quit,
+        KeyCode::Char('q' | 'Q') => Key::Quit,
+        KeyCode::Up | KeyCode::Char('k') => Key::Up,
+        KeyCode::Down | KeyCode::Char('j') => Key::Down,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::End | KeyCode::Char('G') => Key::End,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Collects the log output of one event into lines for the events pane
+pub struct LogWriter {
+    events: Arc<Mutex<VecDeque<String>>>,
+    line: Vec<u8>,
+}
+
+impl LogWriter {
+    fn push(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).trim_end().to_string();
+        self.line.clear();
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(line);
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            match byte {
+                b'\n' => self.push(),
+                _ => self.line.push(byte),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.push();
+        }
+    }
+}
+
+/// What a frame shows
+struct View {
+    summary: Summary,
+    node: Node,
+    /// By hashrate, fastest first
+    workers: Vec<(String, Hashrates)>,
+    /// Pool hashrate per bucket, oldest first
+    chart: Vec<f64>,
+    /// Oldest first
+    events: Vec<String>,
+    /// Event lines scrolled back from the latest
+    scroll: usize,
+}
+
+impl View {
+    fn draw(&self, frame: &mut Frame) {
+        let area = frame.area();
+        // Half of what is left for the workers, the rest for the events
+        let rest = area.height.saturating_sub(10) as usize;
+        let shown = self.workers.len().min(rest.div_ceil(2));
+        let more = (shown < self.workers.len()) as usize;
+        let table = (shown + more + 2) as u16;
+        let [header, chart, workers, events] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(3),
+            Constraint::Length(table),
+            Constraint::Min(0),
+        ])
+        .areas(area);
+
+        let summary = &self.summary;
+        let node = match (&self.node.online, &self.node.version) {
+            (true, Some(version)) => vec![
+                Span::raw("node online").fg(Color::Green),
+                Span::raw(format!(", kaspad {version}")),
+            ],
+            (true, None) => vec![Span::raw("node online").fg(Color::Green)],
+            (false, _) => vec![Span::raw("node offline").fg(Color::Red)],
+        };
+        let title = format!("kaspad-stratum {}  ", env!("CARGO_PKG_VERSION"));
+        let template = match (summary.daa_score, summary.template_age) {
+            (Some(score), Some(age)) => format!(
+                "DAA score {score}, reward {:.2} KAS, template {:.1}s old",
+                summary.reward.total() as f64 / SOMPI_PER_KAS as f64,
+                age.as_secs_f64()
+            ),
+            _ => "no template yet".into(),
+        };
+        let lines = vec![
+            Line::from([vec![Span::raw(title).bold()], node].concat()),
+            Line::from(template),
+            Line::from(format!(
+                "{} workers, {}, {:.1} shares/min, {} blocks found",
+                summary.workers,
+                format_hashrate(summary.hashrate),
+                summary.shares_per_min,
+                summary.blocks_found
+            )),
+        ];
+        frame.render_widget(Paragraph::new(lines), header);
+
+        let [title, sparkline, _] = Layout::vertical([Constraint::Length(1); 3]).areas(chart);
+        frame.render_widget(Line::from("Hashrate, last 5 minutes").bold(), title);
+        let data = stretch(&self.chart, sparkline.width as usize);
+        let sparkline_widget = Sparkline::default()
+            .data(data)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(sparkline_widget, sparkline);
+
+        let right = |text: String| Line::from(text).right_aligned();
+        let mut rows: Vec<Row> = self.workers[..shown]
+            .iter()
+            .map(|(worker, rates)| {
+                Row::new([
+                    Line::from(worker.as_str()),
+                    right(format_hashrate(rates.five_minutes)),
+                    right(format_hashrate(rates.hour)),
+                    right(format_hashrate(rates.day)),
+                ])
+            })
+            .collect();
+        if more > 0 {
+            let more = format!("... {} more", self.workers.len() - shown);
+            rows.push(Row::new([Line::from(more)]));
+        }
+        let header = Row::new([
+            Line::from("WORKER"),
+            right("5M".into()),
+            right("1H".into()),
+            right("24H".into()),
+        ])
+        .bold();
+        let widths = [
+            Constraint::Length(32),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ];
+        frame.render_widget(Table::new(rows, widths).header(header), workers);
+
+        let [heading, log] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(events);
+        let title = match self.scroll {
+            0 => vec![
+                Span::raw("Recent events").bold(),
+                Span::raw("  arrows or PgUp/PgDn scroll, q quits"),
+            ],
+            n => vec![
+                Span::raw("Recent events").bold(),
+                Span::raw(format!("  {n} lines back, End follows the latest")),
+            ],
+        };
+        frame.render_widget(Line::from(title), heading);
+        let end = self.events.len().saturating_sub(self.scroll);
+        let skip = end.saturating_sub(log.height as usize);
+        let lines: Vec<Line> = self.events[skip..end]
+            .iter()
+            .map(|e| Line::from(e.as_str()))
+            .collect();
+        frame.render_widget(Paragraph::new(lines), log);
+    }
+}
+
+/// Each value repeated to stretch the chart over the width, the hashrates
+/// in whole H/s
+fn stretch(values: &[f64], width: usize) -> Vec<u64> {
+    let repeat = (width / values.len().max(1)).max(1);
+    values
+        .iter()
+        .flat_map(|&v| std::iter::repeat_n(v as u64, repeat))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key, stretch, Dashboard, Key, Node, View};
+    use crate::kaspad::BlockReward;
+    use crate::stratum::{Hashrates, Summary};
+    use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// The lines of a `width` by `height` terminal showing the view
+    fn render(view: &View, width: u16, height: u16) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| view.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..height)
+            .map(|y| {
+                let line: String = (0..width).map(|x| buffer[(x, y)].symbol()).collect();
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_panes() {
+        let rates = |h| Hashrates {
+            five_minutes: h,
+            hour: h,
+            day: h,
+        };
+        let view = View {
+            summary: Summary {
+                workers: 4,
+                hashrate: 3e12,
+                shares_per_min: 12.0,
+                blocks_found: 1,
+                daa_score: Some(60_000_000),
+                template_age: Some(Duration::from_millis(400)),
+                reward: BlockReward::default(),
+            },
+            node: Node {
+                online: true,
+                version: Some("0.13.4".into()),
+            },
+            workers: vec![
+                ("rig1".into(), rates(2e12)),
+                ("rig2".into(), rates(5e11)),
+                ("rig3".into(), rates(5e11)),
+                ("rig4".into(), rates(1e9)),
+            ],
+            chart: vec![0.0, 1.0, 2.0],
+            events: (0..20).map(|i| format!("event {i}")).collect(),
+            scroll: 0,
+        };
+        let lines = render(&view, 60, 15);
+        assert!(lines[0].contains("node online") && lines[0].contains("0.13.4"));
+        assert!(lines[1].starts_with("DAA score 60000000"));
+        assert!(lines[2].starts_with("4 workers, 3.00 TH/s"));
+        assert!(lines[8].starts_with("rig1") && lines[8].contains("2.00 TH/s"));
+        assert!(lines.iter().any(|l| l == "... 1 more"));
+        // The latest events that fit
+        assert_eq!(lines.last().unwrap(), "event 19");
+
+        let view = View { scroll: 5, ..view };
+        let lines = render(&view, 60, 15);
+        assert!(lines.iter().any(|l| l.contains("5 lines back")));
+        assert_eq!(lines.last().unwrap(), "event 14");
+
+        assert_eq!(stretch(&[0.0, 1.0, 2.0], 6), [0, 0, 1, 1, 2, 2]);
+        assert!(stretch(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn reads_keys() {
+        let press = |code| key(KeyEvent::new(code, KeyModifiers::NONE));
+        assert_eq!(press(KeyCode::Char('k')), Some(Key::Up));
+        assert_eq!(press(KeyCode::Up), Some(Key::Up));
+        assert_eq!(press(KeyCode::PageDown), Some(Key::PageDown));
+        assert_eq!(press(KeyCode::End), Some(Key::End));
+        assert_eq!(press(KeyCode::Char('q')), Some(Key::Quit));
+        assert_eq!(press(KeyCode::Char('x')), None);
+        // Raw mode delivers Ctrl-C as a key instead of a signal
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(key(ctrl_c), Some(Key::Quit));
+        assert_eq!(press(KeyCode::Char('c')), None);
+        let release =
+            KeyEvent::new_with_kind(KeyCode::Up, KeyModifiers::NONE, KeyEventKind::Release);
+        assert_eq!(key(release), None);
+    }
+
+    #[test]
+    fn collects_log_lines() {
+        let dashboard = Dashboard::default();
+        let mut writer = dashboard.log_writer();
+        writer.write_all(b"first\nsec").unwrap();
+        writer.write_all(b"ond").unwrap();
+        drop(writer);
+        let events = dashboard.events.lock().unwrap();
+        assert_eq!(*events, ["first", "second"]);
+    }
+}
@@ -6,6 +6,7 @@
 //! Implementation of various large-but-fixed sized unsigned integer types.
 //! The functions here are designed to be fast.
 
+#[allow(dead_code)]
 pub trait BitArray {
     /// Is bit set?
     fn bit(&self, idx: usize) -> bool;
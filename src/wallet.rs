@@ -0,0 +1,349 @@
+use anyhow::{anyhow, bail, Result};
+use proto::kaspawalletd_client::KaspawalletdClient;
+use proto::{GetBalanceRequest, GetExternalSpendableUtxOsRequest, SendRequest};
+use std::fmt;
+use std::time::Duration;
+use tokio::time;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+/// Time the daemon has to answer a send, after which the payment may or
+/// may not have gone out
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/kaspawalletd.rs"));
+}
+
+/// Sompi held by the wallet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalletBalance {
+    pub available: u64,
+    /// Received but not spendable yet
+    pub pending: u64,
+}
+
+/// An unspent output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub transaction_id: String,
+    pub index: u32,
+    /// Sompi
+    pub amount: u64,
+    pub daa_score: u64,
+    pub coinbase: bool,
+}
+
+impl Utxo {
+    /// `transaction_id:index`
+    pub fn outpoint(&self) -> String {
+        format!("{}:{}", self.transaction_id, self.index)
+    }
+}
+
+/// The daemon refused a send before broadcasting anything, so it can be
+/// retried. Any other error of a send leaves open whether it went out.
+#[derive(Debug)]
+pub struct Refused(Status);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.message())
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Client of a kaspawallet daemon (`kaspawallet start-daemon`), which holds
+/// the keys and signs. The password unlocks the keys for sending, so the
+/// daemon should only be reachable locally or over a secure network.
+#[derive(Clone)]
+pub struct Wallet {
+    client: KaspawalletdClient<Channel>,
+    password: String,
+}
+
+impl Wallet {
+    pub async fn connect(url: &str, password: &str) -> Result<Self> {
+        let url = match url.starts_with("http") {
+            true => url.to_string(),
+            false => format!("http://{url}"),
+        };
+        let channel = Endpoint::from_shared(url)?.connect().await?;
+        Ok(Wallet {
+            client: KaspawalletdClient::new(channel),
+            password: password.into(),
+        })
+    }
+
+    pub async fn balance(&self) -> Result<WalletBalance> {
+        let res = self
+            .client
+            .clone()
+            .get_balance(GetBalanceRequest {})
+            .await?
+            .into_inner();
+        Ok(WalletBalance {
+            available: res.available,
+            pending: res.pending,
+        })
+    }
+
+    /// Outputs of `address` the wallet can spend now
+    pub async fn spendable_utxos(&self, address: &str) -> Result<Vec<Utxo>> {
+        let req = GetExternalSpendableUtxOsRequest {
+            address: address.into(),
+        };
+        let res = self
+            .client
+            .clone()
+            .get_external_spendable_utx_os(req)
+            .await?
+            .into_inner();
+        Ok(res
+            .entries
+            .into_iter()
+            .filter_map(|e| {
+                let (outpoint, entry) = (e.outpoint?, e.utxo_entry?);
+                Some(Utxo {
+                    transaction_id: outpoint.transaction_id,
+                    index: outpoint.index,
+                    amount: entry.amount,
+                    daa_score: entry.block_daa_score,
+                    coinbase: entry.is_coinbase,
+                })
+            })
+            .collect())
+    }
+
+    /// Send `amount` sompi to `to`, spending outputs of the `from`
+    /// addresses, or of any address of the wallet if empty. Returns the ids
+    /// of the transactions. Fails with [`Refused`] if nothing was sent.
+    pub async fn send(&self, to: &str, amount: u64, from: &[String]) -> Result<Vec<String>> {
+        let req = SendRequest {
+            to_address: to.into(),
+            amount,
+            password: self.password.clone(),
+            from: from.to_vec(),
+            use_existing_change_address: true,
+            is_send_all: false,
+        };
+        let mut client = self.client.clone();
+        let res = match time::timeout(SEND_TIMEOUT, client.send(req)).await {
+            Ok(Ok(res)) => res.into_inner(),
+            // Requests the daemon rejects outright, anything else may have
+            // failed after broadcasting
+            Ok(Err(status))
+                if matches!(
+                    status.code(),
+                    Code::InvalidArgument
+                        | Code::FailedPrecondition
+                        | Code::Unauthenticated
+                        | Code::PermissionDenied
+                ) =>
+            {
+                return Err(Refused(status).into())
+            }
+            Ok(Err(status)) => return Err(status.into()),
+            Err(_) => return Err(anyhow!("The wallet didn't answer in {SEND_TIMEOUT:?}")),
+        };
+        if res.tx_i_ds.is_empty() {
+            bail!("The wallet sent no transaction");
+        }
+        Ok(res.tx_i_ds)
+    }
+}
+
+/// The largest outputs covering `amount`, `None` if all of them don't.
+/// Taking the largest first keeps transactions small.
+pub fn select_utxos(utxos: &[Utxo], amount: u64) -> Option<Vec<Utxo>> {
+    let mut sorted: Vec<_> = utxos.iter().collect();
+    sorted.sort_by_key(|u| std::cmp::Reverse(u.amount));
+    let mut selected = vec![];
+    let mut total = 0u64;
+    for utxo in sorted {
+        if total >= amount {
+            break;
+        }
+        total = total.saturating_add(utxo.amount);
+        selected.push(utxo.clone());
+    }
+    (total >= amount).then_some(selected)
+}
+
+/// Kaspawallet daemon for tests, answering from fixed balances and outputs
+/// and recording what it is asked to send
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::proto::kaspawalletd_server::{Kaspawalletd, KaspawalletdServer};
+    use super::proto::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response, Status};
+
+    /// `(to, amount, from)` of a send
+    pub type Send = (String, u64, Vec<String>);
+
+    #[derive(Clone, Default)]
+    pub struct MockWallet {
+        pub available: u64,
+        /// Amounts of the outputs
+        pub utxos: Vec<u64>,
+        pub sent: Arc<Mutex<Vec<Send>>>,
+        /// Sends to this address are refused
+        pub failing: Option<String>,
+        /// Sends to this address go out but answer with an error, as when
+        /// the connection drops
+        pub lost: Option<String>,
+    }
+
+    impl MockWallet {
+        /// Serve on a free port, returning the URL
+        pub async fn serve(self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(
+                tonic::transport::Server::builder()
+                    .add_service(KaspawalletdServer::new(self))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            url
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Kaspawalletd for MockWallet {
+        async fn get_balance(
+            &self,
+            _: Request<GetBalanceRequest>,
+        ) -> Result<Response<GetBalanceResponse>, Status> {
+            Ok(Response::new(GetBalanceResponse {
+                available: self.available,
+                pending: 0,
+                address_balances: vec![],
+            }))
+        }
+
+        async fn get_external_spendable_utx_os(
+            &self,
+            req: Request<GetExternalSpendableUtxOsRequest>,
+        ) -> Result<Response<GetExternalSpendableUtxOsResponse>, Status> {
+            let address = req.into_inner().address;
+            let entry = |transaction_id, amount, i: usize, is_coinbase| UtxosByAddressesEntry {
+                address: address.clone(),
+                outpoint: Some(Outpoint {
+                    transaction_id,
+                    index: 0,
+                }),
+                utxo_entry: Some(UtxoEntry {
+                    amount,
+                    script_public_key: None,
+                    block_daa_score: 1000 + i as u64,
+                    is_coinbase,
+                }),
+            };
+            let mut entries: Vec<_> = self
+                .utxos
+                .iter()
+                .enumerate()
+                .map(|(i, &amount)| entry(format!("{i:064x}"), amount, i, true))
+                .collect();
+            // Outputs of the sends, at the addresses paid
+            let sent = self.sent.lock().unwrap();
+            for (i, (to, amount, _)) in sent.iter().enumerate() {
+                if *to == address {
+                    entries.push(entry(format!("tx{}", i + 1), *amount, i, false));
+                }
+            }
+            Ok(Response::new(GetExternalSpendableUtxOsResponse { entries }))
+        }
+
+        async fn send(&self, req: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
+            let req = req.into_inner();
+            if req.password != "secret" {
+                return Err(Status::unauthenticated("wrong password"));
+            }
+            if self.failing.as_ref() == Some(&req.to_address) {
+                return Err(Status::failed_precondition("insufficient funds"));
+            }
+            let lost = self.lost.as_ref() == Some(&req.to_address);
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((req.to_address, req.amount, req.from));
+            if lost {
+                return Err(Status::unavailable("connection reset"));
+            }
+            Ok(Response::new(SendResponse {
+                tx_i_ds: vec![format!("tx{}", sent.len())],
+                signed_transactions: vec![],
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockWallet;
+    use super::{select_utxos, Refused, Utxo, Wallet, WalletBalance};
+
+    fn utxo(amount: u64) -> Utxo {
+        Utxo {
+            transaction_id: amount.to_string(),
+            index: 0,
+            amount,
+            daa_score: 0,
+            coinbase: true,
+        }
+    }
+
+    #[test]
+    fn selects_largest_utxos() {
+        let utxos = [utxo(5), utxo(50), utxo(20)];
+        let amounts = |amount| {
+            select_utxos(&utxos, amount).map(|s| s.iter().map(|u| u.amount).collect::<Vec<_>>())
+        };
+        assert_eq!(amounts(40), Some(vec![50]));
+        assert_eq!(amounts(60), Some(vec![50, 20]));
+        assert_eq!(amounts(75), Some(vec![50, 20, 5]));
+        assert_eq!(amounts(76), None);
+        assert_eq!(amounts(0), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn talks_to_the_daemon() {
+        let mock = MockWallet {
+            available: 70,
+            utxos: vec![50, 20],
+            ..Default::default()
+        };
+        let sent = mock.sent.clone();
+        let url = mock.serve().await;
+        let wallet = Wallet::connect(&url, "secret").await.unwrap();
+        assert_eq!(
+            wallet.balance().await.unwrap(),
+            WalletBalance {
+                available: 70,
+                pending: 0
+            }
+        );
+        let utxos = wallet.spendable_utxos("kaspa:pool").await.unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert_eq!((utxos[1].amount, utxos[1].daa_score), (20, 1001));
+
+        let from = vec!["kaspa:pool".to_string()];
+        let txs = wallet.send("kaspa:qqa", 30, &from).await.unwrap();
+        assert_eq!(txs, ["tx1"]);
+        assert_eq!(
+            sent.lock().unwrap()[..],
+            [("kaspa:qqa".to_string(), 30, from.clone())]
+        );
+
+        let utxos = wallet.spendable_utxos("kaspa:qqa").await.unwrap();
+        assert_eq!(utxos.len(), 3);
+        assert_eq!((utxos[2].outpoint(), utxos[2].amount), ("tx1:0".into(), 30));
+
+        let locked = Wallet::connect(&url, "wrong").await.unwrap();
+        let e = locked.send("kaspa:qqa", 30, &from).await.unwrap_err();
+        assert!(e.is::<Refused>(), "{e}");
+    }
+}
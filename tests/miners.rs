@@ -0,0 +1,800 @@
+//! Sessions in the shape of each miner's messages, played against the server
+//! to check every dialect gets its shares accepted. They are written after
+//! the miners' behavior, not captured from them.
+
+use kaspad_stratum::kaspad::{KaspadHandle, RpcBlock, RpcBlockHeader, RpcBlockLevelParents};
+use kaspad_stratum::stratum::{
+    Auth, Config, DialectConfig, NoncePrefix, NotifyLimits, Overrides, Preset, Stratum, TlsConfig,
+    VarDiffConfig,
+};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::rustls::{self, Certificate, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+fn hash(seed: usize) -> String {
+    format!("{seed:064x}")
+}
+
+fn template() -> RpcBlock {
+    let parents = (0..2)
+        .map(|level| RpcBlockLevelParents {
+            parent_hashes: (0..2).map(|i| hash(level * 10 + i)).collect(),
+        })
+        .collect();
+    RpcBlock {
+        header: Some(RpcBlockHeader {
+            version: 1,
+            parents,
+            hash_merkle_root: hash(100),
+            accepted_id_merkle_root: hash(101),
+            utxo_commitment: hash(102),
+            timestamp: 1_700_000_000_000,
+            bits: 0x1b0404cb,
+            nonce: 0,
+            daa_score: 60_000_000,
+            blue_work: "3bc3ec5e1f2aa8d".into(),
+            pruning_point: hash(103),
+            blue_score: 59_000_000,
+        }),
+        transactions: vec![],
+        verbose_data: None,
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap()
+        .port()
+}
+
+/// A server with a job where every nonce is a share
+async fn serve(config: Config) -> (Stratum, SocketAddr) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = Config {
+        vardiff: Some(VarDiffConfig {
+            start_difficulty: 1,
+            min_difficulty: 1,
+            by_agent: false,
+            ..Default::default()
+        }),
+        ..config
+    };
+    let (handle, _) = KaspadHandle::new();
+    let stratum = Stratum::new(&addr.to_string(), handle, config)
+        .await
+        .unwrap();
+    stratum.broadcast(template()).await;
+    (stratum, addr)
+}
+
+fn data(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+struct Miner {
+    lines: Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Miner {
+    async fn connect(addr: SocketAddr) -> Self {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        Miner::new(reader, writer)
+    }
+
+    /// Connect over TLS, trusting the certificate of the test data
+    async fn connect_tls(addr: SocketAddr) -> Self {
+        let pem = std::fs::read(data("cert.pem")).unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &pem[..]).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let name = "localhost".try_into().unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, conn)
+            .await
+            .unwrap();
+        let (reader, writer) = tokio::io::split(stream);
+        Miner::new(reader, writer)
+    }
+
+    fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Miner {
+            lines: BufReader::new(Box::new(reader) as Box<_>).lines(),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Send a line and collect the messages until the server goes quiet
+    async fn send(&mut self, line: &str) -> Vec<Value> {
+        self.writer.write_all(line.as_bytes()).await.unwrap();
+        self.writer.write_all(b"\n").await.unwrap();
+        self.receive().await
+    }
+
+    /// Collect the messages until the server goes quiet
+    async fn receive(&mut self) -> Vec<Value> {
+        let mut received = vec![];
+        while let Ok(Ok(Some(line))) =
+            time::timeout(Duration::from_millis(200), self.lines.next_line()).await
+        {
+            received.push(serde_json::from_str(&line).unwrap());
+        }
+        received
+    }
+}
+
+fn method<'a>(msgs: &'a [Value], name: &str) -> Option<&'a Value> {
+    msgs.iter().find(|m| m["method"] == name)
+}
+
+fn position(msgs: &[Value], name: &str) -> usize {
+    msgs.iter().position(|m| m["method"] == name).unwrap()
+}
+
+#[tokio::test]
+async fn lolminer() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["lolMiner 1.88","EthereumStratum/1.0.0"]}"#)
+        .await;
+    assert_eq!(
+        msgs[0]["result"],
+        serde_json::json!([true, "EthereumStratum/1.0.0"])
+    );
+    let extranonce = method(&msgs, "set_extranonce").unwrap()["params"][0]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(position(&msgs, "set_extranonce") < position(&msgs, "mining.notify"));
+    // Scaled to lolMiner's difficulty 1 target
+    let difficulty = method(&msgs, "mining.set_difficulty").unwrap()["params"][0]
+        .as_f64()
+        .unwrap();
+    assert_eq!(difficulty, 65535.0 / 65536.0 / (1u64 << 32) as f64);
+
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.lol","x"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.lol","00","0x{extranonce}00000000002a"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["id"], 3);
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+/// Written after GMiner's handshake, not captured from it
+#[tokio::test]
+async fn gminer() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["GMiner/3.44"]}"#)
+        .await;
+    let result = &msgs[0]["result"];
+    assert_eq!(result[0][0][0], "mining.notify");
+    let extranonce = result[1].as_str().unwrap().to_string();
+    assert_eq!(result[2], 6);
+    assert!(method(&msgs, "mining.set_extranonce").is_none());
+    assert!(position(&msgs, "mining.set_difficulty") < position(&msgs, "mining.notify"));
+
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.extranonce.subscribe","params":[]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    assert_eq!(msgs[1]["method"], "mining.set_extranonce");
+    assert_eq!(msgs[1]["params"][0], extranonce.as_str());
+
+    let msgs = miner
+        .send(r#"{"id":3,"method":"mining.authorize","params":["kaspa:qz0000.gminer","x"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let submit = format!(
+        r#"{{"id":4,"method":"mining.submit","params":["kaspa:qz0000.gminer","00","{extranonce}000000000007"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["id"], 4);
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+/// Written after SRBMiner's handshake, not captured from it
+#[tokio::test]
+async fn srbminer() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    // SRBMiner authorizes and asks for the extranonce before subscribing
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.authorize","params":["kaspa:qz0000.srb","x"]}"#)
+        .await;
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0]["result"], true);
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.extranonce.subscribe","params":[]}"#)
+        .await;
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0]["result"], true);
+
+    let msgs = miner
+        .send(r#"{"id":3,"method":"mining.subscribe","params":["SRBMiner-MULTI/2.4.6"]}"#)
+        .await;
+    assert_eq!(msgs[0]["id"], 3);
+    let extranonce = msgs[0]["result"][1].as_str().unwrap();
+    let set_extranonce = method(&msgs, "mining.set_extranonce").unwrap();
+    assert_eq!(set_extranonce["params"][0], extranonce);
+    assert!(position(&msgs, "mining.set_extranonce") < position(&msgs, "mining.notify"));
+
+    // Only the nonce bytes left to the miner
+    let msgs = miner
+        .send(r#"{"id":4,"method":"mining.submit","params":["kaspa:qz0000.srb","00","00000000002a"]}"#)
+        .await;
+    assert_eq!(msgs[0]["id"], 4);
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+    let full = format!(
+        r#"{{"id":5,"method":"mining.submit","params":["kaspa:qz0000.srb","00","{extranonce}00000000002b"]}}"#
+    );
+    let msgs = miner.send(&full).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn kaspa_miner() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let methods: Vec<_> = msgs[1..].iter().map(|m| m["method"].clone()).collect();
+    assert_eq!(
+        methods,
+        ["set_extranonce", "mining.notify", "mining.set_difficulty"]
+    );
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+    assert!(msgs[2]["params"][1].is_array());
+
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.ref"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.ref","00","0x{extranonce}000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+    // The 0x is optional
+    let submit = format!(
+        r#"{{"id":4,"method":"mining.submit","params":["kaspa:qz0000.ref","00","{extranonce}000000000002"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn goldshell_port() {
+    let port = free_port();
+    let config = Config {
+        dialect_ports: vec![(port, Preset::Goldshell)],
+        ..Default::default()
+    };
+    let (_stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect(SocketAddr::new(addr.ip(), port)).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.login","params":["kaspa:qz0000.ka0"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.subscribe","params":[]}"#)
+        .await;
+    let extranonce = msgs[0]["result"][1].as_str().unwrap();
+    let notify = method(&msgs, "mining.notify").unwrap();
+    // The header with the timestamp in one hex string
+    assert_eq!(notify["params"].as_array().unwrap().len(), 2);
+
+    let nonce = u64::from_str_radix(&format!("{extranonce}000000000003"), 16).unwrap();
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.ka0","00","{}"]}}"#,
+        hex::encode(nonce.to_le_bytes())
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn listener_profile() {
+    let port = free_port();
+    let listeners =
+        format!(r#"[{{"port": {port}, "dialect": "kaspa-miner", "extranonce_size": 1}}]"#);
+    let config = Config {
+        listeners: serde_json::from_str(&listeners).unwrap(),
+        ..Default::default()
+    };
+    let (_stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect(SocketAddr::new(addr.ip(), port)).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["proxy/1.0"]}"#)
+        .await;
+    let set_extranonce = method(&msgs, "set_extranonce").unwrap();
+    let extranonce = set_extranonce["params"][0].as_str().unwrap().to_string();
+    assert_eq!(extranonce.len(), 2);
+    assert_eq!(set_extranonce["params"][1], 7);
+
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.nh"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.nh","00","0x{extranonce}00000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+
+    // The stratum address keeps its two bytes
+    let mut miner = Miner::connect(addr).await;
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    assert_eq!(msgs[1]["params"][0].as_str().unwrap().len(), 4);
+    assert_eq!(msgs[1]["params"][1], 6);
+}
+
+#[tokio::test]
+async fn decimal_nonce() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":[]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap();
+    let prefix = u64::from_str_radix(extranonce, 16).unwrap();
+    miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.asic"]}"#)
+        .await;
+    let nonce = prefix << 48 | 12345;
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.asic","00","{nonce}"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn worker_from_subscribe() {
+    let (stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["BzMiner/v17.0.0","kaspa:qz0000.rig1"]}"#)
+        .await;
+    let connections = stratum.control().connections();
+    assert_eq!(connections[0].worker.as_deref(), Some("kaspa:qz0000.rig1"));
+
+    let extranonce = method(&msgs, "set_extranonce").unwrap()["params"][0]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let submit = format!(
+        r#"{{"id":2,"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x{extranonce}000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn refuses_unknown_workers() {
+    let path =
+        std::env::temp_dir().join(format!("kaspad-stratum-credentials-{}", std::process::id()));
+    std::fs::write(&path, "kaspa:qz0000.rig1 hunter2\n").unwrap();
+    let (_stratum, addr) = serve(Config {
+        auth: Some(Auth::from_file(&path).unwrap()),
+        ..Default::default()
+    })
+    .await;
+    let mut miner = Miner::connect(addr).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.rig1","x"]}"#)
+        .await;
+    assert_eq!(msgs[0]["error"][1], "Unauthorized worker", "{}", msgs[0]);
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x{extranonce}000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert!(msgs[0]["error"].is_array(), "{}", msgs[0]);
+
+    let msgs = miner
+        .send(r#"{"id":4,"method":"mining.authorize","params":["kaspa:qz0000.rig1","hunter2"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn shares_without_authorize() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let submit = r#"{"id":2,"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x0000000000000001"]}"#;
+    let msgs = miner.send(submit).await;
+    assert_eq!(msgs[0]["error"][1], "Not subscribed", "{}", msgs[0]);
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+    // Without credentials configured the worker needn't authorize
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x{extranonce}000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+}
+
+#[tokio::test]
+async fn line_too_long() {
+    let (_stratum, addr) = serve(Config::default()).await;
+    let mut miner = Miner::connect(addr).await;
+
+    let params = "0".repeat(20_000);
+    let line = format!(r#"{{"id":1,"method":"mining.subscribe","params":["{params}"]}}"#);
+    let msgs = miner.send(&line).await;
+    assert_eq!(msgs.len(), 1, "{msgs:?}");
+    assert_eq!(msgs[0]["id"], Value::Null);
+    assert_eq!(msgs[0]["error"][1], "Line too long");
+    // Then the connection is closed
+    assert!(miner.lines.next_line().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn tls_port() {
+    let port = free_port();
+    let config = Config {
+        tls: Some(TlsConfig {
+            cert: data("cert.pem"),
+            key: data("key.pem"),
+            ports: vec![port],
+        }),
+        ..Default::default()
+    };
+    let (_stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect_tls(SocketAddr::new(addr.ip(), port)).await;
+
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+    let msgs = miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.rig1"]}"#)
+        .await;
+    assert_eq!(msgs[0]["result"], true);
+    let submit = format!(
+        r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x{extranonce}000000000001"]}}"#
+    );
+    let msgs = miner.send(&submit).await;
+    assert_eq!(msgs[0]["result"], true, "{}", msgs[0]);
+
+    // The main port stays plain
+    let mut plain = Miner::connect(addr).await;
+    let msgs = plain
+        .send(r#"{"id":1,"method":"mining.subscribe","params":[]}"#)
+        .await;
+    assert_eq!(msgs[0]["id"], 1);
+}
+
+#[tokio::test]
+async fn notify_interval() {
+    let port = free_port();
+    let config = Config {
+        dialect_ports: vec![(port, Preset::KaspaMiner)],
+        notify_limits: NotifyLimits {
+            ports: vec![(port, Duration::from_secs(1))],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect(SocketAddr::new(addr.ip(), port)).await;
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":[]}"#)
+        .await;
+    assert!(method(&msgs, "mining.notify").is_some());
+
+    // The next job waits for the interval
+    stratum.broadcast(template()).await;
+    assert!(method(&miner.receive().await, "mining.notify").is_none());
+    time::sleep(Duration::from_millis(800)).await;
+    assert!(method(&miner.receive().await, "mining.notify").is_some());
+}
+
+#[tokio::test]
+async fn newest_job_after_burst() {
+    let port = free_port();
+    let config = Config {
+        dialect_ports: vec![(port, Preset::KaspaMiner)],
+        notify_limits: NotifyLimits {
+            ports: vec![(port, Duration::from_secs(1))],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect(SocketAddr::new(addr.ip(), port)).await;
+    miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":[]}"#)
+        .await;
+
+    // Held back by the interval, with the older template arriving last
+    let with_daa_score = |offset| {
+        let mut template = template();
+        template.header.as_mut().unwrap().daa_score += offset;
+        template
+    };
+    stratum.broadcast(with_daa_score(2)).await;
+    stratum.broadcast(with_daa_score(1)).await;
+    assert!(method(&miner.receive().await, "mining.notify").is_none());
+    time::sleep(Duration::from_millis(800)).await;
+    let msgs = miner.receive().await;
+    let notify = method(&msgs, "mining.notify").unwrap();
+    assert_eq!(notify["params"][0], "01");
+}
+
+#[tokio::test]
+async fn nonce_prefix() {
+    for (policy, accepted) in [
+        (None, false),
+        (Some(NoncePrefix::Rewrite), true),
+        (Some(NoncePrefix::Off), true),
+    ] {
+        let config = Config {
+            dialect: DialectConfig {
+                overrides: Overrides {
+                    nonce_prefix: policy,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (_stratum, addr) = serve(config).await;
+        let mut miner = Miner::connect(addr).await;
+        let msgs = miner
+            .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+            .await;
+        let extranonce = msgs[1]["params"][0].as_str().unwrap();
+        // Firmware ignoring the extranonce rolls the whole nonce
+        let foreign = u16::from_str_radix(extranonce, 16).unwrap() ^ 0xffff;
+        miner
+            .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.asic"]}"#)
+            .await;
+        let submit = format!(
+            r#"{{"id":3,"method":"mining.submit","params":["kaspa:qz0000.asic","00","0x{foreign:04x}000000000001"]}}"#
+        );
+        let msgs = miner.send(&submit).await;
+        assert_eq!(
+            msgs[0]["result"] == true,
+            accepted,
+            "{policy:?}: {}",
+            msgs[0]
+        );
+    }
+}
+
+#[tokio::test]
+async fn timestamp_rolling() {
+    let config = Config {
+        dialect: DialectConfig {
+            overrides: Overrides {
+                timestamp_rolling: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (_stratum, addr) = serve(config).await;
+    let mut miner = Miner::connect(addr).await;
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+    miner
+        .send(r#"{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.rig"]}"#)
+        .await;
+    let submit = |id: u32, timestamp: u64| {
+        format!(
+            r#"{{"id":{id},"method":"mining.submit","params":["kaspa:qz0000.rig","00","0x{extranonce}000000000001",{timestamp}]}}"#
+        )
+    };
+    let template = template().header.unwrap().timestamp as u64;
+    // The same nonce hashes differently at another timestamp
+    for (id, timestamp, accepted) in [
+        (3, template, true),
+        (4, template + 1000, true),
+        (5, template + 1000, false),
+        (6, template - 1, false),
+        (7, u64::MAX, false),
+    ] {
+        let msgs = miner.send(&submit(id, timestamp)).await;
+        assert_eq!(msgs[0]["result"] == true, accepted, "{}", msgs[0]);
+    }
+}
+
+#[tokio::test]
+async fn caps_pending_blocks() {
+    let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let config = Config {
+        vardiff: Some(VarDiffConfig {
+            start_difficulty: 1,
+            min_difficulty: 1,
+            by_agent: false,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    // Kaspad never answers the blocks
+    let (handle, mut kaspad) = KaspadHandle::new();
+    let stratum = Stratum::new(&addr.to_string(), handle, config)
+        .await
+        .unwrap();
+    // About every second nonce is a block
+    let mut block = template();
+    block.header.as_mut().unwrap().bits = 0x207fffff;
+    stratum.broadcast(block).await;
+    let mut miner = Miner::connect(addr).await;
+    let msgs = miner
+        .send(r#"{"id":1,"method":"mining.subscribe","params":["kaspa-miner/0.2.1"]}"#)
+        .await;
+    let extranonce = msgs[1]["params"][0].as_str().unwrap().to_string();
+
+    let (mut held, mut submitted) = (0, vec![]);
+    for nonce in 1..200 {
+        let id = nonce + 1;
+        let submit = format!(
+            r#"{{"id":{id},"method":"mining.submit","params":["kaspa:qz0000.rig1","00","0x{extranonce}{nonce:012x}"]}}"#
+        );
+        let msgs = miner.send(&submit).await;
+        match msgs.iter().find(|m| m["id"] == id) {
+            // Held until kaspad answers
+            None => held += 1,
+            Some(m) => assert_eq!(m["result"], true, "{m}"),
+        }
+        // Kept unanswered, dropping them fails the blocks
+        while let Ok(command) = kaspad.try_recv() {
+            submitted.push(command);
+        }
+        if submitted.len() > 10 {
+            break;
+        }
+    }
+    // Blocks beyond the cap still reach kaspad, answered right away
+    assert_eq!(held, 8);
+    assert!(submitted.len() > 10, "{}", submitted.len());
+}
+
+/// Miners following 10 templates a second with the `--high-bps` settings,
+/// submitting shares for the job from three templates ago, as miners
+/// switching jobs slowly do. Every miner has to end up with the last job
+/// and get all its shares accepted.
+async fn high_bps(miners: usize, secs: u64) {
+    let config = Config {
+        job_expiry_daa: Some(20),
+        wide_job_ids: true,
+        ..Default::default()
+    };
+    let (stratum, addr) = serve(config).await;
+    let templates = secs as usize * 10;
+    let last_job = format!("{templates:04x}");
+
+    let mut tasks = vec![];
+    for i in 0..miners {
+        let last_job = last_job.clone();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        tasks.push(tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let login = format!(
+                "{}\n{}\n",
+                r#"{"id":1,"method":"mining.subscribe","params":["loadtest/1.0"]}"#,
+                format_args!(
+                    r#"{{"id":2,"method":"mining.authorize","params":["kaspa:qz0000.rig{i}"]}}"#
+                ),
+            );
+            writer.write_all(login.as_bytes()).await.unwrap();
+            let (mut extranonce, mut jobs, mut accepted) = (String::new(), vec![], 0);
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let msg: Value = serde_json::from_str(&line).unwrap();
+                if msg["method"] == "set_extranonce" {
+                    extranonce = msg["params"][0].as_str().unwrap().to_string();
+                } else if msg["method"] == "mining.notify" {
+                    let job = msg["params"][0].as_str().unwrap().to_string();
+                    if job == last_job {
+                        return (jobs.len(), accepted);
+                    }
+                    jobs.push(job);
+                    if jobs.len() > 3 && jobs.len() % 5 == 0 {
+                        let submit = format!(
+                            r#"{{"id":{},"method":"mining.submit","params":["kaspa:qz0000.rig{i}","{}","0x{extranonce}{:012x}"]}}"#,
+                            jobs.len() + 2,
+                            jobs[jobs.len() - 4],
+                            jobs.len()
+                        );
+                        writer.write_all(submit.as_bytes()).await.unwrap();
+                        writer.write_all(b"\n").await.unwrap();
+                    }
+                } else if msg["method"].is_null() && msg["id"].as_u64().is_some_and(|id| id > 2) {
+                    assert_eq!(msg["result"], true, "rig{i}: {msg}");
+                    accepted += 1;
+                }
+            }
+            panic!("rig{i} disconnected");
+        }));
+    }
+    // Everyone got the first job
+    time::sleep(Duration::from_millis(500)).await;
+
+    let mut interval = time::interval(Duration::from_millis(100));
+    for i in 1..=templates as u64 {
+        interval.tick().await;
+        let mut template = template();
+        template.header.as_mut().unwrap().daa_score += i;
+        stratum.broadcast(template).await;
+    }
+    let (mut notified, mut accepted) = (0, 0);
+    for task in tasks {
+        let (jobs, shares) = time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("every miner gets the last job")
+            .unwrap();
+        notified += jobs;
+        accepted += shares;
+    }
+    // Slow readers may skip jobs, but most arrive
+    assert!(notified >= miners * templates / 2, "{notified} notifies");
+    assert!(accepted > 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn high_bps_templates() {
+    high_bps(100, 2).await;
+}
+
+/// `cargo test --release -- --ignored high_bps_thousands`
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn high_bps_thousands() {
+    high_bps(3000, 10).await;
+}